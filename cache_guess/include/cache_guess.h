@@ -0,0 +1,89 @@
+/*
+ * C interface of the cache_guess matcher, from src/capi.rs.
+ * Build the library with: cargo build --release --features capi
+ * and link against target/release/libcache_guess.so or libcache_guess.a.
+ */
+
+#ifndef CACHE_GUESS_H
+#define CACHE_GUESS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/* fs-block size: the unit that is hashed and matched. */
+#define CG_BLOCK_SIZE 8192
+/* Bytes of a digest from cg_digest. */
+#define CG_DIGEST_BYTES 20
+
+typedef struct CgIndex CgIndex;
+
+/* The best origin cache block for one cache block, by aligned fs-block hits. */
+typedef struct CgMatch {
+    uint64_t cache_block;
+    uint64_t origin_block;
+    /* fs-blocks of the cache block found at their own position in origin_block. */
+    uint32_t count;
+    uint32_t fs_blocks;
+    /* Another origin block has as many hits; origin_block is the lowest of them. */
+    bool tied;
+} CgMatch;
+
+/* Fills buf with len bytes from offset of the caller's device; returns 0 on success. */
+typedef int (*CgReadFn)(void *ctx, uint64_t offset, uint8_t *buf, size_t len);
+/* How far a long call got, in bytes. */
+typedef void (*CgProgressFn)(void *ctx, uint64_t done, uint64_t total);
+/* One matched cache block. */
+typedef void (*CgMatchFn)(void *ctx, const CgMatch *result);
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Why the last call on this thread that returned NULL failed; valid until the next failing call. */
+const char *cg_last_error(void);
+
+/* Indexes origin_size bytes read through read; progress may be NULL. Returns NULL if a read fails. */
+CgIndex *cg_index_build(uint64_t origin_size, CgReadFn read, CgProgressFn progress, void *ctx);
+
+/* Loads an index file written by cache_guess collect. Returns NULL if it cannot be read or is damaged. */
+CgIndex *cg_index_load(const char *path);
+
+/* Size of the origin device the index covers, in bytes. */
+uint64_t cg_index_origin_size(const CgIndex *index);
+
+void cg_index_free(CgIndex *index);
+
+/* Writes the CG_DIGEST_BYTES SHA-1 digest of len bytes at data to digest. */
+void cg_digest(const uint8_t *data, size_t len, uint8_t *digest);
+
+/*
+ * Stores up to max origin offsets holding data with this digest in offsets, in ascending order,
+ * and returns how many there are in total.
+ */
+size_t cg_index_query(const CgIndex *index, const uint8_t *digest, uint64_t *offsets, size_t max);
+
+/*
+ * Like cg_index_query, for an fs-block of data hashed the way the index was: some indexes only
+ * cover the leading bytes of each block, or keep truncated digests.
+ */
+size_t cg_index_lookup(const CgIndex *index, const uint8_t *block, size_t len, uint64_t *offsets, size_t max);
+
+/*
+ * Matches every cache_block_size block of the cache device read through read against the index,
+ * calling on_match for each one with at least one hit. Returns 0, -1 if cache_block_size is not
+ * a multiple of CG_BLOCK_SIZE, or -2 if a read fails.
+ */
+int cg_find(const CgIndex *index,
+            uint64_t cache_size,
+            uint32_t cache_block_size,
+            CgReadFn read,
+            CgMatchFn on_match,
+            CgProgressFn progress,
+            void *ctx);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CACHE_GUESS_H */
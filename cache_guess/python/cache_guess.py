@@ -0,0 +1,272 @@
+"""Python bindings for the cache_guess matcher, over its C ABI (include/cache_guess.h).
+
+These are plain ctypes over the `capi` library rather than a PyO3 extension module: the build has no pyo3 crate to
+link against, and going through the C ABI keeps the one interface other recovery tools embed as well. Nothing needs
+compiling on the Python side, only the library.
+
+Build the library first with `cargo build --release --features capi`; it is looked up next to this file in
+../target/{release,debug}, or wherever CACHE_GUESS_LIB points.
+
+    import cache_guess as cg
+    index = cg.Index.load("origin.idx")
+    for m in index.find("cache.img", cache_block_size=64 * 1024):
+        print(m.cache_block, m.origin_block, m.count, m.fs_blocks)
+"""
+
+import ctypes
+import json
+import os
+from dataclasses import dataclass
+from typing import Callable, Dict, List, Optional
+
+BLOCK_SIZE = 8192
+DIGEST_BYTES = 20
+
+
+def _library_path():
+    if "CACHE_GUESS_LIB" in os.environ:
+        return os.environ["CACHE_GUESS_LIB"]
+    target = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "target")
+    for profile in ("release", "debug"):
+        path = os.path.join(target, profile, "libcache_guess.so")
+        if os.path.exists(path):
+            return path
+    return "libcache_guess.so"
+
+
+class _Match(ctypes.Structure):
+    _fields_ = [
+        ("cache_block", ctypes.c_uint64),
+        ("origin_block", ctypes.c_uint64),
+        ("count", ctypes.c_uint32),
+        ("fs_blocks", ctypes.c_uint32),
+        ("tied", ctypes.c_bool),
+    ]
+
+
+_READ = ctypes.CFUNCTYPE(ctypes.c_int, ctypes.c_void_p, ctypes.c_uint64, ctypes.POINTER(ctypes.c_uint8), ctypes.c_size_t)
+_PROGRESS = ctypes.CFUNCTYPE(None, ctypes.c_void_p, ctypes.c_uint64, ctypes.c_uint64)
+_ON_MATCH = ctypes.CFUNCTYPE(None, ctypes.c_void_p, ctypes.POINTER(_Match))
+
+_lib = ctypes.CDLL(_library_path())
+_lib.cg_last_error.restype = ctypes.c_char_p
+_lib.cg_index_build.argtypes = [ctypes.c_uint64, _READ, _PROGRESS, ctypes.c_void_p]
+_lib.cg_index_build.restype = ctypes.c_void_p
+_lib.cg_index_load.argtypes = [ctypes.c_char_p]
+_lib.cg_index_load.restype = ctypes.c_void_p
+_lib.cg_index_origin_size.argtypes = [ctypes.c_void_p]
+_lib.cg_index_origin_size.restype = ctypes.c_uint64
+_lib.cg_index_free.argtypes = [ctypes.c_void_p]
+_lib.cg_digest.argtypes = [ctypes.c_char_p, ctypes.c_size_t, ctypes.c_char_p]
+_lib.cg_index_query.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.POINTER(ctypes.c_uint64), ctypes.c_size_t]
+_lib.cg_index_query.restype = ctypes.c_size_t
+_lib.cg_index_lookup.argtypes = [
+    ctypes.c_void_p,
+    ctypes.c_char_p,
+    ctypes.c_size_t,
+    ctypes.POINTER(ctypes.c_uint64),
+    ctypes.c_size_t,
+]
+_lib.cg_index_lookup.restype = ctypes.c_size_t
+_lib.cg_find.argtypes = [ctypes.c_void_p, ctypes.c_uint64, ctypes.c_uint32, _READ, _ON_MATCH, _PROGRESS, ctypes.c_void_p]
+_lib.cg_find.restype = ctypes.c_int
+
+
+def _reader(fd):
+    def read(_ctx, offset, buf, length):
+        try:
+            data = os.pread(fd, length, offset)
+        except OSError:
+            return -1
+        if len(data) != length:
+            return -1
+        ctypes.memmove(buf, data, length)
+        return 0
+
+    return _READ(read)
+
+
+def _progress(progress):
+    return _PROGRESS(lambda _ctx, done, total: progress(done, total)) if progress else _PROGRESS()
+
+
+def _size(fd):
+    return os.lseek(fd, 0, os.SEEK_END)
+
+
+def digest(data: bytes) -> bytes:
+    """SHA-1 of `data`, as collect stores it for a whole fs-block."""
+    out = ctypes.create_string_buffer(DIGEST_BYTES)
+    _lib.cg_digest(data, len(data), out)
+    return out.raw
+
+
+@dataclass
+class Match:
+    cache_block: int
+    origin_block: int
+    count: int
+    fs_blocks: int
+    tied: bool
+
+
+class Index:
+    """An origin index held in memory."""
+
+    def __init__(self, handle):
+        self._handle = handle
+
+    @classmethod
+    def load(cls, path: str) -> "Index":
+        """Loads an index file written by `cache_guess collect`."""
+        handle = _lib.cg_index_load(os.fsencode(path))
+        if not handle:
+            raise OSError(_lib.cg_last_error().decode(errors="replace"))
+        return cls(handle)
+
+    @classmethod
+    def build(cls, origin_path: str, progress: Optional[Callable[[int, int], None]] = None) -> "Index":
+        """Indexes a device or image directly, without writing an index file."""
+        fd = os.open(origin_path, os.O_RDONLY)
+        try:
+            handle = _lib.cg_index_build(_size(fd), _reader(fd), _progress(progress), None)
+        finally:
+            os.close(fd)
+        if not handle:
+            raise OSError(_lib.cg_last_error().decode(errors="replace"))
+        return cls(handle)
+
+    def __del__(self):
+        if getattr(self, "_handle", None):
+            _lib.cg_index_free(self._handle)
+            self._handle = None
+
+    @property
+    def origin_size(self) -> int:
+        return _lib.cg_index_origin_size(self._handle)
+
+    @staticmethod
+    def _offsets(call) -> List[int]:
+        count = call(None, 0)
+        offsets = (ctypes.c_uint64 * count)()
+        call(offsets, count)
+        return list(offsets)
+
+    def query(self, block_digest: bytes) -> List[int]:
+        """Origin offsets of fs-blocks with this SHA-1 digest."""
+        if len(block_digest) != DIGEST_BYTES:
+            raise ValueError("digest must be %d bytes" % DIGEST_BYTES)
+        return self._offsets(lambda offsets, count: _lib.cg_index_query(self._handle, block_digest, offsets, count))
+
+    def lookup(self, block: bytes) -> List[int]:
+        """Origin offsets of fs-blocks holding this data, hashed the way the index was."""
+        return self._offsets(lambda offsets, count: _lib.cg_index_lookup(self._handle, block, len(block), offsets, count))
+
+    def candidates(self, data: bytes, cache_block_size: int) -> Dict[int, int]:
+        """Origin cache blocks by how many fs-blocks of `data`, one cache block, they hold at the same position."""
+        fs_blocks = cache_block_size // BLOCK_SIZE
+        counts: Dict[int, int] = {}
+        for fs_block in range(fs_blocks):
+            block = data[fs_block * BLOCK_SIZE : (fs_block + 1) * BLOCK_SIZE]
+            for offset in self.lookup(block):
+                if (offset // BLOCK_SIZE) % fs_blocks == fs_block:
+                    counts[offset // cache_block_size] = counts.get(offset // cache_block_size, 0) + 1
+        return counts
+
+    def find(
+        self,
+        cache_path: str,
+        cache_block_size: int,
+        progress: Optional[Callable[[int, int], None]] = None,
+    ) -> List[Match]:
+        """The best origin block of every cache block with at least one hit."""
+        matches = []
+
+        def on_match(_ctx, result):
+            r = result.contents
+            matches.append(Match(r.cache_block, r.origin_block, r.count, r.fs_blocks, r.tied))
+
+        fd = os.open(cache_path, os.O_RDONLY)
+        try:
+            status = _lib.cg_find(
+                self._handle,
+                _size(fd),
+                cache_block_size,
+                _reader(fd),
+                _ON_MATCH(on_match),
+                _progress(progress),
+                None,
+            )
+        finally:
+            os.close(fd)
+        if status == -1:
+            raise ValueError("cache_block_size must be a multiple of %d" % BLOCK_SIZE)
+        if status != 0:
+            raise OSError("reading %s failed" % cache_path)
+        return matches
+
+
+@dataclass
+class Mapping:
+    """One best-match line of a find result, or a copy line of plan-writeback or classify."""
+
+    cache_block: int
+    origin_block: int
+    tied: bool
+    match_percent: Optional[float] = None
+    confidence: Optional[float] = None
+    note: str = ""
+
+
+def _parse_text(line: str) -> Optional[Mapping]:
+    cache_block, sep, rest = line.partition(" -> ")
+    if not sep:
+        return None
+    if " (" in rest:
+        origin_block, _, stats = rest.partition(" (")
+        percent, _, rest = stats.partition("% match, ")
+        confidence, _, note = rest.partition(" confidence)")
+        return Mapping(
+            int(cache_block),
+            int(origin_block),
+            " [tied]" in note,
+            float(percent),
+            float(confidence),
+            note.strip(),
+        )
+    origin_block, sep, note = rest.partition(" # ")
+    if not sep:
+        return None
+    return Mapping(int(cache_block), int(origin_block), " [tied]" in note, note=note)
+
+
+def read_mapping(path: str) -> List[Mapping]:
+    """Reads a find result (text or --format jsonl), a plan-writeback plan or a classify output."""
+    mappings = []
+    with open(path) as f:
+        for number, line in enumerate(f, 1):
+            line = line.rstrip("\n")
+            if not line.strip() or line.startswith("#") or line.startswith(("{\"checkpoint\":", "{\"device\":", "{\"provenance\":")):
+                continue
+            if line.startswith("{"):
+                record = json.loads(line)
+                if record["candidates"]:
+                    best = record["candidates"][0]
+                    mappings.append(
+                        Mapping(
+                            record["cache_block"],
+                            best["origin_block"],
+                            record.get("tie", False),
+                            best["match"],
+                            best["confidence"],
+                        )
+                    )
+                continue
+            try:
+                mapping = _parse_text(line)
+            except ValueError:
+                mapping = None
+            if mapping is None:
+                raise ValueError("%s:%d: not a mapping line: %r" % (path, number, line))
+            mappings.append(mapping)
+    return mappings
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+use memmap2::Advice;
+
+use crate::{inuse, MappedFile};
+
+/// How to read devices: sequential passes with sorted, batched confirmations for rotational disks,
+/// parallel random reads for solid-state ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Sequential,
+    Parallel,
+}
+
+/// Whether the device `path` is (or, for an image file, lives on) a rotational disk, if sysfs says.
+fn rotational(path: &Path) -> Option<bool> {
+    let mut sysfs = inuse::backing_sysfs_dir(path)?;
+    if sysfs.join("partition").exists() {
+        sysfs.pop();
+    }
+    let value = fs::read_to_string(sysfs.join("queue/rotational")).ok()?;
+    Some(value.trim() == "1")
+}
+
+impl Strategy {
+    /// Picks the strategy for a set of devices from `--access`; `auto` goes sequential if any of them is
+    /// rotational or unknown, since random reads are the expensive mistake.
+    pub fn choose(access: &str, paths: &[&Path]) -> Self {
+        let strategy = match access {
+            "hdd" => Self::Sequential,
+            "ssd" => Self::Parallel,
+            _ if paths.iter().all(|path| rotational(path) == Some(false)) => Self::Parallel,
+            _ => Self::Sequential,
+        };
+        eprintln!("access strategy: {:?} ({})", strategy, access);
+        strategy
+    }
+
+    pub fn advise(self, device: &MappedFile, scanned: bool) {
+        let advice = match (self, scanned) {
+            (_, true) => Advice::Sequential,
+            (Self::Sequential, false) => Advice::Normal,
+            (Self::Parallel, false) => Advice::Random,
+        };
+        // Only a hint; not every kind of mapping supports it.
+        let _ = device.mmap.advise(advice);
+    }
+
+    /// Evaluates `check` for every item, in ascending `key` order when sequential, across threads otherwise.
+    pub fn run<T: Sync>(
+        self,
+        items: &mut [T],
+        key: impl Fn(&T) -> usize,
+        check: impl Fn(&T) -> bool + Sync,
+    ) -> Vec<bool> {
+        match self {
+            Self::Sequential => {
+                items.sort_by_key(key);
+                items.iter().map(check).collect()
+            }
+            Self::Parallel => {
+                let threads = thread::available_parallelism().map_or(1, |n| n.get());
+                let chunk = items.len().div_ceil(threads).max(1);
+                thread::scope(|scope| {
+                    let workers: Vec<_> = items
+                        .chunks(chunk)
+                        .map(|part| scope.spawn(|| part.iter().map(&check).collect::<Vec<bool>>()))
+                        .collect();
+                    workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+                })
+            }
+        }
+    }
+}
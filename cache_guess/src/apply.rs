@@ -0,0 +1,726 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::block_errors::{self, Kind};
+use crate::cache_xml::CacheMetadata;
+use crate::confirm;
+use crate::conflict::{self, Conflict};
+use crate::critical::Critical;
+use crate::hash;
+use crate::identity::Identity;
+use crate::mapping::{self, MappingEntry};
+use crate::provenance;
+use crate::snapshot::Snapshot;
+use crate::throttle::Throttle;
+use crate::verify::Reader;
+use crate::{hash_block, inuse, log_complete, log_status, open_device, WritableMappedFile};
+
+pub struct ApplyOptions<'a> {
+    pub cache_block_size: usize,
+    pub partition: Option<usize>,
+    pub origin_partition: Option<usize>,
+    pub journal: &'a Path,
+    pub resume: bool,
+    pub batch: usize,
+    /// Write in origin order, copying runs of adjacent blocks in one piece, rather than in mapping order.
+    pub coalesce: bool,
+    /// Read what the origin holds where each block goes and leave it alone when it is the same already.
+    pub skip_identical: bool,
+    pub conflict: Conflict,
+    pub bwlimit: Option<f64>,
+    /// Read every batch back from the origin device once it is synced and compare it with the cache blocks.
+    pub verify: bool,
+    /// Write an image file directly instead of a clone of it that replaces it once every copy is done.
+    pub in_place: bool,
+    /// Copy-on-write store file and size of a dm-snapshot to write an origin block device through.
+    pub snapshot_cow: Option<(&'a Path, usize)>,
+    /// Write without asking for the origin device name first.
+    pub yes: bool,
+    /// Critical origin ranges, see `Critical::read`; the blocks covering them are written first.
+    pub priority_ranges: Option<&'a Path>,
+    pub priority_fs: Option<&'a str>,
+    /// Writes the blocks the policy hints of `expected_mapping` rate hottest first, after the critical ones.
+    pub hot_first: bool,
+    /// cache_dump XML whose mapping decides between entries that claim the same block.
+    pub expected_mapping: Option<&'a Path>,
+    /// Where to list the entries that claimed the same block and the cache blocks with identical data.
+    pub duplicates: Option<&'a Path>,
+    pub force: bool,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// Where the copies of an image file go until they are all done: a clone of the image next to it.
+fn staging_path(origin_path: &Path) -> PathBuf {
+    let mut name = origin_path.as_os_str().to_owned();
+    name.push(".apply.tmp");
+    PathBuf::from(name)
+}
+
+/// Clones an image file as a reflink where the filesystem shares extents (btrfs, XFS), or copies it.
+fn clone_image(source: &Path, target: &Path) -> io::Result<()> {
+    let from = File::open(source)?;
+    let to = File::create(target)?;
+    if unsafe { libc::ioctl(to.as_raw_fd(), FICLONE as _, from.as_raw_fd()) } == 0 {
+        eprintln!("writing to a reflinked clone, {}", target.display());
+    } else {
+        eprintln!(
+            "cannot reflink ({}), copying {} to {} first",
+            io::Error::last_os_error(),
+            source.display(),
+            target.display()
+        );
+        drop(to);
+        fs::copy(source, target)?;
+    }
+    File::open(target)?.sync_all()
+}
+
+/// A redo log of block copies: each batch is written and synced as `begin`/`copy` lines before any data
+/// is touched, and closed with `commit` once the origin has been synced. Copies are idempotent because
+/// the cache device is never modified, so an uncommitted batch can simply be replayed. With `--verify`, copies
+/// that read back wrong are recorded as `mismatch` lines before the commit and are replayed like uncommitted ones.
+struct Journal {
+    file: File,
+}
+
+struct JournalState {
+    done: HashSet<(usize, usize)>,
+    pending: Vec<(usize, usize)>,
+    next_batch: usize,
+}
+
+impl Journal {
+    fn create(path: &Path, params: &str) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| {
+                if e.kind() == io::ErrorKind::AlreadyExists {
+                    invalid(format!(
+                        "journal {} already exists, use --resume-apply to continue it",
+                        path.display()
+                    ))
+                } else {
+                    e
+                }
+            })?;
+        writeln!(file, "# cache_guess apply journal")?;
+        writeln!(file, "{}", params)?;
+        file.sync_all()?;
+        Ok(Self { file })
+    }
+
+    fn resume(path: &Path, params: &str) -> io::Result<(Self, JournalState)> {
+        let mut state = JournalState {
+            done: HashSet::new(),
+            pending: Vec::new(),
+            next_batch: 0,
+        };
+        let mut open_batch: Option<(usize, Vec<(usize, usize)>)> = None;
+        // Copies of the open batch that read back wrong.
+        let mut mismatched: HashSet<(usize, usize)> = HashSet::new();
+        let mut saw_params = false;
+
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with("params ") {
+                if line != params {
+                    return Err(invalid(format!(
+                        "journal was written with different parameters:\n  journal: {}\n  now:     {}",
+                        line, params
+                    )));
+                }
+                saw_params = true;
+                continue;
+            }
+            let fields: Vec<&str> = line.split(' ').collect();
+            let numbers: Option<Vec<usize>> = fields[1..].iter().map(|f| f.parse().ok()).collect();
+            // A torn final line from a crash parses as garbage and is ignored.
+            match (fields[0], numbers.as_deref()) {
+                ("begin", Some(&[batch])) => {
+                    if let Some((_, copies)) = open_batch.take() {
+                        state.pending.extend(copies);
+                    }
+                    open_batch = Some((batch, Vec::new()));
+                    mismatched.clear();
+                    state.next_batch = batch + 1;
+                }
+                ("copy", Some(&[cache_block, origin_block])) => {
+                    if let Some((_, copies)) = open_batch.as_mut() {
+                        copies.push((cache_block, origin_block));
+                    }
+                }
+                ("mismatch", Some(&[cache_block, origin_block])) => {
+                    mismatched.insert((cache_block, origin_block));
+                }
+                ("commit", Some(&[batch])) => {
+                    if let Some((open, copies)) = open_batch.take() {
+                        if open == batch {
+                            for copy in copies {
+                                if mismatched.contains(&copy) {
+                                    state.done.remove(&copy);
+                                    state.pending.push(copy);
+                                } else {
+                                    state.done.insert(copy);
+                                }
+                            }
+                        } else {
+                            state.pending.extend(copies);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !saw_params {
+            return Err(invalid(format!("{} is not an apply journal", path.display())));
+        }
+        if let Some((_, copies)) = open_batch {
+            state.pending.extend(copies);
+        }
+        state.pending.retain(|copy| !state.done.contains(copy));
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok((Self { file }, state))
+    }
+
+    fn begin(&mut self, batch: usize, copies: &[(usize, usize)]) -> io::Result<()> {
+        let mut record = format!("begin {}\n", batch);
+        for (cache_block, origin_block) in copies {
+            record += &format!("copy {} {}\n", cache_block, origin_block);
+        }
+        self.file.write_all(record.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    fn mismatch(&mut self, (cache_block, origin_block): (usize, usize)) -> io::Result<()> {
+        writeln!(self.file, "mismatch {} {}", cache_block, origin_block)
+    }
+
+    fn commit(&mut self, batch: usize) -> io::Result<()> {
+        writeln!(self.file, "commit {}", batch)?;
+        self.file.sync_data()
+    }
+}
+
+/// The `params` line of a journal, which a resume must match: besides the sizes, a digest of the mapping file and
+/// the size and fingerprint of the cache device, so that neither can change between the runs.
+fn journal_params(
+    mapping_path: &Path,
+    cache_device: &Reader,
+    cache_block_size: usize,
+    origin_size: usize,
+    entries: usize,
+) -> io::Result<String> {
+    Ok(format!(
+        "params cache_block_size={} origin_size={} entries={} mapping={:016x} cache_size={} cache_fingerprint={}",
+        cache_block_size,
+        origin_size,
+        entries,
+        hash::xxh64(&fs::read(mapping_path)?),
+        cache_device.size(),
+        provenance::fingerprint(&Identity::of(cache_device.view()))
+    ))
+}
+
+/// `run` cache blocks from `cache_block` on, read in one piece, or block by block when that fails, so that only
+/// the unreadable ones, recorded as block errors, are left out; which ones were read comes alongside.
+fn read_run(cache_device: &Reader, cache_block: usize, run: usize, cache_block_size: usize) -> (Vec<u8>, Vec<bool>) {
+    if run > 1 {
+        if let Ok(data) = cache_device.read_at(cache_block * cache_block_size, run * cache_block_size) {
+            return (data, vec![true; run]);
+        }
+    }
+    let mut data = vec![0; run * cache_block_size];
+    let readable = (0..run)
+        .map(|block| match cache_device.read(cache_block + block, cache_block_size) {
+            Ok(read) => {
+                data[block * cache_block_size..(block + 1) * cache_block_size].copy_from_slice(&read);
+                true
+            }
+            Err(_) => false,
+        })
+        .collect();
+    (data, readable)
+}
+
+pub fn apply(
+    mapping_path: &Path,
+    cache_device_path: &Path,
+    origin_path: &Path,
+    options: &ApplyOptions,
+) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path, origin_path], options.force)?;
+
+    let cache_block_size = options.cache_block_size;
+    let (tied, entries): (Vec<MappingEntry>, Vec<MappingEntry>) =
+        mapping::read(mapping_path)?.into_iter().partition(|entry| entry.tied);
+    if !tied.is_empty() {
+        eprintln!(
+            "skipping {} cache blocks with tied candidates, starting with {}",
+            tied.len(),
+            tied[0].cache_block
+        );
+    }
+    // Read with plain reads, so that a bad sector of the cache device skips its block instead of raising SIGBUS.
+    let cache_device = Reader::open(cache_device_path, options.partition)?;
+    let entries = conflict::resolve_copies(
+        entries,
+        options.conflict,
+        &cache_device,
+        cache_block_size,
+        options.expected_mapping,
+        options.duplicates,
+    )?;
+    // Opened for its partition window only; data goes through a separate writable mapping.
+    let origin_view = open_device(origin_path, options.origin_partition)?;
+    // A half-written image may be the only copy there is, so image files are written as a clone that replaces
+    // them at the end. Devices are written in place; the journal is what makes those safe to interrupt.
+    let is_file = fs::metadata(origin_path)?.is_file();
+    let critical = Critical::read(options.priority_ranges, options.priority_fs, Some(&origin_view))?;
+    let is_critical = |origin_block: usize| {
+        critical
+            .as_ref()
+            .is_some_and(|critical| critical.touches(origin_block * cache_block_size, cache_block_size))
+    };
+    let hints = match options.expected_mapping.filter(|_| options.hot_first) {
+        Some(path) => CacheMetadata::read(path)?.hints,
+        None => HashMap::new(),
+    };
+    let hotness = |cache_block: usize| Reverse(hints.get(&cache_block).copied());
+    if is_file && options.snapshot_cow.is_some() {
+        return Err(invalid(
+            "--snapshot-cow is for block devices, image files are written through a clone of them already".to_string(),
+        ));
+    }
+    let staging = (is_file && !options.in_place).then(|| staging_path(origin_path));
+
+    for entry in &entries {
+        if (entry.cache_block + 1) * cache_block_size > cache_device.size() {
+            return Err(invalid(format!(
+                "cache block {} is past the cache device end",
+                entry.cache_block
+            )));
+        }
+        if (entry.origin_block + 1) * cache_block_size > origin_view.size() {
+            return Err(invalid(format!(
+                "origin block {} is past the origin device end",
+                entry.origin_block
+            )));
+        }
+    }
+
+    let params = journal_params(
+        mapping_path,
+        &cache_device,
+        cache_block_size,
+        origin_view.size(),
+        entries.len(),
+    )?;
+    let resumed = options
+        .resume
+        .then(|| Journal::resume(options.journal, &params))
+        .transpose()?;
+    let remaining: Vec<&MappingEntry> = entries
+        .iter()
+        .filter(|entry| {
+            resumed
+                .as_ref()
+                .is_none_or(|(_, state)| !state.done.contains(&(entry.cache_block, entry.origin_block)))
+        })
+        .collect();
+    let how = match (&staging, options.snapshot_cow) {
+        (Some(_), _) => "through a clone that replaces it at the end",
+        (None, Some(_)) => "through a dm-snapshot, leaving it untouched until merged",
+        (None, None) => "in place",
+    };
+    let mut summary = vec![
+        format!(
+            "about to write to {} ({} bytes) {}",
+            origin_path.display(),
+            origin_view.size(),
+            how
+        ),
+        format!("  from cache device {}", cache_device_path.display()),
+        format!(
+            "  {} cache blocks of {} bytes, {} bytes{}",
+            remaining.len(),
+            cache_block_size,
+            remaining.len() * cache_block_size,
+            if options.resume { " still to copy" } else { "" }
+        ),
+        format!("  confidence: {}", confirm::confidence_distribution(&remaining)),
+    ];
+    if critical.is_some() {
+        summary.push(format!(
+            "  {} of them covering critical origin ranges, written first",
+            remaining.iter().filter(|entry| is_critical(entry.origin_block)).count()
+        ));
+    }
+    if options.hot_first {
+        summary.push(format!(
+            "  {} of them with policy hints, written hottest first",
+            remaining
+                .iter()
+                .filter(|entry| hints.contains_key(&entry.cache_block))
+                .count()
+        ));
+    }
+    confirm::confirm(origin_path, &summary, options.yes)?;
+    let (mut journal, state) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let journal = Journal::create(options.journal, &params)?;
+            let state = JournalState {
+                done: HashSet::new(),
+                pending: Vec::new(),
+                next_batch: 0,
+            };
+            (journal, state)
+        }
+    };
+    if options.resume {
+        eprintln!(
+            "resuming: {} copies done, {} to replay",
+            state.done.len(),
+            state.pending.len()
+        );
+    }
+    let snapshot = options
+        .snapshot_cow
+        .map(|(cow, size)| Snapshot::setup(origin_path, origin_view.mmap.len(), cow, size, options.resume))
+        .transpose()?;
+    let target = match &staging {
+        Some(staging) if options.resume && staging.exists() => {
+            eprintln!("continuing in {}", staging.display());
+            staging.as_path()
+        }
+        Some(staging) => {
+            // Unless the interrupted run got to replace the image, its copies are lost with the clone.
+            if options.resume
+                && !state.done.is_empty()
+                && !entries
+                    .iter()
+                    .all(|entry| state.done.contains(&(entry.cache_block, entry.origin_block)))
+            {
+                return Err(invalid(format!(
+                    "{} with the interrupted run's copies is gone, start over without --resume-apply",
+                    staging.display()
+                )));
+            }
+            clone_image(origin_path, staging)?;
+            staging.as_path()
+        }
+        None => snapshot
+            .as_ref()
+            .map_or(origin_path, |snapshot| snapshot.device.as_path()),
+    };
+    let mut origin = WritableMappedFile::open_existing(target)?.window(origin_view.base, origin_view.size())?;
+
+    let pending: HashSet<(usize, usize)> = state.pending.iter().copied().collect();
+    let mut copies = state.pending.clone();
+    copies.extend(
+        entries
+            .iter()
+            .map(|entry| (entry.cache_block, entry.origin_block))
+            .filter(|copy| !state.done.contains(copy) && !pending.contains(copy)),
+    );
+    if options.coalesce {
+        copies.sort_by_key(|&(cache_block, origin_block)| {
+            (!is_critical(origin_block), hotness(cache_block), origin_block)
+        });
+        let runs = 1 + copies.windows(2).filter(|pair| pair[1].1 != pair[0].1 + 1).count();
+        eprintln!(
+            "writing in origin order, {} copies in {} runs of adjacent origin blocks",
+            copies.len(),
+            if copies.is_empty() { 0 } else { runs }
+        );
+    } else {
+        copies.sort_by_key(|&(cache_block, origin_block)| (!is_critical(origin_block), hotness(cache_block)));
+    }
+
+    let mut throttle = Throttle::new(options.bwlimit);
+    let total = copies.len();
+    let (mut mismatches, mut identical, mut unreadable) = (0, 0, HashSet::new());
+    for (chunk_index, chunk) in copies.chunks(options.batch.max(1)).enumerate() {
+        log_status(chunk_index * options.batch, total, "blocks", false);
+        let batch = state.next_batch + chunk_index;
+        journal.begin(batch, chunk)?;
+        let mut at = 0;
+        while at < chunk.len() {
+            let (cache_block, origin_block) = chunk[at];
+            // Blocks that follow each other on both devices are read and written in one piece.
+            let run = match options.coalesce {
+                true => {
+                    1 + chunk[at + 1..]
+                        .iter()
+                        .zip(1..)
+                        .take_while(|&(&(next_cache, next_origin), n)| {
+                            next_cache == cache_block + n && next_origin == origin_block + n
+                        })
+                        .count()
+                }
+                false => 1,
+            };
+            let len = run * cache_block_size;
+            let (data, readable) = read_run(&cache_device, cache_block, run, cache_block_size);
+            let offset = origin_block * cache_block_size;
+            throttle.consume(len);
+            unreadable.extend(
+                (0..run)
+                    .filter(|&block| !readable[block])
+                    .map(|block| (cache_block + block, origin_block + block)),
+            );
+            // What an earlier partial recovery wrote already is left out, the rest of a run still in one piece.
+            let differs: Vec<bool> = (0..run)
+                .map(|block| {
+                    let at = block * cache_block_size;
+                    readable[block]
+                        && (!options.skip_identical
+                            || origin.slice(offset + at, cache_block_size) != &data[at..at + cache_block_size])
+                })
+                .collect();
+            if options.skip_identical {
+                throttle.consume(len);
+                identical += (0..run).filter(|&block| readable[block] && !differs[block]).count();
+            }
+            let mut block = 0;
+            while block < run {
+                let blocks = differs[block..].iter().take_while(|&&differs| differs).count();
+                if blocks == 0 {
+                    block += 1;
+                    continue;
+                }
+                let (at, len) = (block * cache_block_size, blocks * cache_block_size);
+                throttle.consume(len);
+                origin.slice_mut(offset + at, len).copy_from_slice(&data[at..at + len]);
+                block += blocks;
+            }
+            at += run;
+        }
+        if let Err(e) = origin.flush() {
+            for &(_, origin_block) in chunk {
+                block_errors::record(
+                    Kind::IoWrite,
+                    origin_path,
+                    origin_block,
+                    cache_block_size,
+                    e.to_string(),
+                );
+            }
+            return Err(e);
+        }
+        if options.verify {
+            for &(cache_block, origin_block) in chunk.iter().filter(|copy| !unreadable.contains(copy)) {
+                let offset = origin_block * cache_block_size;
+                origin.evict(offset, cache_block_size)?;
+                throttle.consume(cache_block_size);
+                let Ok(data) = cache_device.read(cache_block, cache_block_size) else {
+                    continue;
+                };
+                if hash_block(origin.slice(offset, cache_block_size)) != hash_block(&data) {
+                    eprintln!(
+                        "\norigin block {} reads back different from cache block {}",
+                        origin_block, cache_block
+                    );
+                    journal.mismatch((cache_block, origin_block))?;
+                    block_errors::record(
+                        Kind::Checksum,
+                        origin_path,
+                        origin_block,
+                        cache_block_size,
+                        format!("reads back different from cache block {}", cache_block),
+                    );
+                    mismatches += 1;
+                }
+            }
+        }
+        journal.commit(batch)?;
+    }
+    log_complete(total, "blocks");
+    if options.skip_identical {
+        eprintln!(
+            "\n{} blocks written, {} held the same already and were skipped, journal {}",
+            total - identical - unreadable.len(),
+            identical,
+            options.journal.display()
+        );
+    } else {
+        eprintln!(
+            "\n{} blocks written, journal {}",
+            total - unreadable.len(),
+            options.journal.display()
+        );
+    }
+    if !unreadable.is_empty() {
+        eprintln!(
+            "{} of {} cache blocks could not be read and were skipped",
+            unreadable.len(),
+            total
+        );
+    }
+    if let Some(staging) = staging.as_ref().filter(|_| mismatches == 0) {
+        drop(origin);
+        fs::rename(staging, origin_path)?;
+        let directory = origin_path.parent().filter(|parent| !parent.as_os_str().is_empty());
+        File::open(directory.unwrap_or(Path::new(".")))?.sync_all()?;
+        eprintln!("{} replaced by the written clone", origin_path.display());
+    }
+    if let Some(snapshot) = &snapshot {
+        snapshot.check()?;
+        snapshot.print_instructions();
+    }
+    if options.verify {
+        eprintln!(
+            "{} of them verified, {} read back wrong",
+            total - unreadable.len() - mismatches,
+            mismatches
+        );
+    }
+    if mismatches > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} blocks did not read back as written; --resume-apply writes them again",
+                mismatches
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: &str = "params cache_block_size=65536 cache=cache.img origin=origin.img";
+
+    fn journal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cache_guess-{}-{}.journal", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn sorted(copies: impl IntoIterator<Item = (usize, usize)>) -> Vec<(usize, usize)> {
+        let mut copies: Vec<(usize, usize)> = copies.into_iter().collect();
+        copies.sort_unstable();
+        copies
+    }
+
+    #[test]
+    fn resume_replays_uncommitted_and_mismatched_copies() {
+        let path = journal_path("resume");
+        let mut journal = Journal::create(&path, PARAMS).unwrap();
+        journal.begin(0, &[(0, 10), (1, 11)]).unwrap();
+        journal.commit(0).unwrap();
+        journal.begin(1, &[(2, 12), (3, 13)]).unwrap();
+        journal.mismatch((3, 13)).unwrap();
+        journal.commit(1).unwrap();
+        journal.begin(2, &[(4, 14)]).unwrap();
+        drop(journal);
+
+        let (_, state) = Journal::resume(&path, PARAMS).unwrap();
+        assert_eq!(sorted(state.done), [(0, 10), (1, 11), (2, 12)]);
+        assert_eq!(sorted(state.pending), [(3, 13), (4, 14)]);
+        assert_eq!(state.next_batch, 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resume_ignores_a_torn_last_line() {
+        let path = journal_path("torn");
+        let mut journal = Journal::create(&path, PARAMS).unwrap();
+        journal.begin(0, &[(0, 10)]).unwrap();
+        journal.commit(0).unwrap();
+        journal.begin(1, &[(1, 11)]).unwrap();
+        drop(journal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "comm").unwrap();
+        drop(file);
+
+        let (_, state) = Journal::resume(&path, PARAMS).unwrap();
+        assert_eq!(sorted(state.done), [(0, 10)]);
+        assert_eq!(state.pending, [(1, 11)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resume_refuses_other_parameters() {
+        let path = journal_path("params");
+        Journal::create(&path, PARAMS).unwrap();
+        let other = PARAMS.replace("65536", "32768");
+        let e = Journal::resume(&path, &other).err().unwrap();
+        assert!(
+            e.to_string().contains("journal was written with different parameters"),
+            "{}",
+            e
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn params_bind_the_mapping_and_the_cache_device() {
+        let dir = std::env::temp_dir();
+        let mapping = dir.join(format!("cache_guess-params-{}.txt", std::process::id()));
+        let cache = dir.join(format!("cache_guess-params-{}.img", std::process::id()));
+        let params = || {
+            let cache_device = Reader::open(&cache, None).unwrap();
+            journal_params(&mapping, &cache_device, 65536, 1 << 20, 2).unwrap()
+        };
+        fs::write(&mapping, "0 -> 3\n1 -> 4\n").unwrap();
+        fs::write(&cache, vec![1; 131072]).unwrap();
+        let first = params();
+        assert_eq!(params(), first);
+
+        fs::write(&mapping, "0 -> 3\n1 -> 5\n").unwrap();
+        let other_mapping = params();
+        fs::write(&mapping, "0 -> 3\n1 -> 4\n").unwrap();
+        fs::write(&cache, [vec![2; 4096], vec![1; 131072 - 4096]].concat()).unwrap();
+        let other_contents = params();
+        fs::write(&cache, vec![1; 196608]).unwrap();
+        let other_size = params();
+        fs::remove_file(&mapping).unwrap();
+        fs::remove_file(&cache).unwrap();
+        for other in [&other_mapping, &other_contents, &other_size] {
+            assert_ne!(other, &first);
+        }
+
+        let path = journal_path("bound");
+        Journal::create(&path, &first).unwrap();
+        let e = Journal::resume(&path, &other_mapping).err().unwrap();
+        assert!(e.to_string().contains("different parameters"), "{}", e);
+        assert!(Journal::resume(&path, &first).is_ok());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn create_refuses_an_existing_journal() {
+        let path = journal_path("exists");
+        Journal::create(&path, PARAMS).unwrap();
+        let e = Journal::create(&path, PARAMS).err().unwrap();
+        assert!(e.to_string().contains("use --resume-apply"), "{}", e);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resume_refuses_a_file_that_is_no_journal() {
+        let path = journal_path("not-a-journal");
+        fs::write(&path, "0 -> 3\n1 -> 4\n").unwrap();
+        let e = Journal::resume(&path, PARAMS).err().unwrap();
+        assert!(e.to_string().contains("is not an apply journal"), "{}", e);
+        fs::remove_file(path).unwrap();
+    }
+}
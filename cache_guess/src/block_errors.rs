@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::output::{json_string, Output};
+use crate::provenance;
+
+/// The exit status of a run that went through but left blocks behind on errors, apart from the 1 of a run that
+/// stopped on one.
+pub const EXIT_CODE: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    /// A block that could not be read.
+    IoRead,
+    /// A block that could not be written, or was not written through.
+    IoWrite,
+    /// A block whose data is not what its checksum or a read-back says it should be.
+    Checksum,
+    /// A block whose metadata or mapping entry makes no sense.
+    Format,
+}
+
+const KINDS: [Kind; 4] = [Kind::IoRead, Kind::IoWrite, Kind::Checksum, Kind::Format];
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::IoRead => "io-read",
+            Kind::IoWrite => "io-write",
+            Kind::Checksum => "checksum",
+            Kind::Format => "format",
+        }
+    }
+}
+
+struct Record {
+    kind: Kind,
+    device: String,
+    block: usize,
+    block_size: usize,
+    detail: String,
+}
+
+/// Every block skipped over an error so far, in the order the errors came up.
+static RECORDS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+/// The kind, device, block and block size of each of them.
+static SEEN: Mutex<BTreeSet<(Kind, String, usize, usize)>> = Mutex::new(BTreeSet::new());
+
+/// Notes that `block` of `block_size` bytes of `device` was skipped over an error, for the summary at the end of
+/// the run; whoever records it carries on without the block. A block read again, on a later pass or for another
+/// shard, is noted once: false when it was already.
+pub fn record(kind: Kind, device: &Path, block: usize, block_size: usize, detail: impl Into<String>) -> bool {
+    let device = device.display().to_string();
+    if !SEEN.lock().unwrap().insert((kind, device.clone(), block, block_size)) {
+        return false;
+    }
+    RECORDS.lock().unwrap().push(Record {
+        kind,
+        device,
+        block,
+        block_size,
+        detail: detail.into(),
+    });
+    true
+}
+
+pub fn count() -> usize {
+    RECORDS.lock().unwrap().len()
+}
+
+fn counts(records: &[Record]) -> Vec<(Kind, usize)> {
+    KINDS
+        .iter()
+        .map(|&kind| (kind, records.iter().filter(|record| record.kind == kind).count()))
+        .collect()
+}
+
+/// The line of counts for people, of the kinds there were any of.
+fn describe(records: &[Record]) -> String {
+    format!(
+        "{} blocks skipped over errors: {}",
+        records.len(),
+        counts(records)
+            .iter()
+            .filter(|&&(_, count)| count > 0)
+            .map(|(kind, count)| format!("{} {}", count, kind.name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// The summary of `records` as one JSON object: the command line, counts by kind and a record per block.
+fn to_json(records: &[Record], command: &str) -> String {
+    let blocks: Vec<String> = records
+        .iter()
+        .map(|record| {
+            format!(
+                "{{\"kind\": \"{}\", \"device\": {}, \"block\": {}, \"block_size\": {}, \"detail\": {}}}",
+                record.kind.name(),
+                json_string(&record.device),
+                record.block,
+                record.block_size,
+                json_string(&record.detail)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"command\": {}, \"errors\": {}, \"by_kind\": {{{}}}, \"blocks\": [{}]}}",
+        json_string(command),
+        records.len(),
+        counts(records)
+            .iter()
+            .map(|(kind, count)| format!("\"{}\": {}", kind.name(), count))
+            .collect::<Vec<_>>()
+            .join(", "),
+        blocks.join(", ")
+    )
+}
+
+/// Writes the block errors of the run as `to_json`, to `path` or without it to stderr, after the line of
+/// counts. Nothing when there were none.
+pub fn summarize(path: Option<&Path>) -> io::Result<()> {
+    let records = RECORDS.lock().unwrap();
+    if records.is_empty() {
+        return Ok(());
+    }
+    eprintln!("\n{}", describe(&records));
+    let json = to_json(&records, &provenance::command_line());
+    match path {
+        Some(path) => {
+            let mut out = Output::create(Some(path))?;
+            writeln!(out, "{}", json)?;
+            out.commit()?;
+            eprintln!("error summary written to {}", path.display());
+        }
+        None => eprintln!("{}", json),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_of(kind: Kind, device: &str, block: usize, detail: &str) -> Record {
+        Record {
+            kind,
+            device: device.to_string(),
+            block,
+            block_size: 65536,
+            detail: detail.to_string(),
+        }
+    }
+
+    #[test]
+    fn summary_counts_every_kind_and_lists_the_blocks() {
+        let records = [
+            record_of(Kind::IoRead, "/dev/sdb", 7, "read error: Input/output error"),
+            record_of(Kind::Checksum, "origin.img", 3, "reads back \"different\""),
+            record_of(Kind::IoRead, "/dev/sdb", 9, "read error: Input/output error"),
+        ];
+        assert_eq!(
+            describe(&records),
+            "3 blocks skipped over errors: 2 io-read, 1 checksum"
+        );
+        assert_eq!(
+            to_json(&records, "cache_guess find origin.idx /dev/sdb"),
+            "{\"command\": \"cache_guess find origin.idx /dev/sdb\", \"errors\": 3, \
+             \"by_kind\": {\"io-read\": 2, \"io-write\": 0, \"checksum\": 1, \"format\": 0}, \"blocks\": [\
+             {\"kind\": \"io-read\", \"device\": \"/dev/sdb\", \"block\": 7, \"block_size\": 65536, \
+             \"detail\": \"read error: Input/output error\"}, \
+             {\"kind\": \"checksum\", \"device\": \"origin.img\", \"block\": 3, \"block_size\": 65536, \
+             \"detail\": \"reads back \\\"different\\\"\"}, \
+             {\"kind\": \"io-read\", \"device\": \"/dev/sdb\", \"block\": 9, \"block_size\": 65536, \
+             \"detail\": \"read error: Input/output error\"}]}"
+        );
+    }
+
+    #[test]
+    fn summary_of_nothing_is_empty() {
+        assert_eq!(
+            to_json(&[], "cache_guess verify"),
+            "{\"command\": \"cache_guess verify\", \"errors\": 0, \
+             \"by_kind\": {\"io-read\": 0, \"io-write\": 0, \"checksum\": 0, \"format\": 0}, \"blocks\": []}"
+        );
+    }
+
+    #[test]
+    fn records_each_block_once() {
+        // A device of its own, as the records are shared by the whole process.
+        let device = Path::new("block-errors-test.img");
+        assert!(record(Kind::IoRead, device, 4, 4096, "first pass"));
+        assert!(!record(Kind::IoRead, device, 4, 4096, "second pass"));
+        assert!(record(Kind::IoRead, device, 4, 65536, "as a cache block"));
+        assert!(record(Kind::Format, device, 4, 4096, "past the device end"));
+        let records = RECORDS.lock().unwrap();
+        let details: Vec<&str> = records
+            .iter()
+            .filter(|record| record.device == "block-errors-test.img")
+            .map(|record| record.detail.as_str())
+            .collect();
+        assert_eq!(details, ["first pass", "as a cache block", "past the device end"]);
+    }
+}
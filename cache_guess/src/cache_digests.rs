@@ -0,0 +1,118 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::identity::Identity;
+use crate::output::Output;
+use crate::{MappedFile, BLOCK_SIZE, HASH_BYTES};
+
+const MAGIC: &[u8; 8] = b"CGCDIG01";
+const HEADER: usize = 128;
+const FILTER: u32 = 1;
+
+/// The digest of every fs-block of a cache device, hashed by `collect --cache-device` alongside the origin, for
+/// `find` to look the fs-blocks up without reading and hashing them again. Each record is the SHA-1 of the leading
+/// bytes the index covers, then for two-tier indexes the xxh64 filter hash of them.
+pub struct CacheDigests {
+    map: MappedFile,
+    pub sample: usize,
+    pub filter: bool,
+    record: usize,
+}
+
+fn record_len(filter: bool) -> usize {
+    HASH_BYTES + if filter { 8 } else { 0 }
+}
+
+impl CacheDigests {
+    pub fn sidecar_path(index_path: &Path) -> PathBuf {
+        let mut path = index_path.as_os_str().to_owned();
+        path.push(".cache-digests");
+        PathBuf::from(path)
+    }
+
+    /// The digests stored next to the index, if there are any and they are of `device` as it is now.
+    pub fn open(index_path: &Path, device: &MappedFile) -> io::Result<Option<Self>> {
+        let path = Self::sidecar_path(index_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let map = MappedFile::open(&path)?;
+        let header = map.slice(0, HEADER.min(map.size()));
+        let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap()) as usize;
+        if header.len() < HEADER || &header[..8] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: not a cache digest file", path.display()),
+            ));
+        }
+        let filter = u32_at(12) & FILTER != 0;
+        let record = record_len(filter);
+        let fs_blocks = u64_at(24);
+        let recorded = Identity::decode(&header[32..]);
+        if u64_at(16) != device.size()
+            || fs_blocks != device.size() / BLOCK_SIZE
+            || map.size() != HEADER + fs_blocks * record
+            || !recorded.mismatches(&Identity::of(device)).is_empty()
+        {
+            eprintln!(
+                "{} is of another cache device, or this one as it was; hashing its fs-blocks instead",
+                path.display()
+            );
+            return Ok(None);
+        }
+        let sample = u32_at(8) as usize;
+        eprintln!(
+            "looking up the cache device's fs-blocks by the digests in {}",
+            path.display()
+        );
+        Ok(Some(Self {
+            map,
+            sample,
+            filter,
+            record,
+        }))
+    }
+
+    pub fn digest(&self, fs_block: usize) -> &[u8] {
+        self.map.slice(HEADER + fs_block * self.record, HASH_BYTES)
+    }
+
+    pub fn filter_hash(&self, fs_block: usize) -> u64 {
+        let at = HEADER + fs_block * self.record + HASH_BYTES;
+        u64::from_le_bytes(self.map.slice(at, 8).try_into().unwrap())
+    }
+}
+
+/// Writes the records of a cache device's fs-blocks in order, replacing the file once they are all there.
+pub struct CacheDigestsWriter {
+    out: Output,
+    filter: bool,
+}
+
+impl CacheDigestsWriter {
+    pub fn create(index_path: &Path, device: &MappedFile, sample: usize, filter: bool) -> io::Result<Self> {
+        let mut header = [0u8; HEADER];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&(sample as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(if filter { FILTER } else { 0 }).to_le_bytes());
+        header[16..24].copy_from_slice(&(device.size() as u64).to_le_bytes());
+        header[24..32].copy_from_slice(&((device.size() / BLOCK_SIZE) as u64).to_le_bytes());
+        Identity::of(device).encode(&mut header[32..]);
+        let mut out = Output::create(Some(&CacheDigests::sidecar_path(index_path)))?;
+        out.write_all(&header)?;
+        Ok(Self { out, filter })
+    }
+
+    pub fn push(&mut self, digest: &[u8], filter_hash: u64) -> io::Result<()> {
+        self.out.write_all(&digest[..HASH_BYTES])?;
+        if self.filter {
+            self.out.write_all(&filter_hash.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.out.commit()
+    }
+}
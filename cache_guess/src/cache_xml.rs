@@ -0,0 +1,233 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::provenance;
+
+/// A cache block mapping as recorded in dm-cache metadata.
+#[derive(Debug, Clone, Copy)]
+pub struct Recorded {
+    pub origin_block: usize,
+    pub dirty: bool,
+}
+
+/// The parts of a `cache_dump` XML file needed to check guesses against.
+#[derive(Debug, Default)]
+pub struct CacheMetadata {
+    /// In sectors, like `--cache-block-size`.
+    pub block_size: Option<usize>,
+    pub mappings: HashMap<usize, Recorded>,
+    /// The policy hint of each cache block; for smq, how hot the block is, a level up to 63.
+    pub hints: HashMap<usize, u32>,
+}
+
+fn invalid(path: &Path, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(at) = rest.find(name) {
+        let before = rest[..at].chars().next_back();
+        rest = &rest[at + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        if let Some(value) = rest.trim_start().strip_prefix('=') {
+            let value = value.trim_start();
+            let quote = value.chars().next()?;
+            let value = &value[1..];
+            return value.find(quote).map(|end| &value[..end]);
+        }
+    }
+    None
+}
+
+/// The bytes of a hint's `data`, which `cache_dump` writes in base64.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// The contents of every `<name ...>` tag, without the brackets.
+fn tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}", name);
+    xml.match_indices(open.as_str())
+        .filter_map(|(start, _)| {
+            let tag = &xml[start + open.len()..];
+            if !tag.starts_with(char::is_whitespace) {
+                return None;
+            }
+            tag.find('>').map(|end| tag[..end].trim_end_matches('/'))
+        })
+        .collect()
+}
+
+impl CacheMetadata {
+    /// `cache_blocks` that have a hint, hottest first and in ascending order within a level.
+    pub fn hottest_first(&self, cache_blocks: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut hot: Vec<(u32, usize)> = cache_blocks
+            .filter_map(|cache_block| self.hints.get(&cache_block).map(|&level| (level, cache_block)))
+            .collect();
+        hot.sort_unstable_by_key(|&(level, cache_block)| (Reverse(level), cache_block));
+        hot.into_iter().map(|(_, cache_block)| cache_block).collect()
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::parse(path, true).map(|(metadata, _)| metadata)
+    }
+
+    /// Reads what survives of damaged metadata: broken and duplicate mappings are dropped and counted instead of
+    /// failing the whole file.
+    pub fn read_partial(path: &Path) -> io::Result<(Self, usize)> {
+        Self::parse(path, false)
+    }
+
+    fn parse(path: &Path, strict: bool) -> io::Result<(Self, usize)> {
+        // A damaged dump may not even be valid UTF-8.
+        let xml = String::from_utf8_lossy(&fs::read(path)?).into_owned();
+        let mut metadata = Self::default();
+        let mut dropped = 0;
+        if let Some(superblock) = tags(&xml, "superblock").first() {
+            metadata.block_size = attribute(superblock, "block_size").and_then(|size| size.parse().ok());
+        }
+        for tag in tags(&xml, "mapping") {
+            let number = |name: &str| {
+                attribute(tag, name)
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .ok_or_else(|| invalid(path, format!("mapping without a valid {}: <mapping{}>", name, tag)))
+            };
+            let parsed = number("cache_block").and_then(|cache_block| {
+                let recorded = Recorded {
+                    origin_block: number("origin_block")?,
+                    dirty: attribute(tag, "dirty") == Some("true"),
+                };
+                if metadata.mappings.contains_key(&cache_block) {
+                    return Err(invalid(path, format!("cache block {} is mapped twice", cache_block)));
+                }
+                Ok((cache_block, recorded))
+            });
+            match parsed {
+                Ok((cache_block, recorded)) => {
+                    metadata.mappings.insert(cache_block, recorded);
+                }
+                Err(e) if strict => return Err(e),
+                Err(_) => dropped += 1,
+            }
+        }
+        // Only an ordering aid, so hints that do not parse are left out whatever the mode.
+        for tag in tags(&xml, "hint") {
+            let hint = attribute(tag, "cache_block")
+                .and_then(|value| value.parse::<usize>().ok())
+                .zip(
+                    attribute(tag, "data")
+                        .and_then(decode_base64)
+                        .filter(|data| data.len() <= 4),
+                );
+            if let Some((cache_block, data)) = hint {
+                let mut level = [0; 4];
+                level[..data.len()].copy_from_slice(&data);
+                metadata.hints.insert(cache_block, u32::from_le_bytes(level));
+            }
+        }
+        if metadata.mappings.is_empty() {
+            return Err(invalid(
+                path,
+                "no mappings found, is this cache_dump output?".to_string(),
+            ));
+        }
+        provenance::record_input(path, format!("cache metadata, {} mappings", metadata.mappings.len()));
+        Ok((metadata, dropped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str, xml: &str, strict: bool) -> io::Result<(CacheMetadata, usize)> {
+        let path = std::env::temp_dir().join(format!("cache_guess-{}-{}.xml", name, std::process::id()));
+        fs::write(&path, xml).unwrap();
+        let parsed = CacheMetadata::parse(&path, strict);
+        fs::remove_file(path).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("Zm9vYmFy").unwrap(), b"foobar");
+        assert_eq!(decode_base64("Zm9vYg==").unwrap(), b"foob");
+        assert_eq!(decode_base64("KgAAAA==").unwrap(), [42, 0, 0, 0]);
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert!(decode_base64("Kg*A").is_none());
+    }
+
+    #[test]
+    fn reads_hints_as_little_endian_levels() {
+        let xml = r#"<superblock uuid="" block_size="128" nr_cache_blocks="4" policy="smq" hint_width="4">
+  <mappings>
+    <mapping cache_block="0" origin_block="9" dirty="false"/>
+    <mapping cache_block="1" origin_block="3" dirty="true"/>
+  </mappings>
+  <hints>
+    <hint cache_block="0" data="BwAAAA=="/>
+    <hint cache_block="1" data="PwAAAA=="/>
+    <hint cache_block="2" data="AQ=="/>
+    <hint cache_block="3" data="not base64!"/>
+    <hint cache_block="4" data="AAAAAAAAAAA="/>
+  </hints>
+</superblock>"#;
+        let (metadata, dropped) = parse("hints", xml, true).unwrap();
+        assert_eq!(dropped, 0);
+        assert_eq!(metadata.block_size, Some(128));
+        assert!(metadata.mappings[&1].dirty && !metadata.mappings[&0].dirty);
+        assert_eq!(metadata.hints.get(&0), Some(&7));
+        assert_eq!(metadata.hints.get(&1), Some(&63));
+        assert_eq!(metadata.hints.get(&2), Some(&1));
+        // Hints that do not decode, or are wider than a level, are left out.
+        assert!(!metadata.hints.contains_key(&3) && !metadata.hints.contains_key(&4));
+        assert_eq!(metadata.hottest_first(0..5), [1, 0, 2]);
+    }
+
+    #[test]
+    fn hottest_first_keeps_block_order_within_a_level() {
+        let metadata = CacheMetadata {
+            hints: [(5, 2), (1, 2), (3, 9), (4, 0)].into_iter().collect(),
+            ..CacheMetadata::default()
+        };
+        assert_eq!(metadata.hottest_first(0..6), [3, 1, 5, 4]);
+        assert_eq!(metadata.hottest_first([5, 4].into_iter()), [5, 4]);
+    }
+
+    #[test]
+    fn partial_reads_drop_what_strict_ones_refuse() {
+        let xml = r#"<mapping cache_block="0" origin_block="1" dirty="false"/>
+<mapping cache_block="0" origin_block="2" dirty="false"/>
+<mapping cache_block="1" dirty="false"/>"#;
+        let e = parse("strict", xml, true).unwrap_err();
+        assert!(e.to_string().contains("cache block 0 is mapped twice"), "{}", e);
+        let (metadata, dropped) = parse("partial", xml, false).unwrap();
+        assert_eq!(dropped, 2);
+        assert_eq!(metadata.mappings[&0].origin_block, 1);
+    }
+}
@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::{ptr, slice};
+
+use crate::hash::{self, Key, SortedIndex};
+use crate::index::Index;
+use crate::{BLOCK_SIZE, HASH_BYTES};
+
+/// Fills `buf` with `len` bytes from `offset` of the caller's device; returns 0 on success.
+pub type ReadFn = unsafe extern "C" fn(ctx: *mut c_void, offset: u64, buf: *mut u8, len: usize) -> c_int;
+/// How far a long call got, in bytes; may be null.
+pub type ProgressFn = Option<unsafe extern "C" fn(ctx: *mut c_void, done: u64, total: u64)>;
+/// One matched cache block.
+pub type MatchFn = unsafe extern "C" fn(ctx: *mut c_void, result: *const CgMatch);
+
+/// The best origin cache block for one cache block, by aligned fs-block hits.
+#[repr(C)]
+pub struct CgMatch {
+    pub cache_block: u64,
+    pub origin_block: u64,
+    /// fs-blocks of the cache block found at their own position in `origin_block`.
+    pub count: u32,
+    pub fs_blocks: u32,
+    /// Another origin block has as many hits; `origin_block` is the lowest of them.
+    pub tied: bool,
+}
+
+/// An in-memory index of an origin device.
+pub struct CgIndex {
+    digests: SortedIndex<Key>,
+    digest_len: usize,
+    /// Leading bytes of each fs-block the digests cover.
+    sample: usize,
+    origin_size: usize,
+}
+
+impl CgIndex {
+    fn key(&self, block: &[u8]) -> Key {
+        let digest = hash::hash_blocks(&[&block[..self.sample.min(block.len())]]).remove(0);
+        hash::key(&digest[..self.digest_len])
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(msg: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).unwrap_or_default());
+}
+
+/// Why the last call on this thread that returned null failed; valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn cg_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+unsafe fn read_into(read: ReadFn, ctx: *mut c_void, offset: usize, buf: &mut [u8]) -> bool {
+    read(ctx, offset as u64, buf.as_mut_ptr(), buf.len()) == 0
+}
+
+unsafe fn report(progress: ProgressFn, ctx: *mut c_void, done: usize, total: usize) {
+    if let Some(progress) = progress {
+        progress(ctx, done as u64, total as u64);
+    }
+}
+
+/// Indexes `origin_size` bytes read through `read`, `CG_BLOCK_SIZE` bytes at a time per fs-block.
+/// Returns null if a read fails; free the index with `cg_index_free`.
+///
+/// # Safety
+///
+/// `read` must fill the buffer it is given; `ctx` is passed to both callbacks untouched.
+#[no_mangle]
+pub unsafe extern "C" fn cg_index_build(
+    origin_size: u64,
+    read: ReadFn,
+    progress: ProgressFn,
+    ctx: *mut c_void,
+) -> *mut CgIndex {
+    let origin_size = origin_size as usize;
+    let mut entries = Vec::with_capacity(origin_size.div_ceil(BLOCK_SIZE));
+    let mut chunk = vec![0; BLOCK_SIZE * hash::BATCH];
+    for offset in (0..origin_size).step_by(chunk.len()) {
+        report(progress, ctx, offset, origin_size);
+        let data = &mut chunk[..(origin_size - offset).min(BLOCK_SIZE * hash::BATCH)];
+        if !read_into(read, ctx, offset, data) {
+            set_error(format!("reading {} bytes at {} failed", data.len(), offset));
+            return ptr::null_mut();
+        }
+        let blocks: Vec<&[u8]> = data.chunks(BLOCK_SIZE).collect();
+        for (i, digest) in hash::hash_blocks(&blocks).into_iter().enumerate() {
+            entries.push((hash::key(&digest), offset + i * BLOCK_SIZE));
+        }
+    }
+    report(progress, ctx, origin_size, origin_size);
+    Box::into_raw(Box::new(CgIndex {
+        digests: SortedIndex::new(entries),
+        digest_len: HASH_BYTES,
+        sample: BLOCK_SIZE,
+        origin_size,
+    }))
+}
+
+/// Loads an index file written by `cache_guess collect`. Returns null if it cannot be read or is damaged, see
+/// `cg_last_error`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cg_index_load(path: *const c_char) -> *mut CgIndex {
+    let path = Path::new(OsStr::from_bytes(CStr::from_ptr(path).to_bytes()));
+    let load = || {
+        let index = Index::open(path)?;
+        let mut entries = Vec::with_capacity(index.entry_count());
+        index.for_each(|entry, digest| entries.push((hash::key(digest), entry * BLOCK_SIZE)))?;
+        Ok::<_, std::io::Error>(CgIndex {
+            digests: SortedIndex::new(entries),
+            digest_len: index.digest_len(),
+            sample: index.sample_bytes().unwrap_or(BLOCK_SIZE),
+            origin_size: index
+                .origin()
+                .map_or(index.entry_count() * BLOCK_SIZE, |(size, _)| size),
+        })
+    };
+    match load() {
+        Ok(index) => Box::into_raw(Box::new(index)),
+        Err(e) => {
+            set_error(format!("{}: {}", path.display(), e));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Size of the origin device the index covers, in bytes.
+///
+/// # Safety
+///
+/// `index` must be a live index.
+#[no_mangle]
+pub unsafe extern "C" fn cg_index_origin_size(index: *const CgIndex) -> u64 {
+    (*index).origin_size as u64
+}
+
+/// # Safety
+///
+/// `index` must come from `cg_index_build` and not be used afterwards; null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn cg_index_free(index: *mut CgIndex) {
+    if !index.is_null() {
+        drop(Box::from_raw(index));
+    }
+}
+
+/// Writes the `CG_DIGEST_BYTES` SHA-1 digest of `len` bytes at `data` to `digest`.
+///
+/// # Safety
+///
+/// `data` must be readable for `len` bytes and `digest` writable for `CG_DIGEST_BYTES`.
+#[no_mangle]
+pub unsafe extern "C" fn cg_digest(data: *const u8, len: usize, digest: *mut u8) {
+    let hashed = hash::hash_blocks(&[slice::from_raw_parts(data, len)]).remove(0);
+    ptr::copy_nonoverlapping(hashed.as_ptr(), digest, HASH_BYTES);
+}
+
+/// Stores up to `max` origin offsets holding data with this digest in `offsets`, in ascending order, and
+/// returns how many there are in total.
+///
+/// # Safety
+///
+/// `index` must be a live index, `digest` readable for `CG_DIGEST_BYTES` and `offsets` writable for `max`.
+#[no_mangle]
+pub unsafe extern "C" fn cg_index_query(
+    index: *const CgIndex,
+    digest: *const u8,
+    offsets: *mut u64,
+    max: usize,
+) -> usize {
+    let index = &*index;
+    let key = hash::key(&slice::from_raw_parts(digest, HASH_BYTES)[..index.digest_len]);
+    store_offsets(index, key, offsets, max)
+}
+
+/// Like `cg_index_query`, for an fs-block of data hashed the way the index was: some indexes only cover the
+/// leading bytes of each block, or keep truncated digests.
+///
+/// # Safety
+///
+/// `index` must be a live index, `block` readable for `len` bytes and `offsets` writable for `max`.
+#[no_mangle]
+pub unsafe extern "C" fn cg_index_lookup(
+    index: *const CgIndex,
+    block: *const u8,
+    len: usize,
+    offsets: *mut u64,
+    max: usize,
+) -> usize {
+    let index = &*index;
+    store_offsets(index, index.key(slice::from_raw_parts(block, len)), offsets, max)
+}
+
+unsafe fn store_offsets(index: &CgIndex, key: Key, offsets: *mut u64, max: usize) -> usize {
+    let mut total = 0;
+    for offset in index.digests.get(key) {
+        if total < max {
+            *offsets.add(total) = offset as u64;
+        }
+        total += 1;
+    }
+    total
+}
+
+/// Matches every `cache_block_size` block of a `cache_size` byte cache device read through `read` against
+/// the index, calling `on_match` for each one with at least one hit. Returns 0, -1 if `cache_block_size` is
+/// not a multiple of `CG_BLOCK_SIZE`, or -2 if a read fails.
+///
+/// # Safety
+///
+/// `index` must be a live index and `read` must fill the buffer it is given; `ctx` is passed to all
+/// callbacks untouched.
+#[no_mangle]
+pub unsafe extern "C" fn cg_find(
+    index: *const CgIndex,
+    cache_size: u64,
+    cache_block_size: u32,
+    read: ReadFn,
+    on_match: MatchFn,
+    progress: ProgressFn,
+    ctx: *mut c_void,
+) -> c_int {
+    let cache_block_size = cache_block_size as usize;
+    if cache_block_size == 0 || !cache_block_size.is_multiple_of(BLOCK_SIZE) {
+        return -1;
+    }
+    let fs_blocks = cache_block_size / BLOCK_SIZE;
+    let cache_blocks = cache_size as usize / cache_block_size;
+    let total = cache_blocks * cache_block_size;
+    let mut data = vec![0; cache_block_size];
+    for cache_block in 0..cache_blocks {
+        report(progress, ctx, cache_block * cache_block_size, total);
+        if !read_into(read, ctx, cache_block * cache_block_size, &mut data) {
+            return -2;
+        }
+        let index = &*index;
+        let blocks: Vec<&[u8]> = data.chunks(BLOCK_SIZE).map(|block| &block[..index.sample]).collect();
+        let mut counts: HashMap<usize, u32> = HashMap::new();
+        for (fs_block, digest) in hash::hash_blocks(&blocks).into_iter().enumerate() {
+            for origin_offset in index.digests.get(hash::key(&digest[..index.digest_len])) {
+                // Same as find: only hits at the fs-block's own position count towards a mapping.
+                if (origin_offset / BLOCK_SIZE) % fs_blocks == fs_block {
+                    *counts.entry(origin_offset / cache_block_size).or_insert(0) += 1;
+                }
+            }
+        }
+        let Some((&origin_block, &count)) = counts.iter().max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0))) else {
+            continue;
+        };
+        let result = CgMatch {
+            cache_block: cache_block as u64,
+            origin_block: origin_block as u64,
+            count,
+            fs_blocks: fs_blocks as u32,
+            tied: counts.values().filter(|&&other| other == count).count() > 1,
+        };
+        on_match(ctx, &result);
+    }
+    report(progress, ctx, total, total);
+    0
+}
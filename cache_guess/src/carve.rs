@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::output::Output;
+use crate::selection::Selection;
+use crate::{inuse, log_complete, log_status, luks, mapping, open_device, provenance};
+
+/// Files and filesystem structures start on a block boundary, so signatures are only looked for at these.
+const STEP: usize = 512;
+
+pub struct CarveOptions<'a> {
+    pub cache_block_size: usize,
+    pub partition: Option<usize>,
+    pub blocks: Option<&'a Selection>,
+    /// Scan every cache block this `find` result has no mapping for.
+    pub unmatched: Option<&'a Path>,
+    pub output: Option<&'a Path>,
+    pub force: bool,
+}
+
+/// Looks past the magic where it is short enough to turn up by chance; gets the data from the start on, and
+/// returns what it tells about it.
+type Check = fn(&[u8]) -> Option<String>;
+
+struct Signature {
+    name: &'static str,
+    /// Where the magic is, from the start of what it identifies.
+    offset: usize,
+    magic: &'static [u8],
+    check: Option<Check>,
+}
+
+const fn signature(name: &'static str, offset: usize, magic: &'static [u8], check: Option<Check>) -> Signature {
+    Signature {
+        name,
+        offset,
+        magic,
+        check,
+    }
+}
+
+const SIGNATURES: &[Signature] = &[
+    signature("jpeg", 0, b"\xff\xd8\xff", None),
+    signature("png", 0, b"\x89PNG\r\n\x1a\n", None),
+    signature("gif", 0, b"GIF8", None),
+    signature("pdf", 0, b"%PDF-", None),
+    signature("zip (also docx, odt, jar, apk)", 0, b"PK\x03\x04", None),
+    signature("gzip", 0, b"\x1f\x8b\x08", None),
+    signature("bzip2", 0, b"BZh", Some(bzip2)),
+    signature("xz", 0, b"\xfd7zXZ\x00", None),
+    signature("zstd", 0, b"\x28\xb5\x2f\xfd", None),
+    signature("7z", 0, b"7z\xbc\xaf\x27\x1c", None),
+    signature("sqlite", 0, b"SQLite format 3\x00", None),
+    signature("ole2 (doc, xls, msg)", 0, b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", None),
+    signature("elf", 0, b"\x7fELF", None),
+    signature("tar", 257, b"ustar", None),
+    signature("mp4/mov", 4, b"ftyp", None),
+    signature("riff", 0, b"RIFF", Some(riff)),
+    signature("luks header", 0, luks::MAGIC, None),
+    signature("lvm2 label", 0, b"LABELONE", None),
+    signature("ext4 superblock", 56, b"\x53\xef", Some(ext4)),
+    signature("xfs superblock", 0, b"XFSB", Some(xfs)),
+];
+
+fn u32_le(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn bzip2(data: &[u8]) -> Option<String> {
+    // A block size digit, then the block magic, the BCD digits of pi.
+    (data.len() >= 10 && (b'1'..=b'9').contains(&data[3]) && &data[4..10] == b"\x31\x41\x59\x26\x53\x59")
+        .then(String::new)
+}
+
+fn riff(data: &[u8]) -> Option<String> {
+    let kind = data.get(8..12)?;
+    kind.iter()
+        .all(|byte| byte.is_ascii_alphanumeric() || *byte == b' ')
+        .then(|| String::from_utf8_lossy(kind).trim().to_lowercase())
+}
+
+/// A 2-byte magic is everywhere, so the geometry has to be plausible too. Backup superblocks tell their group,
+/// which places the filesystem start; the primary one is 1024 bytes into it.
+fn ext4(data: &[u8]) -> Option<String> {
+    if data.len() < 0x5c {
+        return None;
+    }
+    let log_block_size = u32_le(data, 0x18);
+    let inodes = u32_le(data, 0x00);
+    let blocks_per_group = u32_le(data, 0x20);
+    let rev_level = u32_le(data, 0x4c);
+    if log_block_size > 6 || inodes == 0 || blocks_per_group == 0 || rev_level > 1 {
+        return None;
+    }
+    let block_size = 1024 << log_block_size;
+    match u16::from_le_bytes([data[0x5a], data[0x5b]]) {
+        0 => Some(format!(
+            "{} byte blocks, primary, the filesystem starts 1024 bytes before",
+            block_size
+        )),
+        group => Some(format!("{} byte blocks, backup of group {}", block_size, group)),
+    }
+}
+
+fn xfs(data: &[u8]) -> Option<String> {
+    let block_size = u32::from_be_bytes(data.get(4..8)?.try_into().unwrap());
+    (block_size.is_power_of_two() && (512..=65536).contains(&block_size)).then(|| format!("{} byte blocks", block_size))
+}
+
+/// What signatures `data` holds, by the offset of what they identify.
+fn signatures(data: &[u8]) -> Vec<(usize, &'static str, String)> {
+    let mut found = Vec::new();
+    for at in (0..data.len()).step_by(STEP) {
+        for signature in SIGNATURES {
+            let start = at + signature.offset;
+            if data.get(start..start + signature.magic.len()) != Some(signature.magic) {
+                continue;
+            }
+            let detail = match signature.check {
+                Some(check) => match check(&data[at..]) {
+                    Some(detail) => detail,
+                    None => continue,
+                },
+                None => String::new(),
+            };
+            found.push((at, signature.name, detail));
+        }
+    }
+    found
+}
+
+/// Scans cache blocks, usually the ones `find` matched nothing to, for the signatures of common file formats and
+/// filesystem structures. Dirty blocks hold the only copy of what was written last; where a file starts and what
+/// it is tells which of them are worth recovering by hand.
+pub fn carve(cache_device_path: &Path, options: &CarveOptions) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path], options.force)?;
+    let cache_device = open_device(cache_device_path, options.partition)?;
+    let cache_block_size = options.cache_block_size;
+    let total_blocks = cache_device.size() / cache_block_size;
+
+    let mut blocks: Vec<usize> = match options.blocks {
+        Some(selection) => selection.blocks(total_blocks).collect(),
+        None => Vec::new(),
+    };
+    if let Some(mapping_path) = options.unmatched {
+        let matched: HashSet<usize> = mapping::read(mapping_path)?
+            .iter()
+            .map(|entry| entry.cache_block)
+            .collect();
+        blocks.extend((0..total_blocks).filter(|block| !matched.contains(block)));
+    }
+    blocks.sort_unstable();
+    blocks.dedup();
+
+    let mut out = Output::create(options.output)?;
+    provenance::write_comments(&mut out)?;
+    writeln!(out, "# cache_block offset type")?;
+    let mut by_type: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut with_signatures = 0;
+    for (done, &cache_block) in blocks.iter().enumerate() {
+        log_status(done, blocks.len(), "blocks", false);
+        let found = signatures(cache_device.slice(cache_block * cache_block_size, cache_block_size));
+        with_signatures += usize::from(!found.is_empty());
+        for (offset, name, detail) in found {
+            if detail.is_empty() {
+                writeln!(out, "{} {} {}", cache_block, offset, name)?;
+            } else {
+                writeln!(out, "{} {} {} ({})", cache_block, offset, name, detail)?;
+            }
+            *by_type.entry(name).or_default() += 1;
+        }
+    }
+    log_complete(blocks.len(), "blocks");
+    out.commit()?;
+    eprintln!(
+        "\n{} of {} scanned cache blocks hold file signatures",
+        with_signatures,
+        blocks.len()
+    );
+    for (name, count) in by_type {
+        eprintln!("  {:>8} {}", count, name);
+    }
+    Ok(())
+}
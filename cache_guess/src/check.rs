@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::export::{self, Extent};
+use crate::index::Index;
+use crate::mapping::{self, MappingEntry};
+use crate::{open_device, BLOCK_SIZE};
+
+pub struct CheckOptions<'a> {
+    pub cache_block_size: usize,
+    /// The index the mapping was found with, for the origin size.
+    pub index: Option<&'a Path>,
+    pub cache_device: Option<&'a Path>,
+    pub min_confidence: Option<f64>,
+}
+
+fn extent_range(extent: &Extent) -> String {
+    format!(
+        "cache blocks {}-{} -> origin blocks {}-{}",
+        extent.cache_block,
+        extent.cache_block + extent.blocks - 1,
+        extent.origin_block,
+        extent.origin_block + extent.blocks - 1
+    )
+}
+
+/// Checks a mapping the way fsck checks a filesystem: prints every problem found and fails if there were any.
+pub fn check(mapping_path: &Path, options: &CheckOptions) -> io::Result<()> {
+    let cache_block_size = options.cache_block_size;
+    let entries = mapping::read(mapping_path)?;
+    let mut problems = Vec::new();
+
+    if !cache_block_size.is_multiple_of(BLOCK_SIZE) {
+        problems.push(format!(
+            "cache block size {} is not a multiple of the {} byte fs-block size indexes use",
+            cache_block_size, BLOCK_SIZE
+        ));
+    }
+    let origin_blocks = match options.index.map(Index::open).transpose()? {
+        Some(index) => match index.origin() {
+            Some((origin_size, _)) => {
+                if !origin_size.is_multiple_of(cache_block_size) {
+                    println!(
+                        "note: the indexed origin ({} bytes) ends in a partial cache block",
+                        origin_size
+                    );
+                }
+                Some(origin_size.div_ceil(cache_block_size))
+            }
+            None => {
+                println!("note: legacy index without a recorded origin size, origin blocks are not range checked");
+                None
+            }
+        },
+        None => None,
+    };
+    let cache_blocks = options
+        .cache_device
+        .map(|path| open_device(path, None))
+        .transpose()?
+        .map(|device| device.size() / cache_block_size);
+
+    let mut by_cache_block: HashMap<usize, Vec<&MappingEntry>> = HashMap::new();
+    let mut by_origin_block: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut tied = 0;
+    for entry in &entries {
+        by_cache_block.entry(entry.cache_block).or_default().push(entry);
+        by_origin_block
+            .entry(entry.origin_block)
+            .or_default()
+            .push(entry.cache_block);
+        if entry.tied {
+            tied += 1;
+        }
+        if origin_blocks.is_some_and(|origin_blocks| entry.origin_block >= origin_blocks) {
+            problems.push(format!(
+                "cache block {}: origin block {} is past the origin end ({} blocks)",
+                entry.cache_block,
+                entry.origin_block,
+                origin_blocks.unwrap()
+            ));
+        }
+        if cache_blocks.is_some_and(|cache_blocks| entry.cache_block >= cache_blocks) {
+            problems.push(format!(
+                "cache block {} is past the cache device end ({} blocks)",
+                entry.cache_block,
+                cache_blocks.unwrap()
+            ));
+        }
+        match (options.min_confidence, entry.confidence) {
+            (Some(floor), Some(confidence)) if confidence < floor => problems.push(format!(
+                "cache block {}: confidence {:.3} is below {:.3}",
+                entry.cache_block, confidence, floor
+            )),
+            _ => {}
+        }
+    }
+
+    let mut repeated: Vec<(&usize, &Vec<&MappingEntry>)> =
+        by_cache_block.iter().filter(|(_, listed)| listed.len() > 1).collect();
+    repeated.sort_by_key(|(cache_block, _)| **cache_block);
+    for (cache_block, listed) in repeated {
+        let origins: Vec<String> = listed.iter().map(|entry| entry.origin_block.to_string()).collect();
+        problems.push(format!(
+            "cache block {} is listed {} times, mapped to {}",
+            cache_block,
+            listed.len(),
+            origins.join(", ")
+        ));
+    }
+    let mut shared: Vec<(&usize, &Vec<usize>)> = by_origin_block
+        .iter()
+        .filter(|(_, cache_blocks)| cache_blocks.len() > 1)
+        .collect();
+    shared.sort_by_key(|(origin_block, _)| **origin_block);
+    for (origin_block, cache_blocks) in shared {
+        let cache_blocks: Vec<String> = cache_blocks.iter().map(usize::to_string).collect();
+        problems.push(format!(
+            "cache blocks {} all map to origin block {}",
+            cache_blocks.join(", "),
+            origin_block
+        ));
+    }
+    // Single-block overlaps are the shared origin blocks above; runs are reported once per pair.
+    let mut extents = export::coalesce(&entries);
+    extents.sort_by_key(|extent| (extent.origin_block, extent.cache_block));
+    for (i, extent) in extents.iter().enumerate() {
+        for other in extents[i + 1..]
+            .iter()
+            .take_while(|other| other.origin_block < extent.origin_block + extent.blocks)
+            .filter(|other| extent.blocks > 1 || other.blocks > 1)
+        {
+            problems.push(format!(
+                "extents overlap on the origin: {} and {}",
+                extent_range(extent),
+                extent_range(other)
+            ));
+        }
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    eprintln!(
+        "{} entries in {} extents, {} tied, {} problems",
+        entries.len(),
+        extents.len(),
+        tied,
+        problems.len()
+    );
+    if !problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} problems found", mapping_path.display(), problems.len()),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,425 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::block_errors::{self, Kind};
+use crate::cache_digests::CacheDigestsWriter;
+use crate::ddrescue::BadRegions;
+use crate::direct::DirectReader;
+use crate::holes::Holes;
+use crate::identity::{self, Identity, FILESYSTEM_BYTES, SAMPLE_BYTES};
+use crate::index::{Header, Index, IndexWriter};
+use crate::layout::{self, Layout};
+use crate::readahead::{read_full, ReadAhead, StreamAhead};
+use crate::selection::Selection;
+use crate::throttle::Throttle;
+use crate::verify::Reader;
+use crate::{hash, inuse, log_complete, log_status, open_device, provenance, read_free_space, BLOCK_SIZE};
+
+pub struct CollectOptions<'a> {
+    pub partition: Option<usize>,
+    pub fs: Option<&'a str>,
+    pub digest_bytes: usize,
+    pub sample_bytes: Option<usize>,
+    pub filter_hash: bool,
+    /// An earlier index of the same device, whose digests are kept outside `changed`.
+    pub base_index: Option<&'a Path>,
+    /// Byte ranges of the origin written since the base index was built.
+    pub changed: Option<&'a Selection>,
+    pub bwlimit: Option<f64>,
+    pub force: bool,
+    /// GNU ddrescue mapfile of the origin image; blocks it does not mark finished are not indexed.
+    pub ddrescue_map: Option<&'a Path>,
+    /// Splits the index into one file per this many origin bytes.
+    pub shard_size: Option<usize>,
+    /// Writes explicit records, so skipped blocks take no room in the index.
+    pub sparse: bool,
+    /// A cache device to hash in the same run, into the digests `find` looks its fs-blocks up by.
+    pub cache_device: Option<&'a Path>,
+    pub cache_partition: Option<usize>,
+}
+
+/// Hashes a batch of blocks into their index entries.
+fn store(index_file: &mut IndexWriter, options: &CollectOptions, entries: &[usize], blocks: &[&[u8]]) {
+    for ((&entry, block), digest) in entries.iter().zip(blocks).zip(hash::hash_blocks(blocks)) {
+        index_file.set(entry, &digest[..options.digest_bytes]);
+        if options.filter_hash {
+            index_file.set_filter(entry, hash::xxh64(block));
+        }
+    }
+}
+
+/// `len` bytes of the fs-block at `offset`, read with plain reads; None if the read fails, which leaves the block
+/// out of the index and goes into the block errors of the run.
+fn read_block(device: &Reader, offset: usize, len: usize) -> Option<Vec<u8>> {
+    device
+        .read_at(offset, len)
+        .inspect_err(|e| {
+            block_errors::record(Kind::IoRead, device.path(), offset / BLOCK_SIZE, BLOCK_SIZE, e);
+        })
+        .ok()
+}
+
+/// Stores the digest of an all-zero fs-block, which is what a hole holds, without reading or hashing anything.
+fn store_zero(index_file: &mut IndexWriter, options: &CollectOptions, entry: usize, zero: &(Vec<u8>, u64)) {
+    index_file.set(entry, &zero.0[..options.digest_bytes]);
+    if options.filter_hash {
+        index_file.set_filter(entry, zero.1);
+    }
+}
+
+/// Hashes every fs-block of a cache device the way the index hashes the origin's, for `CacheDigests`. Runs on a
+/// thread of its own alongside the origin's hashing, so reading the two devices overlaps.
+fn hash_cache(index_path: &Path, device: &Reader, options: &CollectOptions, done: &AtomicUsize) -> io::Result<()> {
+    let holes = Holes::find(device.path(), device.view())?;
+    let sample = options.sample_bytes.unwrap_or(BLOCK_SIZE).min(BLOCK_SIZE);
+    let zero_block = vec![0; sample];
+    let zero = (hash::hash_blocks(&[&zero_block]).remove(0), hash::xxh64(&zero_block));
+    let mut digests = CacheDigestsWriter::create(index_path, device.view(), sample, options.filter_hash)?;
+    let mut throttle = Throttle::new(options.bwlimit);
+    let fs_blocks = device.size() / BLOCK_SIZE;
+    for first in (0..fs_blocks).step_by(hash::BATCH) {
+        let batch = first..fs_blocks.min(first + hash::BATCH);
+        // An fs-block that cannot be read gets the digest of nothing, which no index entry has.
+        let data: Vec<Option<Vec<u8>>> = batch
+            .clone()
+            .map(|fs_block| {
+                let offset = fs_block * BLOCK_SIZE;
+                (!holes.contains(offset, BLOCK_SIZE)).then(|| read_block(device, offset, sample).unwrap_or_default())
+            })
+            .collect();
+        let blocks: Vec<&[u8]> = data.iter().flatten().map(Vec::as_slice).collect();
+        throttle.consume(blocks.len() * sample);
+        let mut hashed = blocks.iter().zip(hash::hash_blocks(&blocks));
+        for data in &data {
+            if data.is_none() {
+                digests.push(&zero.0, zero.1)?;
+            } else {
+                let (block, digest) = hashed.next().unwrap();
+                digests.push(&digest, if options.filter_hash { hash::xxh64(block) } else { 0 })?;
+            }
+        }
+        done.fetch_add(batch.len() * BLOCK_SIZE, Ordering::Relaxed);
+    }
+    digests.finish()
+}
+
+/// Indexes an origin that can only be read once, front to back, such as `ddrescue` output on a pipe; `source` is
+/// what the provenance names it. Everything that needs random access to the origin (free space, partitions, base
+/// indexes) is unavailable.
+pub fn collect_stream(
+    index_path: &Path,
+    source: &Path,
+    input: &mut (impl Read + Send),
+    device_size: usize,
+    options: &CollectOptions,
+) -> io::Result<()> {
+    if options.fs.is_some()
+        || options.partition.is_some()
+        || options.base_index.is_some()
+        || options.ddrescue_map.is_some()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--fs, --partition, --base-index and --ddrescue-map need random access to the origin, not a stream",
+        ));
+    }
+    if options.cache_device.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--cache-device is hashed alongside an origin that is mapped, not streamed",
+        ));
+    }
+    let mut header = Header::new(
+        device_size,
+        options.digest_bytes,
+        options.sample_bytes,
+        options.filter_hash,
+    );
+    header.explicit = options.sparse;
+    let sample_offsets = Identity::sample_offsets(device_size);
+    let mut identity = Identity::default();
+    let mut index_file = IndexWriter::create(index_path, header, options.shard_size)?;
+    Layout::remove(index_path)?;
+    let mut throttle = Throttle::new(options.bwlimit);
+    eprintln!("hashing with {}", hash::describe());
+
+    thread::scope(|scope| {
+        let chunks = StreamAhead::spawn(scope, &mut *input, device_size, BLOCK_SIZE * hash::BATCH);
+        let mut offset = 0;
+        while offset < device_size {
+            log_status(offset, device_size, "bytes", false);
+            let wanted = (BLOCK_SIZE * hash::BATCH).min(device_size - offset);
+            let data = chunks.next()?;
+            let len = data.len();
+            if len < wanted {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("input ended after {} of {} bytes", offset + len, device_size),
+                ));
+            }
+            throttle.consume(len);
+            if offset == 0 {
+                identity.filesystem = identity::filesystem(&data[..data.len().min(FILESYSTEM_BYTES)]);
+            }
+            for &sample in sample_offsets
+                .iter()
+                .filter(|&&sample| (offset..offset + len).contains(&sample))
+            {
+                let at = sample - offset;
+                identity.fingerprint.push(hash::xxh64(&data[at..at + SAMPLE_BYTES]));
+            }
+
+            let blocks: Vec<&[u8]> = data
+                .chunks(BLOCK_SIZE)
+                .map(|block| {
+                    &block[..options
+                        .sample_bytes
+                        .map_or(block.len(), |sample| sample.min(block.len()))]
+                })
+                .collect();
+            let entries: Vec<usize> = (0..blocks.len()).map(|i| offset / BLOCK_SIZE + i).collect();
+            store(&mut index_file, options, &entries, &blocks);
+            chunks.recycle(data);
+            offset += len;
+        }
+        Ok(())
+    })?;
+    log_complete(device_size, "bytes");
+    if read_full(input, &mut [0])? > 0 {
+        eprintln!("\nwarning: input is longer than --device-size, the rest was ignored");
+    }
+    eprintln!("\norigin has {}", identity.describe());
+    provenance::record_input(
+        source,
+        format!(
+            "stream, {} bytes, fingerprint {}",
+            device_size,
+            provenance::fingerprint(&identity)
+        ),
+    );
+    index_file.set_identity(identity);
+    index_file.finish()
+}
+
+/// Indexes a single origin streamed with O_DIRECT instead of mapped, which leaves the page cache alone.
+pub fn collect_direct(index_path: &Path, path: &Path, options: &CollectOptions) -> io::Result<()> {
+    inuse::ensure_unused(&[path], options.force)?;
+    let (mut reader, device_size) = DirectReader::open(path)?;
+    eprintln!("reading {} with O_DIRECT", path.display());
+    collect_stream(index_path, path, &mut reader, device_size, options)
+}
+
+pub fn collect(index_path: &Path, device_specs: &[&str], options: &CollectOptions) -> io::Result<()> {
+    let paths = device_specs
+        .iter()
+        .map(|spec| layout::parse_spec(spec).map(|(path, _)| path))
+        .collect::<io::Result<Vec<_>>>()?;
+    inuse::ensure_unused(&paths, options.force)?;
+    if let Some(cache_device) = options.cache_device {
+        inuse::ensure_unused(&[cache_device], options.force)?;
+    }
+
+    let mut devices = Vec::with_capacity(device_specs.len());
+    let mut holes = Vec::with_capacity(device_specs.len());
+    let mut specs = Vec::with_capacity(device_specs.len());
+    for spec in device_specs {
+        let (path, offset) = layout::parse_spec(spec)?;
+        let device = open_device(path, options.partition)?;
+        specs.push((path.to_path_buf(), offset, device.size()));
+        holes.push(Holes::find(path, &device)?);
+        devices.push(Reader::over(path, device)?);
+    }
+    let layout = Layout::new(specs)?;
+    if (options.fs.is_some() || options.ddrescue_map.is_some()) && devices.len() > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--fs and --ddrescue-map need a single origin device",
+        ));
+    }
+    let bad = match options.ddrescue_map {
+        Some(map) => BadRegions::read(map, devices[0].view().base, devices[0].size())?,
+        None => BadRegions::default(),
+    };
+    if bad.total() > 0 {
+        eprintln!("ddrescue map: {} bytes not rescued, not indexed", bad.total());
+    }
+
+    let cache = options
+        .cache_device
+        .map(|path| Reader::over(path, open_device(path, options.cache_partition)?))
+        .transpose()?;
+
+    let origin_size = layout.size();
+    let total_size: usize = devices.iter().map(Reader::size).sum::<usize>() + cache.as_ref().map_or(0, Reader::size);
+    let block_count = origin_size.div_ceil(BLOCK_SIZE);
+    let free_space = read_free_space(devices[0].view(), options.fs)?;
+    let mut throttle = Throttle::new(options.bwlimit);
+
+    let mut header = Header::new(
+        origin_size,
+        options.digest_bytes,
+        options.sample_bytes,
+        options.filter_hash,
+    );
+    header.explicit = options.sparse;
+    if devices.len() == 1 {
+        header.identity = Identity::of(devices[0].view());
+        eprintln!("origin has {}", header.identity.describe());
+    }
+    let base = options.base_index.map(Index::open).transpose()?;
+    if let Some(base_path) = options.base_index {
+        if fs::canonicalize(base_path)? == fs::canonicalize(index_path).unwrap_or_default() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the base index must be a different file from the one being written",
+            ));
+        }
+    }
+    // Without a base index everything is hashed.
+    let rehash = options.changed.map(|changed| {
+        let mut rehash = vec![false; block_count];
+        for (start, end) in changed.ranges(origin_size) {
+            rehash[start / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE)].fill(true);
+        }
+        rehash
+    });
+    let mut index_file = IndexWriter::create(index_path, header, options.shard_size)?;
+    if let (Some(base), Some(rehash)) = (&base, &rehash) {
+        let copied = index_file.copy_from(base, |entry, _| !rehash[entry])?;
+        eprintln!(
+            "kept {} digests from the base index, rehashing {} blocks",
+            copied,
+            rehash.iter().filter(|&&changed| changed).count()
+        );
+    }
+    if devices.len() > 1 {
+        layout.write(&Layout::sidecar_path(index_path))?;
+    } else {
+        Layout::remove(index_path)?;
+    }
+
+    let zero_block = vec![0; options.sample_bytes.unwrap_or(BLOCK_SIZE).min(BLOCK_SIZE)];
+    let zero = (hash::hash_blocks(&[&zero_block]).remove(0), hash::xxh64(&zero_block));
+    let mut done = 0;
+    let mut skipped = 0;
+    let mut hole_blocks = 0;
+    let mut unrescued = 0;
+    let mut unreadable = 0;
+    eprintln!("hashing with {}", hash::describe());
+    if let Some(cache) = &cache {
+        eprintln!("hashing cache device {} alongside", cache.path().display());
+    }
+    let cache_done = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        let cache_hashing = cache
+            .as_ref()
+            .map(|device| scope.spawn(|| hash_cache(index_path, device, options, &cache_done)));
+        for ((segment, device), holes) in layout.segments.iter().zip(&devices).zip(&holes) {
+            let device_size = device.size();
+            // Sampling reads too little of each block to be worth reading whole blocks ahead for.
+            let ahead = options.sample_bytes.is_none().then(|| {
+                let (bad, free_space, rehash) = (&bad, &free_space, &rehash);
+                ReadAhead::spawn(scope, device.view(), move |offset, len| {
+                    rehash
+                        .as_ref()
+                        .is_some_and(|rehash| !rehash[(segment.offset + offset) / BLOCK_SIZE])
+                        || free_space.as_ref().is_some_and(|free| free.contains(offset, len))
+                        || bad.touches(offset, len)
+                        || holes.contains(offset, len)
+                })
+            });
+            for batch in (0..device_size).step_by(BLOCK_SIZE * hash::BATCH) {
+                let next = batch + BLOCK_SIZE * hash::BATCH;
+                if let Some(ahead) = &ahead {
+                    // A device read with plain reads is read ahead by the kernel, which faults nothing in.
+                    if !device.will_need(next, (BLOCK_SIZE * hash::BATCH).min(device_size.saturating_sub(next))) {
+                        ahead.prefetch(next, BLOCK_SIZE * hash::BATCH);
+                    }
+                }
+                let mut offsets = Vec::with_capacity(hash::BATCH);
+                let mut blocks = Vec::with_capacity(hash::BATCH);
+                for offset in (batch..device_size.min(batch + BLOCK_SIZE * hash::BATCH)).step_by(BLOCK_SIZE) {
+                    if (done + offset) % (BLOCK_SIZE * 10240) == 0 {
+                        log_status(
+                            done + offset + cache_done.load(Ordering::Relaxed),
+                            total_size,
+                            "bytes",
+                            false,
+                        );
+                    }
+
+                    let len = BLOCK_SIZE.min(device_size - offset);
+                    if rehash
+                        .as_ref()
+                        .is_some_and(|rehash| !rehash[(segment.offset + offset) / BLOCK_SIZE])
+                    {
+                        continue;
+                    }
+                    if free_space.as_ref().is_some_and(|free| free.contains(offset, len)) {
+                        // Left as an all-zero digest, which find never matches.
+                        skipped += 1;
+                    } else if bad.touches(offset, len) {
+                        unrescued += 1;
+                    } else if len == BLOCK_SIZE && holes.contains(offset, len) {
+                        store_zero(&mut index_file, options, (segment.offset + offset) / BLOCK_SIZE, &zero);
+                        hole_blocks += 1;
+                    } else {
+                        let len = options.sample_bytes.map_or(len, |sample| sample.min(len));
+                        throttle.consume(len);
+                        match read_block(device, offset, len) {
+                            Some(data) => {
+                                offsets.push(offset);
+                                blocks.push(data);
+                            }
+                            None => unreadable += 1,
+                        }
+                    }
+                }
+                let blocks: Vec<&[u8]> = blocks.iter().map(Vec::as_slice).collect();
+                let entries: Vec<usize> = offsets
+                    .iter()
+                    .map(|offset| (segment.offset + offset) / BLOCK_SIZE)
+                    .collect();
+                store(&mut index_file, options, &entries, &blocks);
+            }
+            done += device_size;
+        }
+        if let Some(cache_hashing) = cache_hashing {
+            while !cache_hashing.is_finished() {
+                log_status(done + cache_done.load(Ordering::Relaxed), total_size, "bytes", false);
+                thread::sleep(Duration::from_millis(200));
+            }
+            cache_hashing.join().unwrap()?;
+        }
+        io::Result::Ok(())
+    })?;
+    log_complete(total_size, "bytes");
+    index_file.finish()?;
+    if free_space.is_some() {
+        eprintln!("\n{} of {} blocks skipped as free space", skipped, block_count);
+    }
+    if hole_blocks > 0 {
+        eprintln!(
+            "\n{} of {} blocks are holes, indexed as zeroes without reading",
+            hole_blocks, block_count
+        );
+    }
+    if unrescued > 0 {
+        eprintln!(
+            "\n{} of {} blocks touch regions ddrescue did not rescue and were left out",
+            unrescued, block_count
+        );
+    }
+    if unreadable > 0 {
+        eprintln!(
+            "\n{} of {} blocks could not be read and were left out",
+            unreadable, block_count
+        );
+    }
+    Ok(())
+}
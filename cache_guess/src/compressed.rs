@@ -0,0 +1,337 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread;
+
+use memmap2::MmapOptions;
+
+use crate::readahead::read_full;
+use crate::{memory, MappedFile};
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+/// Skippable frames use any of 16 magic numbers; the seek table one of them.
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+const SEEK_TABLE_MAGIC: u32 = 0x184d_2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+
+/// Compressed image formats, decompressed by their command-line tools.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Format {
+    pub fn detect(path: &Path) -> Option<Self> {
+        let mut magic = [0; 6];
+        File::open(path).and_then(|mut file| file.read_exact(&mut magic)).ok()?;
+        if magic.starts_with(GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if magic == XZ_MAGIC {
+            Some(Self::Xz)
+        } else if le32(&magic, 0) == ZSTD_MAGIC {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn invalid(path: &Path, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+}
+
+/// The decompressed contents of an image, read from the tool's output.
+pub struct Stream {
+    path: PathBuf,
+    format: Format,
+    child: Child,
+    pub output: ChildStdout,
+}
+
+impl Stream {
+    pub fn open(path: &Path, format: Format) -> io::Result<Self> {
+        let mut child = Command::new(format.program())
+            .args(["-dcq", "--"])
+            .arg(path)
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "cannot run {} to decompress {}: {}",
+                        format.program(),
+                        path.display(),
+                        e
+                    ),
+                )
+            })?;
+        let output = child.stdout.take().unwrap();
+        eprintln!(
+            "{}: {} compressed, decompressing it on the fly",
+            path.display(),
+            format.program()
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            child,
+            output,
+        })
+    }
+
+    /// Waits for the tool once `result` is in, which is what reading the stream came to: an error of the tool's
+    /// own, such as a bad checksum at the very end, fails the run as well. Output past --device-size is not.
+    pub fn finish(mut self, result: io::Result<()>) -> io::Result<()> {
+        if result.is_err() || read_full(&mut self.output, &mut [0])? > 0 {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            return result;
+        }
+        if !self.child.wait()?.success() {
+            return Err(invalid(
+                &self.path,
+                format!("{} failed to decompress it", self.format.program()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The size of an image once decompressed, when the format records it: xz always does, zstd when every frame
+/// has its content size or there is a seek table. gzip only keeps it modulo 4 GiB, which is no use.
+pub fn uncompressed_size(path: &Path, format: Format) -> io::Result<Option<usize>> {
+    match format {
+        Format::Gzip => Ok(None),
+        Format::Xz => {
+            let output = Command::new("xz")
+                .args(["--robot", "--list", "--"])
+                .arg(path)
+                .output()?;
+            if !output.status.success() {
+                return Err(invalid(path, "xz cannot list it".to_string()));
+            }
+            // totals, streams, blocks, compressed, uncompressed, ...
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(|line| line.strip_prefix("totals\t"))
+                .and_then(|totals| totals.split('\t').nth(3))
+                .and_then(|size| size.parse().ok()))
+        }
+        Format::Zstd => {
+            let image = MappedFile::open(path)?;
+            if let Some(frames) = seek_table(path, &image)? {
+                return Ok(Some(frames.iter().map(|frame| frame.size).sum()));
+            }
+            zstd_content_size(path, &image)
+        }
+    }
+}
+
+/// Sums the content sizes in the frame headers, walking over every block header to find the next frame.
+fn zstd_content_size(path: &Path, image: &MappedFile) -> io::Result<Option<usize>> {
+    let bytes = image.slice(0, image.size());
+    let truncated = || invalid(path, "truncated zstd frame".to_string());
+    let mut total = 0;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let header = bytes.get(pos..pos + 8).ok_or_else(truncated)?;
+        let magic = le32(header, 0);
+        if magic & !0xf == SKIPPABLE_MAGIC {
+            pos += 8 + le32(header, 4) as usize;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            return Err(invalid(path, format!("no zstd frame at offset {}", pos)));
+        }
+        let descriptor = header[4];
+        let single_segment = descriptor & 0x20 != 0;
+        let size_bytes = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        pos += 5 + usize::from(!single_segment) + [0, 1, 2, 4][usize::from(descriptor & 3)];
+        let size = bytes.get(pos..pos + size_bytes).ok_or_else(truncated)?;
+        let mut content = size.iter().rev().fold(0, |value, &byte| value << 8 | byte as usize);
+        if size_bytes == 2 {
+            content += 256;
+        }
+        total += content;
+        pos += size_bytes;
+        loop {
+            let block = bytes.get(pos..pos + 3).ok_or_else(truncated)?;
+            let block = block[0] as usize | (block[1] as usize) << 8 | (block[2] as usize) << 16;
+            // An RLE block repeats a single byte as often as its size says.
+            pos += 3 + if (block >> 1) & 3 == 1 { 1 } else { block >> 3 };
+            if block & 1 != 0 {
+                break;
+            }
+        }
+        if descriptor & 4 != 0 {
+            pos += 4;
+        }
+    }
+    Ok(Some(total))
+}
+
+struct Frame {
+    offset: usize,
+    len: usize,
+    size: usize,
+}
+
+/// The frames of a seekable zstd image, from the seek table in its final skippable frame. None when there is none.
+fn seek_table(path: &Path, image: &MappedFile) -> io::Result<Option<Vec<Frame>>> {
+    if image.size() < 17 {
+        return Ok(None);
+    }
+    let footer = image.slice(image.size() - 9, 9);
+    if le32(footer, 5) != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    let frames = le32(footer, 0) as usize;
+    let entry_len = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+    let table_len = 8 + frames * entry_len + 9;
+    if table_len > image.size() || le32(image.slice(image.size() - table_len, 4), 0) != SEEK_TABLE_MAGIC {
+        return Err(invalid(path, "damaged zstd seek table".to_string()));
+    }
+    let entries = image.slice(image.size() - table_len + 8, frames * entry_len);
+    let mut table = Vec::with_capacity(frames);
+    let mut offset = 0;
+    for entry in entries.chunks(entry_len) {
+        let (len, size) = (le32(entry, 0) as usize, le32(entry, 4) as usize);
+        table.push(Frame { offset, len, size });
+        offset += len;
+    }
+    if offset != image.size() - table_len {
+        return Err(invalid(
+            path,
+            format!(
+                "the seek table accounts for {} bytes of frames, not {}",
+                offset,
+                image.size() - table_len
+            ),
+        ));
+    }
+    Ok(Some(table))
+}
+
+/// Decompresses a seekable zstd image into memory for random access, one run of zstd per core over its share of
+/// the frames. Other compressed images can only be streamed, which collect does.
+pub fn map_seekable(path: &Path, format: Format, force: bool) -> io::Result<MappedFile> {
+    let image = MappedFile::open(path)?;
+    let frames = match format {
+        Format::Zstd => seek_table(path, &image)?,
+        _ => None,
+    };
+    let Some(frames) = frames else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is {} compressed and can only be read from start to end, as collect does; decompress it or \
+                 recompress it as seekable zstd",
+                path.display(),
+                format.program()
+            ),
+        ));
+    };
+    let total: usize = frames.iter().map(|frame| frame.size).sum();
+    memory::preflight(
+        &format!("decompressing {}", path.display()),
+        total,
+        "decompress it to a file instead",
+        force,
+    )?;
+    eprintln!(
+        "{}: seekable zstd, decompressing {} frames into memory",
+        path.display(),
+        frames.len()
+    );
+    let mut output = MmapOptions::new().len(total.max(1)).map_anon()?;
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(frames.len().max(1));
+    let share = total.div_ceil(threads);
+    thread::scope(|scope| {
+        let mut workers = Vec::new();
+        let mut rest = &mut output[..total];
+        let mut frames = &frames[..];
+        while !frames.is_empty() {
+            // Whole frames, about one share of the output each.
+            let mut count = 0;
+            let mut size = 0;
+            while count < frames.len() && (count == 0 || size + frames[count].size <= share) {
+                size += frames[count].size;
+                count += 1;
+            }
+            let (group, remaining) = frames.split_at(count);
+            let (target, tail) = rest.split_at_mut(size);
+            let compressed = image.slice(group[0].offset, group.iter().map(|frame| frame.len).sum());
+            workers.push(scope.spawn(move || decompress_frames(path, compressed, target)));
+            (frames, rest) = (remaining, tail);
+        }
+        workers.into_iter().try_for_each(|worker| {
+            worker
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("decompression panicked")))
+        })
+    })?;
+    Ok(MappedFile::from_mmap(output.make_read_only()?, total))
+}
+
+fn decompress_frames(path: &Path, compressed: &[u8], target: &mut [u8]) -> io::Result<()> {
+    let mut child = Command::new("zstd")
+        .arg("-dcq")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot run zstd to decompress {}: {}", path.display(), e),
+            )
+        })?;
+    let mut input = child.stdin.take().unwrap();
+    let mut output = child.stdout.take().unwrap();
+    let filled = thread::scope(|scope| {
+        scope.spawn(move || input.write_all(compressed));
+        let filled = read_full(&mut output, target)?;
+        // The seek table decides what each frame holds; anything left over means it lied.
+        Ok::<_, io::Error>(filled + read_full(&mut output, &mut [0])?)
+    });
+    if !child.wait()?.success() {
+        return Err(invalid(path, "zstd failed to decompress it".to_string()));
+    }
+    let filled = filled?;
+    if filled != target.len() {
+        return Err(invalid(
+            path,
+            format!(
+                "frames decompress to {} bytes instead of the {} the seek table has",
+                filled,
+                target.len()
+            ),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,531 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use clap::{App, ArgMatches, ArgSettings, ErrorKind};
+
+use crate::output::Output;
+
+/// A value of a session file, in the TOML subset it is written in.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    /// Kept as written; clap parses it like any other argument.
+    Number(String),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// A session file: the keys of each `[subcommand]` table, in file order.
+#[derive(Debug, Default)]
+struct Config {
+    tables: Vec<(String, Vec<(String, Value)>)>,
+}
+
+fn invalid(path: &Path, line: usize, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, msg),
+    )
+}
+
+/// Parses one value off the front of `text`, returning it and what follows.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    let text = text.trim_start();
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, '\\')) => value.push('\\'),
+                    _ => return Err("unsupported escape in a string".to_string()),
+                },
+                c => value.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    } else if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]))
+    } else if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in an array".to_string());
+            }
+        }
+    } else {
+        let end = text
+            .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+            .unwrap_or(text.len());
+        let (word, rest) = text.split_at(end);
+        match word {
+            "true" => Ok((Value::Bool(true), rest)),
+            "false" => Ok((Value::Bool(false), rest)),
+            _ if !word.is_empty() && word.parse::<f64>().is_ok() => Ok((Value::Number(word.to_string()), rest)),
+            _ => Err(format!("'{}' is not a string, number, boolean or array", word)),
+        }
+    }
+}
+
+impl Config {
+    fn read(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot read --config {}: {}", path.display(), e)))?;
+        let mut config = Self::default();
+        let mut lines = text.lines().enumerate();
+        while let Some((number, line)) = lines.next() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name
+                    .split_once(']')
+                    .map(|(name, _)| name.trim())
+                    .ok_or_else(|| invalid(path, number + 1, "unterminated table name"))?;
+                config.tables.push((name.to_string(), Vec::new()));
+                continue;
+            }
+            let (key, mut value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim().trim_matches('"').to_string(), value.to_string()))
+                .ok_or_else(|| invalid(path, number + 1, "expected key = value"))?;
+            // Arrays may go on over several lines.
+            while value.contains('[') && parse_value(&value).is_err() {
+                let Some((_, more)) = lines.next() else {
+                    break;
+                };
+                value = format!("{} {}", value, more.split('#').next().unwrap_or(""));
+            }
+            let (value, rest) = parse_value(&value).map_err(|e| invalid(path, number + 1, &e))?;
+            let rest = rest.trim();
+            if !rest.is_empty() && !rest.starts_with('#') {
+                return Err(invalid(path, number + 1, "unexpected text after the value"));
+            }
+            match config.tables.last_mut() {
+                Some((_, keys)) => keys.push((key, value)),
+                None => {
+                    return Err(invalid(
+                        path,
+                        number + 1,
+                        "keys go in a [subcommand] table, such as [find]",
+                    ))
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn table(&self, name: &str) -> Option<&[(String, Value)]> {
+        self.tables
+            .iter()
+            .find(|(table, _)| table == name)
+            .map(|(_, keys)| keys.as_slice())
+    }
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) | Value::Number(s) => vec![s.clone()],
+        Value::Bool(b) => vec![b.to_string()],
+        Value::Array(items) => items.iter().flat_map(strings).collect(),
+    }
+}
+
+/// `app`, with the global arguments copied into every subcommand as parsing does, so that each lists all it takes.
+fn with_globals<'a, 'b>(app: &App<'a, 'b>) -> App<'a, 'b> {
+    let mut app = app.clone();
+    app.p.propagate_globals();
+    app
+}
+
+/// The first of `args` that names a subcommand of `app`, past the options before it and their values, and that
+/// subcommand; none if a positional argument or `--` comes first.
+fn subcommand<'s, 'a, 'b>(app: &'s App<'a, 'b>, args: &[OsString]) -> Option<(usize, &'s App<'a, 'b>)> {
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].to_string_lossy();
+        if arg == "--" {
+            return None;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            if !long.contains('=') && app.p.opts.iter().any(|opt| opt.s.long == Some(long)) {
+                i += 1;
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // A short option that takes a value takes the rest of its word, or the next one if nothing follows it.
+            let takes = shorts
+                .char_indices()
+                .find(|&(_, short)| app.p.opts.iter().any(|opt| opt.s.short == Some(short)));
+            if takes.is_some_and(|(at, short)| at + short.len_utf8() == shorts.len()) {
+                i += 1;
+            }
+        } else {
+            return app
+                .p
+                .subcommands
+                .iter()
+                .find(|sub| sub.p.meta.name == arg)
+                .map(|sub| (i, sub));
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Parses the command line, with `--config FILE` taken out and standing in for every flag of the subcommand's table
+/// that the command line does not give. Nested subcommands have tables like `[index.gc]`; `args` gives the
+/// positional arguments, used when the command line has none.
+pub fn matches<'a>(app: App<'a, '_>) -> io::Result<ArgMatches<'a>> {
+    matches_from(app, env::args_os().collect())
+}
+
+fn matches_from<'a>(app: App<'a, '_>, mut args: Vec<OsString>) -> io::Result<ArgMatches<'a>> {
+    let mut config_path = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--config") {
+        if i + 1 < args.len() {
+            config_path = Some(args.remove(i + 1));
+        }
+        args.remove(i);
+    } else if let Some(i) = args
+        .iter()
+        .position(|arg| arg.to_string_lossy().starts_with("--config="))
+    {
+        let arg = args.remove(i).to_string_lossy().into_owned();
+        config_path = Some(OsString::from(&arg["--config=".len()..]));
+    }
+    let Some(config_path) = config_path else {
+        return Ok(app.get_matches_from(args));
+    };
+    let config = Config::read(Path::new(&config_path))?;
+
+    // Global options may come before the subcommand, so it is looked for past them, and the positional arguments
+    // go right after it.
+    let globals = with_globals(&app);
+    let Some((at, command)) = subcommand(&globals, &args[1..]) else {
+        return Ok(app.get_matches_from(args));
+    };
+    let (mut table, mut end) = (command.p.meta.name.clone(), 1 + at + 1);
+    if let Some((at, nested)) = subcommand(command, &args[end..])
+        .filter(|(_, nested)| config.table(&format!("{}.{}", table, nested.p.meta.name)).is_some())
+    {
+        table = format!("{}.{}", table, nested.p.meta.name);
+        end += at + 1;
+    }
+    let Some(keys) = config.table(&table) else {
+        return Ok(app.get_matches_from(args));
+    };
+    if keys.iter().any(|(key, _)| key == "yes") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}: [{}] sets yes, which a session file cannot: give --yes on the command line to skip the \
+                 confirmation",
+                Path::new(&config_path).display(),
+                table
+            ),
+        ));
+    }
+    let given = |key: &str| {
+        let flag = format!("--{}", key);
+        args.iter().any(|arg| {
+            let arg = arg.to_string_lossy();
+            arg == flag || arg.starts_with(&format!("{}=", flag))
+        })
+    };
+    let mut flags = Vec::new();
+    let mut positionals = Vec::new();
+    for (key, value) in keys {
+        if key == "args" {
+            positionals = strings(value);
+        } else if given(key) {
+            continue;
+        } else if let Value::Bool(set) = value {
+            if *set {
+                flags.push(format!("--{}", key));
+            }
+        } else {
+            flags.extend(strings(value).into_iter().map(|value| format!("--{}={}", key, value)));
+        }
+    }
+    args.extend(flags.into_iter().map(OsString::from));
+    if !positionals.is_empty() {
+        let missing = matches!(
+            app.clone().get_matches_from_safe(&args),
+            Err(e) if e.kind == ErrorKind::MissingRequiredArgument
+        );
+        if missing {
+            args.splice(end..end, positionals.into_iter().map(OsString::from));
+        }
+    }
+    Ok(app.get_matches_from(args))
+}
+
+/// One flag or option of a subcommand, as the app defines it.
+struct Flag {
+    name: String,
+    takes_value: bool,
+    default: Option<String>,
+    help: String,
+}
+
+impl Flag {
+    /// Takes a default that the help text gives itself, as `[default: ...]`, for one that is computed at run time,
+    /// and puts the help on one line.
+    fn new(name: &str, takes_value: bool, default: Option<String>, help: Option<&str>) -> Self {
+        let mut help = help.unwrap_or("").split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut default = default;
+        if let Some((before, after)) = help.split_once("[default: ") {
+            default = default.or_else(|| after.split_once(']').map(|(default, _)| default.to_string()));
+            help = before.trim().to_string();
+        }
+        Self {
+            name: name.to_string(),
+            takes_value,
+            default,
+            help,
+        }
+    }
+}
+
+/// The positional arguments, flags and nested subcommands of the subcommand at `path`, as `--help` lists them.
+/// `--yes` is left out: a session file may not skip a confirmation.
+fn describe(app: &App, path: &[&str]) -> (Vec<String>, Vec<Flag>, Vec<String>) {
+    let app = path.iter().try_fold(app, |app, name| {
+        app.p.subcommands.iter().find(|sub| sub.p.meta.name == *name)
+    });
+    let Some(app) = app else {
+        return (Vec::new(), Vec::new(), Vec::new());
+    };
+    let positionals = app
+        .p
+        .positionals
+        .values()
+        .filter(|pos| !pos.b.settings.is_set(ArgSettings::Hidden))
+        .map(|pos| {
+            let name = format!("<{}>", pos.b.name);
+            let name = match pos.b.settings.is_set(ArgSettings::Multiple) {
+                true => name + "...",
+                false => name,
+            };
+            match pos.b.settings.is_set(ArgSettings::Required) {
+                true => name,
+                false => format!("[{}]", name),
+            }
+        })
+        .collect();
+    let mut switches: Vec<Flag> = app
+        .p
+        .flags
+        .iter()
+        .filter(|flag| !flag.b.settings.is_set(ArgSettings::Hidden))
+        .filter_map(|flag| Some(Flag::new(flag.s.long?, false, None, flag.b.long_help.or(flag.b.help))))
+        .collect();
+    let mut options: Vec<Flag> = app
+        .p
+        .opts
+        .iter()
+        .filter(|opt| !opt.b.settings.is_set(ArgSettings::Hidden))
+        .filter_map(|opt| {
+            let default = opt.v.default_val.map(|default| default.to_string_lossy().into_owned());
+            Some(Flag::new(opt.s.long?, true, default, opt.b.long_help.or(opt.b.help)))
+        })
+        .collect();
+    // Flags then options, each by name, in the order of --help.
+    switches.sort_by(|a, b| a.name.cmp(&b.name));
+    options.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut flags = switches;
+    flags.append(&mut options);
+    flags.retain(|flag| !["help", "version", "config", "yes"].contains(&flag.name.as_str()));
+    let mut subcommands: Vec<String> = app
+        .p
+        .subcommands
+        .iter()
+        .filter(|sub| !sub.p.is_set(clap::AppSettings::Hidden))
+        .map(|sub| sub.p.meta.name.clone())
+        .collect();
+    subcommands.sort();
+    (positionals, flags, subcommands)
+}
+
+fn write_tables(out: &mut Output, app: &App, path: &mut Vec<String>) -> io::Result<()> {
+    let words: Vec<&str> = path.iter().map(String::as_str).collect();
+    let (positionals, flags, subcommands) = describe(app, &words);
+    if !path.is_empty() && (subcommands.is_empty() || !flags.is_empty()) {
+        writeln!(out, "\n[{}]", path.join("."))?;
+        if !positionals.is_empty() {
+            let names: Vec<String> = positionals.iter().map(|name| format!("\"{}\"", name)).collect();
+            writeln!(out, "# args = [{}]", names.join(", "))?;
+        }
+        for flag in &flags {
+            let value = match (&flag.default, flag.takes_value) {
+                (Some(default), _) => format!("\"{}\"", default),
+                (None, true) => "\"\"".to_string(),
+                (None, false) => "true".to_string(),
+            };
+            writeln!(out, "# {} = {}  # {}", flag.name, value, flag.help)?;
+        }
+    }
+    for subcommand in subcommands {
+        if path.is_empty() && subcommand == "config" {
+            continue;
+        }
+        path.push(subcommand);
+        write_tables(out, app, path)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Writes a session file with every subcommand's flags and their defaults, commented out.
+pub fn init(app: &App, output: Option<&Path>) -> io::Result<()> {
+    let app = with_globals(app);
+    let mut out = Output::create(output)?;
+    writeln!(
+        out,
+        "# cache_guess session defaults, for --config: a table per subcommand ([index.gc] for nested ones) keyed by\n\
+         # long flag names. Arguments on the command line win; `args` gives the positional arguments for when the\n\
+         # command line has none. Uncomment and fill in what stays the same from run to run; --yes is only taken\n\
+         # from the command line."
+    )?;
+    write_tables(&mut out, &app, &mut Vec::new())?;
+    out.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, SubCommand};
+
+    fn app() -> App<'static, 'static> {
+        App::new("cache_guess")
+            .arg(
+                Arg::with_name("progress")
+                    .long("progress")
+                    .short("p")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(Arg::with_name("quiet").long("quiet").global(true))
+            .subcommand(
+                SubCommand::with_name("find")
+                    .arg(Arg::with_name("index").required(true))
+                    .arg(Arg::with_name("cache_device").required(true).multiple(true))
+                    .arg(
+                        Arg::with_name("cache-block-size")
+                            .long("cache-block-size")
+                            .takes_value(true)
+                            .help("In sectors,\n             or bytes"),
+                    )
+                    .arg(Arg::with_name("yes").long("yes")),
+            )
+            .subcommand(
+                SubCommand::with_name("index").subcommand(
+                    SubCommand::with_name("gc")
+                        .arg(Arg::with_name("index").required(true))
+                        .arg(Arg::with_name("keep").long("keep").takes_value(true)),
+                ),
+            )
+    }
+
+    fn session(name: &str, text: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("cache_guess-config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn args(words: &[&str], config: &Path) -> Vec<OsString> {
+        let mut args: Vec<OsString> = words.iter().map(OsString::from).collect();
+        args.extend([OsString::from("--config"), config.as_os_str().to_owned()]);
+        args
+    }
+
+    #[test]
+    fn table_applies_past_global_options_before_the_subcommand() {
+        let path = session(
+            "globals",
+            "[find]\nargs = [\"origin.idx\", \"cache.img\"]\ncache-block-size = \"64K\"\n",
+        );
+        for words in [
+            &["cache_guess", "--progress", "json", "find"][..],
+            &["cache_guess", "-p", "json", "--quiet", "find"],
+            &["cache_guess", "--progress=json", "find"],
+        ] {
+            let matches = matches_from(app(), args(words, &path)).unwrap();
+            let (command, Some(find)) = matches.subcommand() else {
+                panic!("no subcommand for {:?}", words);
+            };
+            assert_eq!(command, "find");
+            assert_eq!(find.value_of("index"), Some("origin.idx"), "{:?}", words);
+            assert_eq!(find.value_of("cache_device"), Some("cache.img"));
+            assert_eq!(find.value_of("cache-block-size"), Some("64K"));
+            assert_eq!(find.value_of("progress"), Some("json"));
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn nested_table_and_command_line_win() {
+        let path = session("nested", "[index.gc]\nargs = [\"from-config.idx\"]\nkeep = 3\n");
+        let words = ["cache_guess", "--quiet", "index", "gc", "--keep", "5"];
+        let matches = matches_from(app(), args(&words, &path)).unwrap();
+        let gc = matches
+            .subcommand_matches("index")
+            .unwrap()
+            .subcommand_matches("gc")
+            .unwrap();
+        assert_eq!(gc.value_of("index"), Some("from-config.idx"));
+        assert_eq!(gc.value_of("keep"), Some("5"));
+
+        let words = ["cache_guess", "index", "gc", "given.idx"];
+        let matches = matches_from(app(), args(&words, &path)).unwrap();
+        let gc = matches
+            .subcommand_matches("index")
+            .unwrap()
+            .subcommand_matches("gc")
+            .unwrap();
+        assert_eq!(gc.value_of("index"), Some("given.idx"));
+        assert_eq!(gc.value_of("keep"), Some("3"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn session_file_cannot_say_yes() {
+        let path = session("yes", "[find]\nyes = true\n");
+        let words = ["cache_guess", "find", "origin.idx", "cache.img"];
+        let e = matches_from(app(), args(&words, &path)).unwrap_err();
+        fs::remove_file(path).unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("give --yes on the command line"), "{}", e);
+    }
+
+    #[test]
+    fn description_comes_from_the_definitions() {
+        let app = with_globals(&app());
+        let (positionals, flags, subcommands) = describe(&app, &["find"]);
+        assert_eq!(positionals, ["<index>", "<cache_device>..."]);
+        let names: Vec<&str> = flags.iter().map(|flag| flag.name.as_str()).collect();
+        assert_eq!(names, ["quiet", "cache-block-size", "progress"]);
+        assert_eq!(flags[1].help, "In sectors, or bytes");
+        assert!(flags[1].takes_value && !flags[0].takes_value);
+        assert!(subcommands.is_empty());
+        assert_eq!(describe(&app, &["index"]).2, ["gc"]);
+    }
+}
@@ -0,0 +1,60 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::mapping::MappingEntry;
+
+/// How many of the entries `find` scored in each confidence band, for the summary before writing them.
+pub fn confidence_distribution(entries: &[&MappingEntry]) -> String {
+    let bands = [
+        (0.99, ">= 0.99"),
+        (0.9, "0.90-0.99"),
+        (0.5, "0.50-0.90"),
+        (f64::NEG_INFINITY, "< 0.50"),
+    ];
+    let mut counts = [0; 4];
+    let mut unscored = 0;
+    for entry in entries {
+        match entry.confidence {
+            Some(confidence) => counts[bands.iter().position(|&(floor, _)| confidence >= floor).unwrap()] += 1,
+            None => unscored += 1,
+        }
+    }
+    let mut parts: Vec<String> = bands
+        .iter()
+        .zip(counts)
+        .map(|(&(_, band), count)| format!("{} {}", count, band))
+        .collect();
+    if unscored > 0 {
+        parts.push(format!("{} without a confidence", unscored));
+    }
+    parts.join(", ")
+}
+
+/// Shows what is about to be written to `target` and goes ahead only once the device name is typed back, so that
+/// swapped arguments do not overwrite the wrong device. `yes` skips the question, for scripts.
+pub fn confirm(target: &Path, summary: &[String], yes: bool) -> io::Result<()> {
+    for line in summary {
+        eprintln!("{}", line);
+    }
+    if yes {
+        return Ok(());
+    }
+    let name = target
+        .file_name()
+        .map_or(target.to_string_lossy(), |name| name.to_string_lossy());
+    eprint!(
+        "type {} to write to it, anything else to stop (--yes skips this): ",
+        name
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    if answer == name || Path::new(answer) == target {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Interrupted,
+        format!("not confirmed, nothing was written to {}", target.display()),
+    ))
+}
@@ -0,0 +1,315 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::cache_xml::CacheMetadata;
+use crate::mapping::MappingEntry;
+use crate::output::Output;
+use crate::verify::Reader;
+use crate::{hash_block, metrics, provenance};
+
+/// What to do when several entries claim the same origin block, or one cache block is listed more than once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    /// Keep the entry with the highest confidence; ties go to the lowest cache block, then origin block.
+    Best,
+    /// Drop every entry of the conflict.
+    Skip,
+    Fail,
+    /// Ask on the terminal.
+    Interactive,
+}
+
+impl Conflict {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "skip" => Self::Skip,
+            "fail" => Self::Fail,
+            "interactive" => Self::Interactive,
+            _ => Self::Best,
+        }
+    }
+}
+
+/// What besides confidence tells which of several entries for one block to keep: after a cache was re-created,
+/// the device can hold an older copy of an origin block next to the newer one.
+#[derive(Default)]
+pub struct Evidence<'a> {
+    /// The cache's own mapping, from cache_dump; the entry it agrees with wins.
+    pub expected: Option<&'a CacheMetadata>,
+    /// Digests a cache block's data; entries whose cache blocks hold the same data do not conflict.
+    pub digest: Option<&'a dyn Fn(usize) -> Vec<u8>>,
+    /// Entries continuing a run of consecutive cache blocks onto consecutive origin blocks, as dm-cache tends to
+    /// allocate them, win over isolated ones.
+    neighbors: HashSet<(usize, usize)>,
+}
+
+impl Evidence<'_> {
+    fn agrees(&self, entry: &MappingEntry) -> bool {
+        self.expected.is_some_and(|expected| {
+            expected
+                .mappings
+                .get(&entry.cache_block)
+                .is_some_and(|recorded| recorded.origin_block == entry.origin_block)
+        })
+    }
+
+    fn neighbor_support(&self, entry: &MappingEntry) -> usize {
+        let before = entry.cache_block.checked_sub(1).zip(entry.origin_block.checked_sub(1));
+        let after = Some((entry.cache_block + 1, entry.origin_block + 1));
+        [before, after]
+            .into_iter()
+            .flatten()
+            .filter(|pair| self.neighbors.contains(pair))
+            .count()
+    }
+
+    fn note(&self, entry: &MappingEntry) -> String {
+        let mut notes = Vec::new();
+        if self.agrees(entry) {
+            notes.push("as in the metadata".to_string());
+        }
+        match self.neighbor_support(entry) {
+            0 => {}
+            1 => notes.push("1 neighbor agrees".to_string()),
+            n => notes.push(format!("{} neighbors agree", n)),
+        }
+        match notes.is_empty() {
+            true => describe(entry),
+            false => format!("{} [{}]", describe(entry), notes.join(", ")),
+        }
+    }
+}
+
+fn describe(entry: &MappingEntry) -> String {
+    match entry.confidence {
+        Some(confidence) => format!(
+            "{} -> {} ({:.3} confidence)",
+            entry.cache_block, entry.origin_block, confidence
+        ),
+        None => format!("{} -> {}", entry.cache_block, entry.origin_block),
+    }
+}
+
+fn ask(what: &str, group: &[MappingEntry]) -> io::Result<Option<usize>> {
+    let stdin = io::stdin();
+    loop {
+        eprintln!("conflict: {}", what);
+        for (i, entry) in group.iter().enumerate() {
+            eprintln!("  {}) {}", i + 1, describe(entry));
+        }
+        eprint!("keep which [1-{}, s to skip all]? ", group.len());
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no answer to a conflict, input ended",
+            ));
+        }
+        match answer.trim() {
+            "s" => return Ok(None),
+            number => match number.parse::<usize>() {
+                Ok(n) if (1..=group.len()).contains(&n) => return Ok(Some(n - 1)),
+                _ => eprintln!("'{}' is not one of the choices", number),
+            },
+        }
+    }
+}
+
+/// Keeps at most one entry of every group sharing `key`, and describes each group in `groups`.
+fn resolve_by(
+    entries: Vec<MappingEntry>,
+    conflict: Conflict,
+    evidence: &Evidence,
+    kind: &str,
+    key: impl Fn(&MappingEntry) -> usize,
+    groups_out: &mut Vec<String>,
+) -> io::Result<(Vec<MappingEntry>, usize)> {
+    let mut groups: HashMap<usize, Vec<MappingEntry>> = HashMap::new();
+    for entry in entries {
+        groups.entry(key(&entry)).or_default().push(entry);
+    }
+    let mut keys: Vec<usize> = groups.keys().copied().collect();
+    keys.sort_unstable();
+    let mut kept = Vec::with_capacity(keys.len());
+    let mut conflicts = 0;
+    for key in keys {
+        let mut group = groups.remove(&key).unwrap();
+        if group.len() == 1 {
+            kept.append(&mut group);
+            continue;
+        }
+        let best = group
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| {
+                evidence
+                    .agrees(a)
+                    .cmp(&evidence.agrees(b))
+                    .then(evidence.neighbor_support(a).cmp(&evidence.neighbor_support(b)))
+                    .then(
+                        a.confidence
+                            .unwrap_or(f64::NEG_INFINITY)
+                            .total_cmp(&b.confidence.unwrap_or(f64::NEG_INFINITY)),
+                    )
+                    .then(b.cache_block.cmp(&a.cache_block))
+                    .then(b.origin_block.cmp(&a.origin_block))
+            })
+            .map(|(i, _)| i);
+        let claims: Vec<String> = group.iter().map(|entry| evidence.note(entry)).collect();
+        // Copies of the same data give the same result whichever is written.
+        let identical = evidence.digest.is_some_and(|digest| {
+            let first = digest(group[0].cache_block);
+            group[1..].iter().all(|entry| digest(entry.cache_block) == first)
+        });
+        if identical && kind == "origin block" {
+            groups_out.push(format!(
+                "{} {}: {}; identical data, keeping {}",
+                kind,
+                key,
+                claims.join(", "),
+                group[best.unwrap()].cache_block
+            ));
+            kept.push(group.swap_remove(best.unwrap()));
+            continue;
+        }
+        conflicts += 1;
+        let what = format!("{} {} is claimed {} times", kind, key, group.len());
+        let choice = match conflict {
+            Conflict::Best => best,
+            Conflict::Skip => None,
+            Conflict::Fail => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {} (see --conflict)", what, claims.join(", ")),
+                ));
+            }
+            Conflict::Interactive => ask(&what, &group)?,
+        };
+        groups_out.push(format!(
+            "{} {}: {}; {}",
+            kind,
+            key,
+            claims.join(", "),
+            choice.map_or("keeping none".to_string(), |choice| format!(
+                "keeping {}",
+                describe(&group[choice])
+            ))
+        ));
+        if let Some(choice) = choice {
+            kept.push(group.swap_remove(choice));
+        }
+    }
+    Ok((kept, conflicts))
+}
+
+/// Makes the mapping one-to-one: every cache block goes to one origin block and every origin block is written once.
+/// Returns what is kept and a line for every group of entries that shared a block.
+fn resolve(
+    entries: Vec<MappingEntry>,
+    conflict: Conflict,
+    evidence: Evidence,
+) -> io::Result<(Vec<MappingEntry>, Vec<String>)> {
+    let evidence = Evidence {
+        neighbors: entries
+            .iter()
+            .map(|entry| (entry.cache_block, entry.origin_block))
+            .collect(),
+        ..evidence
+    };
+    let mut groups = Vec::new();
+    let (entries, repeated) = resolve_by(
+        entries,
+        conflict,
+        &evidence,
+        "cache block",
+        |entry| entry.cache_block,
+        &mut groups,
+    )?;
+    let (entries, shared) = resolve_by(
+        entries,
+        conflict,
+        &evidence,
+        "origin block",
+        |entry| entry.origin_block,
+        &mut groups,
+    )?;
+    metrics::count("conflicts_resolved", repeated + shared);
+    if repeated + shared > 0 {
+        eprintln!(
+            "{} conflicts resolved: {} repeated cache blocks, {} shared origin blocks",
+            repeated + shared,
+            repeated,
+            shared
+        );
+    }
+    Ok((entries, groups))
+}
+
+/// Writes the groups `resolve` found, then every group of mapped cache blocks holding identical data.
+fn write_duplicates(
+    path: &Path,
+    groups: &[String],
+    entries: &[MappingEntry],
+    digest: &dyn Fn(usize) -> Vec<u8>,
+) -> io::Result<()> {
+    let mut by_digest: HashMap<Vec<u8>, Vec<&MappingEntry>> = HashMap::new();
+    for entry in entries {
+        by_digest.entry(digest(entry.cache_block)).or_default().push(entry);
+    }
+    let mut identical: Vec<Vec<&MappingEntry>> = by_digest.into_values().filter(|group| group.len() > 1).collect();
+    for group in &mut identical {
+        group.sort_by_key(|entry| entry.cache_block);
+    }
+    identical.sort_by_key(|group| group[0].cache_block);
+
+    let mut out = Output::create(Some(path))?;
+    provenance::write_comments(&mut out)?;
+    writeln!(out, "# entries that claimed the same block, and which was kept")?;
+    for group in groups {
+        writeln!(out, "{}", group)?;
+    }
+    writeln!(out, "# mapped cache blocks with identical data")?;
+    for group in &identical {
+        let claims: Vec<String> = group.iter().map(|entry| describe(entry)).collect();
+        writeln!(out, "identical: {}", claims.join(", "))?;
+    }
+    out.commit()?;
+    eprintln!(
+        "{} conflict groups and {} groups of identical cache blocks written to {}",
+        groups.len(),
+        identical.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// `resolve` for entries about to be copied out of `cache_device`: copies of the same data never conflict, and
+/// with `expected_mapping` the cache's own mapping decides. `duplicates` is where `write_duplicates` goes.
+pub fn resolve_copies(
+    entries: Vec<MappingEntry>,
+    conflict: Conflict,
+    cache_device: &Reader,
+    cache_block_size: usize,
+    expected_mapping: Option<&Path>,
+    duplicates: Option<&Path>,
+) -> io::Result<Vec<MappingEntry>> {
+    let expected = expected_mapping.map(CacheMetadata::read).transpose()?;
+    // Entries past the device end, or that cannot be read, are reported once the conflicts are resolved.
+    let digest = |cache_block: usize| match cache_device.read_unrecorded(cache_block, cache_block_size) {
+        Ok(data) => hash_block(&data),
+        Err(_) => Vec::new(),
+    };
+    let all = duplicates.map(|_| entries.clone());
+    let evidence = Evidence {
+        expected: expected.as_ref(),
+        digest: Some(&digest),
+        ..Evidence::default()
+    };
+    let (entries, groups) = resolve(entries, conflict, evidence)?;
+    if let (Some(path), Some(all)) = (duplicates, all) {
+        write_duplicates(path, &groups, &all, &digest)?;
+    }
+    Ok(entries)
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::identity::Identity;
+use crate::index::{Header, Index, IndexWriter, MIN_DIGEST_BYTES};
+use crate::layout::Layout;
+use crate::{log_complete, log_status, open_device, provenance, MappedFile, BLOCK_SIZE, HASH_BYTES};
+
+pub struct ConvertOptions<'a> {
+    /// Explicit records or one entry per origin block; the layout of the index converted when not given.
+    pub explicit: Option<bool>,
+    pub shard_size: Option<usize>,
+    /// Keep only this many leading bytes of each digest.
+    pub digest_bytes: Option<usize>,
+    pub drop_filter: bool,
+    /// The origin a legacy index was built from, for the size and identity it did not record.
+    pub origin: Option<&'a Path>,
+    /// The size of the origin of a legacy index, when the origin itself is gone.
+    pub device_size: Option<usize>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// The header a legacy index would have had: full SHA-1 digests of whole blocks, no filter, and for the size and
+/// identity of its origin what the options say.
+fn legacy_header(options: &ConvertOptions) -> io::Result<Header> {
+    let (device_size, identity) = match (options.origin, options.device_size) {
+        (Some(path), _) => {
+            let origin = open_device(path, None)?;
+            if options.device_size.is_some_and(|size| size != origin.size()) {
+                return Err(invalid(format!(
+                    "--device-size does not match the {} bytes of {}",
+                    origin.size(),
+                    path.display()
+                )));
+            }
+            (origin.size(), Identity::of(&origin))
+        }
+        (None, Some(size)) => (size, Identity::default()),
+        (None, None) => {
+            return Err(invalid(
+                "a legacy index does not record its origin, give it with --origin or its size with --device-size"
+                    .to_string(),
+            ))
+        }
+    };
+    let mut header = Header::new(device_size, HASH_BYTES, None, false);
+    header.identity = identity;
+    Ok(header)
+}
+
+/// Rewrites an index in another of the layouts `collect` writes, without reading the origin again: a legacy
+/// headerless one gets a header and block checksums; positional and explicit layouts, sharded or not, convert
+/// into each other; digests can be truncated and the filter hashes of a two-tier index dropped. Anything that
+/// needs more than the index holds, like longer digests or filter hashes it does not have, takes a new collect.
+pub fn convert(index_path: &Path, output: &Path, options: &ConvertOptions) -> io::Result<()> {
+    if fs::canonicalize(output).ok() == Some(fs::canonicalize(index_path)?) {
+        return Err(invalid(
+            "the output must be a different file from the index being converted".to_string(),
+        ));
+    }
+    let index = Index::open(index_path)?;
+    let mut header = match index.header() {
+        Some(base) => {
+            if options.origin.is_some() || options.device_size.is_some() {
+                return Err(invalid(
+                    "the index records its origin already; --origin and --device-size are for legacy indexes"
+                        .to_string(),
+                ));
+            }
+            let mut header = Header::new(base.device_size, base.digest_len, index.sample_bytes(), base.filter);
+            header.identity = base.identity.clone();
+            header.explicit = base.explicit;
+            header
+        }
+        None => {
+            let header = legacy_header(options)?;
+            // Legacy indexes pack entries into whole blocks with the rest of the last one left empty, so the file
+            // tells the origin size to a block.
+            let per_block = BLOCK_SIZE / HASH_BYTES;
+            let blocks = header.entry_count.div_ceil(per_block);
+            let past_end = MappedFile::open(index_path)?
+                .slice(0, index.size())
+                .get((blocks - 1) * BLOCK_SIZE + (header.entry_count - (blocks - 1) * per_block) * HASH_BYTES..)
+                .is_some_and(|rest| rest.iter().any(|&byte| byte != 0));
+            if index.size() != blocks * BLOCK_SIZE || past_end {
+                return Err(invalid(format!(
+                    "{} is not laid out as the index of a {} byte origin",
+                    index_path.display(),
+                    header.device_size
+                )));
+            }
+            header
+        }
+    };
+    let digest_len = options.digest_bytes.unwrap_or(header.digest_len);
+    if !(MIN_DIGEST_BYTES..=header.digest_len).contains(&digest_len) {
+        return Err(invalid(format!(
+            "the index holds {} byte digests; they can be cut to between {} and that many bytes",
+            header.digest_len, MIN_DIGEST_BYTES
+        )));
+    }
+    let filter = header.filter && !options.drop_filter;
+    let explicit = options.explicit.unwrap_or(header.explicit);
+    if digest_len != header.digest_len {
+        provenance::record_setting("digest_bytes", digest_len);
+    }
+    if header.filter && !filter {
+        provenance::record_setting("filter_hash", "dropped");
+    }
+    provenance::record_setting("layout", if explicit { "explicit" } else { "positional" });
+    header.digest_len = digest_len;
+    header.filter = filter;
+    header.explicit = explicit;
+    let entries = header.entry_count;
+    let device_size = header.device_size;
+
+    let mut writer = IndexWriter::create(output, header, options.shard_size)?;
+    let mut copied = 0;
+    index.for_each(|entry, digest| {
+        if entry.is_multiple_of(1 << 20) {
+            log_status(entry, entries, "entries", false);
+        }
+        writer.set(entry, &digest[..digest_len]);
+        copied += 1;
+    })?;
+    if filter {
+        index.for_each_filter_in(0..index.shard_count(), |entry, hash| writer.set_filter(entry, hash))?;
+    }
+    log_complete(entries, "entries");
+    writer.finish()?;
+    match Layout::read(index_path)? {
+        Some(layout) => layout.write(&Layout::sidecar_path(output))?,
+        None => Layout::remove(output)?,
+    }
+
+    let converted = Index::open(output)?;
+    println!(
+        "{}: {} entries of {} origin blocks, {} bytes, {} bytes before",
+        output.display(),
+        copied,
+        device_size.div_ceil(BLOCK_SIZE),
+        converted.size(),
+        index.size()
+    );
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::path::Path;
+
+use crate::{mapping, report, visualize};
+
+pub struct CoverageOptions<'a> {
+    pub cache_block_size: usize,
+    pub origin_size: usize,
+    /// Origin blocks per report line.
+    pub region_blocks: usize,
+    /// Where to draw the mapping too, see `visualize`.
+    pub visualize: Option<&'a Path>,
+    /// Where to write an HTML report of the mapping, see `report`.
+    pub report: Option<&'a Path>,
+}
+
+/// How many of the largest uncovered runs are listed.
+pub const LARGEST_GAPS: usize = 10;
+
+/// Prints what share of the origin the mapping restores, region by region, and where the biggest holes are.
+pub fn coverage(mapping_path: &Path, options: &CoverageOptions) -> io::Result<()> {
+    let origin_blocks = options.origin_size / options.cache_block_size;
+    let entries = mapping::read(mapping_path)?;
+    let mut covered = vec![false; origin_blocks];
+    let (mut tied, mut outside) = (0, 0);
+    for entry in &entries {
+        if entry.tied {
+            tied += 1;
+        } else if entry.origin_block >= origin_blocks {
+            outside += 1;
+        } else {
+            covered[entry.origin_block] = true;
+        }
+    }
+    if tied > 0 {
+        eprintln!("{} tied entries not counted", tied);
+    }
+    if outside > 0 {
+        eprintln!("warning: {} entries point past the origin end", outside);
+    }
+    if let Some(path) = options.visualize {
+        visualize::visualize(path, &entries, origin_blocks)?;
+    }
+
+    for (region, blocks) in covered.chunks(options.region_blocks).enumerate() {
+        let start = region * options.region_blocks;
+        let count = blocks.iter().filter(|&&covered| covered).count();
+        println!(
+            "origin blocks {}-{}: {:.1}% covered ({} of {}){}",
+            start,
+            start + blocks.len() - 1,
+            100.0 * count as f64 / blocks.len() as f64,
+            count,
+            blocks.len(),
+            if count == 0 { " [uncovered]" } else { "" }
+        );
+    }
+
+    let mut gaps = Vec::new();
+    let mut start = None;
+    for (block, &is_covered) in covered.iter().chain([&true]).enumerate() {
+        match (start, is_covered) {
+            (None, false) => start = Some(block),
+            (Some(gap_start), true) => {
+                gaps.push((block - gap_start, gap_start));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    gaps.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    if !gaps.is_empty() {
+        println!("# largest uncovered runs:");
+    }
+    for &(len, start) in gaps.iter().take(LARGEST_GAPS) {
+        println!("#   origin blocks {}-{} ({} blocks)", start, start + len - 1, len);
+    }
+
+    if let Some(path) = options.report {
+        report::write(path, mapping_path, &entries, &covered, &gaps, options.cache_block_size)?;
+    }
+
+    let total = covered.iter().filter(|&&covered| covered).count();
+    eprintln!(
+        "{} of {} origin blocks covered ({:.1}%), {} uncovered runs",
+        total,
+        origin_blocks,
+        100.0 * total as f64 / origin_blocks.max(1) as f64,
+        gaps.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+
+use crate::selection::Selection;
+use crate::{ext4, provenance, xfs, MappedFile, BLOCK_SIZE};
+
+/// Origin byte ranges to match and write back before everything else, for when the cache device may not last
+/// the whole run: given in a file, or the filesystem metadata a repair tool needs.
+pub struct Critical {
+    ranges: Vec<(usize, usize)>,
+}
+
+impl Critical {
+    /// `path` lists byte ranges as `start..end`, one or more per line; `fs` derives them from the filesystem on
+    /// `origin`. None when neither is given.
+    pub fn read(path: Option<&Path>, fs: Option<&str>, origin: Option<&MappedFile>) -> io::Result<Option<Self>> {
+        let mut ranges = Vec::new();
+        if let Some(path) = path {
+            let mut selection = Selection::default();
+            selection.add_file(path)?;
+            ranges.extend(selection.ranges(usize::MAX));
+            provenance::record_setting("priority_ranges", path.display());
+        }
+        if let Some(fs) = fs {
+            let Some(origin) = origin else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--priority-fs reads the filesystem on the origin, which needs --origin",
+                ));
+            };
+            ranges.extend(match fs {
+                "ext4" => ext4::critical_ranges(origin)?,
+                _ => xfs::critical_ranges(origin)?,
+            });
+            provenance::record_setting("priority_fs", fs);
+        }
+        if path.is_none() && fs.is_none() {
+            return Ok(None);
+        }
+        ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges.into_iter().filter(|(start, end)| start < end) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        let critical = Self { ranges: merged };
+        eprintln!(
+            "{} critical origin ranges, {} bytes, go first",
+            critical.ranges.len(),
+            critical.ranges.iter().map(|(start, end)| end - start).sum::<usize>()
+        );
+        Ok(Some(critical))
+    }
+
+    pub fn touches(&self, offset: usize, len: usize) -> bool {
+        let idx = self.ranges.partition_point(|&(_, end)| end <= offset);
+        self.ranges.get(idx).is_some_and(|&(start, _)| start < offset + len)
+    }
+
+    /// The fs-blocks within a cache block that tell whether it holds critical data: wherever a cache block maps,
+    /// the first critical fs-block of that origin region lies at the same place within it. Usually only a few.
+    pub fn positions(&self, cache_block_size: usize) -> Vec<usize> {
+        let mut positions = BTreeSet::new();
+        for &(start, end) in &self.ranges {
+            positions.insert(start % cache_block_size / BLOCK_SIZE);
+            // Every further region the range reaches into starts with it.
+            if end > start - start % cache_block_size + cache_block_size {
+                positions.insert(0);
+            }
+        }
+        positions.into_iter().collect()
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::provenance;
+
+/// Every run of `find` adds a row to `runs`; its cache blocks and their ranked candidates refer to it, so runs over
+/// several cache devices or with different indexes can be joined on `cache_block` or `origin_block`.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    time TEXT,
+    version TEXT,
+    command TEXT,
+    indexes TEXT,
+    cache_device TEXT,
+    cache_block_size INTEGER
+);
+CREATE TABLE IF NOT EXISTS inputs (run INTEGER REFERENCES runs (id), input TEXT);
+CREATE TABLE IF NOT EXISTS blocks (
+    run INTEGER REFERENCES runs (id),
+    cache_block INTEGER,
+    classification TEXT,
+    fake_matches INTEGER,
+    collisions INTEGER,
+    partial INTEGER,
+    PRIMARY KEY (run, cache_block)
+);
+CREATE TABLE IF NOT EXISTS candidates (
+    run INTEGER REFERENCES runs (id),
+    cache_block INTEGER,
+    rank INTEGER,
+    origin_block INTEGER,
+    count INTEGER,
+    match REAL,
+    confidence REAL,
+    tied INTEGER,
+    tainted INTEGER,
+    free_space INTEGER,
+    segment TEXT,
+    segment_offset INTEGER,
+    indexes TEXT,
+    PRIMARY KEY (run, cache_block, rank)
+);
+CREATE INDEX IF NOT EXISTS candidates_by_origin_block ON candidates (origin_block);
+";
+
+/// Blocks written between commits, so an interrupted run keeps most of what it found.
+const COMMIT_BLOCKS: usize = 10000;
+
+fn sql_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn sql_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or("NULL".to_string(), |value| value.to_string())
+}
+
+/// One ranked candidate of a cache block, as `find` prints it.
+pub struct CandidateRow<'a> {
+    pub rank: usize,
+    pub origin_block: usize,
+    pub count: usize,
+    pub match_percent: f64,
+    pub confidence: f64,
+    pub tied: bool,
+    pub tainted: bool,
+    /// Only known with --fs.
+    pub free_space: Option<bool>,
+    pub segment: Option<(&'a Path, usize)>,
+    /// The indexes it matches best in, when several were searched.
+    pub indexes: Vec<&'a str>,
+}
+
+/// A results database written through the `sqlite3` command-line shell, which takes the statements on stdin.
+pub struct ResultsDb {
+    child: Child,
+    sql: BufWriter<ChildStdin>,
+    blocks: usize,
+}
+
+impl ResultsDb {
+    /// Opens or creates the database and starts a run.
+    pub fn open(path: &Path, indexes: &[String], cache_device: &Path, cache_block_size: usize) -> io::Result<Self> {
+        let mut child = Command::new("sqlite3")
+            .arg("-batch")
+            .arg("-bail")
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot run sqlite3 for --db: {}", e)))?;
+        let mut sql = BufWriter::new(child.stdin.take().unwrap());
+        write!(sql, "{}", SCHEMA)?;
+        writeln!(sql, "BEGIN;")?;
+        writeln!(
+            sql,
+            "INSERT INTO runs (time, version, command, indexes, cache_device, cache_block_size) \
+             VALUES ({}, {}, {}, {}, {}, {});",
+            sql_string(&provenance::timestamp()),
+            sql_string(env!("CARGO_PKG_VERSION")),
+            sql_string(&provenance::command_line()),
+            sql_string(&indexes.join(", ")),
+            sql_string(&cache_device.to_string_lossy()),
+            cache_block_size
+        )?;
+        writeln!(sql, "CREATE TEMP TABLE current (run INTEGER);")?;
+        writeln!(sql, "INSERT INTO current VALUES (last_insert_rowid());")?;
+        for input in provenance::inputs() {
+            writeln!(
+                sql,
+                "INSERT INTO inputs SELECT run, {} FROM current;",
+                sql_string(&input)
+            )?;
+        }
+        Ok(Self { child, sql, blocks: 0 })
+    }
+
+    pub fn block(
+        &mut self,
+        cache_block: usize,
+        classification: &str,
+        fake_matches: usize,
+        collisions: usize,
+        partial: bool,
+        candidates: &[CandidateRow],
+    ) -> io::Result<()> {
+        writeln!(
+            self.sql,
+            "INSERT INTO blocks SELECT run, {}, {}, {}, {}, {} FROM current;",
+            cache_block,
+            sql_string(classification),
+            fake_matches,
+            collisions,
+            partial as u8
+        )?;
+        for candidate in candidates {
+            writeln!(
+                self.sql,
+                "INSERT INTO candidates SELECT run, {}, {}, {}, {}, {:.3}, {:.3}, {}, {}, {}, {}, {}, {} FROM current;",
+                cache_block,
+                candidate.rank,
+                candidate.origin_block,
+                candidate.count,
+                candidate.match_percent,
+                candidate.confidence,
+                candidate.tied as u8,
+                candidate.tainted as u8,
+                sql_option(candidate.free_space.map(|free| free as u8)),
+                sql_option(candidate.segment.map(|(path, _)| sql_string(&path.to_string_lossy()))),
+                sql_option(candidate.segment.map(|(_, offset)| offset)),
+                sql_option((!candidate.indexes.is_empty()).then(|| sql_string(&candidate.indexes.join(", "))))
+            )?;
+        }
+        self.blocks += 1;
+        if self.blocks.is_multiple_of(COMMIT_BLOCKS) {
+            writeln!(self.sql, "COMMIT;\nBEGIN;")?;
+        }
+        Ok(())
+    }
+
+    /// Commits the rest and waits for sqlite3 to write it.
+    pub fn finish(self) -> io::Result<()> {
+        let Self {
+            mut child,
+            mut sql,
+            blocks,
+        } = self;
+        writeln!(sql, "COMMIT;")?;
+        drop(sql.into_inner().map_err(|e| e.into_error())?);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("sqlite3 failed writing --db ({})", status)));
+        }
+        eprintln!("{} cache blocks written to the results database", blocks);
+        Ok(())
+    }
+}
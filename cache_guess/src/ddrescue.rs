@@ -0,0 +1,188 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// The regions a GNU ddrescue mapfile does not mark finished (`+`), as offsets into the device window: they hold
+/// whatever the image had before, usually zeroes, not the device's data.
+#[derive(Debug, Default)]
+pub struct BadRegions {
+    extents: Vec<(usize, usize)>,
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// How far a rescue has come: the size of the device its data lines cover, and whether ddrescue is done with it.
+/// Fails on a mapfile whose data lines do not cover the device from the start, as one caught being rewritten may.
+pub fn progress(path: &Path) -> io::Result<(usize, bool)> {
+    let mut end = 0;
+    let mut finished = false;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue;
+        }
+        let (Some(pos), Some(size)) = (parse_number(fields[0]), fields.get(1).and_then(|f| parse_number(f))) else {
+            // The current position and status line; ddrescue marks it + once it is done.
+            finished = fields.get(1) == Some(&"+");
+            continue;
+        };
+        if pos != end || fields.len() < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: data lines do not follow on at {}", path.display(), end),
+            ));
+        }
+        end = pos + size;
+    }
+    Ok((end, finished))
+}
+
+impl BadRegions {
+    /// Reads the mapfile of an image, keeping the part inside the window of `size` bytes at `base` that the
+    /// device was opened with.
+    pub fn read(path: &Path, base: usize, size: usize) -> io::Result<Self> {
+        let (start, end) = (base, base + size);
+        let mut extents: Vec<(usize, usize)> = Vec::new();
+        for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            // The current position and status line has a status character where data lines have a size.
+            let (Some(pos), Some(size)) = (parse_number(fields[0]), fields.get(1).and_then(|f| parse_number(f))) else {
+                continue;
+            };
+            let status = fields.get(2).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}:{}: data line without a status: '{}'",
+                        path.display(),
+                        number + 1,
+                        line
+                    ),
+                )
+            })?;
+            if *status == "+" {
+                continue;
+            }
+            let (bad_start, bad_end) = (pos.max(start), (pos + size).min(end));
+            if bad_start >= bad_end {
+                continue;
+            }
+            extents.push((bad_start - start, bad_end - start));
+        }
+        let mut bad = Self::default();
+        bad.extend(extents);
+        Ok(bad)
+    }
+
+    /// Adds more regions known to hold something other than the device's data, such as fs-blocks that read
+    /// differently every time.
+    pub fn extend(&mut self, extents: impl IntoIterator<Item = (usize, usize)>) {
+        let mut extents: Vec<(usize, usize)> = self.extents.drain(..).chain(extents).collect();
+        extents.sort_unstable();
+        for (bad_start, bad_end) in extents {
+            match self.extents.last_mut() {
+                Some(last) if last.1 >= bad_start => last.1 = last.1.max(bad_end),
+                _ => self.extents.push((bad_start, bad_end)),
+            }
+        }
+    }
+
+    /// Whether any byte of `[offset, offset + len)` is in a bad region.
+    pub fn touches(&self, offset: usize, len: usize) -> bool {
+        let idx = self.extents.partition_point(|&(_, end)| end <= offset);
+        self.extents.get(idx).is_some_and(|&(start, _)| start < offset + len)
+    }
+
+    pub fn total(&self) -> usize {
+        self.extents.iter().map(|(start, end)| end - start).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPFILE: &str = "# Mapfile. Created by GNU ddrescue version 1.27
+# current_pos  current_status  current_pass
+0x00030000     +               1
+#      pos        size  status
+0x00000000  0x00010000  +
+0x00010000  0x00004000  -
+0x00014000  0x0000C000  +
+0x00020000  0x00002000  /
+0x00022000  0x00001000  *
+0x00023000  0x0000D000  +
+";
+
+    fn mapfile(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("cache_guess-{}-{}.map", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_regions_not_finished() {
+        let path = mapfile("regions", MAPFILE);
+        let bad = BadRegions::read(&path, 0, 0x30000).unwrap();
+        // The non-tried and non-trimmed regions next to each other merge.
+        assert_eq!(bad.extents, [(0x10000, 0x14000), (0x20000, 0x23000)]);
+        assert_eq!(bad.total(), 0x7000);
+        assert!(bad.touches(0x13fff, 1) && bad.touches(0xf000, 0x2000));
+        assert!(!bad.touches(0x14000, 0xc000) && !bad.touches(0xffff, 1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_part_inside_the_window() {
+        let path = mapfile("window", MAPFILE);
+        let bad = BadRegions::read(&path, 0x12000, 0x10000).unwrap();
+        assert_eq!(bad.extents, [(0, 0x2000), (0xe000, 0x10000)]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_data_line_without_status() {
+        let path = mapfile("no-status", "0x0 +\n0x0 0x1000\n");
+        let e = BadRegions::read(&path, 0, 0x1000).unwrap_err();
+        assert!(e.to_string().contains(":2: data line without a status"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn extend_merges_overlaps() {
+        let mut bad = BadRegions::default();
+        bad.extend([(10, 20), (40, 50)]);
+        bad.extend([(15, 25), (25, 30), (60, 70)]);
+        assert_eq!(bad.extents, [(10, 30), (40, 50), (60, 70)]);
+    }
+
+    #[test]
+    fn progress_follows_the_data_lines() {
+        let path = mapfile("progress", MAPFILE);
+        assert_eq!(progress(&path).unwrap(), (0x30000, true));
+        std::fs::write(&path, MAPFILE.replace("0x00030000     +", "0x00030000     ?")).unwrap();
+        assert_eq!(progress(&path).unwrap(), (0x30000, false));
+        // Caught halfway through a rewrite.
+        std::fs::write(&path, MAPFILE.replace("0x00014000  0x0000C000  +\n", "")).unwrap();
+        assert!(progress(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parses_decimal_and_hex() {
+        assert_eq!(parse_number("4096"), Some(4096));
+        assert_eq!(parse_number("0x1000"), Some(4096));
+        assert_eq!(parse_number("0X1a"), Some(26));
+        assert_eq!(parse_number("+"), None);
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::mapped::device_len;
+
+/// Covers the logical block size of any device O_DIRECT is used on.
+const ALIGN: usize = 4096;
+const CHUNK: usize = 1 << 20;
+
+/// Reads a device front to back with O_DIRECT, so a scan does not push everything else out of the page cache.
+/// O_DIRECT needs buffers, offsets and lengths aligned to the logical block size, so the reads go through an aligned
+/// buffer of whole chunks.
+pub struct DirectReader {
+    file: File,
+    buffer: Vec<u8>,
+    /// Where the aligned part of `buffer` starts, and the unread bytes of the last read within it.
+    aligned: usize,
+    start: usize,
+    end: usize,
+}
+
+impl DirectReader {
+    /// Opens `path` and returns the reader and the device length.
+    pub fn open(path: &Path) -> io::Result<(Self, usize)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot open {} with O_DIRECT: {}", path.display(), e)))?;
+        let size = device_len(&mut file)?;
+        // device_len may have sought to the end.
+        file.seek(SeekFrom::Start(0))?;
+        let buffer = vec![0; CHUNK + ALIGN];
+        let aligned = buffer.as_ptr().align_offset(ALIGN);
+        let reader = Self {
+            file,
+            buffer,
+            aligned,
+            start: 0,
+            end: 0,
+        };
+        Ok((reader, size))
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.end {
+            // Only the last read of the device comes back short, so the file offset stays aligned.
+            let chunk = &mut self.buffer[self.aligned..self.aligned + CHUNK];
+            self.end = self.file.read(chunk)?;
+            self.start = 0;
+            if self.end == 0 {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.end - self.start);
+        let from = self.aligned + self.start;
+        buf[..len].copy_from_slice(&self.buffer[from..from + len]);
+        self.start += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cache_xml::CacheMetadata;
+use crate::index::Index;
+use crate::verify::Reader;
+use crate::{format_duration, hash, inuse, log_complete, log_status, provenance, BLOCK_SIZE};
+
+/// The normal quantile of the 95% intervals reported.
+const Z: f64 = 1.96;
+
+pub struct EstimateOptions<'a> {
+    pub cache_block_size: usize,
+    pub partition: Option<usize>,
+    /// Cache blocks picked at random to match.
+    pub samples: usize,
+    /// Picks the same cache blocks again; random without it.
+    pub seed: Option<u64>,
+    /// cache_dump XML, for how many of the cache blocks are dirty.
+    pub expected_mapping: Option<&'a Path>,
+    pub force: bool,
+}
+
+/// SplitMix64, enough to pick cache blocks evenly without a dependency for it.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// `count` distinct numbers below `total`, in ascending order so the device is read front to back (Floyd's
+/// algorithm).
+fn pick(random: &mut Random, count: usize, total: usize) -> Vec<usize> {
+    let mut picked = BTreeSet::new();
+    for bound in total - count..total {
+        let candidate = random.below(bound + 1);
+        if !picked.insert(candidate) {
+            picked.insert(bound);
+        }
+    }
+    picked.into_iter().collect()
+}
+
+/// The Wilson score interval of the rate `hits` of `n` samples of `total` stand for. The more of the total is
+/// sampled, the more the samples count, by the finite population correction; a sample of everything is exact.
+fn interval(hits: usize, n: usize, total: usize) -> (f64, f64) {
+    let p = hits as f64 / n.max(1) as f64;
+    if n == 0 || n >= total {
+        return if n == 0 { (0.0, 1.0) } else { (p, p) };
+    }
+    let n = n as f64 * (total - 1) as f64 / (total - n) as f64;
+    let denominator = 1.0 + Z * Z / n;
+    let center = (p + Z * Z / (2.0 * n)) / denominator;
+    let half = Z * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt() / denominator;
+    ((center - half).max(0.0), (center + half).min(1.0))
+}
+
+fn report(what: &str, hits: usize, n: usize, total: usize) {
+    let (low, high) = interval(hits, n, total);
+    println!(
+        "  {:<28} {:>7}  {:>5.1}% ({:.1}-{:.1}%), about {} to {} of {} cache blocks",
+        what,
+        hits,
+        100.0 * hits as f64 / n.max(1) as f64,
+        100.0 * low,
+        100.0 * high,
+        (low * total as f64).round() as usize,
+        (high * total as f64).round() as usize,
+        total
+    );
+}
+
+/// Matches a random sample of cache blocks against the index and extrapolates what `find` would make of the whole
+/// device, with 95% confidence intervals: how many cache blocks match an origin block in full, in part (the cache
+/// holds writes the origin lacks), or not at all. The index is streamed rather than loaded, so this takes one read
+/// of it and of the samples, not the hours of a full run.
+pub fn estimate(index_path: &Path, cache_device_path: &Path, options: &EstimateOptions) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path], options.force)?;
+    let cache_block_size = options.cache_block_size;
+    let index = Index::open(index_path)?;
+    let cache = Reader::open(cache_device_path, options.partition)?;
+    let expected = options.expected_mapping.map(CacheMetadata::read).transpose()?;
+    if let Some(block_size) = expected.as_ref().and_then(|expected| expected.block_size) {
+        if block_size * 512 != cache_block_size {
+            eprintln!(
+                "warning: metadata uses {} byte cache blocks, but --cache-block-size is {} bytes",
+                block_size * 512,
+                cache_block_size
+            );
+        }
+    }
+    let total = cache.size() / cache_block_size;
+    if total == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the cache device holds less than a cache block",
+        ));
+    }
+    let seed = options.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos() as u64)
+    });
+    provenance::record_setting("seed", seed);
+    let picked = pick(&mut Random(seed), options.samples.min(total), total);
+
+    let digest_len = index.digest_len();
+    let sample = index.sample_bytes().unwrap_or(BLOCK_SIZE);
+    // Zero-filled fs-blocks match every zeroed origin block, so they are left out and the rest tell.
+    let mut wanted: HashMap<Vec<u8>, Vec<(usize, usize)>> = HashMap::new();
+    let mut nonzero = vec![0; picked.len()];
+    let (mut unreadable, mut zero) = (0, 0);
+    for (number, &cache_block) in picked.iter().enumerate() {
+        log_status(number, picked.len(), "samples", false);
+        let Ok(data) = cache.read(cache_block, cache_block_size) else {
+            unreadable += 1;
+            continue;
+        };
+        let blocks: Vec<(usize, &[u8])> = data
+            .chunks(BLOCK_SIZE)
+            .map(|block| &block[..sample])
+            .enumerate()
+            .filter(|(_, block)| block.iter().any(|&byte| byte != 0))
+            .collect();
+        nonzero[number] = blocks.len();
+        zero += usize::from(blocks.is_empty());
+        let slices: Vec<&[u8]> = blocks.iter().map(|&(_, block)| block).collect();
+        for (digest, &(fs_block, _)) in hash::hash_blocks(&slices).into_iter().zip(&blocks) {
+            wanted
+                .entry(digest[..digest_len].to_vec())
+                .or_default()
+                .push((number, fs_block));
+        }
+    }
+    log_complete(picked.len(), "samples");
+
+    eprintln!("\nstreaming the index for the sampled digests");
+    let started = Instant::now();
+    // fs-blocks found where the cache block grid puts them, by sample and origin block.
+    let mut in_place: HashMap<(usize, usize), usize> = HashMap::new();
+    index.for_each(|entry, digest| {
+        for &(number, fs_block) in wanted.get(digest).into_iter().flatten() {
+            let origin_offset = entry * BLOCK_SIZE;
+            if origin_offset % cache_block_size == fs_block * BLOCK_SIZE {
+                *in_place.entry((number, origin_offset / cache_block_size)).or_default() += 1;
+            }
+        }
+    })?;
+    eprintln!("index streamed in {}", format_duration(started.elapsed()));
+    let mut best = vec![0; picked.len()];
+    for (&(number, _), &count) in &in_place {
+        best[number] = best[number].max(count);
+    }
+
+    let (mut full, mut partial, mut unmatched) = (0, 0, 0);
+    for (number, &best) in best.iter().enumerate() {
+        match nonzero[number] {
+            0 => {}
+            fs_blocks if best == fs_blocks => full += 1,
+            _ if best > 0 => partial += 1,
+            _ => unmatched += 1,
+        }
+    }
+    let n = picked.len();
+    println!(
+        "{} of {} cache blocks sampled (--seed {}), {} zero-filled and {} unreadable among them",
+        n, total, seed, zero, unreadable
+    );
+    report("match in full", full, n, total);
+    report("match in part, likely dirty", partial, n, total);
+    report("do not match", unmatched, n, total);
+    report("zero-filled", zero, n, total);
+    if unreadable > 0 {
+        report("unreadable", unreadable, n, total);
+    }
+    if let Some(expected) = &expected {
+        let recorded: Vec<bool> = picked
+            .iter()
+            .filter_map(|cache_block| expected.mappings.get(cache_block))
+            .map(|recorded| recorded.dirty)
+            .collect();
+        let dirty = recorded.iter().filter(|&&dirty| dirty).count();
+        println!("metadata: {} of the sampled cache blocks are mapped", recorded.len());
+        report("dirty in the metadata", dirty, n, total);
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::io::{self, Write};
+
+use crate::mapping::MappingEntry;
+
+/// A run of consecutive cache blocks mapping to consecutive origin blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extent {
+    pub cache_block: usize,
+    pub origin_block: usize,
+    pub blocks: usize,
+}
+
+pub fn coalesce(entries: &[MappingEntry]) -> Vec<Extent> {
+    let mut sorted: Vec<&MappingEntry> = entries.iter().collect();
+    sorted.sort_by_key(|entry| entry.cache_block);
+    let mut extents: Vec<Extent> = Vec::new();
+    for entry in sorted {
+        match extents.last_mut() {
+            Some(last)
+                if last.cache_block + last.blocks == entry.cache_block
+                    && last.origin_block + last.blocks == entry.origin_block =>
+            {
+                last.blocks += 1
+            }
+            _ => extents.push(Extent {
+                cache_block: entry.cache_block,
+                origin_block: entry.origin_block,
+                blocks: 1,
+            }),
+        }
+    }
+    extents
+}
+
+/// Writes extents as sector ranges; ends are exclusive.
+pub fn write(
+    out: &mut impl Write,
+    extents: &[Extent],
+    cache_block_size: usize,
+    sector_size: usize,
+    format: &str,
+) -> io::Result<()> {
+    let sectors = cache_block_size / sector_size;
+    let ranges = extents.iter().map(|extent| {
+        (
+            extent.cache_block * sectors,
+            (extent.cache_block + extent.blocks) * sectors,
+            extent.origin_block * sectors,
+            (extent.origin_block + extent.blocks) * sectors,
+        )
+    });
+    match format {
+        "json" => {
+            writeln!(out, "[")?;
+            for (i, (cache_start, cache_end, origin_start, origin_end)) in ranges.enumerate() {
+                writeln!(
+                    out,
+                    "  {{\"cache_start\": {}, \"cache_end\": {}, \"origin_start\": {}, \"origin_end\": {}}}{}",
+                    cache_start,
+                    cache_end,
+                    origin_start,
+                    origin_end,
+                    if i + 1 < extents.len() { "," } else { "" }
+                )?;
+            }
+            writeln!(out, "]")?;
+        }
+        _ => {
+            writeln!(out, "cache_start,cache_end,origin_start,origin_end")?;
+            for (cache_start, cache_end, origin_start, origin_end) in ranges {
+                writeln!(out, "{},{},{},{}", cache_start, cache_end, origin_start, origin_end)?;
+            }
+        }
+    }
+    out.flush()
+}
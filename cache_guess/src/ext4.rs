@@ -0,0 +1,137 @@
+use std::io;
+
+use crate::MappedFile;
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const EXT4_SUPER_MAGIC: u16 = 0xef53;
+const EXTENT_MAGIC: u16 = 0xf30a;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const INODE_EXTENTS_FL: u32 = 0x80000;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("ext4: {}", msg))
+}
+
+fn read(device: &MappedFile, offset: usize, len: usize) -> io::Result<&[u8]> {
+    if offset.checked_add(len).is_none_or(|end| end > device.size()) {
+        return Err(invalid(format!(
+            "read of {} bytes at {} is past the device end",
+            len, offset
+        )));
+    }
+    Ok(device.slice(offset, len))
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Whether a group keeps a backup of the superblock and group descriptors: all of them, or with sparse_super
+/// only 0, 1 and the powers of 3, 5 and 7.
+fn has_super(group: usize, sparse: bool) -> bool {
+    let power_of = |base: usize| {
+        let mut n = base;
+        while n < group {
+            n *= base;
+        }
+        n == group
+    };
+    !sparse || group <= 1 || power_of(3) || power_of(5) || power_of(7)
+}
+
+/// Byte ranges of what e2fsck cannot do without: the superblock and group descriptors with their backups, and
+/// an internal journal.
+pub fn critical_ranges(device: &MappedFile) -> io::Result<Vec<(usize, usize)>> {
+    let sb = read(device, SUPERBLOCK_OFFSET, 1024)?;
+    if le16(sb, 56) != EXT4_SUPER_MAGIC {
+        return Err(invalid(format!("no superblock found at offset {}", SUPERBLOCK_OFFSET)));
+    }
+    let log_block_size = le32(sb, 24);
+    if log_block_size > 6 {
+        return Err(invalid(format!("implausible block size 2^{} KiB", log_block_size)));
+    }
+    let block_size = 1024 << log_block_size;
+    let incompat = le32(sb, 96);
+    if incompat & INCOMPAT_META_BG != 0 {
+        return Err(invalid(
+            "meta_bg group descriptor layouts are not supported".to_string(),
+        ));
+    }
+    let is_64bit = incompat & INCOMPAT_64BIT != 0;
+    let mut blocks_count = le32(sb, 4) as usize;
+    if is_64bit {
+        blocks_count |= (le32(sb, 0x150) as usize) << 32;
+    }
+    let first_data_block = le32(sb, 20) as usize;
+    let blocks_per_group = le32(sb, 32) as usize;
+    if blocks_per_group == 0 || blocks_count <= first_data_block {
+        return Err(invalid(format!(
+            "implausible geometry ({} blocks, {} per group)",
+            blocks_count, blocks_per_group
+        )));
+    }
+    let desc_size = if is_64bit { (le16(sb, 254) as usize).max(32) } else { 32 };
+    let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+    let gdt_blocks = (groups * desc_size).div_ceil(block_size) + le16(sb, 206) as usize;
+    let sparse = le32(sb, 100) & RO_COMPAT_SPARSE_SUPER != 0;
+
+    let mut ranges = Vec::new();
+    for group in (0..groups).filter(|&group| has_super(group, sparse)) {
+        let first = first_data_block + group * blocks_per_group;
+        // The first group's superblock follows the boot sector, which is critical too.
+        let start = if group == 0 { 0 } else { first * block_size };
+        ranges.push((start, (first + 1 + gdt_blocks) * block_size));
+    }
+
+    let journal_inode = le32(sb, 224) as usize;
+    if le32(sb, 92) & COMPAT_HAS_JOURNAL == 0 || journal_inode == 0 {
+        return Ok(ranges);
+    }
+    let inode_size = if le32(sb, 76) == 0 { 128 } else { le16(sb, 88) as usize };
+    let inodes_per_group = le32(sb, 40) as usize;
+    let (group, index) = (
+        (journal_inode - 1) / inodes_per_group,
+        (journal_inode - 1) % inodes_per_group,
+    );
+    let descriptor = read(
+        device,
+        (first_data_block + 1) * block_size + group * desc_size,
+        desc_size,
+    )?;
+    let mut inode_table = le32(descriptor, 8) as usize;
+    if desc_size >= 64 {
+        inode_table |= (le32(descriptor, 0x28) as usize) << 32;
+    }
+    let inode = read(device, inode_table * block_size + index * inode_size, inode_size)?;
+    if le32(inode, 0x20) & INODE_EXTENTS_FL == 0 {
+        // mke2fs allocates block-mapped journals in one piece.
+        let size = le32(inode, 4) as usize | (le32(inode, 0x6c) as usize) << 32;
+        let start = le32(inode, 0x28) as usize * block_size;
+        ranges.push((start, start + size));
+        return Ok(ranges);
+    }
+    let header = &inode[0x28..0x28 + 60];
+    if le16(header, 0) != EXTENT_MAGIC {
+        return Err(invalid("bad extent header in the journal inode".to_string()));
+    }
+    if le16(header, 6) != 0 {
+        eprintln!("ext4: the journal's extent tree does not fit in its inode, leaving the journal out");
+        return Ok(ranges);
+    }
+    for entry in header[12..].chunks(12).take(le16(header, 2) as usize) {
+        // Lengths above 32768 mark unwritten extents.
+        let len = le16(entry, 4) as usize;
+        let len = if len > 32768 { len - 32768 } else { len };
+        let start = ((le16(entry, 6) as usize) << 32 | le32(entry, 8) as usize) * block_size;
+        ranges.push((start, start + len * block_size));
+    }
+    Ok(ranges)
+}
@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::mapping;
+use crate::selection::Selection;
+use crate::{inuse, log_complete, log_status, open_device};
+
+pub struct ExtractOptions<'a> {
+    pub cache_block_size: usize,
+    pub partition: Option<usize>,
+    pub blocks: Option<&'a Selection>,
+    /// Extract every cache block this `find` result has no mapping for.
+    pub unmatched: Option<&'a Path>,
+    pub output_dir: Option<&'a Path>,
+    /// A tar file, `-` for stdout.
+    pub tar: Option<&'a Path>,
+    pub force: bool,
+}
+
+fn block_name(cache_block: usize) -> String {
+    format!("cache-block-{:010}.bin", cache_block)
+}
+
+/// A ustar member header for a plain file.
+fn tar_header(name: &str, size: usize) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let mut field = |offset: usize, value: &[u8]| header[offset..offset + value.len()].copy_from_slice(value);
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, b"00000000000\0");
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    header
+}
+
+pub fn extract(cache_device_path: &Path, options: &ExtractOptions) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path], options.force)?;
+    let cache_device = open_device(cache_device_path, options.partition)?;
+    let cache_block_size = options.cache_block_size;
+    let total_blocks = cache_device.size() / cache_block_size;
+
+    let mut blocks: Vec<usize> = match options.blocks {
+        Some(selection) => selection.blocks(total_blocks).collect(),
+        None => Vec::new(),
+    };
+    if let Some(mapping_path) = options.unmatched {
+        let matched: HashSet<usize> = mapping::read(mapping_path)?
+            .iter()
+            .map(|entry| entry.cache_block)
+            .collect();
+        blocks.extend((0..total_blocks).filter(|block| !matched.contains(block)));
+    }
+    blocks.sort_unstable();
+    blocks.dedup();
+
+    let mut tar: Option<Box<dyn Write>> = match options.tar {
+        Some(path) if path == Path::new("-") => Some(Box::new(BufWriter::new(io::stdout().lock()))),
+        Some(path) => Some(Box::new(BufWriter::new(File::create(path)?))),
+        None => None,
+    };
+    if let Some(dir) = options.output_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    for (done, &cache_block) in blocks.iter().enumerate() {
+        log_status(done, blocks.len(), "blocks", false);
+        let data = cache_device.slice(cache_block * cache_block_size, cache_block_size);
+        let name = block_name(cache_block);
+        if let Some(tar) = tar.as_mut() {
+            tar.write_all(&tar_header(&name, data.len()))?;
+            tar.write_all(data)?;
+            tar.write_all(&vec![0; data.len().next_multiple_of(512) - data.len()])?;
+        }
+        if let Some(dir) = options.output_dir {
+            fs::write(dir.join(&name), data)?;
+        }
+    }
+    if let Some(mut tar) = tar {
+        // The end-of-archive marker is two zero records.
+        tar.write_all(&[0; 1024])?;
+        tar.flush()?;
+    }
+    log_complete(blocks.len(), "blocks");
+    eprintln!("\n{} cache blocks extracted", blocks.len());
+    Ok(())
+}
@@ -0,0 +1,1938 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::access::Strategy;
+use crate::block_errors::{self, Kind};
+use crate::cache_digests::CacheDigests;
+use crate::cache_xml::CacheMetadata;
+use crate::critical::Critical;
+use crate::db::{CandidateRow, ResultsDb};
+use crate::ddrescue::BadRegions;
+use crate::hash::{Key, SortedIndex};
+use crate::holes::Holes;
+use crate::identity::Identity;
+use crate::index::Index;
+use crate::layout::Layout;
+use crate::output::{csv_field, json_string, Output};
+use crate::pretty::{PrettyReport, Row};
+use crate::readahead::ReadAhead;
+use crate::result::{BlockResult, Candidate};
+use crate::selection::Selection;
+use crate::throttle::Throttle;
+use crate::unmatched::{PartialMatch, UnmatchedReport};
+use crate::verify::Reader;
+use crate::xfs::FreeSpace;
+use crate::{
+    compressed, ddrescue, hash, interrupt, inuse, log_complete, log_status, memory, metrics, open_image, provenance,
+    read_free_space, reread, scoring, BLOCK_SIZE, HASH_BYTES,
+};
+
+/// Origin offsets by digest, or for two-tier indexes by filter hash with the digests left on disk.
+enum Lookup<'a> {
+    Digests(SortedIndex<Key>),
+    Filters {
+        filters: SortedIndex<u64>,
+        index: &'a Index,
+    },
+}
+
+struct Matcher<'a> {
+    index: Lookup<'a>,
+    /// Which of the indexes this is: 0 for the one given first, then the --also-index ones in order.
+    number: usize,
+    digest_len: usize,
+    /// Entries in the whole index, that a digest's share of tells how rare it is.
+    entries: usize,
+    // Truncated digests collide; hits are byte-compared against the origin when it is available.
+    confirm: Option<&'a Reader>,
+    cache_block_size: usize,
+    /// Leading bytes of each fs-block the index digests cover.
+    sample: usize,
+    strategy: Strategy,
+}
+
+impl Matcher<'_> {
+    /// Whether the origin holds `data` at `origin_offset`; an origin block that cannot be read confirms nothing.
+    fn confirmed(&self, data: &[u8], origin_offset: usize) -> bool {
+        match self.confirm {
+            Some(origin) => {
+                origin_offset + data.len() <= origin.size()
+                    && origin
+                        .read(origin_offset / data.len(), data.len())
+                        .is_ok_and(|origin_data| origin_data == data)
+            }
+            None => true,
+        }
+    }
+
+    /// `(fs_block, origin_offset)` for every index entry the blocks hash to.
+    fn lookup(&self, blocks: &[&[u8]], collisions: &mut usize) -> Vec<(usize, usize)> {
+        let mut hits = Vec::new();
+        metrics::count("fs_blocks_looked_up", blocks.len());
+        match &self.index {
+            Lookup::Digests(digests) => {
+                for (fs_block, digest) in hash::hash_blocks(blocks).into_iter().enumerate() {
+                    hits.extend(
+                        digests
+                            .get(hash::key(&digest[..self.digest_len]))
+                            .map(|origin_offset| (fs_block, origin_offset)),
+                    );
+                }
+            }
+            Lookup::Filters { filters, index } => {
+                let filtered: Vec<(usize, Vec<usize>)> = blocks
+                    .iter()
+                    .enumerate()
+                    .map(|(fs_block, block)| (fs_block, filters.get(hash::xxh64(block)).collect::<Vec<_>>()))
+                    .filter(|(_, offsets)| !offsets.is_empty())
+                    .collect();
+                let hit_blocks: Vec<&[u8]> = filtered.iter().map(|&(fs_block, _)| blocks[fs_block]).collect();
+                for ((fs_block, offsets), digest) in filtered.into_iter().zip(hash::hash_blocks(&hit_blocks)) {
+                    for origin_offset in offsets {
+                        if index.digest(origin_offset / BLOCK_SIZE) == &digest[..self.digest_len] {
+                            hits.push((fs_block, origin_offset));
+                        } else {
+                            *collisions += 1;
+                        }
+                    }
+                }
+            }
+        }
+        metrics::count("candidate_hits", hits.len());
+        hits
+    }
+
+    /// As `lookup`, for `count` fs-blocks of the cache device from `first_fs_block` on, by the digests stored for
+    /// them instead of their data.
+    fn lookup_stored(
+        &self,
+        digests: &CacheDigests,
+        first_fs_block: usize,
+        count: usize,
+        collisions: &mut usize,
+    ) -> Vec<(usize, usize)> {
+        let mut hits = Vec::new();
+        metrics::count("fs_blocks_looked_up", count);
+        for fs_block in 0..count {
+            let digest = &digests.digest(first_fs_block + fs_block)[..self.digest_len];
+            match &self.index {
+                Lookup::Digests(index) => {
+                    hits.extend(
+                        index
+                            .get(hash::key(digest))
+                            .map(|origin_offset| (fs_block, origin_offset)),
+                    );
+                }
+                Lookup::Filters { filters, index } => {
+                    for origin_offset in filters.get(digests.filter_hash(first_fs_block + fs_block)) {
+                        if index.digest(origin_offset / BLOCK_SIZE) == digest {
+                            hits.push((fs_block, origin_offset));
+                        } else {
+                            *collisions += 1;
+                        }
+                    }
+                }
+            }
+        }
+        metrics::count("candidate_hits", hits.len());
+        hits
+    }
+
+    /// Whether `digests` were taken the way this matcher's index was, so they can stand in for hashing the data.
+    fn takes(&self, digests: &CacheDigests) -> bool {
+        digests.sample == self.sample && (digests.filter || matches!(self.index, Lookup::Digests(_)))
+    }
+
+    /// Adds where the fs-blocks of a cache block are found in this matcher's part of the index to `found`; `data` is
+    /// what `CacheScan::read` read of it.
+    fn search(&self, cache: &CacheScan, cache_block: usize, data: &[u8], throttle: &mut Throttle, found: &mut Found) {
+        let bad = &cache.bad;
+        let cache_block_size = self.cache_block_size;
+        let base = cache_block * cache_block_size;
+        let len = data.len();
+        let blocks: Vec<&[u8]> = data.chunks(BLOCK_SIZE).map(|block| &block[..self.sample]).collect();
+        let hits = match cache.digests.as_ref().filter(|digests| self.takes(digests)) {
+            Some(digests) => self.lookup_stored(digests, base / BLOCK_SIZE, blocks.len(), &mut found.collisions),
+            None => {
+                throttle.consume(len / BLOCK_SIZE * self.sample);
+                self.lookup(&blocks, &mut found.collisions)
+            }
+        };
+        // fs-blocks that were never rescued hold whatever the image had, not cache data.
+        let mut hits: Vec<(usize, usize, usize)> = hits
+            .into_iter()
+            .map(|(fs_block, origin_offset)| (fs_block, base + fs_block * BLOCK_SIZE, origin_offset))
+            .filter(|&(_, offset, _)| !bad.touches(offset, BLOCK_SIZE))
+            .collect();
+
+        let confirmed = match self.confirm {
+            Some(_) => self.strategy.run(
+                &mut hits,
+                |&(_, _, origin_offset)| origin_offset,
+                |&(fs_block, _, origin_offset)| {
+                    self.confirmed(&data[fs_block * BLOCK_SIZE..][..BLOCK_SIZE], origin_offset)
+                },
+            ),
+            None => vec![true; hits.len()],
+        };
+        // How often each fs-block's data occurs in the origin, and how much its contents say at all.
+        let mut copies = vec![0; blocks.len()];
+        for (&(fs_block, _, _), &confirmed) in hits.iter().zip(&confirmed) {
+            copies[fs_block] += usize::from(confirmed);
+        }
+        let entropy: Vec<f64> = blocks
+            .iter()
+            .zip(&copies)
+            .map(|(block, &copies)| if copies > 0 { scoring::entropy(block) } else { 0.0 })
+            .collect();
+        for (&(fs_block, _, match_offset), confirmed) in hits.iter().zip(confirmed) {
+            if !confirmed {
+                found.collisions += 1;
+                continue;
+            }
+            found.hit_fs_blocks[fs_block] = true;
+            let origin_fs_block = match_offset / BLOCK_SIZE;
+            let origin_cache_block = match_offset / cache_block_size;
+            let origin_local_fs_block = origin_fs_block % (cache_block_size / BLOCK_SIZE);
+
+            if origin_local_fs_block != fs_block {
+                found.fake_matches += 1;
+                found.partial.push(PartialMatch {
+                    fs_block,
+                    origin_offset: match_offset,
+                });
+                continue;
+            }
+            let (count, evidence) = found
+                .matches
+                .entry((origin_cache_block, self.number))
+                .or_insert((0, 0.0));
+            *count += 1;
+            *evidence += scoring::rarity(self.entries, copies[fs_block], entropy[fs_block]);
+        }
+    }
+}
+
+/// Matches a cache block against the matchers' parts of the indexes, on top of what earlier passes over other
+/// shards found.
+fn match_cache_block(
+    matchers: &[Matcher],
+    cache: &CacheScan,
+    cache_block: usize,
+    data: &[u8],
+    throttle: &mut Throttle,
+    earlier: Option<Found>,
+) -> BlockResult {
+    let cache_block_size = matchers[0].cache_block_size;
+    let mut found = earlier.unwrap_or_else(|| Found::new(cache_block_size / BLOCK_SIZE));
+    for matcher in matchers {
+        matcher.search(cache, cache_block, data, throttle, &mut found);
+    }
+    let bad = &cache.bad;
+    let base = cache_block * cache_block_size;
+    let len = data.len();
+    let mut partial = found.partial;
+    partial.sort_by_key(|m| m.fs_block);
+    // An origin block found through several indexes counts as well as it matches in the best of them.
+    let mut by_origin_block: HashMap<usize, Vec<(usize, Aligned)>> = HashMap::new();
+    for ((origin_block, number), matched) in found.matches {
+        by_origin_block.entry(origin_block).or_default().push((number, matched));
+    }
+
+    BlockResult {
+        cache_block,
+        candidates: by_origin_block
+            .into_iter()
+            .map(|(origin_block, counts)| {
+                let count = counts.iter().map(|&(_, (count, _))| count).max().unwrap_or(0);
+                let evidence = counts
+                    .iter()
+                    .filter(|&&(_, (c, _))| c == count)
+                    .map(|&(_, (_, evidence))| evidence)
+                    .fold(0.0, f64::max);
+                let mut indexes: Vec<usize> = counts
+                    .into_iter()
+                    .filter(|&(_, (c, _))| c == count)
+                    .map(|(number, _)| number)
+                    .collect();
+                indexes.sort_unstable();
+                Candidate {
+                    origin_block,
+                    count,
+                    evidence,
+                    confidence: 0.0,
+                    indexes,
+                }
+            })
+            .collect(),
+        fake_matches: found.fake_matches,
+        collisions: found.collisions,
+        partial,
+        matched_fs_blocks: found.hit_fs_blocks.iter().filter(|&&hit| hit).count(),
+        tied: 0,
+        tainted: bad.touches(base, len),
+    }
+}
+
+/// How many fs-blocks matched at their own position, and their `Candidate::evidence`.
+type Aligned = (usize, f64);
+
+/// Where a cache block's fs-blocks have been found so far. A sharded index too large to load at once is searched
+/// a shard per pass over the cache device, and what each pass finds adds up here.
+#[derive(Clone)]
+struct Found {
+    /// Aligned fs-block hits per origin cache block and index.
+    matches: HashMap<(usize, usize), Aligned>,
+    hit_fs_blocks: Vec<bool>,
+    fake_matches: usize,
+    collisions: usize,
+    partial: Vec<PartialMatch>,
+}
+
+impl Found {
+    fn new(fs_blocks: usize) -> Self {
+        Self {
+            matches: HashMap::new(),
+            hit_fs_blocks: vec![false; fs_blocks],
+            fake_matches: 0,
+            collisions: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.matches.is_empty() && self.partial.is_empty() && self.collisions == 0
+    }
+}
+
+/// What the passes over earlier shards found for one cache device, by cache block.
+#[derive(Default)]
+struct Earlier {
+    blocks: HashMap<usize, Found>,
+    /// For every cache block in a hole, as they all hold the same zeroes.
+    hole: Option<Found>,
+}
+
+#[derive(Clone)]
+pub struct FindOptions<'a> {
+    pub cache_block_size: usize,
+    /// From the metadata superblock; without it, the whole cache device is scanned.
+    pub cache_blocks: Option<usize>,
+    pub partition: Option<usize>,
+    pub origin_path: Option<&'a Path>,
+    pub origin_partition: Option<usize>,
+    pub fs: Option<&'a str>,
+    pub unmatched_report: Option<&'a Path>,
+    pub expected_mapping: Option<&'a Path>,
+    pub selection: Option<Selection>,
+    pub resume: Option<&'a Path>,
+    pub format: &'a str,
+    pub access: &'a str,
+    pub bwlimit: Option<f64>,
+    pub force: bool,
+    /// GNU ddrescue mapfiles of the cache device and origin images; unrescued regions taint the mappings they touch.
+    pub ddrescue_map: Option<&'a Path>,
+    pub origin_ddrescue_map: Option<&'a Path>,
+    /// Further indexes, of other states of the origin, searched alongside the main one.
+    pub also_index: Vec<&'a Path>,
+    /// SQLite database to add the results to, as one run per cache device.
+    pub db: Option<&'a Path>,
+    /// Stops the run once fewer than this percentage of the first this many cache blocks match anything.
+    pub abort: Option<(f64, usize)>,
+    /// Critical origin ranges, see `Critical::read`; the cache blocks holding them are matched first.
+    pub priority_ranges: Option<&'a Path>,
+    pub priority_fs: Option<&'a str>,
+    /// Matches the cache blocks the policy hints of `expected_mapping` rate hottest first, after the critical ones.
+    pub hot_first: bool,
+    /// Directory the lookup tables are saved to once built, and mapped from on later runs.
+    pub lookup_cache: Option<&'a Path>,
+    /// "heuristic" or "likelihood", see `scoring`.
+    pub confidence_model: &'a str,
+    /// Candidates scored below it are left out.
+    pub min_confidence: Option<f64>,
+    /// Candidates with a smaller share of their fs-blocks in place, in percent, are left out too.
+    pub min_match: Option<f64>,
+    /// Candidates listed for each cache block, at most.
+    pub top_n: Option<usize>,
+    /// Reads of the cache device to take the majority of, see `reread`.
+    pub passes: usize,
+    pub instability_report: Option<&'a Path>,
+    /// How often to look for newly rescued cache blocks, see `watch`.
+    pub watch: Option<Duration>,
+}
+
+/// The summary histogram buckets of best-match percentages, best first.
+pub const QUALITY_BUCKETS: [&str; 5] = ["100%", "90-99%", "50-89%", "1-49%", "0%"];
+
+/// What `--format csv` and `--db` say of a cache block's best candidate.
+fn classification(result: &BlockResult, fs_blocks: usize, tainted: impl Fn(usize) -> bool) -> &'static str {
+    match result.candidates.first() {
+        None => "unmatched",
+        Some(best) if tainted(best.origin_block) => "tainted",
+        Some(_) if result.tied != 0 => "tied",
+        Some(best) if best.count >= fs_blocks => "full",
+        Some(_) => "partial",
+    }
+}
+
+fn quality_bucket(result: &BlockResult, fs_blocks: usize) -> usize {
+    match result.candidates.first().map(|best| best.count) {
+        Some(count) if count >= fs_blocks => 0,
+        Some(count) if count * 10 >= fs_blocks * 9 => 1,
+        Some(count) if count * 2 >= fs_blocks => 2,
+        Some(count) if count > 0 => 3,
+        _ => 4,
+    }
+}
+
+/// One cache device to scan, and where its results go; stdout without an output.
+pub struct Job<'a> {
+    pub cache_device: &'a Path,
+    pub output: Option<&'a Path>,
+}
+
+/// What the origin side contributes to every job's results.
+struct Context<'a> {
+    layout: Option<&'a Layout>,
+    free_space: Option<&'a FreeSpace>,
+    expected: Option<&'a CacheMetadata>,
+    origin_bad: &'a BadRegions,
+    /// The paths of the indexes searched, by number; candidates name theirs when there is more than one.
+    index_labels: Vec<String>,
+}
+
+/// Reads a job file: one cache device per line, optionally followed by an output file.
+pub fn read_jobs(path: &Path) -> io::Result<Vec<(PathBuf, Option<PathBuf>)>> {
+    let mut jobs = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let cache_device = PathBuf::from(fields.next().unwrap());
+        jobs.push((cache_device, fields.next().map(PathBuf::from)));
+    }
+    Ok(jobs)
+}
+
+const CHECKPOINT: &str = "#checkpoint ";
+/// Starts the results of each cache device when several are scanned.
+const DEVICE: &str = "#device ";
+const JSON_DEVICE: &str = "{\"device\":";
+const JSON_CHECKPOINT: &str = "{\"checkpoint\":";
+/// The header of `--format csv`, after a `device` column when several cache devices are scanned. Blocks without a
+/// candidate leave its columns empty; an interrupted run ends in a `#checkpoint` line all the same.
+pub const CSV_COLUMNS: [&str; 9] = [
+    "cache_block",
+    "origin_block",
+    "match",
+    "confidence",
+    "runner_up",
+    "runner_up_confidence",
+    "fake_matches",
+    "collisions",
+    "classification",
+];
+
+fn checkpoint_line(line: &str) -> Option<usize> {
+    line.strip_prefix(CHECKPOINT)
+        .or_else(|| line.strip_prefix(JSON_CHECKPOINT)?.strip_suffix('}'))
+        .and_then(|block| block.trim().parse().ok())
+}
+
+/// The number a line starts with, and the rest of it.
+fn leading_number(text: &str) -> Option<(usize, &str)> {
+    let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    text[..end].parse().ok().map(|number| (number, &text[end..]))
+}
+
+/// The cache block whose results a line starts, in any of the formats.
+fn result_block(line: &str, format: &str) -> Option<usize> {
+    match format {
+        "jsonl" => leading_number(line.strip_prefix("{\"cache_block\":")?).map(|(block, _)| block),
+        "csv" => leading_number(line)
+            .filter(|(_, rest)| rest.starts_with(','))
+            .map(|(block, _)| block),
+        // Alternatives and unmatched or partial blocks come as comments; fake match and collision counts follow
+        // the block they belong to.
+        _ => leading_number(line.strip_prefix('#').unwrap_or(line))
+            .filter(|(_, rest)| {
+                [" -> ", " unmatched", " partial cache block"]
+                    .iter()
+                    .any(|s| rest.starts_with(s))
+            })
+            .map(|(block, _)| block),
+    }
+}
+
+/// What an earlier run that did not finish left in its results.
+struct Partial {
+    /// Its lines up to the last cache block known to be complete, without any checkpoint.
+    lines: Vec<String>,
+    /// That cache block; `None` if not even the first one is.
+    through: Option<usize>,
+    /// The inputs and settings its provenance records.
+    recorded: Vec<String>,
+}
+
+impl Partial {
+    /// Reads the results of an interrupted run, which end in a checkpoint, or of one that was killed, which end
+    /// wherever the output stopped: a torn last line is dropped, and so are all lines of the last cache block in
+    /// text results, which may have been cut short between them.
+    fn read(path: &Path, format: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let complete = match text.rfind('\n') {
+            Some(end) => &text[..end + 1],
+            None => "",
+        };
+        let mut lines: Vec<String> = Vec::new();
+        let mut checkpoint = None;
+        let mut recorded = Vec::new();
+        for line in complete.lines() {
+            match checkpoint_line(line) {
+                Some(block) => checkpoint = Some(block),
+                None => {
+                    recorded.extend(provenance::recorded(line));
+                    lines.push(line.to_string());
+                }
+            }
+        }
+        if let Some(checkpoint) = checkpoint {
+            return Ok(Self {
+                lines,
+                through: Some(checkpoint),
+                recorded,
+            });
+        }
+        let looks_like = match lines.iter().find(|line| !line.is_empty()) {
+            Some(line) if line.starts_with('{') => "jsonl",
+            Some(line) if line.starts_with("cache_block,") || line.starts_with("device,") => "csv",
+            Some(_) => "text",
+            None => format,
+        };
+        if looks_like != format {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} holds {} results, continue it with --format {}",
+                    path.display(),
+                    looks_like,
+                    looks_like
+                ),
+            ));
+        }
+        let starts: Vec<(usize, usize)> = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| result_block(line, format).map(|block| (i, block)))
+            .collect();
+        let (keep, through) = match (format, starts.as_slice()) {
+            (_, []) => (lines.len(), None),
+            ("text", [.., (last_start, _)]) => {
+                let through = starts.iter().rev().map(|&(_, block)| block).nth(1);
+                (*last_start, through)
+            }
+            (_, [.., (_, last)]) => (lines.len(), Some(*last)),
+        };
+        if format == "text" && !starts.is_empty() {
+            eprintln!(
+                "{} has no checkpoint; redoing cache block {}, whose results may be cut short",
+                path.display(),
+                starts[starts.len() - 1].1
+            );
+        }
+        lines.truncate(keep);
+        Ok(Self {
+            lines,
+            through,
+            recorded,
+        })
+    }
+
+    /// Fails unless this run reads the same inputs with the same settings as the one whose results it continues.
+    fn check(&self, path: &Path) -> io::Result<()> {
+        if self.recorded.is_empty() {
+            eprintln!(
+                "warning: {} records no provenance, so nothing checks that it came from the same inputs",
+                path.display()
+            );
+            return Ok(());
+        }
+        let now: Vec<String> = provenance::lines()
+            .into_iter()
+            .filter(|line| line.starts_with("input: ") || line.starts_with("setting: "))
+            .collect();
+        let mut differences: Vec<String> = self
+            .recorded
+            .iter()
+            .filter(|line| !now.contains(line))
+            .map(|line| format!("  then {}", line))
+            .collect();
+        differences.extend(
+            now.iter()
+                .filter(|line| !self.recorded.contains(line))
+                .map(|line| format!("  now  {}", line)),
+        );
+        if differences.is_empty() {
+            return Ok(());
+        }
+        differences.dedup();
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} came from different inputs or settings:\n{}",
+                path.display(),
+                differences.join("\n")
+            ),
+        ))
+    }
+}
+
+/// Loads the index once and scans every job's cache device against it in turn.
+pub fn find(index_path: &Path, jobs: &[Job], options: &FindOptions) -> io::Result<()> {
+    if jobs.len() > 1
+        && (options.resume.is_some()
+            || options.unmatched_report.is_some()
+            || options.expected_mapping.is_some()
+            || options.cache_blocks.is_some()
+            || options.ddrescue_map.is_some()
+            || options.instability_report.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--resume-from, --unmatched-report, --expected-mapping, --metadata-device, --ddrescue-map and \
+             --instability-report describe a single cache device",
+        ));
+    }
+    if options.passes == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--passes needs at least one pass",
+        ));
+    }
+    if options.format == "pretty" && (options.resume.is_some() || options.watch.is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--format pretty is for reading, --resume-from and --watch need results they can read back",
+        ));
+    }
+    provenance::record_setting("cache_block_size", options.cache_block_size);
+    let mut paths: Vec<&Path> = jobs.iter().map(|job| job.cache_device).collect();
+    paths.extend(options.origin_path);
+    inuse::ensure_unused(&paths, options.force)?;
+
+    let origin = options
+        .origin_path
+        .map(|origin_path| open_image(origin_path, options.origin_partition, options.force))
+        .transpose()?;
+    let free_space = match &origin {
+        Some(origin) => read_free_space(origin, options.fs)?,
+        None => None,
+    };
+    let mut unmatched_report = options.unmatched_report.map(UnmatchedReport::create).transpose()?;
+    let layout = Layout::read(index_path)?;
+    let expected = options.expected_mapping.map(CacheMetadata::read).transpose()?;
+    let critical = Critical::read(options.priority_ranges, options.priority_fs, origin.as_ref())?;
+    if (critical.is_some() || options.hot_first) && options.resume.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--priority-ranges, --priority-fs and --hot-first put the results out of cache block order, which \
+             --resume-from cannot continue",
+        ));
+    }
+    if let Some(block_size) = expected.as_ref().and_then(|expected| expected.block_size) {
+        // dm-cache counts in 512 byte sectors whatever the device's sector size.
+        if block_size * 512 != options.cache_block_size {
+            eprintln!(
+                "warning: metadata uses {} byte cache blocks, but --cache-block-size is {} bytes",
+                block_size * 512,
+                options.cache_block_size
+            );
+        }
+    }
+
+    let index_file = Index::open(index_path)?;
+    let also_index = options
+        .also_index
+        .iter()
+        .map(|path| Index::open(path))
+        .collect::<io::Result<Vec<_>>>()?;
+    if let (Some(origin), Some((device_size, recorded))) = (&origin, index_file.origin()) {
+        let mut problems = Vec::new();
+        if layout.is_none() && origin.size() != device_size {
+            problems.push(format!(
+                "the index was built from a {} byte device, this one has {} bytes",
+                device_size,
+                origin.size()
+            ));
+        }
+        if !recorded.is_empty() {
+            problems.extend(recorded.mismatches(&Identity::of(origin)));
+        }
+        if !problems.is_empty() {
+            let msg = format!(
+                "--origin does not look like the indexed device: {}",
+                problems.join("; ")
+            );
+            if !options.force {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} (--force to go ahead anyway)", msg),
+                ));
+            }
+            eprintln!("warning: {}", msg);
+        }
+    }
+    let entry_count = index_file.entry_count();
+    let entry_bytes = lookup_entry_bytes(&index_file);
+    // The --also-index ones are loaded whole for the last pass.
+    let also_bytes: usize = also_index
+        .iter()
+        .map(|index| index.entry_count() * lookup_entry_bytes(index))
+        .sum();
+    // A sharded index that does not fit in memory as a whole is searched a shard per pass over the cache devices.
+    let shard_count = index_file.shard_count();
+    let by_shard = shard_count > 1
+        && memory::available().is_some_and(|available| entry_count * entry_bytes + also_bytes > available);
+    let per_pass = if by_shard { 1 } else { shard_count };
+    let passes: Vec<Range<usize>> = (0..shard_count)
+        .step_by(per_pass)
+        .map(|first| first..first + per_pass)
+        .collect();
+    let loaded = if by_shard {
+        (0..shard_count)
+            .map(|shard| index_file.shard_entry_count(shard))
+            .max()
+            .unwrap_or(0)
+    } else {
+        entry_count
+    };
+    let what = match (by_shard, index_file.has_filter(), also_index.is_empty()) {
+        (_, _, false) => "the indexes",
+        (true, _, true) => "each index shard",
+        (false, true, true) => "the index filters",
+        (false, false, true) => "the index",
+    };
+    let advice = if index_file.has_filter() {
+        "this two-tier index already keeps its digests on disk; split it with collect --shard-size or run on a \
+         machine with more memory"
+            .to_string()
+    } else {
+        format!(
+            "rebuild it with collect --filter-hash, which keeps the digests on disk and needs about {}, or split it \
+             with --shard-size",
+            memory::gib(entry_count * mem::size_of::<(u64, usize)>())
+        )
+    };
+    memory::preflight(what, loaded * entry_bytes + also_bytes, &advice, options.force)?;
+    if by_shard && critical.is_some() && expected.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "finding the critical cache blocks without --metadata-device or --expected-mapping needs the whole index \
+             at once, not shard by shard",
+        ));
+    }
+    if by_shard && options.watch.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--watch matches every round against the whole index, which does not fit in memory",
+        ));
+    }
+    if by_shard {
+        eprintln!(
+            "searching the {} index shards one at a time, with a pass over the cache for each",
+            shard_count
+        );
+    }
+    let digest_len = index_file.digest_len();
+    if digest_len < HASH_BYTES && origin.is_none() {
+        eprintln!(
+            "warning: index holds {} byte digests and no --origin is given to confirm matches against",
+            digest_len
+        );
+    }
+    let sample = index_file.sample_bytes();
+    if let Some(sample) = sample {
+        eprintln!(
+            "index covers the first {} bytes of each block, {}",
+            sample,
+            if origin.is_some() {
+                "hits are confirmed against the full origin blocks"
+            } else {
+                "matches are provisional until confirmed with --origin or verify-mapping"
+            }
+        );
+    }
+
+    for (path, index) in options.also_index.iter().zip(&also_index) {
+        // --origin is the device as it is now, which other states of it need not match.
+        if index.digest_len() < HASH_BYTES || index.sample_bytes().is_some() {
+            eprintln!(
+                "warning: {} holds truncated or sampled digests, its matches are not confirmed",
+                path.display()
+            );
+        }
+    }
+
+    let strategy = Strategy::choose(options.access, &paths);
+    eprintln!("hashing with {}", hash::describe());
+    if let Some(origin) = &origin {
+        strategy.advise(origin, false);
+    }
+    // Without --origin the map is taken to cover the indexed device as a whole.
+    let origin_bad = match options.origin_ddrescue_map {
+        Some(map) => match &origin {
+            Some(origin) => BadRegions::read(map, origin.base, origin.size())?,
+            None => BadRegions::read(
+                map,
+                0,
+                index_file
+                    .origin()
+                    .map_or(index_file.entry_count() * BLOCK_SIZE, |(size, _)| size),
+            )?,
+        },
+        None => BadRegions::default(),
+    };
+    if origin_bad.total() > 0 {
+        eprintln!("origin ddrescue map: {} bytes not rescued", origin_bad.total());
+    }
+    // Hits are confirmed with plain reads, so that a bad sector of the origin only costs its own.
+    let origin = options
+        .origin_path
+        .zip(origin)
+        .map(|(origin_path, origin)| Reader::over(origin_path, origin))
+        .transpose()?;
+    let context = Context {
+        layout: layout.as_ref(),
+        free_space: free_space.as_ref(),
+        expected: expected.as_ref(),
+        origin_bad: &origin_bad,
+        index_labels: std::iter::once(index_path)
+            .chain(options.also_index.iter().copied())
+            .map(|path| path.display().to_string())
+            .collect(),
+    };
+    let mut caches = Vec::with_capacity(jobs.len());
+    let mut earlier: Vec<Earlier> = jobs.iter().map(|_| Earlier::default()).collect();
+    for (pass, shards) in passes.iter().enumerate() {
+        if passes.len() > 1 {
+            eprintln!("\nindex shard {} of {}", pass + 1, passes.len());
+        }
+        let last = pass + 1 == passes.len();
+        let mut matchers = vec![Matcher {
+            index: load(&index_file, index_path, shards.clone(), options.lookup_cache)?,
+            number: 0,
+            digest_len,
+            entries: entry_count,
+            confirm: origin.as_ref().filter(|_| digest_len < HASH_BYTES || sample.is_some()),
+            cache_block_size: options.cache_block_size,
+            sample: sample.unwrap_or(BLOCK_SIZE),
+            strategy,
+        }];
+        if last {
+            for (number, (index, path)) in also_index.iter().zip(&options.also_index).enumerate() {
+                matchers.push(Matcher {
+                    index: load(index, path, 0..index.shard_count(), options.lookup_cache)?,
+                    number: number + 1,
+                    digest_len: index.digest_len(),
+                    entries: index.entry_count(),
+                    confirm: None,
+                    cache_block_size: options.cache_block_size,
+                    sample: index.sample_bytes().unwrap_or(BLOCK_SIZE),
+                    strategy,
+                });
+            }
+        }
+        if let Some(interval) = options.watch {
+            watch(
+                &matchers,
+                &context,
+                &jobs[0],
+                strategy,
+                &mut unmatched_report,
+                interval,
+                options,
+            )?;
+            break;
+        }
+        for (number, job) in jobs.iter().enumerate() {
+            if jobs.len() > 1 {
+                eprintln!(
+                    "\ncache device {} of {}: {}",
+                    number + 1,
+                    jobs.len(),
+                    job.cache_device.display()
+                );
+            }
+            if pass == 0 {
+                let mut cache = CacheScan::open(job, strategy, options)?;
+                cache.use_digests(index_path, options)?;
+                caches.push(cache);
+            }
+            if let Some(critical) = critical.as_ref().filter(|_| last) {
+                caches[number].prioritize(critical, &matchers[0], expected.as_ref(), options);
+            }
+            if let Some(expected) = expected.as_ref().filter(|_| last && options.hot_first) {
+                caches[number].hottest_first(expected);
+            }
+            if last {
+                scan(
+                    &matchers,
+                    &context,
+                    &caches[number],
+                    &mut earlier[number],
+                    (jobs.len() > 1).then_some(number),
+                    &mut unmatched_report,
+                    options,
+                )?;
+            } else {
+                search_shard(&matchers[0], &caches[number], &mut earlier[number], options)?;
+            }
+        }
+    }
+    if let Some(report) = unmatched_report {
+        eprintln!("\n{} unmatched cache blocks reported", report.finish()?);
+    }
+    Ok(())
+}
+
+/// What an entry of an index takes in memory once loaded.
+pub fn lookup_entry_bytes(index_file: &Index) -> usize {
+    if index_file.has_filter() {
+        mem::size_of::<(u64, usize)>()
+    } else {
+        mem::size_of::<(Key, usize)>()
+    }
+}
+
+/// What a saved lookup table must have been built from to stand in for some shards of the index at `path`.
+fn lookup_source(path: &Path, index_file: &Index, shards: &Range<usize>) -> io::Result<String> {
+    let modified = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(format!(
+        "{}, {} bytes, modified {}.{:09}, shards {}..{} of {}, {}",
+        fs::canonicalize(path)?.display(),
+        index_file.size(),
+        modified.as_secs(),
+        modified.subsec_nanos(),
+        shards.start,
+        shards.end,
+        index_file.shard_count(),
+        if index_file.has_filter() { "filters" } else { "digests" }
+    ))
+}
+
+/// Loads the lookup table for some shards of the index, or with `saved` maps the one an earlier run saved there,
+/// saving it for later runs if there is none yet.
+fn load<'a>(index_file: &'a Index, path: &Path, shards: Range<usize>, saved: Option<&Path>) -> io::Result<Lookup<'a>> {
+    let started = Instant::now();
+    let saved = match saved {
+        Some(dir) => {
+            let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
+            Some((
+                dir.join(format!("{}.{}-{}.lookup", name, shards.start, shards.end)),
+                lookup_source(path, index_file, &shards)?,
+            ))
+        }
+        None => None,
+    };
+    if let Some((saved_path, source)) = &saved {
+        let lookup = if index_file.has_filter() {
+            SortedIndex::open_saved(saved_path, source)?.map(|filters| Lookup::Filters {
+                filters,
+                index: index_file,
+            })
+        } else {
+            SortedIndex::open_saved(saved_path, source)?.map(Lookup::Digests)
+        };
+        if let Some(lookup) = lookup {
+            eprintln!("using the lookup table saved in {}", saved_path.display());
+            metrics::time("index_load", started.elapsed());
+            return Ok(lookup);
+        }
+    }
+    let entry_count: usize = shards.clone().map(|shard| index_file.shard_entry_count(shard)).sum();
+    let index = if index_file.has_filter() {
+        let mut filters = Vec::with_capacity(entry_count);
+        index_file.for_each_filter_in(shards, |entry, filter| filters.push((filter, entry * BLOCK_SIZE)))?;
+        Lookup::Filters {
+            filters: SortedIndex::new(filters),
+            index: index_file,
+        }
+    } else {
+        let mut digests = Vec::with_capacity(entry_count);
+        index_file.for_each_in(shards, |entry, digest| {
+            digests.push((hash::key(digest), entry * BLOCK_SIZE))
+        })?;
+        Lookup::Digests(SortedIndex::new(digests))
+    };
+    log_complete(entry_count, "entries");
+    if let Some((saved_path, source)) = &saved {
+        let written = match &index {
+            Lookup::Digests(digests) => digests.save(saved_path, source),
+            Lookup::Filters { filters, .. } => filters.save(saved_path, source),
+        };
+        match written {
+            Ok(()) => eprintln!("\nsaved the lookup table to {} for later runs", saved_path.display()),
+            Err(e) => eprintln!(
+                "\nwarning: could not save the lookup table to {}: {}",
+                saved_path.display(),
+                e
+            ),
+        }
+    }
+    metrics::time("index_load", started.elapsed());
+    Ok(index)
+}
+
+/// A job's cache device, opened once for all passes, and which of its cache blocks are matched.
+struct CacheScan<'a> {
+    job: &'a Job<'a>,
+    device: Reader,
+    holes: Holes,
+    bad: BadRegions,
+    cache_block_size: usize,
+    total_blocks: usize,
+    /// fs-blocks of a final cache block that the device end cuts short, and that block.
+    partial_fs_blocks: usize,
+    partial_block: Option<usize>,
+    selection: Option<Selection>,
+    /// The results of the earlier run that `--resume-from` continues.
+    partial: Option<Partial>,
+    /// Cache blocks matched before the rest, in this order: those holding critical origin data, then with
+    /// `--hot-first` those the policy hints rate hottest.
+    first: Vec<usize>,
+    prioritized: HashSet<usize>,
+    /// What `collect --cache-device` hashed the fs-blocks to, looked up instead of reading them.
+    digests: Option<CacheDigests>,
+}
+
+impl<'a> CacheScan<'a> {
+    fn open(job: &'a Job<'a>, strategy: Strategy, options: &FindOptions) -> io::Result<Self> {
+        let mut device = open_image(job.cache_device, options.partition, options.force)?;
+        // Holes in a compressed image say nothing about what it decompresses to.
+        let holes = match compressed::Format::detect(job.cache_device) {
+            Some(_) => Holes::default(),
+            None => Holes::find(job.cache_device, &device)?,
+        };
+        let mut bad = match options.ddrescue_map {
+            Some(map) => BadRegions::read(map, device.base, device.size())?,
+            None => BadRegions::default(),
+        };
+        if bad.total() > 0 {
+            eprintln!(
+                "ddrescue map: {} bytes of the cache device not rescued, not matched",
+                bad.total()
+            );
+        }
+        if options.passes > 1 {
+            let reread = reread::reread(
+                job.cache_device,
+                &device,
+                options.passes,
+                options.cache_block_size,
+                options.instability_report,
+                options.force,
+            )?;
+            device = reread.device;
+            bad.extend(reread.unreliable);
+        }
+        let device = match options.passes {
+            1 => Reader::over(job.cache_device, device)?,
+            _ => Reader::in_memory(job.cache_device, device),
+        };
+        if holes.total() > 0 {
+            eprintln!(
+                "cache device has {} bytes of holes, matched as zeroes once",
+                holes.total()
+            );
+        }
+        strategy.advise(device.view(), true);
+        let cache_block_size = options.cache_block_size;
+        let fs_blocks = cache_block_size / BLOCK_SIZE;
+        let mut total_blocks = device.size() / cache_block_size;
+        let tail = device.size() % cache_block_size;
+        let partial_fs_blocks = tail / BLOCK_SIZE;
+        if partial_fs_blocks > 0 {
+            eprintln!(
+                "cache device ends in a partial cache block {}: {} of {} fs-blocks",
+                total_blocks, partial_fs_blocks, fs_blocks
+            );
+            total_blocks += 1;
+        }
+        if !tail.is_multiple_of(BLOCK_SIZE) {
+            eprintln!(
+                "warning: the last {} bytes of the cache device are less than an fs-block and are not matched",
+                tail % BLOCK_SIZE
+            );
+        }
+        let partial_block = (partial_fs_blocks > 0).then(|| total_blocks - 1);
+        if let Some(cache_blocks) = options.cache_blocks {
+            if cache_blocks > total_blocks {
+                eprintln!(
+                    "warning: the metadata has {} cache blocks, but the cache device only holds {}",
+                    cache_blocks, total_blocks
+                );
+            }
+            total_blocks = total_blocks.min(cache_blocks);
+        }
+        let mut selection = options.selection.clone();
+        let partial = options
+            .resume
+            .map(|path| Partial::read(path, options.format))
+            .transpose()?;
+        match partial.as_ref().map(|partial| partial.through) {
+            Some(Some(through)) => {
+                eprintln!("resuming after cache block {}", through);
+                selection
+                    .get_or_insert_with(|| Selection::parse("0..").unwrap())
+                    .remove_through(through);
+            }
+            Some(None) => eprintln!("no cache block of the earlier results is complete, starting over"),
+            None => {}
+        }
+        Ok(Self {
+            job,
+            device,
+            holes,
+            bad,
+            cache_block_size,
+            total_blocks,
+            partial_fs_blocks,
+            partial_block,
+            selection,
+            partial,
+            first: Vec::new(),
+            prioritized: HashSet::new(),
+            digests: None,
+        })
+    }
+
+    /// Takes up the digests `collect --cache-device` stored next to the index, unless the device is read over
+    /// and the majority of the reads matched, which they were not taken of.
+    fn use_digests(&mut self, index_path: &Path, options: &FindOptions) -> io::Result<()> {
+        if options.passes == 1 {
+            self.digests = CacheDigests::open(index_path, self.device.view())?;
+        }
+        Ok(())
+    }
+
+    /// Finds the cache blocks that hold critical origin data, for `blocks` to yield first: those the metadata maps
+    /// there, or without it those where the fs-blocks at `Critical::positions` match critical index entries.
+    fn prioritize(
+        &mut self,
+        critical: &Critical,
+        matcher: &Matcher,
+        expected: Option<&CacheMetadata>,
+        options: &FindOptions,
+    ) {
+        let cache_block_size = self.cache_block_size;
+        let (blocks, block_count) = self.blocks();
+        let first: Vec<usize> = match expected {
+            Some(expected) => blocks
+                .filter(|cache_block| {
+                    expected.mappings.get(cache_block).is_some_and(|recorded| {
+                        critical.touches(recorded.origin_block * cache_block_size, cache_block_size)
+                    })
+                })
+                .collect(),
+            None => {
+                eprintln!("\nprobing the cache blocks for critical origin data");
+                let positions = critical.positions(cache_block_size);
+                let mut throttle = Throttle::new(options.bwlimit);
+                let mut first = Vec::new();
+                for (done, cache_block) in blocks.enumerate() {
+                    log_status(done, block_count, "blocks", true);
+                    if self.in_hole(cache_block) {
+                        continue;
+                    }
+                    // Zeroes are everywhere and would make every cache block critical.
+                    let probes: Vec<(usize, Vec<u8>)> = positions
+                        .iter()
+                        .map(|&position| (position, cache_block * cache_block_size + position * BLOCK_SIZE))
+                        .filter(|&(_, offset)| {
+                            offset + BLOCK_SIZE <= self.device.size() && !self.bad.touches(offset, BLOCK_SIZE)
+                        })
+                        .filter_map(|(position, offset)| {
+                            let data = self.device.read(offset / BLOCK_SIZE, BLOCK_SIZE).ok()?;
+                            Some((position, data[..matcher.sample].to_vec()))
+                        })
+                        .filter(|(_, data)| data.iter().any(|&byte| byte != 0))
+                        .collect();
+                    throttle.consume(probes.len() * matcher.sample);
+                    let data: Vec<&[u8]> = probes.iter().map(|(_, data)| data.as_slice()).collect();
+                    let held = matcher.lookup(&data, &mut 0).into_iter().any(|(probe, origin_offset)| {
+                        origin_offset % cache_block_size == probes[probe].0 * BLOCK_SIZE
+                            && critical.touches(origin_offset, BLOCK_SIZE)
+                    });
+                    if held {
+                        first.push(cache_block);
+                    }
+                }
+                log_complete(block_count, "blocks");
+                eprintln!();
+                first
+            }
+        };
+        eprintln!(
+            "{} cache blocks hold critical origin data and are matched first",
+            first.len()
+        );
+        self.put_first(first);
+    }
+
+    /// Moves the cache blocks with policy hints to the front, hottest first, since they are likeliest to hold
+    /// recent data. Critical ones stay ahead of them, but go hottest first among themselves.
+    fn hottest_first(&mut self, expected: &CacheMetadata) {
+        if expected.hints.is_empty() {
+            eprintln!("warning: the metadata holds no policy hints, --hot-first leaves the order as it is");
+            return;
+        }
+        let (hot_critical, hot): (Vec<usize>, Vec<usize>) = expected
+            .hottest_first(self.blocks().0)
+            .into_iter()
+            .partition(|cache_block| self.prioritized.contains(cache_block));
+        let cold_critical = self
+            .first
+            .iter()
+            .filter(|cache_block| !expected.hints.contains_key(cache_block));
+        let first: Vec<usize> = hot_critical.iter().chain(cold_critical).chain(&hot).copied().collect();
+        eprintln!(
+            "\n{} cache blocks with policy hints are matched hottest first",
+            hot.len()
+        );
+        self.put_first(first);
+    }
+
+    fn put_first(&mut self, first: Vec<usize>) {
+        self.prioritized = first.iter().copied().collect();
+        self.first = first;
+    }
+
+    /// The cache blocks to match, in order, and how many there are.
+    /// Reads the cache blocks ahead of the matching, leaving out unrescued regions.
+    fn read_ahead<'scope, 'env>(&'env self, scope: &'scope thread::Scope<'scope, 'env>) -> ReadAhead {
+        ReadAhead::spawn(scope, self.device.view(), |offset, len| self.bad.touches(offset, len))
+    }
+
+    /// Asks for `next` to be read while the current cache block is matched; holes are all zeroes and never read.
+    /// A device read with plain reads is read ahead by the kernel, which faults nothing in.
+    fn prefetch(&self, ahead: &ReadAhead, next: Option<usize>) {
+        if let Some(next) = next.filter(|&next| !self.in_hole(next)) {
+            let (offset, len) = (next * self.cache_block_size, self.cache_block_size);
+            if !self.device.will_need(offset, len.min(self.device.size() - offset)) {
+                ahead.prefetch(offset, len);
+            }
+        }
+    }
+
+    /// The whole fs-blocks of `cache_block`, read with plain reads; a cache block cut short by the device end has
+    /// fewer. None if the read fails: the cache block is then left out, and goes into the block errors of the run.
+    fn read(&self, cache_block: usize) -> Option<Vec<u8>> {
+        let base = cache_block * self.cache_block_size;
+        let len = self.cache_block_size.min(self.device.size() - base) / BLOCK_SIZE * BLOCK_SIZE;
+        self.device
+            .read_at(base, len)
+            .inspect_err(|e| {
+                block_errors::record(
+                    Kind::IoRead,
+                    self.job.cache_device,
+                    cache_block,
+                    self.cache_block_size,
+                    e,
+                );
+            })
+            .ok()
+    }
+
+    fn blocks(&self) -> (Box<dyn Iterator<Item = usize> + '_>, usize) {
+        let (blocks, count): (Box<dyn Iterator<Item = usize>>, usize) = match &self.selection {
+            Some(selection) => (
+                Box::new(selection.blocks(self.total_blocks)),
+                selection.count(self.total_blocks),
+            ),
+            None => (Box::new(0..self.total_blocks), self.total_blocks),
+        };
+        if self.first.is_empty() {
+            return (blocks, count);
+        }
+        let rest = blocks.filter(|cache_block| !self.prioritized.contains(cache_block));
+        (Box::new(self.first.iter().copied().chain(rest)), count)
+    }
+
+    fn in_hole(&self, cache_block: usize) -> bool {
+        let offset = cache_block * self.cache_block_size;
+        self.holes.contains(offset, self.cache_block_size) && !self.bad.touches(offset, self.cache_block_size)
+    }
+}
+
+/// Matches a cache device image that ddrescue is still writing, in rounds: each matches the cache blocks the
+/// mapfile marks rescued in full since the last, and rewrites the output with all results so far in cache block
+/// order. Stops once ddrescue is done and everything it rescued is matched, or when interrupted. Neighbors matched
+/// in different rounds do not score each other.
+fn watch(
+    matchers: &[Matcher],
+    context: &Context,
+    job: &Job,
+    strategy: Strategy,
+    unmatched_report: &mut Option<UnmatchedReport>,
+    interval: Duration,
+    options: &FindOptions,
+) -> io::Result<()> {
+    let (Some(map), Some(output)) = (options.ddrescue_map, job.output) else {
+        unreachable!("--watch requires --ddrescue-map and --output");
+    };
+    let mut round_path = output.as_os_str().to_owned();
+    round_path.push(".round");
+    let round_path = PathBuf::from(round_path);
+    let cache_block_size = options.cache_block_size;
+    interrupt::install();
+    // Lines before the first result, and every cache block's result lines.
+    let mut header: Option<Vec<String>> = None;
+    let mut results: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut done: Vec<bool> = Vec::new();
+    let mut covered = 0;
+    loop {
+        let (size, finished) = match ddrescue::progress(map) {
+            Ok((size, _)) if size < covered => (None, false),
+            Ok((size, finished)) => (Some(size), finished),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => (None, false),
+            Err(e) => return Err(e),
+        };
+        let Some(size) = size else {
+            eprintln!("{} is being rewritten, trying again", map.display());
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        };
+        covered = size;
+        let bad = BadRegions::read(map, 0, size)?;
+        let written = fs::metadata(job.cache_device).map_or(0, |metadata| metadata.len() as usize);
+        let total = size.div_ceil(cache_block_size);
+        done.resize(total, false);
+        let mut wanted = vec![options.selection.is_none(); total];
+        for cache_block in options.selection.iter().flat_map(|selection| selection.blocks(total)) {
+            wanted[cache_block] = true;
+        }
+        let mut fresh = Selection::default();
+        let mut count = 0;
+        for cache_block in 0..total {
+            if !wanted[cache_block] || done[cache_block] {
+                continue;
+            }
+            let start = cache_block * cache_block_size;
+            let end = (start + cache_block_size).min(size);
+            if end <= written && !bad.touches(start, end - start) {
+                fresh.add_range(cache_block, cache_block + 1);
+                done[cache_block] = true;
+                count += 1;
+            }
+        }
+        if count > 0 {
+            eprintln!("\n{} newly rescued cache blocks", count);
+            let round_job = Job {
+                cache_device: job.cache_device,
+                output: Some(&round_path),
+            };
+            let mut round = options.clone();
+            round.selection = Some(fresh);
+            let cache = CacheScan::open(&round_job, strategy, &round)?;
+            scan(
+                matchers,
+                context,
+                &cache,
+                &mut Earlier::default(),
+                None,
+                unmatched_report,
+                &round,
+            )?;
+            let text = fs::read_to_string(&round_path)?;
+            fs::remove_file(&round_path)?;
+            let mut block = None;
+            let mut preamble = Vec::new();
+            for line in text.lines().filter(|line| checkpoint_line(line).is_none()) {
+                block = result_block(line, options.format).or(block);
+                match block {
+                    Some(block) => results.entry(block).or_default().push(line.to_string()),
+                    None => preamble.push(line.to_string()),
+                }
+            }
+            // The latest round's provenance has the image as it has grown.
+            let header = header.insert(preamble);
+            let mut out = Output::create(Some(output))?;
+            for line in header.iter().chain(results.values().flatten()) {
+                writeln!(out, "{}", line)?;
+            }
+            out.commit()?;
+            eprintln!(
+                "{} of {} cache blocks matched so far, results in {}",
+                done.iter()
+                    .zip(&wanted)
+                    .filter(|&(&done, &wanted)| done && wanted)
+                    .count(),
+                wanted.iter().filter(|&&wanted| wanted).count(),
+                output.display()
+            );
+        }
+        if interrupt::requested() {
+            eprintln!("\ninterrupted, no longer watching {}", job.cache_device.display());
+            return Ok(());
+        }
+        if finished {
+            eprintln!("\nddrescue is done with {}", job.cache_device.display());
+            return Ok(());
+        }
+        let slept = Instant::now();
+        while slept.elapsed() < interval && !interrupt::requested() {
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+/// A pass over a cache device that only records what one shard of the index finds, for the last pass to add to.
+fn search_shard(matcher: &Matcher, cache: &CacheScan, earlier: &mut Earlier, options: &FindOptions) -> io::Result<()> {
+    interrupt::install();
+    let mut throttle = Throttle::new(options.bwlimit);
+    let fs_blocks = options.cache_block_size / BLOCK_SIZE;
+    let (blocks, block_count) = cache.blocks();
+    let mut hole_searched = false;
+    thread::scope(|scope| {
+        let ahead = cache.read_ahead(scope);
+        let mut blocks = blocks.enumerate().peekable();
+        while let Some((done, cache_block)) = blocks.next() {
+            if interrupt::requested() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "interrupted while searching the index shard by shard, before anything was written",
+                ));
+            }
+            log_status(done, block_count, "blocks", true);
+            cache.prefetch(&ahead, blocks.peek().map(|&(_, next)| next));
+            if cache.in_hole(cache_block) && hole_searched {
+                continue;
+            }
+            // Holes read as zeroes without touching the disk.
+            let Some(data) = cache.read(cache_block) else {
+                continue;
+            };
+            if cache.in_hole(cache_block) {
+                let found = earlier.hole.get_or_insert_with(|| Found::new(fs_blocks));
+                matcher.search(cache, cache_block, &data, &mut throttle, found);
+                hole_searched = true;
+                continue;
+            }
+            let mut found = earlier
+                .blocks
+                .remove(&cache_block)
+                .unwrap_or_else(|| Found::new(fs_blocks));
+            matcher.search(cache, cache_block, &data, &mut throttle, &mut found);
+            if !found.is_empty() {
+                earlier.blocks.insert(cache_block, found);
+            }
+        }
+        Ok(())
+    })?;
+    log_complete(block_count, "blocks");
+    Ok(())
+}
+
+/// Fails the run when too few of its first cache blocks matched anything for the rest to be worth scanning.
+/// Scores a cache block's candidates with the chosen model and leaves out those below --min-confidence or
+/// --min-match, and past --top-n; true if the floors left none.
+fn rate(
+    result: &mut BlockResult,
+    prev: Option<&BlockResult>,
+    next: Option<&BlockResult>,
+    fs_blocks: usize,
+    options: &FindOptions,
+) -> bool {
+    match options.confidence_model {
+        "likelihood" => scoring::likelihood(result, prev, next, fs_blocks),
+        _ => scoring::score(result, prev, next, fs_blocks),
+    }
+    let had_candidates = !result.candidates.is_empty();
+    if options.min_confidence.is_some() || options.min_match.is_some() {
+        let floor = options.min_confidence.unwrap_or(f64::MIN);
+        let min_count = options.min_match.unwrap_or(0.0) / 100.0 * fs_blocks as f64;
+        result
+            .candidates
+            .retain(|candidate| candidate.confidence >= floor && candidate.count as f64 >= min_count);
+        result.tied = result.tied.min(result.candidates.len().saturating_sub(1));
+    }
+    // A tie stays one when its runners-up are not listed.
+    if let Some(top_n) = options.top_n {
+        result.candidates.truncate(top_n);
+    }
+    had_candidates && result.candidates.is_empty()
+}
+
+fn hopeless(matched: usize, probed: usize, percent: f64, cache_block_size: usize) -> io::Result<()> {
+    let share = matched as f64 / probed as f64 * 100.0;
+    if share >= percent {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "only {} of the first {} cache blocks ({:.2}%) matched anything, below --abort-if-below {}%; stopped \
+         without writing results. The parameters are likely wrong: check that the index is of this cache's origin, \
+         that --cache-block-size {} is the cache's own (the metadata superblock records it), the --partition, \
+         --data-offset and --origin-partition offsets, and shift-scan for an origin that starts at another offset",
+        matched,
+        probed,
+        share,
+        percent,
+        cache_block_size / 512
+    )))
+}
+
+fn scan(
+    matchers: &[Matcher],
+    context: &Context,
+    cache: &CacheScan,
+    earlier: &mut Earlier,
+    // Which of several cache devices this is; its results are then labelled with it.
+    number: Option<usize>,
+    unmatched_report: &mut Option<UnmatchedReport>,
+    options: &FindOptions,
+) -> io::Result<()> {
+    let job = cache.job;
+    let label = number.is_some();
+    let mut throttle = Throttle::new(options.bwlimit);
+    let cache_block_size = options.cache_block_size;
+    let fs_blocks = cache_block_size / BLOCK_SIZE;
+    let (partial_fs_blocks, partial_block) = (cache.partial_fs_blocks, cache.partial_block);
+    let (layout, free_space, expected) = (context.layout, context.free_space, context.expected);
+    let mut tainted_mappings = 0;
+    // Guesses that agree with the metadata, disagree, and recorded blocks without any guess.
+    let (mut agreed, mut disagreed, mut missed) = (0, 0, 0);
+
+    let (jsonl, csv) = (options.format == "jsonl", options.format == "csv");
+    if let (Some(partial), Some(path)) = (&cache.partial, options.resume) {
+        partial.check(path)?;
+    }
+    let mut out = Output::create(job.output)?;
+    // CSV goes into spreadsheets, which would take comment lines for rows.
+    if jsonl {
+        writeln!(out, "{}", provenance::json())?;
+    } else if !csv {
+        provenance::write_comments(&mut out)?;
+    }
+    let mut mapped = 0;
+    let carried = cache.partial.is_some() && out.is_file();
+    // A file output replaces the earlier results, so carry them over.
+    if let Some(partial) = cache.partial.as_ref().filter(|_| carried) {
+        for line in &partial.lines {
+            writeln!(out, "{}", line)?;
+        }
+    }
+
+    // Several cache devices written to stdout share one header.
+    if csv {
+        if !carried && (job.output.is_some() || number.unwrap_or(0) == 0) {
+            writeln!(out, "{}{}", if label { "device," } else { "" }, CSV_COLUMNS.join(","))?;
+        }
+    } else if label {
+        if jsonl {
+            writeln!(
+                out,
+                "{}{}}}",
+                JSON_DEVICE,
+                json_string(&job.cache_device.to_string_lossy())
+            )?;
+        } else {
+            writeln!(out, "{}{}", DEVICE, job.cache_device.display())?;
+        }
+    }
+    let mut pretty = (options.format == "pretty").then(|| PrettyReport::new(&out, cache.total_blocks));
+    if let Some(pretty) = &pretty {
+        pretty.start(&mut out)?;
+    }
+
+    let mut db = options
+        .db
+        .map(|path| ResultsDb::open(path, &context.index_labels, job.cache_device, cache_block_size))
+        .transpose()?;
+    let mut quality = [0; QUALITY_BUCKETS.len()];
+    let mut emit = |result: &BlockResult| -> io::Result<()> {
+        let partial = partial_block == Some(result.cache_block);
+        if !result.candidates.is_empty() && !partial {
+            mapped += 1;
+        }
+        quality[quality_bucket(result, fs_blocks)] += 1;
+        let recorded = expected
+            .as_ref()
+            .map(|expected| expected.mappings.get(&result.cache_block));
+        let tainted = |origin_block: usize| {
+            result.tainted
+                || context
+                    .origin_bad
+                    .touches(origin_block * cache_block_size, cache_block_size)
+        };
+        // A cache block that the device end cuts short cannot be copied whole, so its match is only reported, in a
+        // way that the readers of mappings skip.
+        let class = match partial {
+            true => "short",
+            false => classification(result, fs_blocks, tainted),
+        };
+        if result.candidates.first().is_some_and(|best| tainted(best.origin_block)) {
+            tainted_mappings += 1;
+        }
+        let mut json_candidates = Vec::new();
+        let mut pretty_rows = Vec::new();
+        let mut db_rows = Vec::new();
+        let mut first = true;
+        for (rank, candidate) in result.candidates.iter().enumerate() {
+            let tied = result.tied != 0 && rank <= result.tied;
+            let origin_offset = candidate.origin_block * cache_block_size;
+            let segment = layout.as_ref().and_then(|layout| layout.locate(origin_offset));
+            let in_free_space = free_space
+                .as_ref()
+                .is_some_and(|free| free.contains(origin_offset, cache_block_size));
+            let match_percent = candidate.count as f64 / fs_blocks as f64 * 100.0;
+            match recorded.filter(|_| first) {
+                Some(Some(recorded)) if recorded.origin_block == candidate.origin_block => agreed += 1,
+                Some(Some(_)) => disagreed += 1,
+                _ => {}
+            }
+            let indexes: Vec<&str> = candidate
+                .indexes
+                .iter()
+                .filter(|_| context.index_labels.len() > 1)
+                .map(|&number| context.index_labels[number].as_str())
+                .collect();
+            if db.is_some() {
+                db_rows.push(CandidateRow {
+                    rank,
+                    origin_block: candidate.origin_block,
+                    count: candidate.count,
+                    match_percent,
+                    confidence: candidate.confidence,
+                    tied,
+                    tainted: tainted(candidate.origin_block),
+                    free_space: free_space.is_some().then_some(in_free_space),
+                    segment: segment.map(|(segment, offset)| (segment.path.as_path(), offset)),
+                    indexes: indexes.clone(),
+                });
+            }
+
+            if jsonl {
+                let mut json = format!(
+                    "{{\"origin_block\":{},\"count\":{},\"match\":{:.3},\"confidence\":{:.3}",
+                    candidate.origin_block, candidate.count, match_percent, candidate.confidence
+                );
+                if let Some((segment, offset)) = segment {
+                    json += &format!(
+                        ",\"segment\":{{\"path\":{},\"offset\":{}}}",
+                        json_string(&segment.path.to_string_lossy()),
+                        offset
+                    );
+                }
+                if free_space.is_some() {
+                    json += &format!(",\"free_space\":{}", in_free_space);
+                }
+                if tied {
+                    json += ",\"tied\":true";
+                }
+                if tainted(candidate.origin_block) {
+                    json += ",\"tainted\":true";
+                }
+                if context.index_labels.len() > 1 {
+                    let labels: Vec<String> = indexes.iter().map(|index| json_string(index)).collect();
+                    json += &format!(",\"indexes\":[{}]", labels.join(","));
+                }
+                json_candidates.push(json + "}");
+                first = false;
+                continue;
+            }
+            if csv {
+                first = false;
+                continue;
+            }
+
+            let mut notes = String::new();
+            if tied {
+                notes += " [tied]";
+            }
+            if let Some((segment, offset)) = segment {
+                notes += &format!(" [{}+{}]", segment.path.display(), offset);
+            }
+            if in_free_space {
+                notes += " [free space]";
+            }
+            if tainted(candidate.origin_block) {
+                notes += " [tainted]";
+            }
+            if context.index_labels.len() > 1 {
+                notes += &format!(" [in {}]", indexes.join(", "));
+            }
+            match recorded.filter(|_| first) {
+                Some(Some(recorded)) if recorded.origin_block == candidate.origin_block => {
+                    notes += &format!(" [metadata agrees{}]", if recorded.dirty { ", dirty" } else { "" })
+                }
+                Some(Some(recorded)) => {
+                    notes += &format!(
+                        " [metadata says {}{}]",
+                        recorded.origin_block,
+                        if recorded.dirty { ", dirty" } else { "" }
+                    )
+                }
+                Some(None) => notes += " [not in metadata]",
+                None => {}
+            }
+            if pretty.is_some() {
+                if rank < 2 {
+                    pretty_rows.push(Row {
+                        origin_block: candidate.origin_block,
+                        match_percent,
+                        confidence: candidate.confidence,
+                        notes,
+                    });
+                }
+                first = false;
+                continue;
+            }
+            writeln!(
+                out,
+                "{}{} -> {} ({:.3}% match, {:.3} confidence){}",
+                if first && !partial { "" } else { "#" },
+                result.cache_block,
+                candidate.origin_block,
+                match_percent,
+                candidate.confidence,
+                notes
+            )?;
+            first = false;
+        }
+        if result.candidates.is_empty() && recorded.is_some_and(|recorded| recorded.is_some()) {
+            missed += 1;
+        }
+        if let Some(db) = db.as_mut() {
+            db.block(
+                result.cache_block,
+                class,
+                result.fake_matches,
+                result.collisions,
+                partial,
+                &db_rows,
+            )?;
+        }
+
+        if let Some(pretty) = pretty.as_mut() {
+            match (class, pretty_rows.first()) {
+                ("full", Some(best)) => pretty.mapped(&mut out, result.cache_block, best)?,
+                (class, Some(best)) => pretty.ambiguous(result.cache_block, class, best, pretty_rows.get(1)),
+                (_, None) => pretty.unmatched(result.cache_block),
+            }
+        } else if csv {
+            let best = result.candidates.first();
+            let runner_up = result.candidates.get(1);
+            let mut row = Vec::new();
+            if label {
+                row.push(csv_field(&job.cache_device.to_string_lossy()));
+            }
+            row.push(result.cache_block.to_string());
+            row.push(best.map_or(String::new(), |best| best.origin_block.to_string()));
+            row.push(best.map_or(String::new(), |best| {
+                format!("{:.3}", best.count as f64 / fs_blocks as f64 * 100.0)
+            }));
+            row.push(best.map_or(String::new(), |best| format!("{:.3}", best.confidence)));
+            row.push(runner_up.map_or(String::new(), |runner_up| runner_up.origin_block.to_string()));
+            row.push(runner_up.map_or(String::new(), |runner_up| format!("{:.3}", runner_up.confidence)));
+            row.push(result.fake_matches.to_string());
+            row.push(result.collisions.to_string());
+            row.push(class.to_string());
+            writeln!(out, "{}", row.join(","))?;
+            out.flush()?;
+        } else if jsonl {
+            let mut json = format!(
+                "{{\"cache_block\":{},\"candidates\":[{}],\"fake_matches\":{},\"collisions\":{}",
+                result.cache_block,
+                json_candidates.join(","),
+                result.fake_matches,
+                result.collisions
+            );
+            if result.tied != 0 {
+                json += ",\"tie\":true";
+            }
+            if partial {
+                json += &format!(",\"partial_fs_blocks\":{}", partial_fs_blocks);
+            }
+            match recorded {
+                Some(Some(recorded)) => {
+                    json += &format!(
+                        ",\"metadata\":{{\"origin_block\":{},\"dirty\":{}}}",
+                        recorded.origin_block, recorded.dirty
+                    )
+                }
+                Some(None) => json += ",\"metadata\":null",
+                None => {}
+            }
+            writeln!(out, "{}}}", json)?;
+            out.flush()?;
+        } else if let Some(recorded) = recorded.flatten().filter(|_| result.candidates.is_empty()) {
+            writeln!(
+                out,
+                "#{} unmatched, metadata says {}{}",
+                result.cache_block,
+                recorded.origin_block,
+                if recorded.dirty { " (dirty)" } else { "" }
+            )?;
+        }
+        let text = !jsonl && !csv && pretty.is_none();
+        if text && partial {
+            writeln!(
+                out,
+                "#{} partial cache block, {} of {} fs-blocks before the device end",
+                result.cache_block, partial_fs_blocks, fs_blocks
+            )?;
+        }
+        if text && result.fake_matches != 0 {
+            writeln!(out, "#{} fake matches", result.fake_matches)?;
+        }
+        if text && result.collisions != 0 {
+            writeln!(out, "#{} digest collisions rejected", result.collisions)?;
+        }
+
+        if let Some(report) = unmatched_report.as_mut().filter(|_| result.candidates.is_empty()) {
+            if let Some(data) = cache.read(result.cache_block) {
+                report.record(result.cache_block, &data, &result.partial, cache_block_size)?;
+            }
+        }
+        Ok(())
+    };
+
+    // Scoring needs both neighbors, so each block is emitted once the next one has been matched.
+    interrupt::install();
+    let mut interrupted = false;
+    // Cache blocks outside holes matched so far, and how many of them matched anything, for --abort-if-below.
+    let (mut probed, mut matched) = (0, 0);
+    // Cache blocks whose every candidate --min-confidence left out.
+    let mut below_floor = 0;
+    let mut prev: Option<BlockResult> = None;
+    let mut current: Option<BlockResult> = None;
+    // Every cache block that lies in a hole is all zeroes and matches the same way.
+    let mut hole_result: Option<BlockResult> = None;
+    let (blocks, block_count) = cache.blocks();
+    let scanned = thread::scope(|scope| {
+        let ahead = cache.read_ahead(scope);
+        let mut blocks = blocks.enumerate().peekable();
+        while let Some((done, cache_block)) = blocks.next() {
+            if interrupt::requested() {
+                interrupted = true;
+                break;
+            }
+            log_status(done, block_count, "blocks", true);
+            cache.prefetch(&ahead, blocks.peek().map(|&(_, next)| next));
+            let next = if let Some(hole) = hole_result.as_ref().filter(|_| cache.in_hole(cache_block)) {
+                BlockResult {
+                    cache_block,
+                    ..hole.clone()
+                }
+            } else {
+                // Holes read as zeroes without touching the disk.
+                let Some(data) = cache.read(cache_block) else {
+                    continue;
+                };
+                if cache.in_hole(cache_block) {
+                    let hole =
+                        match_cache_block(matchers, cache, cache_block, &data, &mut throttle, earlier.hole.take());
+                    hole_result.insert(hole).clone()
+                } else {
+                    let found = earlier.blocks.remove(&cache_block);
+                    let next = match_cache_block(matchers, cache, cache_block, &data, &mut throttle, found);
+                    probed += 1;
+                    matched += usize::from(!next.candidates.is_empty());
+                    if let Some((percent, after)) = options.abort.filter(|&(_, after)| probed == after) {
+                        hopeless(matched, after, percent, cache_block_size)?;
+                    }
+                    next
+                }
+            };
+            if let Some(mut ready) = current.take() {
+                below_floor += usize::from(rate(&mut ready, prev.as_ref(), Some(&next), fs_blocks, options));
+                emit(&ready)?;
+                prev = Some(ready);
+            }
+            current = Some(next);
+        }
+        io::Result::Ok(())
+    });
+    if let Err(e) = scanned {
+        out.discard();
+        return Err(e);
+    }
+    if let Some(mut ready) = current {
+        below_floor += usize::from(rate(&mut ready, prev.as_ref(), None, fs_blocks, options));
+        emit(&ready)?;
+        if interrupted && !cache.first.is_empty() {
+            eprintln!("\nthe results of a run with priorities are not in cache block order and cannot be resumed");
+        } else if interrupted {
+            if jsonl {
+                writeln!(out, "{}{}}}", JSON_CHECKPOINT, ready.cache_block)?;
+            } else {
+                writeln!(out, "{}{}", CHECKPOINT, ready.cache_block)?;
+            }
+        }
+    }
+    if !interrupted {
+        log_complete(block_count, "blocks");
+    }
+    if let Some(pretty) = pretty {
+        pretty.finish(&mut out)?;
+    }
+    let reported: usize = quality.iter().sum();
+    eprintln!("\nbest match quality:");
+    for (label, count) in QUALITY_BUCKETS.iter().zip(quality) {
+        eprintln!(
+            "  {:>7}: {:>10} ({:.1}%)",
+            label,
+            count,
+            100.0 * count as f64 / reported.max(1) as f64
+        );
+    }
+    if tainted_mappings > 0 {
+        eprintln!(
+            "\n{} mappings tainted by regions ddrescue did not rescue",
+            tainted_mappings
+        );
+    }
+    if below_floor > 0 {
+        eprintln!(
+            "\n{} cache blocks left unmatched, all their candidates below --min-confidence or --min-match",
+            below_floor
+        );
+    }
+    if expected.is_some() {
+        eprintln!(
+            "\nmetadata: {} guesses agree, {} disagree, {} recorded blocks without a guess",
+            agreed, disagreed, missed
+        );
+    }
+    if out.is_file() {
+        println!(
+            "{} of {} cache blocks mapped{}",
+            mapped,
+            block_count,
+            if interrupted { " before the interruption" } else { "" }
+        );
+    }
+    out.commit()?;
+    if let Some(db) = db {
+        db.finish()?;
+    }
+    if interrupted {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "interrupted, the results so far end in a checkpoint, continue with --resume-from",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+use crate::hash::{self, Key};
+use crate::index::{Header, Index, IndexWriter};
+use crate::layout::Layout;
+use crate::selection::Selection;
+use crate::{hash_block, memory, BLOCK_SIZE};
+
+pub struct GcOptions<'a> {
+    /// Digests found at more origin blocks than this are dropped everywhere.
+    pub max_duplicates: usize,
+    pub keep_zero: bool,
+    /// Origin byte ranges whose entries are dropped.
+    pub exclude: Option<&'a Selection>,
+    pub shard_size: Option<usize>,
+    pub force: bool,
+}
+
+/// Whether any byte of `[offset, offset + len)` is in one of the sorted, disjoint `ranges`.
+fn overlaps(ranges: &[(usize, usize)], offset: usize, len: usize) -> bool {
+    let idx = ranges.partition_point(|&(_, end)| end <= offset);
+    ranges.get(idx).is_some_and(|&(start, _)| start < offset + len)
+}
+
+/// Rewrites an index without the entries that only make `find` slower: all-zero blocks, which match every
+/// zeroed cache block; digests repeated so often that a hit on them says nothing; and excluded origin ranges.
+/// The output always holds explicit records, so that what is dropped takes no room.
+pub fn gc(index_path: &Path, output: &Path, options: &GcOptions) -> io::Result<()> {
+    if fs::canonicalize(output).ok() == Some(fs::canonicalize(index_path)?) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the output must be a different file from the index being cleaned",
+        ));
+    }
+    let index = Index::open(index_path)?;
+    let Some(base) = index.header() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a legacy index has no header to carry over, rebuild it with collect",
+        ));
+    };
+    let digest_len = index.digest_len();
+    let zero_block = vec![0; index.sample_bytes().unwrap_or(BLOCK_SIZE)];
+    let zero = hash_block(&zero_block)[..digest_len].to_vec();
+    let excluded: Vec<(usize, usize)> = options
+        .exclude
+        .map_or(Vec::new(), |exclude| exclude.ranges(base.device_size).collect());
+
+    memory::preflight(
+        "counting the digests",
+        index.entry_count() * mem::size_of::<(Key, usize)>() * 2,
+        "run on a machine with more memory",
+        options.force,
+    )?;
+    let mut counts: HashMap<Key, usize> = HashMap::new();
+    index.for_each(|_, digest| *counts.entry(hash::key(digest)).or_insert(0) += 1)?;
+    let repeated = counts
+        .iter()
+        .filter(|&(key, &count)| count > options.max_duplicates && (options.keep_zero || key[..digest_len] != zero))
+        .count();
+
+    let mut header = Header::new(base.device_size, digest_len, index.sample_bytes(), base.filter);
+    header.identity = base.identity.clone();
+    header.explicit = true;
+    let mut writer = IndexWriter::create(output, header, options.shard_size)?;
+    let (mut zeroes, mut duplicates, mut dropped_ranges) = (0, 0, 0);
+    let kept = writer.copy_from(&index, |entry, digest| {
+        if !options.keep_zero && digest == zero.as_slice() {
+            zeroes += 1;
+            false
+        } else if overlaps(&excluded, entry * BLOCK_SIZE, BLOCK_SIZE) {
+            dropped_ranges += 1;
+            false
+        } else if counts[&hash::key(digest)] > options.max_duplicates {
+            duplicates += 1;
+            false
+        } else {
+            true
+        }
+    })?;
+    drop(counts);
+    writer.finish()?;
+    match Layout::read(index_path)? {
+        Some(layout) => layout.write(&Layout::sidecar_path(output))?,
+        None => Layout::remove(output)?,
+    }
+
+    let total = kept + zeroes + duplicates + dropped_ranges;
+    eprintln!("kept {} of {} entries", kept, total);
+    if zeroes > 0 {
+        eprintln!("  {} all-zero blocks dropped", zeroes);
+    }
+    if duplicates > 0 {
+        eprintln!(
+            "  {} entries of {} digests found more than {} times dropped",
+            duplicates, repeated, options.max_duplicates
+        );
+    }
+    if dropped_ranges > 0 {
+        eprintln!("  {} entries in excluded ranges dropped", dropped_ranges);
+    }
+    let cleaned = Index::open(output)?;
+    let entry_bytes = if base.filter {
+        mem::size_of::<(u64, usize)>()
+    } else {
+        mem::size_of::<(Key, usize)>()
+    };
+    println!(
+        "{}: {} bytes, {} bytes before; find needs about {} instead of {}",
+        output.display(),
+        cleaned.size(),
+        index.size(),
+        memory::gib(kept * entry_bytes),
+        memory::gib(index.entry_count() * entry_bytes)
+    );
+    Ok(())
+}
@@ -0,0 +1,494 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use memmap2::Mmap;
+
+use crate::metrics;
+use crate::output::Output;
+use crate::{hash_block, HASH_BYTES};
+
+/// How many blocks callers should hand to `hash_blocks` at once.
+pub const BATCH: usize = 64;
+
+/// A digest as a map key, zero-padded when truncated.
+pub type Key = [u8; HASH_BYTES];
+
+pub fn key(digest: &[u8]) -> Key {
+    let mut key = [0; HASH_BYTES];
+    key[..digest.len()].copy_from_slice(digest);
+    key
+}
+
+/// Keys as `SortedIndex` saves them.
+pub trait SavedKey: Ord + Copy {
+    const LEN: usize;
+    fn write_to(&self, out: &mut Vec<u8>);
+    fn read_from(bytes: &[u8]) -> Self;
+}
+
+impl SavedKey for Key {
+    const LEN: usize = HASH_BYTES;
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+    fn read_from(bytes: &[u8]) -> Self {
+        bytes.try_into().unwrap()
+    }
+}
+
+impl SavedKey for u64 {
+    const LEN: usize = 8;
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn read_from(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+const SAVED_MAGIC: &[u8] = b"cache_guess lookup 1\n";
+/// The magic and what the entries were built from, zero-padded; entries follow, each the key and then the origin
+/// offset as 8 little-endian bytes.
+const SAVED_HEADER: usize = 4096;
+
+/// `(key, origin offset)` pairs sorted by key, looked up by binary search: one allocation for the whole index
+/// where a map holds a vector per digest. Saved to a file, they are searched where they are mapped.
+pub struct SortedIndex<K> {
+    entries: Entries<K>,
+}
+
+enum Entries<K> {
+    Built(Vec<(K, usize)>),
+    Saved(Mmap),
+}
+
+impl<K: SavedKey> SortedIndex<K> {
+    pub fn new(mut entries: Vec<(K, usize)>) -> Self {
+        entries.sort_unstable();
+        entries.shrink_to_fit();
+        Self {
+            entries: Entries::Built(entries),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.entries {
+            Entries::Built(entries) => entries.len(),
+            Entries::Saved(mmap) => (mmap.len() - SAVED_HEADER) / (K::LEN + 8),
+        }
+    }
+
+    fn entry(&self, n: usize) -> (K, usize) {
+        match &self.entries {
+            Entries::Built(entries) => entries[n],
+            Entries::Saved(mmap) => {
+                let raw = &mmap[SAVED_HEADER + n * (K::LEN + 8)..][..K::LEN + 8];
+                let offset = u64::from_le_bytes(raw[K::LEN..].try_into().unwrap());
+                (K::read_from(&raw[..K::LEN]), offset as usize)
+            }
+        }
+    }
+
+    /// Origin offsets stored under `key`, in ascending order.
+    pub fn get(&self, key: K) -> impl Iterator<Item = usize> + '_ {
+        let (mut start, mut end) = (0, self.len());
+        while start < end {
+            let mid = start + (end - start) / 2;
+            if self.entry(mid).0 < key {
+                start = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+        (start..self.len())
+            .map(|n| self.entry(n))
+            .take_while(move |(entry, _)| *entry == key)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Writes the entries to `path` for `open_saved` to map, with `source` saying what they were built from.
+    pub fn save(&self, path: &Path, source: &str) -> io::Result<()> {
+        let mut header = SAVED_MAGIC.to_vec();
+        header.extend_from_slice(source.as_bytes());
+        if header.len() >= SAVED_HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the lookup's source does not fit in its header",
+            ));
+        }
+        header.resize(SAVED_HEADER, 0);
+        let mut out = Output::create(Some(path))?;
+        out.write_all(&header)?;
+        let mut chunk = Vec::with_capacity((K::LEN + 8) * 65536);
+        for start in (0..self.len()).step_by(65536) {
+            chunk.clear();
+            for n in start..self.len().min(start + 65536) {
+                let (key, offset) = self.entry(n);
+                key.write_to(&mut chunk);
+                chunk.extend_from_slice(&(offset as u64).to_le_bytes());
+            }
+            out.write_all(&chunk)?;
+        }
+        out.commit()
+    }
+
+    /// Maps entries `save` wrote, if `path` holds some built from `source`.
+    pub fn open_saved(path: &Path, source: &str) -> io::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mmap = unsafe { Mmap::map(&file)? };
+        let recorded = mmap
+            .get(SAVED_MAGIC.len()..SAVED_HEADER)
+            .filter(|_| mmap.starts_with(SAVED_MAGIC))
+            .map(|header| &header[..header.iter().position(|&byte| byte == 0).unwrap_or(header.len())]);
+        if recorded != Some(source.as_bytes()) || !(mmap.len() - SAVED_HEADER).is_multiple_of(K::LEN + 8) {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            entries: Entries::Saved(mmap),
+        }))
+    }
+}
+
+/// Which SHA-1 implementation `hash_blocks` ends up using, for the log.
+pub fn describe() -> &'static str {
+    if multibuffer::available() {
+        return "avx2 multi-buffer sha1";
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if std::arch::is_x86_feature_detected!("sha") {
+        return "sha-ni sha1";
+    }
+    "portable sha1"
+}
+
+/// SHA-1 of every block, same as `hash_block` on each, but several equally sized blocks at a time when the
+/// multi-buffer path is available.
+pub fn hash_blocks(blocks: &[&[u8]]) -> Vec<Vec<u8>> {
+    let started = Instant::now();
+    let digests = sha1_blocks(blocks);
+    metrics::hashed(blocks.iter().map(|block| block.len()).sum(), started.elapsed());
+    digests
+}
+
+fn sha1_blocks(blocks: &[&[u8]]) -> Vec<Vec<u8>> {
+    if !multibuffer::available() {
+        return blocks.iter().map(|block| hash_block(block)).collect();
+    }
+    let mut digests = Vec::with_capacity(blocks.len());
+    for lanes in blocks.chunks(multibuffer::LANES) {
+        if lanes.len() > 1 && lanes.iter().all(|lane| lane.len() == lanes[0].len()) {
+            digests.extend(multibuffer::hash(lanes));
+        } else {
+            digests.extend(lanes.iter().map(|lane| hash_block(lane)));
+        }
+    }
+    digests
+}
+
+#[cfg(all(feature = "multibuffer", target_arch = "x86_64"))]
+mod multibuffer {
+    use std::arch::x86_64::*;
+    use std::sync::OnceLock;
+
+    pub const LANES: usize = 8;
+
+    /// SHA-NI hashes one block faster than AVX2 does eight, so multi-buffer only pays off without it.
+    /// `CACHE_GUESS_HASH=multibuffer` or `=scalar` overrides the choice.
+    pub fn available() -> bool {
+        static AVAILABLE: OnceLock<bool> = OnceLock::new();
+        *AVAILABLE.get_or_init(|| {
+            let avx2 = is_x86_feature_detected!("avx2");
+            match std::env::var("CACHE_GUESS_HASH").as_deref() {
+                Ok("multibuffer") => avx2,
+                Ok("scalar") => false,
+                _ => avx2 && !is_x86_feature_detected!("sha"),
+            }
+        })
+    }
+
+    /// Hashes up to `LANES` messages of equal length side by side, one per 32-bit SIMD lane; needs AVX2 whether or
+    /// not `available` prefers it.
+    pub fn hash(messages: &[&[u8]]) -> Vec<Vec<u8>> {
+        assert!(is_x86_feature_detected!("avx2") && messages.len() <= LANES);
+        let len = messages[0].len();
+        let full_chunks = len / 64;
+        // The remainder and padding differ per lane, so they are laid out separately.
+        let tails: Vec<Vec<u8>> = messages
+            .iter()
+            .map(|message| {
+                let mut tail = message[full_chunks * 64..].to_vec();
+                tail.push(0x80);
+                while tail.len() % 64 != 56 {
+                    tail.push(0);
+                }
+                tail.extend_from_slice(&((len as u64) * 8).to_be_bytes());
+                tail
+            })
+            .collect();
+        // Lanes beyond the message count just repeat the first message.
+        let lane = |i: usize| i.min(messages.len() - 1);
+
+        let mut state = unsafe { init() };
+        for chunk in 0..full_chunks {
+            let blocks: [&[u8]; LANES] = std::array::from_fn(|i| &messages[lane(i)][chunk * 64..chunk * 64 + 64]);
+            unsafe { compress(&mut state, &blocks) };
+        }
+        for chunk in 0..tails[0].len() / 64 {
+            let blocks: [&[u8]; LANES] = std::array::from_fn(|i| &tails[lane(i)][chunk * 64..chunk * 64 + 64]);
+            unsafe { compress(&mut state, &blocks) };
+        }
+
+        let mut words = [[0u32; LANES]; 5];
+        for (word, value) in words.iter_mut().zip(state) {
+            unsafe { _mm256_storeu_si256(word.as_mut_ptr() as *mut __m256i, value) };
+        }
+        (0..messages.len())
+            .map(|i| words.iter().flat_map(|word| word[i].to_be_bytes()).collect())
+            .collect()
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn init() -> [__m256i; 5] {
+        [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0].map(|h| _mm256_set1_epi32(h as i32))
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn rotl<const L: i32, const R: i32>(x: __m256i) -> __m256i {
+        _mm256_or_si256(_mm256_slli_epi32::<L>(x), _mm256_srli_epi32::<R>(x))
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn compress(state: &mut [__m256i; 5], blocks: &[&[u8]; LANES]) {
+        let mut w = [_mm256_setzero_si256(); 16];
+        for (t, word) in w.iter_mut().enumerate() {
+            let lane = |i: usize| u32::from_be_bytes(blocks[i][t * 4..t * 4 + 4].try_into().unwrap()) as i32;
+            *word = _mm256_set_epi32(lane(7), lane(6), lane(5), lane(4), lane(3), lane(2), lane(1), lane(0));
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = *state;
+        for t in 0..80 {
+            if t >= 16 {
+                let x = _mm256_xor_si256(
+                    _mm256_xor_si256(w[(t - 3) % 16], w[(t - 8) % 16]),
+                    _mm256_xor_si256(w[(t - 14) % 16], w[t % 16]),
+                );
+                w[t % 16] = rotl::<1, 31>(x);
+            }
+            let (f, k) = match t {
+                0..=19 => (
+                    _mm256_or_si256(_mm256_and_si256(b, c), _mm256_andnot_si256(b, d)),
+                    0x5a827999u32,
+                ),
+                20..=39 => (_mm256_xor_si256(_mm256_xor_si256(b, c), d), 0x6ed9eba1),
+                40..=59 => (
+                    _mm256_or_si256(
+                        _mm256_or_si256(_mm256_and_si256(b, c), _mm256_and_si256(b, d)),
+                        _mm256_and_si256(c, d),
+                    ),
+                    0x8f1bbcdc,
+                ),
+                _ => (_mm256_xor_si256(_mm256_xor_si256(b, c), d), 0xca62c1d6),
+            };
+            let temp = _mm256_add_epi32(
+                _mm256_add_epi32(rotl::<5, 27>(a), f),
+                _mm256_add_epi32(_mm256_add_epi32(e, _mm256_set1_epi32(k as i32)), w[t % 16]),
+            );
+            e = d;
+            d = c;
+            c = rotl::<30, 2>(b);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in state.iter_mut().zip([a, b, c, d, e]) {
+            *h = _mm256_add_epi32(*h, value);
+        }
+    }
+}
+
+#[cfg(not(all(feature = "multibuffer", target_arch = "x86_64")))]
+mod multibuffer {
+    pub const LANES: usize = 1;
+
+    pub fn available() -> bool {
+        false
+    }
+
+    pub fn hash(_: &[&[u8]]) -> Vec<Vec<u8>> {
+        unreachable!()
+    }
+}
+
+const PRIME64_1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME64_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME64_3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME64_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME64_5: u64 = 0x27d4_eb2f_1656_67c5;
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge(acc: u64, value: u64) -> u64 {
+    (acc ^ xxh64_round(0, value))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+/// XXH64 with seed 0, the cheap filter hash of two-tier indexes.
+pub fn xxh64(data: &[u8]) -> u64 {
+    let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+    let mut at = 0;
+    let mut hash = if data.len() >= 32 {
+        let mut v = [
+            PRIME64_1.wrapping_add(PRIME64_2),
+            PRIME64_2,
+            0,
+            PRIME64_1.wrapping_neg(),
+        ];
+        while at + 32 <= data.len() {
+            for (lane, acc) in v.iter_mut().enumerate() {
+                *acc = xxh64_round(*acc, u64_at(at + lane * 8));
+            }
+            at += 32;
+        }
+        let hash = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(hash, |hash, &acc| xxh64_merge(hash, acc))
+    } else {
+        PRIME64_5
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    while at + 8 <= data.len() {
+        hash = (hash ^ xxh64_round(0, u64_at(at)))
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+        at += 8;
+    }
+    if at + 4 <= data.len() {
+        let word = u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as u64;
+        hash = (hash ^ word.wrapping_mul(PRIME64_1))
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        at += 4;
+    }
+    for &byte in &data[at..] {
+        hash = (hash ^ (byte as u64).wrapping_mul(PRIME64_5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME64_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME64_3);
+    hash ^ (hash >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that differ with `seed`, so that no two lanes hash the same message.
+    fn message(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[cfg(all(feature = "multibuffer", target_arch = "x86_64"))]
+    #[test]
+    fn multibuffer_matches_sha1_for_every_lane_count() {
+        use sha1::{Digest, Sha1};
+        if !is_x86_feature_detected!("avx2") {
+            eprintln!("no AVX2, skipped");
+            return;
+        }
+        // Around the 55 bytes that still leave room for the length in the last chunk, and whole chunks.
+        for len in [0, 1, 55, 56, 63, 64, 65, 119, 120, 128, 4096] {
+            for lanes in 1..=multibuffer::LANES {
+                let messages: Vec<Vec<u8>> = (0..lanes)
+                    .map(|lane| match lane {
+                        1 => vec![0; len],
+                        2 => vec![0xff; len],
+                        _ => message(len, (len * 8 + lane) as u64),
+                    })
+                    .collect();
+                let slices: Vec<&[u8]> = messages.iter().map(Vec::as_slice).collect();
+                let expected: Vec<Vec<u8>> = slices.iter().map(|message| Sha1::digest(message).to_vec()).collect();
+                assert_eq!(multibuffer::hash(&slices), expected, "{} lanes of {} bytes", lanes, len);
+            }
+        }
+    }
+
+    #[test]
+    fn xxh64_reference_vectors() {
+        // Inputs 0, 1, 2... long enough to cross the 32-byte stripes and the 8, 4 and 1-byte tail steps.
+        let vectors = [
+            (0, 0xef46_db37_51d8_e999),
+            (1, 0xe934_a84a_db05_2768),
+            (3, 0xe5c7_bb45_33bc_65dd),
+            (4, 0xffce_d860_4453_cc1e),
+            (7, 0x14cc_643f_630c_72d2),
+            (8, 0x884a_1736_14b8_1b8d),
+            (12, 0x424a_f23f_1f08_dca5),
+            (15, 0xa948_f5f0_f6ab_ac2d),
+            (31, 0xc346_d2b5_9b4d_8ee1),
+            (32, 0xcbf5_9c51_16ff_32b4),
+            (33, 0x0c53_5d1a_cafb_8ead),
+            (63, 0xe26a_a9e2_a95f_8e4f),
+            (64, 0xf7c6_7301_db67_13f0),
+            (100, 0x6ac1_e580_3216_6597),
+        ];
+        for (len, expected) in vectors {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(xxh64(&data), expected, "{} bytes", len);
+        }
+        assert_eq!(xxh64(b"abc"), 0x44bc_2cf5_ad77_0999);
+    }
+
+    #[test]
+    fn saved_lookup_reads_back_like_the_built_one() {
+        let path = std::env::temp_dir().join(format!("cache_guess-lookup-{}", std::process::id()));
+        let entries: Vec<(u64, usize)> = (0..200_000)
+            .map(|n| (xxh64(&message(8, n % 70_000)), n as usize * 4096))
+            .collect();
+        let built = SortedIndex::new(entries.clone());
+        built.save(&path, "origin.idx 1234").unwrap();
+        let saved = SortedIndex::<u64>::open_saved(&path, "origin.idx 1234")
+            .unwrap()
+            .unwrap();
+        let other = SortedIndex::<u64>::open_saved(&path, "origin.idx 5678").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(other.is_none());
+        assert_eq!(saved.len(), entries.len());
+        for &(key, _) in entries.iter().step_by(997).chain([&(0, 0)]) {
+            let offsets: Vec<usize> = saved.get(key).collect();
+            assert_eq!(offsets, built.get(key).collect::<Vec<_>>());
+            assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        assert_eq!(saved.get(entries[5].0).count(), 3);
+        assert!(SortedIndex::<u64>::open_saved(&path, "origin.idx 1234")
+            .unwrap()
+            .is_none());
+    }
+}
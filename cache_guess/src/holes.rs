@@ -0,0 +1,145 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::{device_len, remote, split, MappedFile};
+
+/// The unwritten regions of a sparse image, as offsets into the device window. They read as zeroes, so
+/// nothing needs to be read or hashed to know what they hold.
+#[derive(Debug, Default)]
+pub struct Holes {
+    extents: Vec<(usize, usize)>,
+}
+
+/// `lseek` with SEEK_DATA or SEEK_HOLE; `None` once there is no more data after `offset`.
+fn seek(file: &File, offset: usize, whence: libc::c_int) -> io::Result<Option<usize>> {
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if result >= 0 {
+        return Ok(Some(result as usize));
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        _ => Err(error),
+    }
+}
+
+const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+/// Extents asked for per FIEMAP call.
+const FIEMAP_BATCH: usize = 256;
+
+/// Preallocated extents that were never written, which read as zeroes as holes do but that SEEK_HOLE reports as
+/// data on some filesystems. Empty where FIEMAP is not supported, as on block devices.
+fn unwritten_extents(file: &File, size: usize) -> io::Result<Vec<(usize, usize)>> {
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        // struct fiemap with its extents, 56 bytes each, in u64s for the alignment.
+        let mut buf = vec![0u64; 4 + 7 * FIEMAP_BATCH];
+        buf[0] = offset as u64;
+        buf[1] = (size - offset) as u64;
+        buf[2] = FIEMAP_FLAG_SYNC as u64;
+        buf[3] = FIEMAP_BATCH as u64;
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, buf.as_mut_ptr()) } < 0 {
+            let error = io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL) => Ok(Vec::new()),
+                _ => Err(error),
+            };
+        }
+        let mapped = (buf[2] >> 32) as usize;
+        if mapped == 0 {
+            break;
+        }
+        for extent in buf[4..].chunks(7).take(mapped) {
+            let (logical, len, flags) = (extent[0] as usize, extent[2] as usize, extent[5] as u32);
+            if flags & FIEMAP_EXTENT_UNWRITTEN != 0 {
+                extents.push((logical, (logical + len).min(size)));
+            }
+            offset = logical + len;
+            if flags & FIEMAP_EXTENT_LAST != 0 {
+                return Ok(extents);
+            }
+        }
+    }
+    Ok(extents)
+}
+
+/// The holes of one file, unwritten extents included, as `[start, end)` byte ranges.
+fn file_holes(path: &Path) -> io::Result<Vec<(usize, usize)>> {
+    let mut file = File::open(path)?;
+    let size = device_len(&mut file)?;
+    let mut extents = unwritten_extents(&file, size)?;
+    let mut offset = 0;
+    while offset < size {
+        let data = match seek(&file, offset, libc::SEEK_DATA) {
+            Ok(data) => data.unwrap_or(size),
+            // Filesystems and devices without hole support; everything else counts as data.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+            Err(e) => return Err(e),
+        };
+        if data > offset {
+            extents.push((offset, data));
+        }
+        if data >= size {
+            break;
+        }
+        offset = seek(&file, data, libc::SEEK_HOLE)?.unwrap_or(size);
+    }
+    extents.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(extents.len());
+    for (start, end) in extents {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Ok(merged)
+}
+
+impl Holes {
+    /// Finds the holes of the file or split image at `path`, within the window `device` was opened with.
+    pub fn find(path: &Path, device: &MappedFile) -> io::Result<Self> {
+        if remote::is_remote(path) {
+            return Ok(Self::default());
+        }
+        let chunks = split::series(path).unwrap_or_else(|| vec![PathBuf::from(path)]);
+        let (start, end) = (device.base, device.base + device.size());
+        let mut extents = Vec::new();
+        let mut chunk_start = 0;
+        for chunk in &chunks {
+            for (hole_start, hole_end) in file_holes(chunk)? {
+                let (hole_start, hole_end) = (chunk_start + hole_start, chunk_start + hole_end);
+                if hole_end > start && hole_start < end {
+                    extents.push((hole_start.max(start) - start, hole_end.min(end) - start));
+                }
+            }
+            chunk_start += device_len(&mut File::open(chunk)?)?;
+        }
+        // Holes at the end of one chunk and the start of the next are one hole.
+        extents.dedup_by(|next, previous| {
+            let adjacent = previous.1 == next.0;
+            if adjacent {
+                previous.1 = next.1;
+            }
+            adjacent
+        });
+        Ok(Self { extents })
+    }
+
+    pub fn contains(&self, offset: usize, len: usize) -> bool {
+        let idx = self.extents.partition_point(|&(_, end)| end <= offset);
+        match self.extents.get(idx) {
+            Some(&(start, end)) => start <= offset && offset + len <= end,
+            None => false,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.extents.iter().map(|(start, end)| end - start).sum()
+    }
+}
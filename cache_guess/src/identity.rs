@@ -0,0 +1,132 @@
+use crate::{hash, MappedFile};
+
+const SAMPLES: usize = 8;
+
+/// What an index remembers about its origin device, to catch `find` runs against a different one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Identity {
+    /// Filesystem type and UUID, if a known superblock is at the start of the device.
+    pub filesystem: Option<(u32, [u8; 16])>,
+    /// xxh64 of a few fixed 4 KiB samples spread over the device.
+    pub fingerprint: Vec<u64>,
+}
+
+pub const FILESYSTEM_BYTES: usize = 2048;
+pub const SAMPLE_BYTES: usize = 4096;
+const XFS: u32 = 1;
+const EXT: u32 = 2;
+
+/// Looks for a filesystem superblock in the first `FILESYSTEM_BYTES` of a device.
+pub fn filesystem(start: &[u8]) -> Option<(u32, [u8; 16])> {
+    if start.len() < FILESYSTEM_BYTES {
+        return None;
+    }
+    let uuid = |offset: usize| start[offset..offset + 16].try_into().unwrap();
+    if &start[0..4] == b"XFSB" {
+        Some((XFS, uuid(32)))
+    } else if start[1024 + 56..1024 + 58] == [0x53, 0xef] {
+        Some((EXT, uuid(1024 + 104)))
+    } else {
+        None
+    }
+}
+
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+impl Identity {
+    /// Where the `SAMPLE_BYTES` fingerprint samples of a device of `size` bytes are; page aligned, so a sample
+    /// never straddles two index blocks.
+    pub fn sample_offsets(size: usize) -> Vec<usize> {
+        if size < SAMPLE_BYTES {
+            return Vec::new();
+        }
+        let last = (size - SAMPLE_BYTES) / SAMPLE_BYTES;
+        (0..SAMPLES).map(|i| last * i / (SAMPLES - 1) * SAMPLE_BYTES).collect()
+    }
+
+    pub fn of(device: &MappedFile) -> Self {
+        Self {
+            filesystem: filesystem(device.slice(0, device.size().min(FILESYSTEM_BYTES))),
+            fingerprint: Self::sample_offsets(device.size())
+                .into_iter()
+                .map(|offset| hash::xxh64(device.slice(offset, SAMPLE_BYTES)))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filesystem.is_none() && self.fingerprint.is_empty()
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.filesystem {
+            Some((kind, uuid)) => format!(
+                "{} uuid {}",
+                if *kind == XFS { "xfs" } else { "ext" },
+                format_uuid(uuid)
+            ),
+            None => "no known filesystem".to_string(),
+        }
+    }
+
+    /// Ways the `actual` device differs from this recorded identity; the origin may legitimately have changed
+    /// in a few sampled places since, so only a majority of differing samples counts.
+    pub fn mismatches(&self, actual: &Identity) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.filesystem.is_some() && self.filesystem != actual.filesystem {
+            problems.push(format!(
+                "the index was built from {}, this device has {}",
+                self.describe(),
+                actual.describe()
+            ));
+        }
+        if self.fingerprint.len() == actual.fingerprint.len() {
+            let differing = self
+                .fingerprint
+                .iter()
+                .zip(&actual.fingerprint)
+                .filter(|(a, b)| a != b)
+                .count();
+            if differing * 2 > self.fingerprint.len() {
+                problems.push(format!(
+                    "{} of {} sampled regions differ",
+                    differing,
+                    self.fingerprint.len()
+                ));
+            }
+        }
+        problems
+    }
+
+    pub fn encode(&self, block: &mut [u8]) {
+        if let Some((kind, uuid)) = &self.filesystem {
+            block[0..4].copy_from_slice(&kind.to_le_bytes());
+            block[4..20].copy_from_slice(uuid);
+        }
+        block[20..24].copy_from_slice(&(self.fingerprint.len() as u32).to_le_bytes());
+        for (i, sample) in self.fingerprint.iter().enumerate() {
+            block[24 + i * 8..32 + i * 8].copy_from_slice(&sample.to_le_bytes());
+        }
+    }
+
+    pub fn decode(block: &[u8]) -> Self {
+        let kind = u32::from_le_bytes(block[0..4].try_into().unwrap());
+        let count = (u32::from_le_bytes(block[20..24].try_into().unwrap()) as usize).min(SAMPLES);
+        Self {
+            filesystem: (kind != 0).then(|| (kind, block[4..20].try_into().unwrap())),
+            fingerprint: (0..count)
+                .map(|i| u64::from_le_bytes(block[24 + i * 8..32 + i * 8].try_into().unwrap()))
+                .collect(),
+        }
+    }
+}
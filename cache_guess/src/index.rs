@@ -0,0 +1,862 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::block_errors::{self, Kind};
+use crate::identity::Identity;
+use crate::{hash_block, provenance, MappedFile, WritableMappedFile, BLOCK_SIZE, HASH_BYTES};
+
+const MAGIC: &[u8; 8] = b"CGINDEX\0";
+const VERSION: u32 = 1;
+/// Indexes of explicit records, which readers of the positional layout must not take for one.
+const EXPLICIT_VERSION: u32 = 2;
+/// Bytes of the origin entry number in front of each explicit record.
+const RECORD_ENTRY_BYTES: usize = 8;
+const CHECKSUM_BYTES: usize = 8;
+const LEGACY_ENTRIES_PER_BLOCK: usize = BLOCK_SIZE / HASH_BYTES;
+const FILTER_XXH64: u32 = 1;
+const FILTER_BYTES: usize = 8;
+pub const MIN_DIGEST_BYTES: usize = 4;
+/// Where the header block keeps the provenance text, after a length.
+const PROVENANCE_OFFSET: usize = 1024;
+const MAX_PROVENANCE: usize = BLOCK_SIZE - CHECKSUM_BYTES - PROVENANCE_OFFSET - 4;
+/// Where a shard's header keeps the origin entry its first entry is.
+const FIRST_ENTRY_OFFSET: usize = PROVENANCE_OFFSET - 8;
+/// Where an explicit index's header keeps its record count.
+const RECORD_COUNT_OFFSET: usize = FIRST_ENTRY_OFFSET - 8;
+/// First line of the text file listing the shards of a sharded index.
+const SHARD_MAGIC: &[u8; 8] = b"CGSHARDS";
+
+/// Whether an index block that fails its checksum is left out instead of stopping the run.
+static SKIP_CORRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Makes index blocks that fail their checksum be left out with their entries, recorded as block errors, instead
+/// of failing the run as they do by default.
+pub fn skip_corrupt_blocks() {
+    SKIP_CORRUPT.store(true, Ordering::SeqCst);
+}
+
+/// Index layout: one header block, then blocks of packed digests (one per origin block, by position), each
+/// ending in a truncated SHA-1 of the rest of the block. Files without the magic are the older headerless
+/// layout, which has no checksums. Two-tier indexes put an xxh64 filter hash in front of every digest.
+///
+/// Explicit (sparse) indexes instead hold only the entries that were indexed, as records of a u64 origin entry
+/// number followed by the entry, in origin order; free space and other skipped blocks take no room at all.
+///
+/// A sharded index splits the entries into files by origin offset range, `<index>.shard0000` and up, and the
+/// index path itself is a text file listing them: `CGSHARDS`, then a `first-entry entries file` line each.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub digest_len: usize,
+    pub block_size: usize,
+    pub entry_count: usize,
+    /// The origin entry this file's first entry is; 0 but in the later shards of a sharded index.
+    pub first_entry: usize,
+    pub device_size: usize,
+    /// Digests cover only this many leading bytes of each block; 0 means the whole block.
+    pub sample_bytes: usize,
+    pub filter: bool,
+    /// Explicit records rather than one entry per origin block, and how many.
+    pub explicit: bool,
+    pub record_count: usize,
+    pub identity: Identity,
+    /// How the index was made, see `provenance`; empty in indexes from before it was recorded.
+    pub provenance: String,
+}
+
+impl Header {
+    pub fn new(device_size: usize, digest_len: usize, sample_bytes: Option<usize>, filter: bool) -> Self {
+        Self {
+            digest_len,
+            block_size: BLOCK_SIZE,
+            entry_count: device_size.div_ceil(BLOCK_SIZE),
+            first_entry: 0,
+            device_size,
+            sample_bytes: sample_bytes.unwrap_or(0),
+            filter,
+            explicit: false,
+            record_count: 0,
+            identity: Identity::default(),
+            provenance: String::new(),
+        }
+    }
+
+    fn filter_len(&self) -> usize {
+        if self.filter {
+            FILTER_BYTES
+        } else {
+            0
+        }
+    }
+
+    fn entry_len(&self) -> usize {
+        self.filter_len() + self.digest_len
+    }
+
+    fn entries_per_block(&self) -> usize {
+        (BLOCK_SIZE - CHECKSUM_BYTES) / self.entry_len()
+    }
+
+    fn entry_offset(&self, entry: usize) -> usize {
+        let per_block = self.entries_per_block();
+        (1 + entry / per_block) * BLOCK_SIZE + (entry % per_block) * self.entry_len()
+    }
+
+    fn record_len(&self) -> usize {
+        RECORD_ENTRY_BYTES + self.entry_len()
+    }
+
+    fn records_per_block(&self) -> usize {
+        (BLOCK_SIZE - CHECKSUM_BYTES) / self.record_len()
+    }
+
+    /// The size of the positional layout, which explicit indexes are also assembled in before compacting.
+    fn positional_size(&self) -> usize {
+        (1 + self.entry_count.div_ceil(self.entries_per_block())) * BLOCK_SIZE
+    }
+
+    fn file_size(&self) -> usize {
+        if self.explicit {
+            (1 + self.record_count.div_ceil(self.records_per_block())) * BLOCK_SIZE
+        } else {
+            self.positional_size()
+        }
+    }
+
+    fn encode(&self, block: &mut [u8]) {
+        block.fill(0);
+        block[0..8].copy_from_slice(MAGIC);
+        let version = if self.explicit { EXPLICIT_VERSION } else { VERSION };
+        block[8..12].copy_from_slice(&version.to_le_bytes());
+        block[12..16].copy_from_slice(&(self.digest_len as u32).to_le_bytes());
+        block[16..20].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        block[24..32].copy_from_slice(&(self.entry_count as u64).to_le_bytes());
+        block[32..40].copy_from_slice(&(self.device_size as u64).to_le_bytes());
+        block[40..44].copy_from_slice(&(self.sample_bytes as u32).to_le_bytes());
+        if self.filter {
+            block[44..48].copy_from_slice(&FILTER_XXH64.to_le_bytes());
+        }
+        self.identity.encode(&mut block[48..]);
+        block[FIRST_ENTRY_OFFSET..FIRST_ENTRY_OFFSET + 8].copy_from_slice(&(self.first_entry as u64).to_le_bytes());
+        block[RECORD_COUNT_OFFSET..RECORD_COUNT_OFFSET + 8].copy_from_slice(&(self.record_count as u64).to_le_bytes());
+        let mut len = self.provenance.len().min(MAX_PROVENANCE);
+        while !self.provenance.is_char_boundary(len) {
+            len -= 1;
+        }
+        block[PROVENANCE_OFFSET..PROVENANCE_OFFSET + 4].copy_from_slice(&(len as u32).to_le_bytes());
+        block[PROVENANCE_OFFSET + 4..PROVENANCE_OFFSET + 4 + len].copy_from_slice(&self.provenance.as_bytes()[..len]);
+    }
+
+    fn decode(block: &[u8]) -> io::Result<Self> {
+        let u32_at = |offset: usize| u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap()) as usize;
+        let u64_at = |offset: usize| u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap()) as usize;
+        let explicit = match u32_at(8) as u32 {
+            VERSION => false,
+            EXPLICIT_VERSION => true,
+            version => return Err(corrupt(format!("unsupported index version {}", version))),
+        };
+        let header = Self {
+            digest_len: u32_at(12),
+            block_size: u32_at(16),
+            entry_count: u64_at(24),
+            first_entry: u64_at(FIRST_ENTRY_OFFSET),
+            device_size: u64_at(32),
+            sample_bytes: u32_at(40),
+            filter: match u32_at(44) as u32 {
+                0 => false,
+                FILTER_XXH64 => true,
+                kind => return Err(corrupt(format!("unknown filter hash kind {}", kind))),
+            },
+            explicit,
+            record_count: if explicit { u64_at(RECORD_COUNT_OFFSET) } else { 0 },
+            identity: Identity::decode(&block[48..]),
+            provenance: {
+                let len = u32_at(PROVENANCE_OFFSET).min(MAX_PROVENANCE);
+                String::from_utf8_lossy(&block[PROVENANCE_OFFSET + 4..PROVENANCE_OFFSET + 4 + len]).into_owned()
+            },
+        };
+        if !(MIN_DIGEST_BYTES..=HASH_BYTES).contains(&header.digest_len) || header.block_size != BLOCK_SIZE {
+            return Err(corrupt(format!(
+                "index uses {} byte digests of {} byte blocks, expected up to {} byte digests of {} byte blocks",
+                header.digest_len, header.block_size, HASH_BYTES, BLOCK_SIZE
+            )));
+        }
+        if header.sample_bytes > BLOCK_SIZE {
+            return Err(corrupt(format!(
+                "sample size {} is larger than a block",
+                header.sample_bytes
+            )));
+        }
+        Ok(header)
+    }
+}
+
+fn corrupt(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("index: {}", msg))
+}
+
+fn checksum(block: &[u8]) -> [u8; CHECKSUM_BYTES] {
+    hash_block(&block[..BLOCK_SIZE - CHECKSUM_BYTES])[..CHECKSUM_BYTES]
+        .try_into()
+        .unwrap()
+}
+
+fn seal(block: &mut [u8]) {
+    let sum = checksum(block);
+    block[BLOCK_SIZE - CHECKSUM_BYTES..].copy_from_slice(&sum);
+}
+
+fn verify(block: &[u8], number: usize) -> io::Result<()> {
+    if block[BLOCK_SIZE - CHECKSUM_BYTES..] != checksum(block) {
+        return Err(corrupt(format!("checksum mismatch in index block {}", number)));
+    }
+    Ok(())
+}
+
+/// Where shard `number` of the sharded index listed at `path` is written.
+fn shard_path(path: &Path, number: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".shard{:04}", number));
+    PathBuf::from(name)
+}
+
+/// Where an explicit index is assembled in the positional layout before `finish` compacts it.
+fn staging_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/// One file being written; explicit ones are staged at `staging_path(path)`.
+struct ShardWriter {
+    file: WritableMappedFile,
+    header: Header,
+    path: PathBuf,
+}
+
+impl ShardWriter {
+    fn create(path: PathBuf, header: Header) -> io::Result<Self> {
+        let staged = if header.explicit {
+            staging_path(&path)
+        } else {
+            path.clone()
+        };
+        let file = WritableMappedFile::create(&staged, header.positional_size())?;
+        Ok(Self { file, header, path })
+    }
+
+    /// Writes the non-empty entries of the staged positional file as explicit records, then removes it.
+    fn compact(&mut self) -> io::Result<()> {
+        let entry_len = self.header.entry_len();
+        let per_block = self.header.records_per_block();
+        let empty = vec![0; entry_len];
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        out.write_all(&[0; BLOCK_SIZE])?;
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut slot = 0;
+        self.header.record_count = 0;
+        for entry in 0..self.header.entry_count {
+            let raw = self.file.slice_mut(self.header.entry_offset(entry), entry_len);
+            if *raw == *empty {
+                continue;
+            }
+            let record = &mut block[slot * self.header.record_len()..(slot + 1) * self.header.record_len()];
+            record[..RECORD_ENTRY_BYTES].copy_from_slice(&((self.header.first_entry + entry) as u64).to_le_bytes());
+            record[RECORD_ENTRY_BYTES..].copy_from_slice(raw);
+            self.header.record_count += 1;
+            slot += 1;
+            if slot == per_block {
+                seal(&mut block);
+                out.write_all(&block)?;
+                block.fill(0);
+                slot = 0;
+            }
+        }
+        if slot > 0 {
+            seal(&mut block);
+            out.write_all(&block)?;
+        }
+        self.header.encode(&mut block);
+        seal(&mut block);
+        let mut file = out.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&block)?;
+        file.sync_all()?;
+        fs::remove_file(staging_path(&self.path))
+    }
+}
+
+pub struct IndexWriter {
+    shards: Vec<ShardWriter>,
+    shard_entries: usize,
+    /// Where the shard list goes, for a sharded index.
+    list: Option<PathBuf>,
+}
+
+impl IndexWriter {
+    /// Creates the index file, or with a `shard_size` smaller than the origin one file per that many origin bytes.
+    pub fn create(path: &Path, header: Header, shard_size: Option<usize>) -> io::Result<Self> {
+        let Some(shard_size) = shard_size.filter(|&size| size < header.device_size) else {
+            return Ok(Self {
+                shards: vec![ShardWriter::create(path.to_path_buf(), header)?],
+                shard_entries: usize::MAX,
+                list: None,
+            });
+        };
+        let shard_entries = shard_size / BLOCK_SIZE;
+        let mut shards = Vec::new();
+        for first_entry in (0..header.entry_count).step_by(shard_entries) {
+            let shard = Header {
+                first_entry,
+                entry_count: shard_entries.min(header.entry_count - first_entry),
+                ..header.clone()
+            };
+            shards.push(ShardWriter::create(shard_path(path, shards.len()), shard)?);
+        }
+        Ok(Self {
+            shards,
+            shard_entries,
+            list: Some(path.to_path_buf()),
+        })
+    }
+
+    /// The file an origin entry is stored in, and its offset there.
+    fn locate(&mut self, entry: usize) -> (&mut WritableMappedFile, &Header, usize) {
+        let shard = &mut self.shards[entry / self.shard_entries];
+        let offset = shard.header.entry_offset(entry - shard.header.first_entry);
+        (&mut shard.file, &shard.header, offset)
+    }
+
+    pub fn set(&mut self, entry: usize, digest: &[u8]) {
+        let (file, header, offset) = self.locate(entry);
+        file.slice_mut(offset + header.filter_len(), header.digest_len)
+            .copy_from_slice(digest);
+    }
+
+    pub fn set_filter(&mut self, entry: usize, filter: u64) {
+        let (file, _, offset) = self.locate(entry);
+        file.slice_mut(offset, FILTER_BYTES)
+            .copy_from_slice(&filter.to_le_bytes());
+    }
+
+    /// Copies the entries `keep(entry, digest)` accepts from an index built with the same parameters; returns how
+    /// many.
+    pub fn copy_from(&mut self, base: &Index, mut keep: impl FnMut(usize, &[u8]) -> bool) -> io::Result<usize> {
+        let header = &self.shards[0].header;
+        let compatible = base.shards[0].header.as_ref().is_some_and(|base| {
+            base.digest_len == header.digest_len
+                && base.sample_bytes == header.sample_bytes
+                && base.filter == header.filter
+                && base.device_size == header.device_size
+        });
+        if !compatible {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the base index was built from a different size device or with different digest, sample \
+                 or filter settings",
+            ));
+        }
+        let (entry_len, filter_len) = (header.entry_len(), header.filter_len());
+        let mut copied = 0;
+        base.for_each_entry(0..base.shards.len(), |entry, raw| {
+            if keep(entry, &raw[filter_len..]) {
+                let (file, _, offset) = self.locate(entry);
+                file.slice_mut(offset, entry_len).copy_from_slice(raw);
+                copied += 1;
+            }
+        })?;
+        Ok(copied)
+    }
+
+    /// For origins whose identity is only known once they have been read, like streamed ones.
+    pub fn set_identity(&mut self, identity: Identity) {
+        for shard in &mut self.shards {
+            shard.header.identity = identity.clone();
+        }
+    }
+
+    /// Writes the headers, with the provenance of this run, all block checksums and the shard list.
+    pub fn finish(mut self) -> io::Result<()> {
+        let provenance = provenance::lines().join("\n");
+        for shard in &mut self.shards {
+            shard.header.provenance = provenance.clone();
+            if shard.header.explicit {
+                shard.compact()?;
+                eprintln!(
+                    "\n{}: {} records for {} origin blocks",
+                    shard.path.display(),
+                    shard.header.record_count,
+                    shard.header.entry_count
+                );
+                continue;
+            }
+            shard.header.encode(shard.file.slice_mut(0, BLOCK_SIZE));
+            for offset in (0..shard.file.size()).step_by(BLOCK_SIZE) {
+                seal(shard.file.slice_mut(offset, BLOCK_SIZE));
+            }
+            shard.file.flush()?;
+        }
+        if let Some(list) = &self.list {
+            let mut text = format!("{}\n", String::from_utf8_lossy(SHARD_MAGIC));
+            for shard in &self.shards {
+                text += &format!(
+                    "{} {} {}\n",
+                    shard.header.first_entry,
+                    shard.header.entry_count,
+                    shard.path.file_name().unwrap().to_string_lossy()
+                );
+            }
+            fs::write(list, text)?;
+            eprintln!("\nindex split into {} shards", self.shards.len());
+        }
+        Ok(())
+    }
+}
+
+/// One index file: the whole index, or one shard of a sharded one.
+struct Shard {
+    path: PathBuf,
+    file: MappedFile,
+    header: Option<Header>,
+}
+
+impl Shard {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = MappedFile::open(path)?;
+        if file.size() < BLOCK_SIZE || file.size() % BLOCK_SIZE != 0 {
+            return Err(corrupt(format!(
+                "{} bytes is not a whole number of index blocks",
+                file.size()
+            )));
+        }
+        let first = file.slice(0, BLOCK_SIZE);
+        if &first[0..8] != MAGIC {
+            eprintln!("{}: legacy index without checksums", path.display());
+            return Ok(Self {
+                path: path.to_path_buf(),
+                file,
+                header: None,
+            });
+        }
+        verify(first, 0)?;
+        let header = Header::decode(first)?;
+        if file.size() != header.file_size() {
+            return Err(corrupt(format!(
+                "expected {} bytes for {} {} but the file has {}, it may be truncated",
+                header.file_size(),
+                if header.explicit {
+                    header.record_count
+                } else {
+                    header.entry_count
+                },
+                if header.explicit { "records" } else { "entries" },
+                file.size()
+            )));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            header: Some(header),
+        })
+    }
+
+    fn first_entry(&self) -> usize {
+        self.header.as_ref().map_or(0, |header| header.first_entry)
+    }
+
+    /// The origin entries this file covers.
+    fn entry_count(&self) -> usize {
+        match &self.header {
+            Some(header) => header.entry_count,
+            None => self.file.size() / BLOCK_SIZE * LEGACY_ENTRIES_PER_BLOCK,
+        }
+    }
+
+    /// The entries this file has room for: all it covers, or just the recorded ones of an explicit index.
+    fn stored(&self) -> usize {
+        match &self.header {
+            Some(header) if header.explicit => header.record_count,
+            _ => self.entry_count(),
+        }
+    }
+
+    /// Record `number` of an explicit index.
+    fn record(&self, header: &Header, number: usize) -> (usize, &[u8]) {
+        let per_block = header.records_per_block();
+        let offset = (1 + number / per_block) * BLOCK_SIZE + (number % per_block) * header.record_len();
+        let record = self.file.slice(offset, header.record_len());
+        let entry = u64::from_le_bytes(record[..RECORD_ENTRY_BYTES].try_into().unwrap()) as usize;
+        (entry, &record[RECORD_ENTRY_BYTES..])
+    }
+
+    /// The filter hash and digest of one entry.
+    fn entry(&self, entry: usize) -> Option<&[u8]> {
+        let header = self.header.as_ref()?;
+        if !header.explicit {
+            return Some(
+                self.file
+                    .slice(header.entry_offset(entry - header.first_entry), header.entry_len()),
+            );
+        }
+        let (mut low, mut high) = (0, header.record_count);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.record(header, middle).0 < entry {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        (low < header.record_count)
+            .then(|| self.record(header, low))
+            .filter(|&(found, _)| found == entry)
+            .map(|(_, raw)| raw)
+    }
+
+    /// Whether index block `number` still has its checksum. One that does not fails the run, or with
+    /// `skip_corrupt_blocks` is left out with its entries, which only costs the origin blocks they are of.
+    fn intact(&self, number: usize) -> io::Result<bool> {
+        let block = self.file.slice(number * BLOCK_SIZE, BLOCK_SIZE);
+        if !SKIP_CORRUPT.load(Ordering::SeqCst) {
+            verify(block, number)?;
+        }
+        if block[BLOCK_SIZE - CHECKSUM_BYTES..] == checksum(block) {
+            return Ok(true);
+        }
+        if block_errors::record(
+            Kind::Checksum,
+            &self.path,
+            number,
+            BLOCK_SIZE,
+            "index block checksum mismatch",
+        ) {
+            eprintln!(
+                "\nwarning: {}: checksum mismatch in index block {}, its entries are left out",
+                self.path.display(),
+                number
+            );
+        }
+        Ok(false)
+    }
+
+    fn for_each_record(&self, header: &Header, f: &mut impl FnMut(usize, &[u8])) -> io::Result<()> {
+        for number in 1..self.file.size() / BLOCK_SIZE {
+            if !self.intact(number)? {
+                continue;
+            }
+            let first = (number - 1) * header.records_per_block();
+            for record in first..header.record_count.min(first + header.records_per_block()) {
+                let (entry, raw) = self.record(header, record);
+                f(entry, raw);
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each_entry(&self, f: &mut impl FnMut(usize, &[u8])) -> io::Result<()> {
+        if let Some(header) = self.header.as_ref().filter(|header| header.explicit) {
+            return self.for_each_record(header, f);
+        }
+        let (first_block, per_block, digest_len) = match &self.header {
+            Some(header) => (1, header.entries_per_block(), header.entry_len()),
+            None => (0, LEGACY_ENTRIES_PER_BLOCK, HASH_BYTES),
+        };
+        let (first_entry, entry_count) = (self.first_entry(), self.entry_count());
+        let empty = vec![0; digest_len];
+        for number in first_block..self.file.size() / BLOCK_SIZE {
+            let block = self.file.slice(number * BLOCK_SIZE, BLOCK_SIZE);
+            if self.header.is_some() && !self.intact(number)? {
+                continue;
+            }
+            let first = (number - first_block) * per_block;
+            for slot in 0..per_block.min(entry_count.saturating_sub(first)) {
+                let digest = &block[slot * digest_len..(slot + 1) * digest_len];
+                if digest != empty.as_slice() {
+                    f(first_entry + first + slot, digest);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Index {
+    /// By first entry; a single one for an index that is not sharded.
+    shards: Vec<Shard>,
+}
+
+impl Index {
+    /// Opens an index, or all shards of a sharded one, and checks headers and lengths; block checksums are checked
+    /// as entries are read.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let start = MappedFile::open(path)?;
+        if start.size() < SHARD_MAGIC.len() || start.slice(0, SHARD_MAGIC.len()) != SHARD_MAGIC {
+            let shard = Shard::open(path)?;
+            if shard.header.is_some() {
+                provenance::record_input(path, format!("index, {} bytes", shard.file.size()));
+            }
+            return Ok(Self { shards: vec![shard] });
+        }
+        let list = fs::read_to_string(path)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let mut shards: Vec<Shard> = Vec::new();
+        for line in list.lines().skip(1).filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (Some(first_entry), Some(entry_count), Some(name)) = (
+                fields.first().and_then(|f| f.parse::<usize>().ok()),
+                fields.get(1).and_then(|f| f.parse::<usize>().ok()),
+                fields.get(2),
+            ) else {
+                return Err(corrupt(format!("bad shard list line '{}'", line)));
+            };
+            let shard_path = directory.join(name);
+            let shard = Shard::open(&shard_path)?;
+            let expected = shards.last().map_or(0, |last| last.first_entry() + last.entry_count());
+            let Some(header) = &shard.header else {
+                return Err(corrupt(format!("shard {} has no header", shard_path.display())));
+            };
+            let matches_first = shards
+                .first()
+                .and_then(|first| first.header.as_ref())
+                .is_none_or(|first| {
+                    first.digest_len == header.digest_len
+                        && first.sample_bytes == header.sample_bytes
+                        && first.filter == header.filter
+                        && first.device_size == header.device_size
+                        && first.identity == header.identity
+                });
+            if header.first_entry != first_entry
+                || header.first_entry != expected
+                || header.entry_count != entry_count
+                || !matches_first
+            {
+                return Err(corrupt(format!(
+                    "shard {} does not continue the shards before it",
+                    shard_path.display()
+                )));
+            }
+            shards.push(shard);
+        }
+        let Some(header) = shards.first().and_then(|first| first.header.as_ref()) else {
+            return Err(corrupt(format!("shard list {} names no shards", path.display())));
+        };
+        let entries: usize = shards.iter().map(Shard::entry_count).sum();
+        if entries != header.device_size.div_ceil(BLOCK_SIZE) {
+            return Err(corrupt(format!(
+                "the shards hold {} of the {} entries of the origin, some are missing",
+                entries,
+                header.device_size.div_ceil(BLOCK_SIZE)
+            )));
+        }
+        let index = Self { shards };
+        provenance::record_input(
+            path,
+            format!("sharded index, {} shards, {} bytes", index.shards.len(), index.size()),
+        );
+        Ok(index)
+    }
+
+    /// The header of the (first) index file; legacy indexes have none.
+    pub fn header(&self) -> Option<&Header> {
+        self.shards[0].header.as_ref()
+    }
+
+    pub fn size(&self) -> usize {
+        self.shards.iter().map(|shard| shard.file.size()).sum()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Entries stored in one shard, which for an explicit index are only the indexed ones.
+    pub fn shard_entry_count(&self, shard: usize) -> usize {
+        self.shards[shard].stored()
+    }
+
+    /// How the index was made, one `key: value` per line, if it says.
+    pub fn provenance(&self) -> Option<&str> {
+        self.header()
+            .map(|header| header.provenance.as_str())
+            .filter(|provenance| !provenance.is_empty())
+    }
+
+    /// Entries stored, which for an explicit index are only the indexed ones.
+    pub fn entry_count(&self) -> usize {
+        self.shards.iter().map(Shard::stored).sum()
+    }
+
+    pub fn digest_len(&self) -> usize {
+        self.header().map_or(HASH_BYTES, |header| header.digest_len)
+    }
+
+    /// How many leading bytes of each block were hashed, if not the whole block.
+    pub fn sample_bytes(&self) -> Option<usize> {
+        self.header()
+            .map(|header| header.sample_bytes)
+            .filter(|&sample| sample != 0)
+    }
+
+    /// The size and identity of the device the index was built from; legacy indexes recorded neither.
+    pub fn origin(&self) -> Option<(usize, &Identity)> {
+        self.header().map(|header| (header.device_size, &header.identity))
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.header().is_some_and(|header| header.filter)
+    }
+
+    /// The digest of one entry, for confirming filter hits; its block was verified by `for_each`.
+    pub fn digest(&self, entry: usize) -> &[u8] {
+        let shard = &self.shards[self.shards.partition_point(|shard| shard.first_entry() <= entry) - 1];
+        let header = shard.header.as_ref().expect("only two-tier indexes are read by entry");
+        // Filter hits only ever name recorded entries.
+        shard.entry(entry).map_or(&[], |raw| &raw[header.filter_len()..])
+    }
+
+    /// Calls `f(entry, digest)` for every indexed origin block, verifying each index block on the way.
+    pub fn for_each(&self, f: impl FnMut(usize, &[u8])) -> io::Result<()> {
+        self.for_each_in(0..self.shards.len(), f)
+    }
+
+    /// `for_each` over some of the shards only.
+    pub fn for_each_in(&self, shards: Range<usize>, mut f: impl FnMut(usize, &[u8])) -> io::Result<()> {
+        let filter_len = self.header().map_or(0, Header::filter_len);
+        self.for_each_entry(shards, |entry, raw| f(entry, &raw[filter_len..]))
+    }
+
+    /// Calls `f(entry, filter)` for every indexed origin block in some shards of a two-tier index.
+    pub fn for_each_filter_in(&self, shards: Range<usize>, mut f: impl FnMut(usize, u64)) -> io::Result<()> {
+        self.for_each_entry(shards, |entry, raw| {
+            f(entry, u64::from_le_bytes(raw[..FILTER_BYTES].try_into().unwrap()))
+        })
+    }
+
+    fn for_each_entry(&self, shards: Range<usize>, mut f: impl FnMut(usize, &[u8])) -> io::Result<()> {
+        for shard in &self.shards[shards] {
+            shard.for_each_entry(&mut f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A digest no two test entries share and none leaves all zero, which reads as an empty entry.
+    fn digest_of(entry: usize) -> Vec<u8> {
+        hash_block(&entry.to_le_bytes())
+    }
+
+    /// Writes an index of `entries` origin blocks to a file of its own under the temporary directory.
+    fn write_index(name: &str, entries: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cache_guess-{}-{}.idx", name, std::process::id()));
+        let mut writer =
+            IndexWriter::create(&path, Header::new(entries * BLOCK_SIZE, HASH_BYTES, None, false), None).unwrap();
+        for entry in 0..entries {
+            writer.set(entry, &digest_of(entry));
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn flip_byte(path: &Path, offset: usize) {
+        let mut data = fs::read(path).unwrap();
+        data[offset] ^= 0xff;
+        fs::write(path, data).unwrap();
+    }
+
+    fn entries_of(index: &Index) -> io::Result<Vec<(usize, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        index.for_each(|entry, digest| entries.push((entry, digest.to_vec())))?;
+        Ok(entries)
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let mut header = Header::new(10 * BLOCK_SIZE + 1, 8, Some(4096), true);
+        header.first_entry = 3;
+        header.explicit = true;
+        header.record_count = 7;
+        header.provenance = "cache_guess collect origin.idx /dev/sdb".to_string();
+        let mut block = vec![0; BLOCK_SIZE];
+        header.encode(&mut block);
+        let decoded = Header::decode(&block).unwrap();
+        assert_eq!(decoded.entry_count, 11);
+        assert_eq!(decoded.device_size, 10 * BLOCK_SIZE + 1);
+        assert_eq!((decoded.digest_len, decoded.sample_bytes), (8, 4096));
+        assert!(decoded.filter && decoded.explicit);
+        assert_eq!((decoded.first_entry, decoded.record_count), (3, 7));
+        assert_eq!(decoded.provenance, header.provenance);
+    }
+
+    #[test]
+    fn header_rejects_unknown_version() {
+        let mut block = vec![0; BLOCK_SIZE];
+        Header::new(BLOCK_SIZE, HASH_BYTES, None, false).encode(&mut block);
+        block[8..12].copy_from_slice(&9u32.to_le_bytes());
+        let e = Header::decode(&block).unwrap_err();
+        assert!(e.to_string().contains("unsupported index version 9"), "{}", e);
+    }
+
+    #[test]
+    fn seal_and_verify() {
+        let mut block = vec![7; BLOCK_SIZE];
+        seal(&mut block);
+        verify(&block, 1).unwrap();
+        block[100] ^= 1;
+        let e = verify(&block, 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("checksum mismatch in index block 1"), "{}", e);
+    }
+
+    #[test]
+    fn entries_read_back_across_blocks() {
+        let header = Header::new(0, HASH_BYTES, None, false);
+        let entries = 3 * header.entries_per_block() + 5;
+        let path = write_index("read-back", entries);
+        let index = Index::open(&path).unwrap();
+        assert_eq!(index.entry_count(), entries);
+        let read = entries_of(&index).unwrap();
+        assert_eq!(read.len(), entries);
+        for (entry, digest) in read {
+            assert_eq!(digest, digest_of(entry));
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_header_fails_to_open() {
+        let path = write_index("corrupt-header", 10);
+        flip_byte(&path, 40);
+        let e = Index::open(&path).err().unwrap();
+        assert!(e.to_string().contains("checksum mismatch in index block 0"), "{}", e);
+        fs::remove_file(path).unwrap();
+    }
+
+    // The only test that turns on skipping, which holds for the rest of the process: the others do not read past a
+    // corrupt entry block.
+    #[test]
+    fn corrupt_entry_block_fails_unless_skipped() {
+        let per_block = Header::new(0, HASH_BYTES, None, false).entries_per_block();
+        let path = write_index("corrupt-entries", 3 * per_block);
+        flip_byte(&path, 2 * BLOCK_SIZE + 10);
+        let index = Index::open(&path).unwrap();
+        let e = entries_of(&index).unwrap_err();
+        assert!(e.to_string().contains("checksum mismatch in index block 2"), "{}", e);
+
+        skip_corrupt_blocks();
+        let read: Vec<usize> = entries_of(&index)
+            .unwrap()
+            .into_iter()
+            .map(|(entry, _)| entry)
+            .collect();
+        let expected: Vec<usize> = (0..per_block).chain(2 * per_block..3 * per_block).collect();
+        assert_eq!(read, expected);
+        fs::remove_file(path).unwrap();
+    }
+}
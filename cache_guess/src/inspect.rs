@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::index::Index;
+use crate::layout::Layout;
+use crate::unmatched::entropy;
+use crate::{hash_block, open_device, BLOCK_SIZE};
+
+const PREVIEW_LINE: usize = 16;
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hexdump(data: &[u8], base: usize) {
+    for (line, chunk) in data.chunks(PREVIEW_LINE).enumerate() {
+        let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!(
+            "  {:08x}  {:<47}  {}",
+            base + line * PREVIEW_LINE,
+            bytes.join(" "),
+            text
+        );
+    }
+}
+
+pub struct InspectOptions<'a> {
+    pub block_size: usize,
+    pub partition: Option<usize>,
+    pub index: Option<&'a Path>,
+    pub preview: usize,
+}
+
+/// Prints what cache_guess sees in one block of a device: digests, entropy, a hexdump and, given an index, where
+/// else the same data lives.
+pub fn inspect(device_path: &Path, block: usize, options: &InspectOptions) -> io::Result<()> {
+    let device = open_device(device_path, options.partition)?;
+    let offset = block * options.block_size;
+    if offset + options.block_size > device.size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("block {} is past the end of {}", block, device_path.display()),
+        ));
+    }
+    let data = device.slice(offset, options.block_size);
+    let zeros = data.iter().filter(|&&b| b == 0).count();
+    println!("block {} at byte {} ({} bytes)", block, offset, options.block_size);
+    println!("  sha1     {}", hex(&hash_block(data)));
+    println!("  entropy  {:.3} bits/byte", entropy(data));
+    println!("  zeros    {:.3}%", zeros as f64 / data.len() as f64 * 100.0);
+    hexdump(&data[..options.preview.min(data.len())], offset);
+
+    let Some(index_path) = options.index else {
+        return Ok(());
+    };
+    let index = Index::open(index_path)?;
+    if let Some(provenance) = index.provenance() {
+        println!("index provenance:");
+        for line in provenance.lines() {
+            println!("  {}", line);
+        }
+    }
+    let sample = index.sample_bytes().unwrap_or(BLOCK_SIZE);
+    let fs_blocks: Vec<Vec<u8>> = data
+        .chunks(BLOCK_SIZE)
+        .map(|block| hash_block(&block[..sample.min(block.len())]))
+        .collect();
+    let digest_len = index.digest_len();
+    let wanted: HashMap<&[u8], usize> = fs_blocks
+        .iter()
+        .enumerate()
+        .map(|(i, digest)| (&digest[..digest_len], i))
+        .collect();
+    let mut locations = vec![Vec::new(); fs_blocks.len()];
+    index.for_each(|entry, digest| {
+        if let Some(&i) = wanted.get(digest) {
+            locations[i].push(entry * BLOCK_SIZE);
+        }
+    })?;
+
+    let layout = Layout::read(index_path)?;
+    for (i, digest) in fs_blocks.iter().enumerate() {
+        println!("fs-block {} sha1 {}", i, hex(digest));
+        if locations[i].is_empty() {
+            println!("  not in the index");
+        }
+        for &origin_offset in &locations[i] {
+            let mut place = String::new();
+            if let Some((segment, offset)) = layout.as_ref().and_then(|layout| layout.locate(origin_offset)) {
+                place = format!(" [{}+{}]", segment.path.display(), offset);
+            }
+            println!(
+                "  origin offset {} (block {} + {}){}",
+                origin_offset,
+                origin_offset / options.block_size,
+                origin_offset % options.block_size,
+                place
+            );
+        }
+    }
+    Ok(())
+}
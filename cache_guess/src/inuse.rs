@@ -0,0 +1,172 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::remote;
+
+fn unescape_mount_field(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+fn mounts() -> io::Result<Vec<(String, String)>> {
+    let mut mounts = Vec::new();
+    for line in BufReader::new(File::open("/proc/mounts")?).lines() {
+        let line = line?;
+        let mut fields = line.split(' ');
+        if let (Some(source), Some(target)) = (fields.next(), fields.next()) {
+            mounts.push((unescape_mount_field(source), unescape_mount_field(target)));
+        }
+    }
+    Ok(mounts)
+}
+
+type DevNum = (u64, u64);
+
+fn decode_dev(dev: u64) -> DevNum {
+    (
+        ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff),
+        ((dev >> 12) & 0xffff_ff00) | (dev & 0xff),
+    )
+}
+
+fn block_dev(path: &Path) -> Option<DevNum> {
+    let metadata = fs::metadata(path).ok()?;
+    metadata
+        .file_type()
+        .is_block_device()
+        .then(|| decode_dev(metadata.rdev()))
+}
+
+fn read_dev(sysfs: &Path) -> Option<DevNum> {
+    let dev = fs::read_to_string(sysfs.join("dev")).ok()?;
+    let (major, minor) = dev.trim().split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn sysfs_dir((major, minor): DevNum) -> Option<PathBuf> {
+    fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor)).ok()
+}
+
+/// The sysfs dir of a block device, or of the block device holding a regular file.
+pub fn backing_sysfs_dir(path: &Path) -> Option<PathBuf> {
+    match block_dev(path) {
+        Some(dev) => sysfs_dir(dev),
+        None => sysfs_dir(decode_dev(fs::metadata(path).ok()?.dev())),
+    }
+}
+
+fn dev_name(sysfs: &Path) -> String {
+    sysfs.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+fn holders(sysfs: &Path) -> Vec<String> {
+    fs::read_dir(sysfs.join("holders"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The block device itself plus all of its partitions, as (name, sysfs dir, dev number).
+fn block_family(sysfs: &Path, dev: DevNum) -> Vec<(String, PathBuf, DevNum)> {
+    let mut family = vec![(dev_name(sysfs), sysfs.to_path_buf(), dev)];
+    if let Ok(entries) = fs::read_dir(sysfs) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let dir = entry.path();
+            if !dir.join("partition").exists() {
+                continue;
+            }
+            if let Some(dev) = read_dev(&dir) {
+                family.push((dev_name(&dir), dir, dev));
+            }
+        }
+    }
+    family
+}
+
+fn block_device_usage(dev: DevNum, mounts: &[(String, String)]) -> Option<String> {
+    let sysfs = sysfs_dir(dev)?;
+    for (name, dir, dev) in block_family(&sysfs, dev) {
+        if let Some((_, target)) = mounts
+            .iter()
+            .find(|(source, _)| source.starts_with('/') && block_dev(Path::new(source)) == Some(dev))
+        {
+            return Some(format!("{} is mounted at {}", name, target));
+        }
+        let holders = holders(&dir);
+        if !holders.is_empty() {
+            return Some(format!("{} is held by {}", name, holders.join(", ")));
+        }
+    }
+    None
+}
+
+/// Loop devices currently backed by the given (canonical) file.
+fn loop_devices_for(path: &Path) -> Vec<DevNum> {
+    let Ok(entries) = fs::read_dir("/sys/block") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("loop"))
+        .filter(|entry| {
+            fs::read_to_string(entry.path().join("loop/backing_file"))
+                .is_ok_and(|backing| Path::new(backing.trim_end()) == path)
+        })
+        .filter_map(|entry| read_dev(&entry.path()))
+        .collect()
+}
+
+/// Describes why `path` looks like it is in use (mounted, or held by device-mapper, md, a mounted loop device...).
+pub fn usage(path: &Path) -> io::Result<Option<String>> {
+    // On another machine, where nothing here can tell.
+    if remote::is_remote(path) {
+        return Ok(None);
+    }
+    let path = fs::canonicalize(path)?;
+    let mounts = mounts()?;
+
+    if let Some(dev) = block_dev(&path) {
+        return Ok(block_device_usage(dev, &mounts));
+    }
+
+    if let Some((_, target)) = mounts.iter().find(|(source, _)| Path::new(source) == path) {
+        return Ok(Some(format!("it is mounted at {}", target)));
+    }
+    for dev in loop_devices_for(&path) {
+        if let Some(reason) = block_device_usage(dev, &mounts) {
+            return Ok(Some(format!("it backs a loop device and {}", reason)));
+        }
+    }
+    Ok(None)
+}
+
+/// Fails unless none of `paths` is in use, or `force` is given.
+pub fn ensure_unused(paths: &[&Path], force: bool) -> io::Result<()> {
+    for path in paths {
+        if let Some(reason) = usage(path)? {
+            if force {
+                eprintln!(
+                    "warning: {} is in use ({}), continuing because of --force",
+                    path.display(),
+                    reason
+                );
+            } else {
+                return Err(io::Error::other(format!(
+                    "refusing to use {}: {} (use --force to override)",
+                    path.display(),
+                    reason
+                )));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use std::io;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Puts this process in the idle I/O scheduling class, so its reads only get the disk when nothing else wants it.
+/// Threads started afterwards inherit it. Only schedulers with priorities (BFQ, CFQ) honor it.
+pub fn set_idle() -> io::Result<()> {
+    let prio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    eprintln!("I/O priority: idle");
+    Ok(())
+}
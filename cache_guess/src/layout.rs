@@ -0,0 +1,138 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::BLOCK_SIZE;
+
+/// One underlying device and where it sits in the logical origin.
+#[derive(Debug)]
+pub struct Segment {
+    pub offset: usize,
+    pub size: usize,
+    pub path: PathBuf,
+}
+
+/// How a logical origin is assembled from several devices, stored next to the index as `<index>.layout`.
+#[derive(Debug)]
+pub struct Layout {
+    pub segments: Vec<Segment>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Splits `path[@offset]`, where offset is the logical byte offset of the device.
+pub fn parse_spec(spec: &str) -> io::Result<(&Path, Option<usize>)> {
+    // The user in an ssh:// URI is not an offset.
+    match spec.rsplit_once('@').filter(|(_, offset)| !offset.contains('/')) {
+        Some((path, offset)) => {
+            let offset = offset
+                .parse::<usize>()
+                .map_err(|_| invalid(format!("bad offset in device spec '{}'", spec)))?;
+            Ok((Path::new(path), Some(offset)))
+        }
+        None => Ok((Path::new(spec), None)),
+    }
+}
+
+impl Layout {
+    /// Places devices at their explicit offsets, or right after the previous one (rounded up to a block).
+    pub fn new(devices: Vec<(PathBuf, Option<usize>, usize)>) -> io::Result<Self> {
+        let mut segments = Vec::with_capacity(devices.len());
+        let mut next_offset = 0;
+        for (path, offset, size) in devices {
+            let offset = offset.unwrap_or(next_offset);
+            if offset % BLOCK_SIZE != 0 {
+                return Err(invalid(format!(
+                    "offset of {} must be a multiple of {} bytes",
+                    path.display(),
+                    BLOCK_SIZE
+                )));
+            }
+            next_offset = (offset + size).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            segments.push(Segment { offset, size, path });
+        }
+
+        let mut sorted: Vec<&Segment> = segments.iter().collect();
+        sorted.sort_by_key(|segment| segment.offset);
+        for pair in sorted.windows(2) {
+            if pair[0].offset + pair[0].size > pair[1].offset {
+                return Err(invalid(format!(
+                    "{} overlaps {} in the logical origin",
+                    pair[0].path.display(),
+                    pair[1].path.display()
+                )));
+            }
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn sidecar_path(index_path: &Path) -> PathBuf {
+        let mut path = index_path.as_os_str().to_owned();
+        path.push(".layout");
+        PathBuf::from(path)
+    }
+
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|s| s.offset + s.size).max().unwrap_or(0)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "# offset size path")?;
+        for segment in &self.segments {
+            writeln!(out, "{} {} {}", segment.offset, segment.size, segment.path.display())?;
+        }
+        out.flush()
+    }
+
+    /// Reads the sidecar of an index, if there is one.
+    pub fn read(index_path: &Path) -> io::Result<Option<Self>> {
+        let file = match File::open(Self::sidecar_path(index_path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut segments = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(3, ' ');
+            let mut number = || {
+                fields
+                    .next()
+                    .and_then(|field| field.parse::<usize>().ok())
+                    .ok_or_else(|| invalid(format!("bad layout line '{}'", line)))
+            };
+            let offset = number()?;
+            let size = number()?;
+            let path = fields
+                .next()
+                .ok_or_else(|| invalid(format!("bad layout line '{}'", line)))?;
+            segments.push(Segment {
+                offset,
+                size,
+                path: PathBuf::from(path),
+            });
+        }
+        Ok(Some(Self { segments }))
+    }
+
+    pub fn remove(index_path: &Path) -> io::Result<()> {
+        match fs::remove_file(Self::sidecar_path(index_path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Maps a logical origin offset to the device holding it and the offset within that device.
+    pub fn locate(&self, offset: usize) -> Option<(&Segment, usize)> {
+        self.segments
+            .iter()
+            .find(|s| s.offset <= offset && offset < s.offset + s.size)
+            .map(|s| (s, offset - s.offset))
+    }
+}
@@ -0,0 +1,38 @@
+//! The matcher behind `cache_guess find`, as a C library for embedding in other recovery tools. Everything is
+//! behind the `capi` feature; `include/cache_guess.h` declares the functions.
+#![cfg(feature = "capi")]
+
+use sha1::{Digest, Sha1};
+
+// Shared with the binary, which uses more of them.
+#[allow(dead_code)]
+mod block_errors;
+#[allow(dead_code)]
+mod hash;
+#[allow(dead_code)]
+mod identity;
+#[allow(dead_code)]
+mod index;
+#[allow(dead_code)]
+mod mapped;
+#[allow(dead_code)]
+mod metrics;
+#[allow(dead_code)]
+mod output;
+#[allow(dead_code)]
+mod provenance;
+#[allow(dead_code)]
+mod split;
+
+pub mod capi;
+
+use mapped::{MappedFile, WritableMappedFile};
+
+const HASH_BYTES: usize = 20;
+const BLOCK_SIZE: usize = 8 * 1024;
+
+fn hash_block(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
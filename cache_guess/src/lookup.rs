@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::index::Index;
+use crate::inspect::hex;
+use crate::layout::Layout;
+use crate::throttle::Throttle;
+use crate::{hash, hash_block, inuse, log_complete, log_status, open_device, BLOCK_SIZE};
+
+pub struct LookupOptions<'a> {
+    pub cache_block_size: usize,
+    /// SHA-1 digests as hex, at least as long as the index's truncated ones.
+    pub digests: Vec<&'a str>,
+    /// Files whose fs-block-sized chunks are looked up.
+    pub files: Vec<&'a Path>,
+    /// Contents of an fs-block as hex.
+    pub bytes: Vec<&'a str>,
+    pub cache_devices: Vec<&'a Path>,
+    pub partition: Option<usize>,
+    pub bwlimit: Option<f64>,
+    pub force: bool,
+}
+
+/// Something to look for, and what the results are listed under.
+struct Query {
+    label: String,
+    digest: Vec<u8>,
+}
+
+fn parse_hex(text: &str, what: &str) -> io::Result<Vec<u8>> {
+    let text = text.trim();
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} '{}' is not an even number of hex digits", what, text),
+        )
+    };
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(&text[at..at + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+/// The queries for some content, one per fs-block. Like a filesystem stores it, the last fs-block is padded with
+/// zeroes, and only the leading bytes an index with --sample-bytes covers are hashed. Zero-filled fs-blocks are
+/// everywhere and are left out.
+fn content_queries(label: &str, data: &[u8], sample: usize, queries: &mut Vec<Query>) -> usize {
+    let mut zeros = 0;
+    for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+        let mut block = chunk.to_vec();
+        block.resize(BLOCK_SIZE, 0);
+        if block.iter().all(|&byte| byte == 0) {
+            zeros += 1;
+            continue;
+        }
+        let label = if data.len() > BLOCK_SIZE {
+            format!(
+                "{} fs-block {} (bytes {}..{})",
+                label,
+                i,
+                i * BLOCK_SIZE,
+                i * BLOCK_SIZE + chunk.len()
+            )
+        } else {
+            label.to_string()
+        };
+        queries.push(Query {
+            label,
+            digest: hash_block(&block[..sample]),
+        });
+    }
+    zeros
+}
+
+/// Reports where the index, and optionally cache devices, hold the given content: for every digest, file chunk
+/// or fs-block of bytes, the origin offsets the index has it at and the cache blocks holding a copy. Answers
+/// whether some data made it into the cache without a full find run.
+pub fn lookup(index_path: &Path, options: &LookupOptions) -> io::Result<()> {
+    inuse::ensure_unused(&options.cache_devices, options.force)?;
+    let index = Index::open(index_path)?;
+    let digest_len = index.digest_len();
+    let sample = index.sample_bytes().unwrap_or(BLOCK_SIZE);
+    let mut queries = Vec::new();
+    for digest in &options.digests {
+        let bytes = parse_hex(digest, "digest")?;
+        if bytes.len() < digest_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "digest {} is shorter than the {} bytes the index keeps of each",
+                    digest, digest_len
+                ),
+            ));
+        }
+        queries.push(Query {
+            label: "digest".to_string(),
+            digest: bytes,
+        });
+    }
+    let mut zeros = 0;
+    for path in &options.files {
+        let data = fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        zeros += content_queries(&path.display().to_string(), &data, sample, &mut queries);
+    }
+    for bytes in &options.bytes {
+        let data = parse_hex(bytes, "--bytes")?;
+        if data.len() > BLOCK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--bytes takes at most an fs-block, {} bytes; use --file for more",
+                    BLOCK_SIZE
+                ),
+            ));
+        }
+        zeros += content_queries("bytes", &data, sample, &mut queries);
+    }
+    if zeros > 0 {
+        eprintln!("{} zero-filled fs-blocks left out, they are everywhere", zeros);
+    }
+    if sample < BLOCK_SIZE {
+        eprintln!(
+            "the index covers the first {} bytes of each fs-block, so are the matches",
+            sample
+        );
+    }
+
+    // Digests are compared as far as the index keeps them.
+    let mut wanted: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (i, query) in queries.iter().enumerate() {
+        wanted.entry(&query.digest[..digest_len]).or_default().push(i);
+    }
+    let mut origin = vec![Vec::new(); queries.len()];
+    index.for_each(|entry, digest| {
+        for &i in wanted.get(digest).into_iter().flatten() {
+            origin[i].push(entry * BLOCK_SIZE);
+        }
+    })?;
+
+    let mut throttle = Throttle::new(options.bwlimit);
+    let mut cached = vec![Vec::new(); queries.len()];
+    for (number, path) in options.cache_devices.iter().enumerate() {
+        let device = open_device(path, options.partition)?;
+        let total = device.size() / BLOCK_SIZE;
+        eprintln!("hashing {} with {}", path.display(), hash::describe());
+        for first in (0..total).step_by(hash::BATCH) {
+            log_status(first, total, "fs-blocks", false);
+            let blocks: Vec<&[u8]> = (first..total.min(first + hash::BATCH))
+                .map(|fs_block| device.slice(fs_block * BLOCK_SIZE, sample))
+                .collect();
+            throttle.consume(blocks.len() * sample);
+            for (i, digest) in hash::hash_blocks(&blocks).into_iter().enumerate() {
+                for &query in wanted.get(&digest[..digest_len]).into_iter().flatten() {
+                    cached[query].push((number, (first + i) * BLOCK_SIZE));
+                }
+            }
+        }
+        log_complete(total, "fs-blocks");
+        eprintln!();
+    }
+
+    let layout = Layout::read(index_path)?;
+    let cache_block_size = options.cache_block_size;
+    let mut found = 0;
+    for (i, query) in queries.iter().enumerate() {
+        println!("{} sha1 {}", query.label, hex(&query.digest));
+        if origin[i].is_empty() {
+            println!("  not in the index");
+        }
+        for &origin_offset in &origin[i] {
+            let mut place = String::new();
+            if let Some((segment, offset)) = layout.as_ref().and_then(|layout| layout.locate(origin_offset)) {
+                place = format!(" [{}+{}]", segment.path.display(), offset);
+            }
+            println!(
+                "  origin offset {} (block {} fs-block {}){}",
+                origin_offset,
+                origin_offset / cache_block_size,
+                origin_offset % cache_block_size / BLOCK_SIZE,
+                place
+            );
+        }
+        for &(number, offset) in &cached[i] {
+            println!(
+                "  {} cache block {} fs-block {}",
+                options.cache_devices[number].display(),
+                offset / cache_block_size,
+                offset % cache_block_size / BLOCK_SIZE
+            );
+        }
+        if !options.cache_devices.is_empty() && cached[i].is_empty() {
+            println!("  not on the cache device");
+        }
+        found += usize::from(!origin[i].is_empty() || !cached[i].is_empty());
+    }
+    eprintln!("{} of {} looked up fs-blocks found somewhere", found, queries.len());
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::Mutex;
+
+/// LUKS1 and LUKS2 headers both start with it.
+pub const MAGIC: &[u8] = b"LUKS\xba\xbe";
+
+/// How to unlock the LUKS volumes among the inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    /// They are read as they are, ciphertext and all.
+    None,
+    Keyfile(PathBuf),
+    /// cryptsetup asks for it on the terminal, once per volume.
+    Passphrase,
+}
+
+struct State {
+    key: Key,
+    /// Each unlocked volume and its device-mapper name.
+    unlocked: Vec<(PathBuf, String)>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    key: Key::None,
+    unlocked: Vec::new(),
+});
+
+pub fn set_key(key: Key) {
+    STATE.lock().unwrap().key = key;
+}
+
+pub fn is_luks(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && magic == MAGIC
+}
+
+/// What to read for `path`: the decrypted view of a LUKS volume, read-only, once a key is given; `path` itself
+/// otherwise. cryptsetup sets up the loop device an image file needs.
+pub fn unlocked(path: &Path) -> io::Result<PathBuf> {
+    if !is_luks(path) {
+        return Ok(path.to_path_buf());
+    }
+    let mut state = STATE.lock().unwrap();
+    if let Some((_, name)) = state.unlocked.iter().find(|(volume, _)| volume == path) {
+        return Ok(Path::new("/dev/mapper").join(name));
+    }
+    let key_args = match &state.key {
+        Key::None => {
+            eprintln!(
+                "warning: {} is a LUKS volume and is read encrypted; --luks-keyfile or --luks-passphrase decrypts it",
+                path.display()
+            );
+            return Ok(path.to_path_buf());
+        }
+        Key::Keyfile(keyfile) => vec!["--key-file".into(), keyfile.as_os_str().to_owned()],
+        Key::Passphrase => Vec::new(),
+    };
+    let name = format!("cache_guess-luks-{}-{}", process::id(), state.unlocked.len());
+    let status = Command::new("cryptsetup")
+        .args(["open", "--readonly", "--type", "luks"])
+        .args(key_args)
+        .arg(path)
+        .arg(&name)
+        .status()
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot run cryptsetup to unlock {}: {}", path.display(), e),
+            )
+        })?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("cryptsetup could not unlock {}", path.display()),
+        ));
+    }
+    let device = Path::new("/dev/mapper").join(&name);
+    eprintln!(
+        "{}: LUKS volume, reading it decrypted through {}",
+        path.display(),
+        device.display()
+    );
+    state.unlocked.push((path.to_path_buf(), name));
+    Ok(device)
+}
+
+/// Closes what `unlocked` opened, once nothing maps it any more.
+pub fn close_all() {
+    for (volume, name) in STATE.lock().unwrap().unlocked.drain(..) {
+        let closed = Command::new("cryptsetup")
+            .args(["close", &name])
+            .status()
+            .is_ok_and(|status| status.success());
+        if !closed {
+            eprintln!(
+                "warning: could not close /dev/mapper/{} for {}; cryptsetup close {} does",
+                name,
+                volume.display(),
+                name
+            );
+        }
+    }
+}
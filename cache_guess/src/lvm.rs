@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const SECTOR: u64 = 512;
+const LABEL_ID: &[u8; 8] = b"LABELONE";
+const LABEL_SCAN_SECTORS: u64 = 4;
+const MDA_MAGIC: &[u8; 16] = b" LVM2 x[5A%r0N*>";
+const MDA_HEADER_SIZE: u64 = 512;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("lvm: {}", msg))
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u64 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as u64
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+/// A value of the LVM2 text metadata format.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+    Section(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Section(entries) => entries.get(key),
+            _ => None,
+        }
+    }
+
+    fn int(&self, key: &str) -> Option<u64> {
+        match self.get(key)? {
+            Value::Int(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    fn str(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Value::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn sections(&self) -> impl Iterator<Item = (&String, &Value)> {
+        let entries = match self {
+            Value::Section(entries) => Some(entries),
+            _ => None,
+        };
+        entries
+            .into_iter()
+            .flatten()
+            .filter(|(_, value)| matches!(value, Value::Section(_)))
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_blank(&mut self) {
+        while let Some(&c) = self.text.get(self.pos) {
+            if c == b'#' {
+                while self.text.get(self.pos).is_some_and(|&c| c != b'\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_blank();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> io::Result<()> {
+        if self.peek() != Some(c) {
+            return Err(invalid(format!(
+                "expected '{}' at byte {} of the metadata",
+                c as char, self.pos
+            )));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn word(&mut self) -> io::Result<&str> {
+        self.skip_blank();
+        let start = self.pos;
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|&c| c.is_ascii_alphanumeric() || b"_.-+".contains(&c))
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(invalid(format!("expected a name at byte {} of the metadata", start)));
+        }
+        Ok(std::str::from_utf8(&self.text[start..self.pos]).unwrap())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect(b'"')?;
+        let mut value = Vec::new();
+        loop {
+            match self.text.get(self.pos) {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    value.extend(self.text.get(self.pos));
+                }
+                Some(&c) => value.push(c),
+                None => return Err(invalid("unterminated string in the metadata".to_string())),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(String::from_utf8_lossy(&value).into_owned())
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        match self.peek() {
+            Some(b'"') => Ok(Value::Str(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while self.peek() != Some(b']') {
+                    items.push(self.value()?);
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                    }
+                }
+                self.pos += 1;
+                Ok(Value::List(items))
+            }
+            _ => {
+                let word = self.word()?;
+                word.parse()
+                    .map(Value::Int)
+                    .map_err(|_| invalid(format!("bad value '{}' in the metadata", word)))
+            }
+        }
+    }
+
+    /// Parses `key = value` and `name { ... }` entries up to a closing brace or the end of the text.
+    fn section(&mut self) -> io::Result<Value> {
+        let mut entries = BTreeMap::new();
+        while !matches!(self.peek(), None | Some(b'}')) {
+            let key = self.word()?.to_string();
+            let value = if self.peek() == Some(b'{') {
+                self.pos += 1;
+                let value = self.section()?;
+                self.expect(b'}')?;
+                value
+            } else {
+                self.expect(b'=')?;
+                self.value()?
+            };
+            entries.insert(key, value);
+        }
+        Ok(Value::Section(entries))
+    }
+}
+
+pub fn parse_metadata(text: &str) -> io::Result<Value> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.section()?;
+    if parser.peek().is_some() {
+        return Err(invalid(format!(
+            "unexpected '}}' at byte {} of the metadata",
+            parser.pos
+        )));
+    }
+    Ok(value)
+}
+
+/// Formats a raw 32 character PV UUID the way the metadata writes it.
+fn format_uuid(raw: &[u8]) -> String {
+    let raw = String::from_utf8_lossy(raw);
+    let mut parts = Vec::new();
+    let mut rest = raw.as_ref();
+    for len in [6, 4, 4, 4, 4, 4, 6] {
+        let (part, tail) = rest.split_at(len.min(rest.len()));
+        parts.push(part);
+        rest = tail;
+    }
+    parts.join("-")
+}
+
+/// A physical volume: its label UUID and the current metadata text of its first metadata area.
+struct PhysicalVolume {
+    uuid: String,
+    metadata: Option<String>,
+}
+
+fn read_pv(path: &Path) -> io::Result<PhysicalVolume> {
+    let file = File::open(path)?;
+    let mut label = None;
+    for sector in 0..LABEL_SCAN_SECTORS {
+        let data = read_at(&file, sector * SECTOR, SECTOR as usize)?;
+        if &data[0..8] == LABEL_ID {
+            label = Some((sector, data));
+            break;
+        }
+    }
+    let Some((sector, data)) = label else {
+        return Err(invalid(format!("{} has no LVM2 label", path.display())));
+    };
+    let pv_header = le32(&data, 20) as usize;
+    if pv_header + 40 > data.len() {
+        return Err(invalid(format!("bad label in sector {} of {}", sector, path.display())));
+    }
+    let uuid = format_uuid(&data[pv_header..pv_header + 32]);
+
+    // Two zero-terminated lists of (offset, size): data areas, then metadata areas.
+    let mut areas = [Vec::new(), Vec::new()];
+    let mut at = pv_header + 40;
+    for list in &mut areas {
+        while at + 16 <= data.len() {
+            let (offset, size) = (le64(&data, at), le64(&data, at + 8));
+            at += 16;
+            if offset == 0 {
+                break;
+            }
+            list.push((offset, size));
+        }
+    }
+    let Some(&(mda_offset, mda_size)) = areas[1].first() else {
+        return Ok(PhysicalVolume { uuid, metadata: None });
+    };
+
+    let header = read_at(&file, mda_offset, MDA_HEADER_SIZE as usize)?;
+    if &header[4..20] != MDA_MAGIC {
+        return Err(invalid(format!("bad metadata area header in {}", path.display())));
+    }
+    let (offset, size) = (le64(&header, 40), le64(&header, 48));
+    if size == 0 {
+        return Ok(PhysicalVolume { uuid, metadata: None });
+    }
+    // The metadata is a circular buffer behind the header and may wrap around.
+    let first = size.min(mda_size - offset);
+    let mut text = read_at(&file, mda_offset + offset, first as usize)?;
+    if first < size {
+        text.extend(read_at(&file, mda_offset + MDA_HEADER_SIZE, (size - first) as usize)?);
+    }
+    let text = String::from_utf8_lossy(&text).trim_end_matches('\0').to_string();
+    Ok(PhysicalVolume {
+        uuid,
+        metadata: Some(text),
+    })
+}
+
+/// A contiguous piece of an LV on one PV, in bytes.
+#[derive(Debug)]
+pub struct Piece {
+    pub logical_offset: u64,
+    pub device: PathBuf,
+    pub offset: u64,
+    pub size: u64,
+}
+
+struct VolumeGroup<'a> {
+    name: &'a str,
+    root: &'a Value,
+    extent_size: u64,
+    devices: BTreeMap<&'a str, (PathBuf, u64)>,
+}
+
+impl VolumeGroup<'_> {
+    fn lv(&self, name: &str) -> io::Result<&Value> {
+        self.root
+            .get("logical_volumes")
+            .and_then(|lvs| lvs.get(name))
+            .ok_or_else(|| invalid(format!("{}/{} is not in the metadata", self.name, name)))
+    }
+
+    fn segments<'b>(&self, lv: &'b Value) -> impl Iterator<Item = &'b Value> {
+        lv.sections().map(|(_, segment)| segment)
+    }
+
+    /// Resolves a linear (or single-stripe) LV to its physical pieces.
+    fn pieces(&self, name: &str) -> io::Result<Vec<Piece>> {
+        let lv = self.lv(name)?;
+        let mut pieces = Vec::new();
+        for segment in self.segments(lv) {
+            let field = |key| {
+                segment
+                    .int(key)
+                    .ok_or_else(|| invalid(format!("{}/{}: segment without {}", self.name, name, key)))
+            };
+            let kind = segment.str("type").unwrap_or("");
+            if kind != "striped" || segment.int("stripe_count") != Some(1) {
+                return Err(invalid(format!(
+                    "{}/{}: {} segments are not supported, only linear ones",
+                    self.name, name, kind
+                )));
+            }
+            let (pv, pe) = match segment.get("stripes") {
+                Some(Value::List(stripes)) => match stripes.as_slice() {
+                    [Value::Str(pv), Value::Int(pe)] => (pv.as_str(), *pe as u64),
+                    _ => return Err(invalid(format!("{}/{}: bad stripes", self.name, name))),
+                },
+                _ => return Err(invalid(format!("{}/{}: segment without stripes", self.name, name))),
+            };
+            let (device, pe_start) = self
+                .devices
+                .get(pv)
+                .ok_or_else(|| invalid(format!("{}/{}: {} was not given", self.name, name, pv)))?;
+            let sector = |extents: u64| extents * self.extent_size * SECTOR;
+            pieces.push(Piece {
+                logical_offset: sector(field("start_extent")?),
+                device: device.clone(),
+                offset: pe_start * SECTOR + sector(pe),
+                size: sector(field("extent_count")?),
+            });
+        }
+        Ok(pieces)
+    }
+}
+
+/// An lvmcache volume and the physical location of its parts.
+#[derive(Debug)]
+pub struct CacheVolume {
+    pub name: String,
+    /// In sectors, as `--cache-block-size` takes it.
+    pub chunk_size: u64,
+    pub mode: String,
+    pub origin: Vec<Piece>,
+    pub data: Vec<Piece>,
+    pub metadata: Vec<Piece>,
+}
+
+fn cut(pieces: &[Piece], start: u64, len: u64) -> Vec<Piece> {
+    pieces
+        .iter()
+        .filter(|piece| piece.logical_offset < start + len && start < piece.logical_offset + piece.size)
+        .map(|piece| {
+            let skip = start.saturating_sub(piece.logical_offset);
+            let end = (start + len).min(piece.logical_offset + piece.size);
+            Piece {
+                logical_offset: piece.logical_offset + skip - start,
+                offset: piece.offset + skip,
+                size: end - piece.logical_offset - skip,
+                device: piece.device.clone(),
+            }
+        })
+        .collect()
+}
+
+fn cache_volume(vg: &VolumeGroup, name: &str, segment: &Value) -> io::Result<CacheVolume> {
+    let missing = |key: &str| invalid(format!("{}/{}: cache segment without {}", vg.name, name, key));
+    let pool_name = segment.str("cache_pool").ok_or_else(|| missing("cache_pool"))?;
+    let origin = vg.pieces(segment.str("origin").ok_or_else(|| missing("origin"))?)?;
+    let pool = vg.lv(pool_name)?;
+    let pool_segment = vg.segments(pool).next().ok_or_else(|| missing("a pool segment"))?;
+
+    if pool_segment.str("type") == Some("cache-pool") {
+        let sub_lv = |key: &str| {
+            pool_segment
+                .str(key)
+                .ok_or_else(|| invalid(format!("{}/{}: cache pool without {}", vg.name, pool_name, key)))
+        };
+        return Ok(CacheVolume {
+            name: format!("{}/{}", vg.name, name),
+            chunk_size: pool_segment.int("chunk_size").ok_or_else(|| missing("chunk_size"))?,
+            mode: pool_segment.str("cache_mode").unwrap_or("unknown").to_string(),
+            origin,
+            data: vg.pieces(sub_lv("data")?)?,
+            metadata: vg.pieces(sub_lv("metadata")?)?,
+        });
+    }
+
+    // A cachevol holds both metadata and data; the cache segment says where, in sectors.
+    let cachevol = vg.pieces(pool_name)?;
+    let range = |key: &str| -> io::Result<(u64, u64)> {
+        let start = segment.int(&format!("{}_start", key)).ok_or_else(|| missing(key))?;
+        let len = segment.int(&format!("{}_len", key)).ok_or_else(|| missing(key))?;
+        Ok((start * SECTOR, len * SECTOR))
+    };
+    let (data_start, data_len) = range("data")?;
+    let (metadata_start, metadata_len) = range("metadata")?;
+    Ok(CacheVolume {
+        name: format!("{}/{}", vg.name, name),
+        chunk_size: segment.int("chunk_size").ok_or_else(|| missing("chunk_size"))?,
+        mode: segment.str("cache_mode").unwrap_or("unknown").to_string(),
+        origin,
+        data: cut(&cachevol, data_start, data_len),
+        metadata: cut(&cachevol, metadata_start, metadata_len),
+    })
+}
+
+/// Reads the metadata from the given PVs and finds every cache LV in it.
+pub fn discover(paths: &[&Path]) -> io::Result<Vec<CacheVolume>> {
+    let mut by_uuid = BTreeMap::new();
+    let mut metadata = None;
+    for path in paths {
+        let pv = read_pv(path)?;
+        eprintln!("{}: PV {}", path.display(), pv.uuid);
+        by_uuid.insert(pv.uuid, path.to_path_buf());
+        metadata = metadata.or(pv.metadata);
+    }
+    let Some(metadata) = metadata else {
+        return Err(invalid("none of the PVs carries metadata".to_string()));
+    };
+    let config = parse_metadata(&metadata)?;
+
+    let mut volumes = Vec::new();
+    for (vg_name, root) in config.sections() {
+        let mut devices = BTreeMap::new();
+        for (pv_name, pv) in root
+            .get("physical_volumes")
+            .map(|pvs| pvs.sections())
+            .into_iter()
+            .flatten()
+        {
+            let path = pv.str("id").and_then(|id| by_uuid.get(id));
+            match (path, pv.int("pe_start")) {
+                (Some(path), Some(pe_start)) => {
+                    devices.insert(pv_name.as_str(), (path.clone(), pe_start));
+                }
+                _ => eprintln!(
+                    "{}/{}: not among the given devices (was {})",
+                    vg_name,
+                    pv_name,
+                    pv.str("device").unwrap_or("unknown")
+                ),
+            }
+        }
+        let vg = VolumeGroup {
+            name: vg_name,
+            root,
+            extent_size: root
+                .int("extent_size")
+                .ok_or_else(|| invalid(format!("{}: no extent_size", vg_name)))?,
+            devices,
+        };
+        for (lv_name, lv) in root
+            .get("logical_volumes")
+            .map(|lvs| lvs.sections())
+            .into_iter()
+            .flatten()
+        {
+            for segment in vg.segments(lv).filter(|segment| segment.str("type") == Some("cache")) {
+                volumes.push(cache_volume(&vg, lv_name, segment)?);
+            }
+        }
+    }
+    Ok(volumes)
+}
+
+fn print_pieces(role: &str, pieces: &[Piece]) {
+    for piece in pieces {
+        println!(
+            "  {:<8} {}+{} ({} bytes at logical {})",
+            role,
+            piece.device.display(),
+            piece.offset,
+            piece.size,
+            piece.logical_offset
+        );
+    }
+}
+
+pub fn print(volumes: &[CacheVolume]) {
+    if volumes.is_empty() {
+        println!("no cache volumes found");
+    }
+    for volume in volumes {
+        println!(
+            "{}: {} cache, {} sector chunks",
+            volume.name, volume.mode, volume.chunk_size
+        );
+        print_pieces("origin", &volume.origin);
+        print_pieces("data", &volume.data);
+        print_pieces("metadata", &volume.metadata);
+        let loops = volume
+            .origin
+            .iter()
+            .enumerate()
+            .map(|(i, piece)| (format!("ORIGIN{}", i), piece))
+            .chain(
+                volume
+                    .data
+                    .iter()
+                    .enumerate()
+                    .map(|(i, piece)| (format!("CACHE_DATA{}", i), piece)),
+            );
+        for (variable, piece) in loops {
+            println!(
+                "  {}=$(losetup -r -f --show --offset {} --sizelimit {} {})",
+                variable,
+                piece.offset,
+                piece.size,
+                piece.device.display()
+            );
+        }
+        let origin: Vec<String> = volume
+            .origin
+            .iter()
+            .enumerate()
+            .map(|(i, piece)| format!("$ORIGIN{}@{}", i, piece.logical_offset))
+            .collect();
+        println!("  cache_guess collect <index> {}", origin.join(" "));
+        if volume.data.len() == 1 {
+            println!(
+                "  cache_guess find --cache-block-size {} <index> $CACHE_DATA0",
+                volume.chunk_size
+            );
+        } else {
+            println!(
+                "  # the cache data is split over {} pieces, concatenate them (e.g. with dmsetup) before find",
+                volume.data.len()
+            );
+        }
+    }
+}
@@ -1,208 +1,2476 @@
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io;
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use clap::{App, Arg, SubCommand};
-use memmap2::{MmapMut, MmapOptions};
+use apply::ApplyOptions;
+use carve::CarveOptions;
+use check::CheckOptions;
+use clap::{App, Arg, ArgMatches, Shell, SubCommand};
+use collect::CollectOptions;
+use conflict::Conflict;
+use convert::ConvertOptions;
+use coverage::CoverageOptions;
+use estimate::EstimateOptions;
+use extract::ExtractOptions;
+use find::{FindOptions, Job};
+use gc::GcOptions;
+use inspect::InspectOptions;
+use lookup::LookupOptions;
+use mapped::{device_len, logical_sector_size, physical_sector_size, MappedFile, WritableMappedFile};
+use merge::MergeOptions;
+use preflight::PreflightOptions;
+use reverse::ReverseOptions;
+use selection::Selection;
 use sha1::{Digest, Sha1};
+use shift::ShiftOptions;
+use snapshot::LiveSnapshots;
+use superblock::Superblock;
+use verify::VerifyOptions;
+use writeback::PlanOptions;
+use writecache::WritecacheOptions;
+
+mod access;
+mod apply;
+mod block_errors;
+mod cache_digests;
+mod cache_xml;
+mod carve;
+mod check;
+mod collect;
+mod compressed;
+mod config;
+mod confirm;
+mod conflict;
+mod convert;
+mod coverage;
+mod critical;
+mod db;
+mod ddrescue;
+mod direct;
+mod estimate;
+mod export;
+mod ext4;
+mod extract;
+mod find;
+mod gc;
+mod hash;
+mod holes;
+mod identity;
+mod index;
+mod inspect;
+mod interrupt;
+mod inuse;
+mod ioprio;
+mod layout;
+mod lookup;
+mod luks;
+mod lvm;
+mod mapped;
+mod mapping;
+mod memory;
+mod merge;
+mod metrics;
+mod mirror;
+mod output;
+mod partition;
+mod preflight;
+mod pretty;
+mod provenance;
+mod readahead;
+mod reconcile;
+mod remote;
+mod report;
+mod reread;
+mod result;
+mod reverse;
+mod scoring;
+mod selection;
+mod serve;
+mod shift;
+mod snapshot;
+mod split;
+mod superblock;
+mod throttle;
+mod unmatched;
+mod verify;
+mod visualize;
+mod writeback;
+mod writecache;
+mod xfs;
 
 const HASH_BYTES: usize = 20;
 const BLOCK_SIZE: usize = 8 * 1024;
 
-#[derive(Debug)]
-struct MappedFile {
-    mmap: MmapMut,
-    size: usize,
+fn hash_block(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize().to_vec()
 }
 
-impl MappedFile {
-    fn open(path: &Path, write: bool) -> io::Result<Self> {
-        let file = if write {
-            OpenOptions::new().read(true).write(false/*coderobe: hehe*/).open(path)?
-        } else {
-            OpenOptions::new().read(true).write(false).open(path)?
-        };
-        let size = file.metadata()?.len() as usize;
-        let mmap = unsafe { MmapOptions::new().map_mut(&file)? };
-        Ok(Self { mmap, size })
-    }
+/// When the running operation first reported its status, and how far it was then (it may have resumed midway).
+static STARTED: Mutex<Option<(Instant, usize)>> = Mutex::new(None);
 
-    fn create(path: &Path, size: usize) -> io::Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
-        file.set_len(size as u64)?;
-        let mmap = unsafe { MmapOptions::new().map_mut(&file)? };
-        Ok(Self { mmap, size })
-    }
+/// How status lines are reported: overwriting each other on stderr, as JSON events for wrapper scripts, or not at all.
+enum Progress {
+    Human,
+    Json {
+        /// The subcommand, reported as the phase of every event.
+        phase: String,
+        /// Stderr when `None`.
+        fd: Option<File>,
+        last: Option<Instant>,
+    },
+    Quiet,
+}
 
-    fn size(&self) -> usize {
-        self.size
-    }
+static PROGRESS: Mutex<Progress> = Mutex::new(Progress::Human);
 
-    fn slice(&self, offset: usize, len: usize) -> &[u8] {
-        &self.mmap[offset..offset + len]
-    }
+/// Between JSON progress events, so that a fast loop does not flood the reader.
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+fn write_progress_event(fd: &mut Option<File>, event: String) {
+    // Progress is best-effort: a reader that went away must not fail the operation.
+    let _ = match fd {
+        Some(file) => writeln!(file, "{}", event),
+        None => writeln!(io::stderr(), "{}", event),
+    };
+}
+
+fn json_rate(rate: Option<f64>) -> String {
+    rate.map_or("null".to_string(), |rate| format!("{:.3}", rate))
+}
 
-    fn slice_mut(&mut self, offset: usize, len: usize) -> &mut [u8] {
-        &mut self.mmap[offset..offset + len]
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let clock = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    match seconds / 86400 {
+        0 => clock,
+        days => format!("{}d {}", days, clock),
     }
 }
 
-fn hash_block(data: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha1::new();
-    hasher.update(data);
-    hasher.finalize().to_vec()
+fn format_rate(per_second: f64, unit: &str) -> String {
+    match unit {
+        "bytes" => format!("{:.1} MB/s", per_second / 1e6),
+        unit => format!("{:.1} {}/s", per_second, unit),
+    }
 }
 
 fn log_status(current: usize, total: usize, unit: &str, newline: bool) {
     let percentage = 100.0 * (current as f64 / total as f64);
+    let (start, first) = *STARTED.lock().unwrap().get_or_insert_with(|| (Instant::now(), current));
+    let elapsed = start.elapsed().as_secs_f64();
+    let rate = (current - first.min(current)) as f64 / elapsed;
+    match &mut *PROGRESS.lock().unwrap() {
+        Progress::Human => {}
+        Progress::Quiet => return,
+        Progress::Json { phase, fd, last } => {
+            if last.is_some_and(|last| last.elapsed() < JSON_PROGRESS_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+            let rate = (elapsed >= 1.0 && rate > 0.0).then_some(rate);
+            let eta = rate.map_or("null".to_string(), |rate| {
+                (((total - current.min(total)) as f64 / rate).round() as u64).to_string()
+            });
+            write_progress_event(
+                fd,
+                format!(
+                    "{{\"event\":\"progress\",\"phase\":\"{}\",\"unit\":\"{}\",\"done\":{},\"total\":{},\"percent\":{:.1},\"rate\":{},\"eta_seconds\":{}}}",
+                    phase,
+                    unit,
+                    current,
+                    total,
+                    percentage,
+                    json_rate(rate),
+                    eta
+                ),
+            );
+            return;
+        }
+    }
+    let speed = if elapsed >= 1.0 && rate > 0.0 {
+        format!(
+            ", {}, ETA {}",
+            format_rate(rate, unit),
+            format_duration(Duration::from_secs_f64((total - current.min(total)) as f64 / rate))
+        )
+    } else {
+        String::new()
+    };
     eprint!(
-        "{:5.1} % - {:} of {:} {}{}",
+        "{:5.1} % - {:} of {:} {}{}{}",
         percentage,
         current,
         total,
         unit,
+        speed,
         if newline { "\n" } else { "\r" }
     );
 }
 
+/// Prints the final status line with the wall-clock time and throughput, and starts the clock over.
 fn log_complete(total: usize, unit: &str) {
-    eprint!("100.0 % - {:} of {:} {}\r", total, total, unit);
+    let started = STARTED.lock().unwrap().take();
+    metrics::phase(unit, total, started.map(|(start, _)| start.elapsed()));
+    match &mut *PROGRESS.lock().unwrap() {
+        Progress::Human => {}
+        Progress::Quiet => return,
+        Progress::Json { phase, fd, last } => {
+            *last = None;
+            let elapsed = started.map(|(start, first)| (start.elapsed().as_secs_f64(), first));
+            let rate = elapsed.map(|(elapsed, first)| (total - first.min(total)) as f64 / elapsed.max(1e-3));
+            write_progress_event(
+                fd,
+                format!(
+                    "{{\"event\":\"complete\",\"phase\":\"{}\",\"unit\":\"{}\",\"done\":{},\"total\":{},\"percent\":100.0,\"rate\":{},\"elapsed_seconds\":{}}}",
+                    phase,
+                    unit,
+                    total,
+                    total,
+                    json_rate(rate),
+                    json_rate(elapsed.map(|(elapsed, _)| elapsed))
+                ),
+            );
+            return;
+        }
+    }
+    let timing = match started {
+        Some((start, first)) => {
+            let elapsed = start.elapsed();
+            format!(
+                " in {}, {}",
+                format_duration(elapsed),
+                format_rate(
+                    (total - first.min(total)) as f64 / elapsed.as_secs_f64().max(1e-3),
+                    unit
+                )
+            )
+        }
+        None => String::new(),
+    };
+    // Padded to clear the ETA of the last status line.
+    eprint!("100.0 % - {:} of {:} {}{:<30}\r", total, total, unit, timing);
 }
 
-fn collect(index_path: &Path, device_path: &Path) -> io::Result<()> {
-    let device = MappedFile::open(device_path, false)?;
-    let device_size = device.size();
-    let block_count = (device_size + BLOCK_SIZE - 1) / BLOCK_SIZE;
-    let index_block_count = (block_count + BLOCK_SIZE - 1) / BLOCK_SIZE;
+fn open_device(path: &Path, partition: Option<usize>) -> io::Result<MappedFile> {
+    if remote::is_remote(path) {
+        return select_partition(path, remote::map(path, false)?, partition);
+    }
+    select_partition(path, MappedFile::open(&luks::unlocked(path)?)?, partition)
+}
 
-    let mut index_file = MappedFile::create(index_path, index_block_count * BLOCK_SIZE)?;
+/// The same for find, which also reads seekable zstd images, decompressed into memory.
+fn open_image(path: &Path, partition: Option<usize>, force: bool) -> io::Result<MappedFile> {
+    if remote::is_remote(path) {
+        return select_partition(path, remote::map(path, force)?, partition);
+    }
+    match compressed::Format::detect(path) {
+        Some(format) => select_partition(path, compressed::map_seekable(path, format, force)?, partition),
+        None => open_device(path, partition),
+    }
+}
 
-    let mut index_block = 0;
-    let mut index_entry = 0;
+fn select_partition(path: &Path, device: MappedFile, partition: Option<usize>) -> io::Result<MappedFile> {
+    let Some(number) = partition else {
+        let device = superblock::data_area(path, device)?;
+        provenance::record_device(path, None, &device);
+        return Ok(device);
+    };
+    let partitions = partition::read_table(&device)?;
+    let Some(partition) = partitions.iter().find(|p| p.number == number) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no partition {}", path.display(), number),
+        ));
+    };
+    eprintln!(
+        "{}: using partition {} at {} ({} bytes)",
+        path.display(),
+        number,
+        partition.start,
+        partition.size
+    );
+    let physical = physical_sector_size(path);
+    if !partition.start.is_multiple_of(physical) {
+        eprintln!(
+            "warning: partition {} of {} starts at {}, not on a {} byte physical sector boundary",
+            number,
+            path.display(),
+            partition.start,
+            physical
+        );
+    }
+    let device = device.window(partition.start, partition.size)?;
+    if device.size() >= luks::MAGIC.len() && device.slice(0, luks::MAGIC.len()) == luks::MAGIC {
+        eprintln!(
+            "warning: partition {} of {} is a LUKS volume and is read encrypted; name its own device to decrypt it",
+            number,
+            path.display()
+        );
+    }
+    let device = superblock::data_area(path, device)?;
+    provenance::record_device(path, Some(number), &device);
+    Ok(device)
+}
 
-    for offset in (0..device_size).step_by(BLOCK_SIZE) {
-        if offset % (BLOCK_SIZE * 10240) == 0 {
-            log_status(offset, device_size, "bytes", false);
+fn list_partitions(device_path: &Path) -> io::Result<()> {
+    let device = MappedFile::open(device_path)?;
+    let partitions = partition::read_table(&device)?;
+    println!("# number start size type name");
+    for p in partitions {
+        println!("{} {} {} {} {}", p.number, p.start, p.size, p.kind, p.name);
+    }
+    Ok(())
+}
+
+fn read_free_space(device: &MappedFile, fs: Option<&str>) -> io::Result<Option<xfs::FreeSpace>> {
+    match fs {
+        Some("xfs") => {
+            let free_space = xfs::FreeSpace::read(device)?;
+            eprintln!(
+                "xfs: {} bytes free in {} extents",
+                free_space.free_bytes(),
+                free_space.extent_count()
+            );
+            Ok(Some(free_space))
         }
+        _ => Ok(None),
+    }
+}
 
-        let digest = hash_block(&device.slice(offset, BLOCK_SIZE));
-        let index_offset = index_block * BLOCK_SIZE + index_entry * HASH_BYTES;
+fn is_number(value: String) -> Result<(), String> {
+    value
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a number", value))
+}
 
-        index_file.slice_mut(index_offset, HASH_BYTES).copy_from_slice(&digest);
-        index_entry += 1;
+/// `is_number` for counts and intervals that zero makes no sense of, such as a busy loop for `--watch 0`.
+fn is_positive(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive number", value)),
+    }
+}
 
-        if index_entry >= BLOCK_SIZE {
-            index_block += 1;
-            index_entry = 0;
+/// A byte count with an optional binary K, M, G or T suffix in either case, like `256K` or `8g`.
+fn parse_size(value: &str) -> Result<usize, String> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    // Suffixes are taken in either case, as people write 8g as often as 8G.
+    let suffix = &value[digits.len()..];
+    let shift = match suffix.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kib" => 10,
+        "m" | "mib" => 20,
+        "g" | "gib" => 30,
+        "t" | "tib" => 40,
+        _ => {
+            return Err(format!(
+                "'{}' has an unknown size suffix '{}', use B, K, M, G or T, or KiB to TiB, in either case",
+                value, suffix
+            ))
         }
-    }
-    log_complete(device_size, "bytes");
-    Ok(())
+    };
+    let number = digits
+        .parse::<usize>()
+        .map_err(|_| format!("'{}' is not a size like 4096, 256K or 8G", value))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("'{}' is too large", value))
 }
 
-fn find(index_path: &Path, cache_device_path: &Path, cache_block_size: usize) -> io::Result<()> {
-    let index_file = MappedFile::open(index_path, false)?;
-    let device_size = index_file.size();
-    let mut index = HashMap::new();
+fn is_size(value: String) -> Result<(), String> {
+    parse_size(&value).map(|_| ())
+}
 
-    for block_offset in (0..device_size).step_by(BLOCK_SIZE) {
-        let block_bytes = &index_file.slice(block_offset, BLOCK_SIZE);
-        for entry in (0..BLOCK_SIZE).step_by(HASH_BYTES) {
-            let digest = &block_bytes[entry..entry + HASH_BYTES];
-            let offset = block_offset + entry;
-            index.entry(digest.to_vec()).or_insert_with(Vec::new).push(offset);
-        }
+/// `is_size` for sizes of something to read or write, which cannot be empty.
+fn is_positive_size(value: String) -> Result<(), String> {
+    match parse_size(&value)? {
+        0 => Err(format!("'{}' is not a positive size", value)),
+        _ => Ok(()),
     }
-    log_complete(device_size, "bytes");
+}
 
-    let cache_device = MappedFile::open(cache_device_path, false)?;
-    let cache_block_size = 512 * cache_block_size;
-    let cache_total_blocks = cache_device.size() / cache_block_size;
+fn is_rate(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive rate", value)),
+    }
+}
 
-    for cache_block in 0..cache_total_blocks {
-        log_status(cache_block, cache_total_blocks, "blocks", true);
-        let mut matches = HashMap::new();
-        let mut fake_matches = 0;
+fn bwlimit_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("bwlimit")
+        .long("bwlimit")
+        .takes_value(true)
+        .validator(is_rate)
+        .help("Limit device reads to this many MB/s")
+}
 
-        for fs_block in 0..(cache_block_size / BLOCK_SIZE) {
-            let offset = cache_block * cache_block_size + fs_block * BLOCK_SIZE;
-            let digest = hash_block(&cache_device.slice(offset, BLOCK_SIZE));
+fn conflict_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("conflict")
+        .long("conflict")
+        .default_value("best")
+        .possible_values(&["best", "skip", "fail", "interactive"])
+        .help("When cache blocks share an origin block or one is listed twice: keep the entry the metadata or its neighbors support, else the most confident one, skip them all, fail, or ask; copies of the same data never conflict")
+}
 
-            if let Some(matches_vec) = index.get(&digest) {
-                for match_offset in matches_vec {
-                    let origin_fs_block = match_offset / BLOCK_SIZE;
-                    let origin_cache_block = match_offset / cache_block_size;
-                    let origin_local_fs_block = origin_fs_block % (cache_block_size / BLOCK_SIZE);
+fn duplicate_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("expected-mapping")
+            .long("expected-mapping")
+            .takes_value(true)
+            .help("cache_dump XML; where entries claim the same block, the one it agrees with is kept"),
+        Arg::with_name("duplicates")
+            .long("duplicates")
+            .takes_value(true)
+            .help("List the entries that claimed the same block, which one was kept and why, and the mapped cache blocks with identical data in this file"),
+    ]
+}
 
-                    if origin_local_fs_block != fs_block {
-                        fake_matches += 1;
-                        continue;
-                    }
-                    *matches.entry(origin_cache_block).or_insert(0) += 1;
-                }
-            }
-        }
+fn ddrescue_map_arg(name: &str) -> Arg<'_, '_> {
+    Arg::with_name(name).long(name).takes_value(true)
+}
 
-        let mut first = true;
-        let mut match_vec: Vec<_> = matches.iter().collect();
-        match_vec.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
-        for (origin_cache_block, count) in match_vec {
-            println!(
-                "{}{} -> {} ({:.3}% match)",
-                if first { "" } else { "#" },
-                cache_block,
-                origin_cache_block,
-                *count as f64 / (cache_block_size / BLOCK_SIZE) as f64 * 100.0
-            );
-            first = false;
-        }
+fn shard_size_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("shard-size")
+        .long("shard-size")
+        .takes_value(true)
+        .validator(|value| match parse_size(&value) {
+            Ok(n) if n > 0 && n.is_multiple_of(BLOCK_SIZE) => Ok(()),
+            Err(e) => Err(e),
+            _ => Err(format!("the shard size must be a positive multiple of {} bytes", BLOCK_SIZE)),
+        })
+        .help("Split the index into one file per this many origin bytes; find searches a shard at a time when the whole index does not fit in memory")
+}
 
-        if fake_matches != 0 {
-            println!("#{} fake matches", fake_matches);
+fn force_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("force")
+        .long("force")
+        .help("Proceed even if a device is mounted or held by another device")
+}
+
+fn sector_size_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("sector-size")
+        .long("sector-size")
+        .default_value("auto")
+        .validator(|value| match value.parse::<usize>() {
+            _ if value == "auto" => Ok(()),
+            Ok(n) if n >= 512 && n.is_power_of_two() => Ok(()),
+            _ => Err(format!("'{}' is not auto or a power of two of at least 512", value)),
+        })
+        .help("Bytes per sector for sector-based sizes; auto asks the device and falls back to 512")
+}
+
+/// `--sector-size`, or the sector size of `device` when it is auto.
+fn sector_size(sub_m: &ArgMatches, device: Option<&str>) -> usize {
+    match sub_m.value_of("sector-size") {
+        Some("auto") | None => device.map_or(512, |device| logical_sector_size(Path::new(device))),
+        Some(size) => size.parse().unwrap(),
+    }
+}
+
+/// A size argument in bytes: plain numbers count sectors, values with a suffix are bytes.
+fn sectors_arg(sub_m: &ArgMatches, name: &str, device: Option<&str>) -> usize {
+    let value = sub_m.value_of(name).unwrap();
+    match value.parse::<usize>() {
+        Ok(sectors) => sector_size(sub_m, device) * sectors,
+        Err(_) => parse_size(value).unwrap(),
+    }
+}
+
+/// Refuses a block size that splits logical sectors of one of the `devices`, which cannot be read or written on its
+/// own, and warns about one that splits physical sectors, which is slow and points at a wrong size or offset.
+fn check_alignment(what: &str, size: usize, devices: &[&str]) -> io::Result<()> {
+    for device in devices {
+        let logical = logical_sector_size(Path::new(device));
+        if !size.is_multiple_of(logical) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} of {} bytes is not a multiple of the {} byte logical sectors of {}",
+                    what, size, logical, device
+                ),
+            ));
+        }
+        let physical = physical_sector_size(Path::new(device));
+        if !size.is_multiple_of(physical) {
+            eprintln!(
+                "warning: {} of {} bytes is not a multiple of the {} byte physical sectors of {}",
+                what, size, physical, device
+            );
         }
     }
-    log_complete(cache_total_blocks, "blocks");
     Ok(())
 }
 
+fn cache_block_size_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("cache-block-size")
+        .long("cache-block-size")
+        .default_value("512")
+        .validator(is_positive_size)
+        .help("In sectors (see --sector-size), or bytes with a K/M/G suffix")
+}
+
+/// `--cache-block-size` in bytes, counting sectors of the first of the `devices`, and checked against all of them.
+fn cache_block_size(sub_m: &ArgMatches, devices: &[Option<&str>]) -> io::Result<usize> {
+    let devices: Vec<&str> = devices.iter().flatten().copied().collect();
+    let size = sectors_arg(sub_m, "cache-block-size", devices.first().copied());
+    check_alignment("--cache-block-size", size, &devices)?;
+    Ok(size)
+}
+
+fn cache_mode_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("cache-mode")
+        .long("cache-mode")
+        .default_value("writeback")
+        .possible_values(&["writeback", "writethrough"])
+        .help(
+            "A writethrough cache never holds newer data than the origin, so nothing is planned for writing back and \
+             blocks that differ are reported as corruption instead",
+        )
+}
+
+fn idle_io_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("idle-io")
+        .long("idle-io")
+        .help("Read in the idle I/O class, only when no other process wants the disk")
+}
+
+fn luks_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("luks-keyfile")
+            .long("luks-keyfile")
+            .takes_value(true)
+            .help("Decrypt LUKS1/LUKS2 inputs read-only with cryptsetup, unlocking them with this key file"),
+        Arg::with_name("luks-passphrase")
+            .long("luks-passphrase")
+            .conflicts_with("luks-keyfile")
+            .help("Decrypt LUKS1/LUKS2 inputs read-only with cryptsetup, which asks for each one's passphrase"),
+    ]
+}
+
+fn live_snapshot_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("live-snapshot")
+        .long("live-snapshot")
+        .takes_value(true)
+        .value_name("DIR,SIZE")
+        .validator(|value| match value.split_once(',') {
+            Some((_, size)) => is_positive_size(size.to_string()),
+            None => Err(format!("'{}' is not DIR,SIZE", value)),
+        })
+        .help(
+            "Read devices that are still in use through temporary read-only dm snapshots, each with a \
+             copy-on-write store of SIZE in DIR, for a consistent view; they are removed when the run ends",
+        )
+}
+
+fn live_snapshots(sub_m: &ArgMatches) -> LiveSnapshots {
+    LiveSnapshots::new(sub_m.value_of("live-snapshot").map(|value| {
+        let (dir, size) = value.split_once(',').unwrap();
+        (PathBuf::from(dir), parse_size(size).unwrap())
+    }))
+}
+
+fn priority_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::with_name("priority-ranges")
+            .long("priority-ranges")
+            .takes_value(true)
+            .help("File of critical origin byte ranges, start..end, to recover before everything else"),
+        Arg::with_name("priority-fs")
+            .long("priority-fs")
+            .takes_value(true)
+            .possible_values(&["xfs", "ext4"])
+            .help("Recover the origin filesystem's superblocks, group descriptors or AG headers and journal first"),
+        Arg::with_name("hot-first")
+            .long("hot-first")
+            .requires("expected-mapping")
+            .help(
+                "Recover the cache blocks the policy hints in --expected-mapping rate hottest first, after the critical \
+                 ones; smq hints tell which blocks were in most recent use",
+            ),
+    ]
+}
+
+fn partition_arg(name: &str) -> Arg<'_, '_> {
+    Arg::with_name(name).long(name).takes_value(true).validator(is_number)
+}
+
 fn main() -> io::Result<()> {
-    let matches = App::new("cache_guess")
+    let started = Instant::now();
+    let app = App::new("cache_guess")
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .help("json reports progress as one JSON event per line and at most one a second: phase, unit, done, total, percent, rate and eta_seconds, then a complete event"),
+        )
+        .arg(
+            Arg::with_name("progress-fd")
+                .long("progress-fd")
+                .global(true)
+                .takes_value(true)
+                .validator(is_number)
+                .help("Write progress as --progress json events to this open file descriptor instead of stderr"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .global(true)
+                .conflicts_with("progress-fd")
+                .help("Report no progress at all"),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .global(true)
+                .takes_value(true)
+                .value_name("FILE")
+                .help(
+                    "Write timings per phase, bytes read and written, hash throughput, index load time, lookup and \
+                     conflict counts and peak memory to FILE as JSON, for comparing runs",
+                ),
+        )
+        .arg(
+            Arg::with_name("error-report")
+                .long("error-report")
+                .global(true)
+                .takes_value(true)
+                .value_name("FILE")
+                .help(
+                    "Write the blocks skipped over read, write, checksum or format errors to FILE as JSON instead of \
+                     stderr; a run that skipped any exits with status 3",
+                ),
+        )
+        .arg(
+            Arg::with_name("skip-corrupt-index-blocks")
+                .long("skip-corrupt-index-blocks")
+                .global(true)
+                .help(
+                    "Leave out index blocks that fail their checksum, with their entries, and report them as block \
+                     errors, instead of stopping on the first",
+                ),
+        )
+        .arg(
+            Arg::with_name("data-offset")
+                .long("data-offset")
+                .global(true)
+                .takes_value(true)
+                .validator(is_size)
+                .help(
+                    "Where cache block 0 starts on the cache device, past the metadata kept on the same device; a \
+                     dm-cache superblock at its start tells otherwise. 0 reads the whole device as cache data",
+                ),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .global(true)
+                .takes_value(true)
+                .help("Session file of defaults for the subcommand's flags, see config init"),
+        )
         .subcommand(
             SubCommand::with_name("collect")
+                .about("Hash every block of the origin device into an index for find")
+                .after_help("EXAMPLES:\n    cache_guess collect origin.idx /dev/sdb\n    cache_guess collect --fs xfs --filter-hash xxh64 origin.idx origin.img\n    cache_guess collect --base-index old.idx --changed-ranges written.txt new.idx /dev/sdb\n    cache_guess collect --live-snapshot /var/tmp,4G origin.idx /dev/vg0/data\n    cache_guess collect origin.idx origin.img.xz\n    cache_guess collect origin.idx nbd://donor:10809/sdb\n    cache_guess collect origin.idx ssh://root@donor/dev/sdb\n    cache_guess collect --cache-device /dev/sdc origin.idx /dev/sdb")
                 .arg(Arg::with_name("index").required(true))
-                .arg(Arg::with_name("device").required(true)),
+                .arg(
+                    Arg::with_name("device")
+                        .required(true)
+                        .multiple(true)
+                        .help("Origin device; several devices form one logical origin, each as path[@byte-offset]; - reads a single origin from stdin, and nbd://host[:port][/export] or ssh://[user@]host/path one over the network"),
+                )
+                .arg(
+                    Arg::with_name("device-size")
+                        .long("device-size")
+                        .takes_value(true)
+                        .validator(is_positive_size)
+                        .help("Size of an origin read from stdin, or of a compressed image that does not record it; a remote origin is read no further than this"),
+                )
+                .arg(
+                    Arg::with_name("fs")
+                        .long("fs")
+                        .takes_value(true)
+                        .possible_values(&["xfs"])
+                        .help("Skip blocks the origin filesystem reports as free"),
+                )
+                .arg(partition_arg("partition").help("Only index this partition of the device(s)"))
+                .arg(
+                    Arg::with_name("digest-bytes")
+                        .long("digest-bytes")
+                        .default_value("20")
+                        .validator(|value| match value.parse::<usize>() {
+                            Ok(n) if (index::MIN_DIGEST_BYTES..=HASH_BYTES).contains(&n) => Ok(()),
+                            _ => Err(format!(
+                                "digest bytes must be between {} and {}",
+                                index::MIN_DIGEST_BYTES,
+                                HASH_BYTES
+                            )),
+                        })
+                        .help("Store truncated digests; find then needs --origin to confirm matches"),
+                )
+                .arg(
+                    Arg::with_name("sample-bytes")
+                        .long("sample-bytes")
+                        .takes_value(true)
+                        .validator(|value| match parse_size(&value) {
+                            Ok(n) if (1..=BLOCK_SIZE).contains(&n) => Ok(()),
+                            Err(e) => Err(e),
+                            _ => Err(format!("sample bytes must be between 1 and {}", BLOCK_SIZE)),
+                        })
+                        .help("Only hash the first N bytes of each block for a quick survey; find then gives provisional matches unless it has --origin"),
+                )
+                .arg(
+                    Arg::with_name("filter-hash")
+                        .long("filter-hash")
+                        .takes_value(true)
+                        .possible_values(&["xxh64"])
+                        .help("Also store a 64-bit filter hash per block; find keeps only those in memory and confirms hits against the stored digests"),
+                )
+                .arg(
+                    Arg::with_name("base-index")
+                        .long("base-index")
+                        .takes_value(true)
+                        .requires("changed-ranges")
+                        .help("Earlier index of the same origin to copy unchanged digests from"),
+                )
+                .arg(
+                    Arg::with_name("changed-ranges")
+                        .long("changed-ranges")
+                        .takes_value(true)
+                        .requires("base-index")
+                        .help("File of origin byte ranges written since the base index, one start..end per line"),
+                )
+                .arg(
+                    ddrescue_map_arg("ddrescue-map")
+                        .help("GNU ddrescue mapfile of the origin image; blocks it does not mark rescued are left out"),
+                )
+                .arg(
+                    Arg::with_name("sparse")
+                        .long("sparse")
+                        .help("Store explicit (offset, digest) records, so free space and unrescued blocks take no room in the index"),
+                )
+                .arg(
+                    Arg::with_name("cache-device")
+                        .long("cache-device")
+                        .takes_value(true)
+                        .value_name("DEV")
+                        .conflicts_with("direct")
+                        .help("Hash this cache device too, alongside the origin, into <index>.cache-digests; find then looks its fs-blocks up instead of reading and hashing them again"),
+                )
+                .arg(
+                    partition_arg("cache-partition")
+                        .requires("cache-device")
+                        .help("Partition of the --cache-device to hash"),
+                )
+                .arg(shard_size_arg())
+                .arg(
+                    Arg::with_name("direct")
+                        .long("direct")
+                        .conflicts_with_all(&["fs", "partition", "base-index", "ddrescue-map"])
+                        .help("Stream a single origin device with O_DIRECT instead of mapping it, leaving the page cache to other workloads"),
+                )
+                .arg(idle_io_arg())
+                .args(&luks_args())
+                .arg(live_snapshot_arg())
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about(
+                    "Check the inputs of a find run before it: devices, sizes, cache block size, index and memory, \
+                     and match a sample of cache blocks",
+                )
+                .after_help(
+                    "EXAMPLES:\n    cache_guess check --cache-block-size 128 --origin /dev/sdb origin.idx \
+                     /dev/nvme0n1p1\n    cache_guess check --metadata-device meta.img origin.idx cache.img",
+                )
+                .arg(Arg::with_name("index").required(true))
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(
+                    Arg::with_name("metadata-device")
+                        .long("metadata-device")
+                        .takes_value(true)
+                        .help("dm-cache metadata device; its superblock gives the cache block size and count"),
+                )
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(
+                    Arg::with_name("origin")
+                        .long("origin")
+                        .takes_value(true)
+                        .help("Origin device, checked against what the index recorded of it"),
+                )
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to use"))
+                .arg(
+                    Arg::with_name("samples")
+                        .long("samples")
+                        .default_value("256")
+                        .validator(is_positive)
+                        .help("Cache blocks spread over the device to match against the index"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("estimate")
+                .about(
+                    "Match a random sample of cache blocks against an index and extrapolate how much of the cache a \
+                     find run would recover",
+                )
+                .after_help(
+                    "EXAMPLES:\n    cache_guess estimate --cache-block-size 128 origin.idx /dev/nvme0n1p1\n    \
+                     cache_guess estimate --samples 5000 --seed 1 --expected-mapping dump.xml origin.idx cache.img",
+                )
+                .arg(Arg::with_name("index").required(true))
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(
+                    Arg::with_name("samples")
+                        .long("samples")
+                        .default_value("1000")
+                        .validator(is_positive)
+                        .help("Cache blocks to pick at random; the intervals narrow with the square root of this"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .validator(is_number)
+                        .help("Pick the same cache blocks as the run that printed this seed"),
+                )
+                .arg(
+                    Arg::with_name("expected-mapping")
+                        .long("expected-mapping")
+                        .takes_value(true)
+                        .help("cache_dump XML of the metadata, for how many cache blocks are dirty"),
+                )
+                .arg(force_arg()),
         )
         .subcommand(
             SubCommand::with_name("find")
+                .about("Match every cache block against an origin index and print the mapping it suggests")
+                .after_help("EXAMPLES:\n    cache_guess find --cache-block-size 128 origin.idx /dev/nvme0n1p1 > mapping.txt\n    cache_guess find --metadata-device meta.img --origin /dev/sdb --output mapping.txt origin.idx cache.img\n    cache_guess find --format csv --also-index backup.idx origin.idx cache.img\n    cache_guess find --format pretty origin.idx cache.img | less -R\n    cache_guess find --cache-block-size 128 origin.idx cache.img.zst > mapping.txt\n    cache_guess find --passes 5 --instability-report unstable.txt origin.idx /dev/nvme0n1p1 > mapping.txt\n    cache_guess find --watch 30 --ddrescue-map cache.map --output mapping.txt origin.idx cache.img")
                 .arg(Arg::with_name("index").required(true))
+                .arg(
+                    Arg::with_name("cache_device")
+                        .required_unless("jobs")
+                        .multiple(true)
+                        .help("Several cache devices are scanned one after another against the index, loaded once"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .takes_value(true)
+                        .conflicts_with("cache_device")
+                        .help("File of cache devices to scan, one per line, each optionally followed by an output file"),
+                )
+                .arg(
+                    Arg::with_name("lookup-cache")
+                        .long("lookup-cache")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help(
+                            "Save the lookup tables built from the indexes in DIR, and map them from there on later \
+                             runs against the same indexes instead of loading them again",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("also-index")
+                        .long("also-index")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "Another index to search alongside the first, such as one of a pre-failure backup; \
+                             candidates name the indexes they match best in",
+                        ),
+                )
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(
+                    Arg::with_name("metadata-device")
+                        .long("metadata-device")
+                        .takes_value(true)
+                        .help("dm-cache metadata device; its superblock gives the cache block size and count"),
+                )
+                .arg(partition_arg("partition").help("Only scan this partition of the cache device"))
+                .arg(
+                    Arg::with_name("origin")
+                        .long("origin")
+                        .takes_value(true)
+                        .help("Origin device, read for filesystem metadata"),
+                )
+                .arg(
+                    partition_arg("origin-partition")
+                        .requires("origin")
+                        .help("Partition of the origin device to use"),
+                )
+                .arg(
+                    Arg::with_name("fs")
+                        .long("fs")
+                        .takes_value(true)
+                        .possible_values(&["xfs"])
+                        .requires("origin")
+                        .help("Flag matches that fall in free space of the origin filesystem"),
+                )
+                .arg(
+                    Arg::with_name("unmatched-report")
+                        .long("unmatched-report")
+                        .takes_value(true)
+                        .help("Write an analysis of cache blocks without any match to this file"),
+                )
+                .arg(
+                    Arg::with_name("expected-mapping")
+                        .long("expected-mapping")
+                        .takes_value(true)
+                        .help("cache_dump XML to check each guess against"),
+                )
+                .arg(
+                    Arg::with_name("cache-block-range")
+                        .long("cache-block-range")
+                        .takes_value(true)
+                        .validator(|spec| Selection::parse(&spec).map(|_| ()))
+                        .help("Only process these cache blocks, e.g. 10..20,42,100.."),
+                )
+                .arg(
+                    Arg::with_name("cache-block-file")
+                        .long("cache-block-file")
+                        .takes_value(true)
+                        .help("Only process the cache blocks listed in this file, one number or range per line"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write results to this file instead of stdout, replacing it only once done; with several cache devices, to <output>.1, <output>.2 and so on"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .default_value("text")
+                        .possible_values(&["text", "jsonl", "csv", "pretty"])
+                        .help(
+                            "jsonl writes one JSON object per cache block as soon as it is done; csv one row per \
+                             cache block with the best candidate, its runner-up and a classification (full, \
+                             partial, tied, tainted or unmatched, or short for a last cache block that the device end \
+                             cuts short), without comment lines; pretty aligned columns colored by confidence in \
+                             mapped, ambiguous and unmatched sections, and a summary by region of the cache device",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("resume-from")
+                        .long("resume-from")
+                        .alias("resume")
+                        .takes_value(true)
+                        .help("Continue the results of a run that was interrupted, after their checkpoint, or killed, after their last complete cache block; the inputs and settings must be the same"),
+                )
+                .arg(
+                    Arg::with_name("confidence-model")
+                        .long("confidence-model")
+                        .default_value("heuristic")
+                        .possible_values(&["heuristic", "likelihood"])
+                        .help(
+                            "How candidates are scored and ranked: heuristic averages the match percentage with the \
+                             matched fs-blocks' agreement and boosts it by neighbor support; likelihood is the \
+                             probability of each candidate against all others and none, from how rare the matching \
+                             blocks are in the index, their entropy, the fs-blocks that do not match and neighbor \
+                             consistency",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("min-confidence")
+                        .long("min-confidence")
+                        .takes_value(true)
+                        .validator(is_rate)
+                        .help("Leave out candidates scored below this confidence, the block is unmatched without any"),
+                )
+                .arg(
+                    Arg::with_name("min-match")
+                        .long("min-match")
+                        .takes_value(true)
+                        .value_name("PCT")
+                        .validator(|value| match value.parse::<f64>() {
+                            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(()),
+                            _ => Err("expected a percentage from 0 to 100".to_string()),
+                        })
+                        .help(
+                            "Leave out candidates with fewer than this percentage of the fs-blocks in place, the \
+                             block is unmatched without any",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("top-n")
+                        .long("top-n")
+                        .takes_value(true)
+                        .value_name("K")
+                        .validator(is_positive)
+                        .help(
+                            "List at most this many candidates per cache block, in every --format; a tie is still \
+                             marked when its runners-up are left out",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("access")
+                        .long("access")
+                        .default_value("auto")
+                        .possible_values(&["auto", "hdd", "ssd"])
+                        .help("Read sequentially with batched confirmations (hdd) or in parallel (ssd)"),
+                )
+                .arg(ddrescue_map_arg("ddrescue-map").help(
+                    "GNU ddrescue mapfile of the cache device image; unrescued fs-blocks are not matched and the \
+                     mappings of cache blocks touching them are marked [tainted]",
+                ))
+                .arg(
+                    Arg::with_name("passes")
+                        .long("passes")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(is_positive)
+                        .help(
+                            "Read the cache device N times and match what most reads agree on, for devices that return \
+                             different data on every read of marginal sectors; fs-blocks without a majority are not \
+                             matched and mark their cache blocks [tainted]",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("instability-report")
+                        .long("instability-report")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .requires("passes")
+                        .help("List the fs-blocks that did not read the same in every pass to this file"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .validator(is_positive)
+                        .requires_all(&["ddrescue-map", "output"])
+                        .conflicts_with_all(&["jobs", "resume-from", "priority-ranges", "priority-fs", "hot-first", "db", "abort-if-below", "passes"])
+                        .help(
+                            "Match a cache device image while ddrescue is still writing it: every SECONDS, match the \
+                             cache blocks its mapfile newly marks rescued and rewrite --output with all results so \
+                             far, until ddrescue is done or the run is interrupted",
+                        ),
+                )
+                .arg(
+                    ddrescue_map_arg("origin-ddrescue-map")
+                        .help("GNU ddrescue mapfile of the origin image; mappings onto unrescued regions are marked [tainted]"),
+                )
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .takes_value(true)
+                        .help("Also add the results to this SQLite database through the sqlite3 shell, a run per cache device, to query and join runs with SQL"),
+                )
+                .arg(
+                    Arg::with_name("abort-if-below")
+                        .long("abort-if-below")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<f64>() {
+                            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(()),
+                            _ => Err("expected a percentage from 0 to 100".to_string()),
+                        })
+                        .help("Stop without results when fewer than this percentage of the first --abort-after cache blocks match anything, as they do when the parameters are wrong"),
+                )
+                .arg(
+                    Arg::with_name("abort-after")
+                        .long("abort-after")
+                        .takes_value(true)
+                        .requires("abort-if-below")
+                        .validator(is_positive)
+                        .help("How many cache blocks --abort-if-below looks at, holes left out [default: 1000]"),
+                )
+                .args(&priority_args())
+                .arg(idle_io_arg())
+                .args(&luks_args())
+                .arg(live_snapshot_arg())
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Copy mapped cache blocks onto the origin, journaled so an interruption can be resumed")
+                .after_help("EXAMPLES:\n    cache_guess apply --verify mapping.txt cache.img origin.img\n    cache_guess apply --resume-apply --yes mapping.txt cache.img origin.img\n    cache_guess apply --skip-identical mapping.txt cache.img origin.img\n    cache_guess apply --snapshot-cow /var/tmp/cow,10G mapping.txt /dev/nvme0n1p1 /dev/sdb")
+                .arg(Arg::with_name("mapping").required(true).help("Output of find"))
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(Arg::with_name("origin_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to write to"))
+                .arg(
+                    Arg::with_name("journal")
+                        .long("journal")
+                        .takes_value(true)
+                        .help("Journal file [default: <mapping>.journal]"),
+                )
+                .arg(
+                    Arg::with_name("resume-apply")
+                        .long("resume-apply")
+                        .help("Continue an interrupted apply from its journal"),
+                )
+                .arg(
+                    Arg::with_name("batch")
+                        .long("batch")
+                        .default_value("64")
+                        .validator(is_positive)
+                        .help("Blocks written between journal and origin syncs"),
+                )
+                .arg(Arg::with_name("coalesce").long("coalesce").help(
+                    "Write in origin order instead of mapping order, copying runs of blocks adjacent on both \
+                     devices in one piece; on a spinning origin this saves most of the seeking",
+                ))
+                .arg(Arg::with_name("skip-identical").long("skip-identical").help(
+                    "Compare each block with what the origin holds where it goes and skip the write when they are \
+                     the same, as after an earlier partial recovery; saves writes to a fragile origin",
+                ))
+                .arg(Arg::with_name("verify").long("verify").help(
+                    "Read each batch back from the origin once synced and compare it with the cache blocks; \
+                     mismatches are recorded in the journal and written again by --resume-apply",
+                ))
+                .arg(
+                    Arg::with_name("snapshot-cow")
+                        .long("snapshot-cow")
+                        .takes_value(true)
+                        .value_name("FILE,SIZE")
+                        .validator(|value| match value.split_once(',') {
+                            Some((_, size)) => is_positive_size(size.to_string()),
+                            None => Err(format!("'{}' is not FILE,SIZE", value)),
+                        })
+                        .help(
+                            "Write an origin block device through a dm-snapshot with a copy-on-write store of SIZE \
+                             in FILE, leaving the device itself untouched until the snapshot is merged, or \
+                             discarded to roll back",
+                        ),
+                )
+                .arg(Arg::with_name("in-place").long("in-place").help(
+                    "Write an origin image file directly; by default the copies go to a reflinked or copied clone \
+                     that replaces the image once they are all done",
+                ))
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .help("Write without showing the summary prompt that asks for the origin device name"),
+                )
+                .args(&priority_args())
+                .arg(conflict_arg())
+                .args(&duplicate_args())
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("reverse-find")
+                .about("Search the cache device for copies of specific origin blocks")
+                .after_help("EXAMPLES:\n    cache_guess reverse-find --blocks 1000..1010 cache.img origin.img")
                 .arg(Arg::with_name("cache_device").required(true))
+                .arg(Arg::with_name("origin_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
                 .arg(
-                    Arg::with_name("cache-block-size")
-                        .long("cache-block-size")
+                    Arg::with_name("blocks")
+                        .long("blocks")
+                        .takes_value(true)
+                        .validator(|spec| Selection::parse(&spec).map(|_| ()))
+                        .required_unless("bytes")
+                        .help("Origin blocks of the cache block size, e.g. 0,10..20"),
+                )
+                .arg(
+                    Arg::with_name("bytes")
+                        .long("bytes")
+                        .takes_value(true)
+                        .validator(|spec| Selection::parse(&spec).map(|_| ()))
+                        .help("Origin byte ranges, e.g. 1024..2048"),
+                )
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to use"))
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("shift-scan")
+                .about("Detect origin data at arbitrary byte offsets, e.g. a cache image extracted from a wrong offset")
+                .after_help("EXAMPLES:\n    cache_guess shift-scan cache.img origin.img")
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(Arg::with_name("origin_device").required(true))
+                .arg(
+                    Arg::with_name("scan-bytes")
+                        .long("scan-bytes")
+                        .default_value("64M")
+                        .validator(is_positive_size)
+                        .help("How much of the cache device to scan, from its start"),
+                )
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to use"))
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-mapping")
+                .about("Re-read both devices and check every entry of a mapping against the data")
+                .after_help("EXAMPLES:\n    cache_guess verify-mapping mapping.txt cache.img origin.img")
+                .arg(
+                    Arg::with_name("mapping")
+                        .required(true)
+                        .help("Output of find, or cache_dump XML (*.xml)"),
+                )
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(Arg::with_name("origin_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to use"))
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("check-mapping")
+                .about("Check a mapping for duplicates, out-of-range blocks, overlaps and weak entries; fails on problems")
+                .after_help("EXAMPLES:\n    cache_guess check-mapping mapping.txt")
+                .arg(Arg::with_name("mapping").required(true).help("Output of find, plan-writeback or classify"))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .takes_value(true)
+                        .help("Index the mapping was found with, to check origin blocks against its origin size"),
+                )
+                .arg(
+                    Arg::with_name("cache-device")
+                        .long("cache-device")
+                        .takes_value(true)
+                        .help("Cache device, to check cache blocks against its size"),
+                )
+                .arg(
+                    Arg::with_name("min-confidence")
+                        .long("min-confidence")
+                        .takes_value(true)
+                        .validator(is_rate)
+                        .help("Report entries scored below this confidence"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("classify")
+                .about("Compare every mapped cache block with its origin location and sort it into clean, dirty or unknown")
+                .after_help("EXAMPLES:\n    cache_guess classify mapping.txt cache.img origin.img\n    cache_guess classify --cache-mode writethrough mapping.txt cache.img origin.img")
+                .arg(
+                    Arg::with_name("mapping")
+                        .required(true)
+                        .help("Output of find, or cache_dump XML (*.xml)"),
+                )
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(Arg::with_name("origin_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to use"))
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the classification here instead of stdout; apply copies only the dirty blocks"),
+                )
+                .arg(cache_mode_arg())
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("mirror-compare")
+                .about("Compare the two halves of a RAID1 origin and decide per block which to trust, using the cache as a tiebreaker")
+                .after_help("EXAMPLES:\n    cache_guess mirror-compare --output decisions.txt mapping.txt cache.img sdb.img sdc.img")
+                .arg(
+                    Arg::with_name("mapping")
+                        .required(true)
+                        .help("Output of find, or cache_dump XML (*.xml); where the halves differ, the one matching the mapped cache block wins"),
+                )
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(Arg::with_name("mirror_a").required(true).help("One half of the mirror"))
+                .arg(Arg::with_name("mirror_b").required(true).help("The other half"))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(partition_arg("origin-partition").help("Partition of both halves to use"))
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the decisions here instead of stdout"),
+                )
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("reconcile")
+                .about("Merge find results with whatever mappings survive in damaged metadata")
+                .after_help("EXAMPLES:\n    cache_guess reconcile --output merged.txt mapping.txt cache_dump.xml")
+                .arg(Arg::with_name("mapping").required(true).help("Output of find"))
+                .arg(
+                    Arg::with_name("metadata")
+                        .required(true)
+                        .help("cache_dump XML; broken mappings are dropped"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the merged mapping here instead of stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("plan-writeback")
+                .about("Plan the copies that write a writeback cache's dirty blocks back, from metadata and find results")
+                .after_help("EXAMPLES:\n    cache_guess plan-writeback --output plan.txt mapping.txt cache_dump.xml\n    cache_guess apply plan.txt cache.img origin.img")
+                .arg(Arg::with_name("mapping").required(true).help("Output of find"))
+                .arg(
+                    Arg::with_name("metadata")
+                        .required(true)
+                        .help("cache_dump XML with the dirty bits, possibly partial"),
+                )
+                .arg(
+                    Arg::with_name("copy-unknown")
+                        .long("copy-unknown")
+                        .help("Also copy blocks the metadata does not mention but the content matched"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the plan here instead of stdout; it can be passed to apply"),
+                )
+                .arg(cache_mode_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Write the recovered image: the origin with every mapped block taken from the cache")
+                .after_help("EXAMPLES:\n    cache_guess merge mapping.txt cache.img origin.img recovered.img")
+                .arg(Arg::with_name("mapping").required(true).help("Output of find"))
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(Arg::with_name("origin_device").required(true))
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .help("Image file to write, or - for stdout"),
+                )
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to use"))
+                .arg(conflict_arg())
+                .args(&duplicate_args())
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Write the raw bytes of selected cache blocks to one file each, or to a tar stream")
+                .after_help("EXAMPLES:\n    cache_guess extract --blocks 10..20,42 --output-dir blocks cache.img")
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(
+                    Arg::with_name("blocks")
+                        .long("blocks")
+                        .takes_value(true)
+                        .validator(|spec| Selection::parse(&spec).map(|_| ()))
+                        .required_unless("unmatched")
+                        .help("Cache blocks, e.g. 0,10..20"),
+                )
+                .arg(
+                    Arg::with_name("unmatched")
+                        .long("unmatched")
+                        .takes_value(true)
+                        .help("Every cache block this find result has no mapping for"),
+                )
+                .arg(
+                    Arg::with_name("output-dir")
+                        .long("output-dir")
+                        .takes_value(true)
+                        .required_unless("tar")
+                        .help("Directory for cache-block-<N>.bin files"),
+                )
+                .arg(
+                    Arg::with_name("tar")
+                        .long("tar")
+                        .takes_value(true)
+                        .help("Write a tar archive here instead, - for stdout"),
+                )
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("carve")
+                .about("Look for file signatures in cache blocks, to find the starts of files only the cache holds")
+                .after_help(
+                    "EXAMPLES:\n    cache_guess carve --unmatched mapping.txt cache.img\n    cache_guess carve --blocks \
+                     10..20 --output signatures.txt cache.img",
+                )
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(
+                    Arg::with_name("blocks")
+                        .long("blocks")
+                        .takes_value(true)
+                        .validator(|spec| Selection::parse(&spec).map(|_| ()))
+                        .required_unless("unmatched")
+                        .help("Cache blocks, e.g. 0,10..20"),
+                )
+                .arg(
+                    Arg::with_name("unmatched")
+                        .long("unmatched")
+                        .takes_value(true)
+                        .help("Every cache block this find result has no mapping for"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the signatures found here instead of stdout"),
+                )
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("writecache")
+                .about(
+                    "Read the mapping of a dm-writecache cache device from its metadata, matching the blocks whose \
+                     metadata is damaged by content",
+                )
+                .after_help(
+                    "EXAMPLES:\n    cache_guess writecache --index origin.idx --output mapping.txt cache.img\n    \
+                     cache_guess writecache --content-only --block-size 4K --index origin.idx cache.img",
+                )
+                .arg(Arg::with_name("cache_device").required(true))
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .takes_value(true)
+                        .help("Index of the origin, to match the blocks the metadata does not account for"),
+                )
+                .arg(
+                    Arg::with_name("block-size")
+                        .long("block-size")
+                        .default_value("4096")
+                        .validator(|value| match parse_size(&value)? {
+                            size if size.is_power_of_two() && size >= 512 => Ok(()),
+                            _ => Err(format!("'{}' is not a power of two of at least 512 bytes", value)),
+                        })
+                        .help("Block size in bytes, for when the superblock is damaged and cannot tell"),
+                )
+                .arg(
+                    Arg::with_name("origin-size")
+                        .long("origin-size")
+                        .takes_value(true)
+                        .validator(is_positive_size)
+                        .help("Origin size in bytes, for entries pointing past it; the index's origin otherwise"),
+                )
+                .arg(
+                    Arg::with_name("content-only")
+                        .long("content-only")
+                        .requires("index")
+                        .help("Leave the metadata aside and match every block by content"),
+                )
+                .arg(partition_arg("partition").help("Partition of the cache device to use"))
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the mapping here instead of stdout"),
+                )
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("coverage")
+                .about("Show which parts of the origin a mapping covers, region by region")
+                .after_help("EXAMPLES:\n    cache_guess coverage --origin origin.img mapping.txt")
+                .arg(Arg::with_name("mapping").required(true).help("Output of find"))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(
+                    Arg::with_name("origin")
+                        .long("origin")
+                        .takes_value(true)
+                        .required_unless("origin-size")
+                        .help("Origin device, only read for its size"),
+                )
+                .arg(partition_arg("origin-partition").help("Partition of the origin device to use"))
+                .arg(
+                    Arg::with_name("origin-size")
+                        .long("origin-size")
+                        .takes_value(true)
+                        .validator(is_positive_size)
+                        .conflicts_with("origin")
+                        .help("Origin size in bytes, instead of --origin"),
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .long("region")
+                        .default_value("4096")
+                        .validator(is_positive)
+                        .help("Origin blocks per report line"),
+                )
+                .arg(
+                    Arg::with_name("visualize")
+                        .long("visualize")
+                        .takes_value(true)
+                        .value_name("out.png")
+                        .help(
+                            "Also draw the cache and origin blocks colored by match confidence: a PNG for a .png \
+                             path, ASCII art otherwise, - for stdout",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("report")
+                        .long("report")
+                        .takes_value(true)
+                        .value_name("out.html")
+                        .help(
+                            "Also write a self-contained HTML report: summary, match histogram, coverage map, \
+                             conflicts and a searchable table of every entry",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Coalesce a mapping into contiguous extents, as sector ranges")
+                .after_help("EXAMPLES:\n    cache_guess export mapping.txt")
+                .arg(Arg::with_name("mapping").required(true).help("Output of find"))
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .default_value("csv")
+                        .possible_values(&["csv", "json"]),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write here instead of stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Maintain index files")
+                .subcommand(
+                    SubCommand::with_name("gc")
+                        .about("Rewrite an index as explicit records without the entries that only add noise to find")
+                        .after_help("EXAMPLES:\n    cache_guess index gc --max-duplicates 16 origin.idx origin-gc.idx")
+                        .arg(Arg::with_name("index").required(true))
+                        .arg(Arg::with_name("output").required(true))
+                        .arg(
+                            Arg::with_name("max-duplicates")
+                                .long("max-duplicates")
+                                .default_value("64")
+                                .validator(is_positive)
+                                .help("Drop digests found at more origin blocks than this"),
+                        )
+                        .arg(
+                            Arg::with_name("keep-zero")
+                                .long("keep-zero")
+                                .help("Keep the entries of all-zero blocks"),
+                        )
+                        .arg(
+                            Arg::with_name("exclude-ranges")
+                                .long("exclude-ranges")
+                                .takes_value(true)
+                                .help("File of origin byte ranges to drop, one start..end per line"),
+                        )
+                        .arg(shard_size_arg())
+                        .arg(Arg::with_name("force").long("force").help("Go ahead even if counting the digests may not fit in memory")),
+                )
+                .subcommand(
+                    SubCommand::with_name("convert")
+                        .about("Rewrite an index in another layout, or a legacy one with a header, without hashing the origin again")
+                        .after_help("EXAMPLES:\n    cache_guess index convert --origin /dev/sdb legacy.idx origin.idx\n    cache_guess index convert --explicit --shard-size 1T origin.idx origin-sparse.idx\n    cache_guess index convert --digest-bytes 8 --drop-filter origin.idx origin-small.idx")
+                        .arg(Arg::with_name("index").required(true))
+                        .arg(Arg::with_name("output").required(true))
+                        .arg(
+                            Arg::with_name("explicit")
+                                .long("explicit")
+                                .help("Store explicit (offset, digest) records, as collect --sparse does"),
+                        )
+                        .arg(
+                            Arg::with_name("positional")
+                                .long("positional")
+                                .conflicts_with("explicit")
+                                .help("Store one entry per origin block; without either flag the index keeps its layout"),
+                        )
+                        .arg(
+                            Arg::with_name("digest-bytes")
+                                .long("digest-bytes")
+                                .takes_value(true)
+                                .validator(is_number)
+                                .help("Cut the digests to this many leading bytes"),
+                        )
+                        .arg(
+                            Arg::with_name("drop-filter")
+                                .long("drop-filter")
+                                .help("Leave out the filter hashes of a two-tier index"),
+                        )
+                        .arg(
+                            Arg::with_name("origin")
+                                .long("origin")
+                                .takes_value(true)
+                                .help("Origin a legacy index was built from, for the size and identity it did not record"),
+                        )
+                        .arg(
+                            Arg::with_name("device-size")
+                                .long("device-size")
+                                .takes_value(true)
+                                .validator(is_positive_size)
+                                .help("Size of the origin of a legacy index, when the origin is not at hand"),
+                        )
+                        .arg(shard_size_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Run subcommands for a front-end over JSON-RPC on a Unix socket, with live progress and results")
+                .after_help("EXAMPLES:\n    cache_guess serve /run/cache_guess.sock")
+                .arg(Arg::with_name("socket").required(true).help("Path of the socket to listen on")),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Show the digests, entropy and contents of one block, and where an index has the same data")
+                .after_help("EXAMPLES:\n    cache_guess inspect --index origin.idx cache.img 42")
+                .arg(Arg::with_name("device").required(true))
+                .arg(Arg::with_name("block").required(true).validator(is_number))
+                .arg(
+                    Arg::with_name("block-size")
+                        .long("block-size")
                         .default_value("512")
-                        .help("In sectors (512 bytes)"),
+                        .validator(is_positive_size)
+                        .help("In sectors (see --sector-size), or bytes with a K/M/G suffix"),
+                )
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the device to use"))
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .takes_value(true)
+                        .help("List every origin location with the same data as the block's fs-blocks"),
+                )
+                .arg(
+                    Arg::with_name("preview")
+                        .long("preview")
+                        .default_value("256")
+                        .validator(is_positive_size)
+                        .help("Bytes to hexdump"),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("lookup")
+                .about("List every origin offset in an index, and cache block on a device, that holds some given content")
+                .after_help("EXAMPLES:\n    cache_guess lookup --file lost.pdf origin.idx\n    cache_guess lookup --digest 3f786850e387550fdab836ed7e6dc881de23001b --cache-device cache.img origin.idx")
+                .arg(Arg::with_name("index").required(true))
+                .arg(
+                    Arg::with_name("digest")
+                        .long("digest")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required_unless_one(&["file", "bytes"])
+                        .help("The SHA-1 of an fs-block, in hex"),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Look up every fs-block of this file, the last one padded with zeroes"),
+                )
+                .arg(
+                    Arg::with_name("bytes")
+                        .long("bytes")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("The contents of an fs-block in hex, padded with zeroes if shorter"),
+                )
+                .arg(
+                    Arg::with_name("cache-device")
+                        .long("cache-device")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Also list the cache blocks of this device that hold the content"),
+                )
+                .arg(cache_block_size_arg())
+                .arg(sector_size_arg())
+                .arg(partition_arg("partition").help("Partition of the cache devices to use"))
+                .arg(bwlimit_arg())
+                .arg(force_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("lvm")
+                .about("Find lvmcache volumes in the LVM2 metadata of the given PVs and where their parts are")
+                .after_help("EXAMPLES:\n    cache_guess lvm /dev/sdb /dev/nvme0n1p1")
+                .arg(Arg::with_name("pv").required(true).multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("list-partitions")
+                .about("List the partitions of a device, with the numbers --partition takes")
+                .arg(Arg::with_name("device").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Write a completion script for a shell to stdout")
+                .after_help("EXAMPLES:\n    cache_guess completions bash > /etc/bash_completion.d/cache_guess\n    cache_guess completions zsh > \"${fpath[1]}/_cache_guess\"\n    cache_guess completions fish > ~/.config/fish/completions/cache_guess.fish")
+                .arg(Arg::with_name("shell").required(true).possible_values(&Shell::variants())),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Session files for --config")
+                .subcommand(
+                    SubCommand::with_name("init")
+                        .about("Write a session file listing every subcommand's flags and defaults, commented out")
+                        .after_help("EXAMPLES:\n    cache_guess config init --output session.toml\n    cache_guess --config session.toml find")
+                        .arg(
+                            Arg::with_name("output")
+                                .long("output")
+                                .takes_value(true)
+                                .help("The file to write instead of stdout"),
+                        ),
+                ),
+        );
+    let matches = config::matches(app.clone())?;
 
-    match matches.subcommand() {
+    if let (_, Some(sub_m)) = matches.subcommand() {
+        if sub_m.is_present("idle-io") {
+            ioprio::set_idle()?;
+        }
+        if sub_m.is_present("skip-corrupt-index-blocks") {
+            index::skip_corrupt_blocks();
+        }
+        if let Some(keyfile) = sub_m.value_of("luks-keyfile") {
+            luks::set_key(luks::Key::Keyfile(PathBuf::from(keyfile)));
+        } else if sub_m.is_present("luks-passphrase") {
+            luks::set_key(luks::Key::Passphrase);
+        }
+        if let Some(offset) = sub_m.value_of("data-offset") {
+            let offset = parse_size(offset).unwrap();
+            for device in ["cache_device", "cache-device"]
+                .iter()
+                .flat_map(|name| sub_m.values_of(name).into_iter().flatten())
+            {
+                superblock::set_data_offset(Path::new(device), offset);
+            }
+        }
+        if sub_m.is_present("quiet") {
+            *PROGRESS.lock().unwrap() = Progress::Quiet;
+        } else if sub_m.value_of("progress") == Some("json") || sub_m.is_present("progress-fd") {
+            let fd = sub_m.value_of("progress-fd").map(|fd| {
+                // The caller opened it for us, as with 3>progress.jsonl; nothing else here uses it.
+                unsafe { File::from_raw_fd(fd.parse().unwrap()) }
+            });
+            *PROGRESS.lock().unwrap() = Progress::Json {
+                phase: matches.subcommand_name().unwrap().to_string(),
+                fd,
+                last: None,
+            };
+        }
+    }
+    let result = match matches.subcommand() {
         ("collect", Some(sub_m)) => {
             let index_path = Path::new(sub_m.value_of("index").unwrap());
-            let device_path = Path::new(sub_m.value_of("device").unwrap());
-            collect(index_path, device_path)
+            let mut live = live_snapshots(sub_m);
+            let device_specs: Vec<String> = sub_m
+                .values_of("device")
+                .unwrap()
+                .map(|spec| {
+                    if spec == "-" {
+                        return Ok(spec.to_string());
+                    }
+                    let (path, offset) = layout::parse_spec(spec)?;
+                    let device = live.take(path)?.to_string_lossy().into_owned();
+                    Ok(match offset {
+                        Some(offset) => format!("{}@{}", device, offset),
+                        None => device,
+                    })
+                })
+                .collect::<io::Result<_>>()?;
+            let device_specs: Vec<&str> = device_specs.iter().map(String::as_str).collect();
+            let changed = match sub_m.value_of("changed-ranges") {
+                Some(path) => {
+                    let mut changed = Selection::default();
+                    changed.add_file(Path::new(path))?;
+                    Some(changed)
+                }
+                None => None,
+            };
+            let options = CollectOptions {
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                fs: sub_m.value_of("fs"),
+                digest_bytes: sub_m.value_of("digest-bytes").unwrap().parse::<usize>().unwrap(),
+                sample_bytes: sub_m.value_of("sample-bytes").map(|n| parse_size(n).unwrap()),
+                filter_hash: sub_m.is_present("filter-hash"),
+                base_index: sub_m.value_of("base-index").map(Path::new),
+                changed: changed.as_ref(),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+                ddrescue_map: sub_m.value_of("ddrescue-map").map(Path::new),
+                shard_size: sub_m.value_of("shard-size").map(|n| parse_size(n).unwrap()),
+                sparse: sub_m.is_present("sparse"),
+                cache_device: sub_m.value_of("cache-device").map(Path::new),
+                cache_partition: sub_m.value_of("cache-partition").map(|p| p.parse::<usize>().unwrap()),
+            };
+            let compressed_origin = match device_specs.as_slice() {
+                [spec] => compressed::Format::detect(Path::new(spec)),
+                _ => None,
+            };
+            let remote_origin = matches!(device_specs.as_slice(), [spec] if remote::is_remote(Path::new(spec)));
+            if device_specs == ["-"] {
+                let Some(device_size) = sub_m.value_of("device-size") else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "reading the origin from stdin needs --device-size",
+                    ));
+                };
+                let device_size = parse_size(device_size).unwrap();
+                collect::collect_stream(index_path, Path::new("-"), &mut io::stdin(), device_size, &options)
+            } else if let Some(format) = compressed_origin {
+                let path = Path::new(device_specs[0]);
+                let device_size = match sub_m.value_of("device-size") {
+                    Some(device_size) => parse_size(device_size).unwrap(),
+                    None => compressed::uncompressed_size(path, format)?.ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "{} does not record its size once decompressed, which --device-size gives",
+                                path.display()
+                            ),
+                        )
+                    })?,
+                };
+                let mut stream = compressed::Stream::open(path, format)?;
+                let result = collect::collect_stream(index_path, path, &mut stream.output, device_size, &options);
+                stream.finish(result)
+            } else if remote_origin {
+                let path = Path::new(device_specs[0]);
+                let mut stream = remote::Stream::open(path)?;
+                let device_size = sub_m
+                    .value_of("device-size")
+                    .map_or(stream.size, |device_size| parse_size(device_size).unwrap());
+                let result = collect::collect_stream(index_path, path, &mut stream, device_size, &options);
+                stream.finish(result)
+            } else if sub_m.is_present("direct") {
+                if device_specs.len() > 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "--direct streams a single origin device",
+                    ));
+                }
+                collect::collect_direct(index_path, &luks::unlocked(Path::new(device_specs[0]))?, &options)
+            } else {
+                collect::collect(index_path, &device_specs, &options)
+            }
+        }
+        ("check", Some(sub_m)) => {
+            let cache_device = sub_m.value_of("cache_device").unwrap();
+            let options = PreflightOptions {
+                // Checked for alignment with the rest, as a problem rather than an error.
+                cache_block_size: sectors_arg(sub_m, "cache-block-size", Some(cache_device)),
+                explicit_block_size: sub_m.occurrences_of("cache-block-size") > 0,
+                origin: sub_m.value_of("origin").map(Path::new),
+                metadata_device: sub_m.value_of("metadata-device").map(Path::new),
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                samples: sub_m.value_of("samples").unwrap().parse::<usize>().unwrap().max(1),
+            };
+            preflight::preflight(
+                Path::new(sub_m.value_of("index").unwrap()),
+                Path::new(cache_device),
+                &options,
+            )
+        }
+        ("estimate", Some(sub_m)) => {
+            let cache_device = sub_m.value_of("cache_device").unwrap();
+            let options = EstimateOptions {
+                cache_block_size: cache_block_size(sub_m, &[Some(cache_device)])?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                samples: sub_m.value_of("samples").unwrap().parse::<usize>().unwrap().max(1),
+                seed: sub_m.value_of("seed").map(|seed| seed.parse::<u64>().unwrap()),
+                expected_mapping: sub_m.value_of("expected-mapping").map(Path::new),
+                force: sub_m.is_present("force"),
+            };
+            estimate::estimate(
+                Path::new(sub_m.value_of("index").unwrap()),
+                Path::new(cache_device),
+                &options,
+            )
         }
         ("find", Some(sub_m)) => {
             let index_path = Path::new(sub_m.value_of("index").unwrap());
-            let cache_device_path = Path::new(sub_m.value_of("cache_device").unwrap());
-            let cache_block_size = sub_m.value_of("cache-block-size").unwrap().parse::<usize>().unwrap();
-            find(index_path, cache_device_path, cache_block_size)
+            let mut live = live_snapshots(sub_m);
+            let mut job_list: Vec<(PathBuf, Option<PathBuf>)> = match sub_m.value_of("jobs") {
+                Some(jobs) => find::read_jobs(Path::new(jobs))?,
+                None => {
+                    let devices: Vec<&str> = sub_m.values_of("cache_device").unwrap().collect();
+                    let output = sub_m.value_of("output");
+                    devices
+                        .iter()
+                        .enumerate()
+                        .map(|(number, device)| {
+                            let output = output.map(|output| match devices.len() {
+                                1 => PathBuf::from(output),
+                                _ => PathBuf::from(format!("{}.{}", output, number + 1)),
+                            });
+                            (PathBuf::from(device), output)
+                        })
+                        .collect()
+                }
+            };
+            for (cache_device, _) in &mut job_list {
+                *cache_device = live.take(cache_device)?;
+                if let Some(offset) = sub_m.value_of("data-offset") {
+                    superblock::set_data_offset(cache_device, parse_size(offset).unwrap());
+                }
+            }
+            let origin = sub_m
+                .value_of("origin")
+                .map(|origin| live.take(Path::new(origin)))
+                .transpose()?;
+            let jobs: Vec<Job> = job_list
+                .iter()
+                .map(|(cache_device, output)| Job {
+                    cache_device,
+                    output: output.as_deref(),
+                })
+                .collect();
+            let Some(first) = jobs.first() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the job file lists no cache devices",
+                ));
+            };
+            let mut cache_block_size = sectors_arg(sub_m, "cache-block-size", first.cache_device.to_str());
+            let mut cache_blocks = None;
+            if let Some(metadata_device) = sub_m.value_of("metadata-device") {
+                let superblock = Superblock::read(Path::new(metadata_device))?;
+                eprintln!(
+                    "metadata: version {}, policy {}, {} cache blocks of {} sectors",
+                    superblock.version, superblock.policy, superblock.cache_blocks, superblock.data_block_size
+                );
+                // Always 512 byte sectors, whatever the device's sector size.
+                let metadata_block_size = 512 * superblock.data_block_size;
+                if sub_m.occurrences_of("cache-block-size") > 0 && cache_block_size != metadata_block_size {
+                    eprintln!(
+                        "warning: --cache-block-size of {} bytes conflicts with the metadata, using {}",
+                        cache_block_size, metadata_block_size
+                    );
+                }
+                cache_block_size = metadata_block_size;
+                cache_blocks = Some(superblock.cache_blocks);
+            }
+            let devices: Vec<&str> = jobs
+                .iter()
+                .filter_map(|job| job.cache_device.to_str())
+                .chain(origin.as_deref().and_then(Path::to_str))
+                .collect();
+            check_alignment("the cache block size", cache_block_size, &devices)?;
+            let selection = match (sub_m.value_of("cache-block-range"), sub_m.value_of("cache-block-file")) {
+                (None, None) => None,
+                (range, file) => {
+                    let mut selection = range.map(|r| Selection::parse(r).unwrap()).unwrap_or_default();
+                    if let Some(file) = file {
+                        selection.add_file(Path::new(file))?;
+                    }
+                    Some(selection)
+                }
+            };
+            let options = FindOptions {
+                cache_block_size,
+                cache_blocks,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_path: origin.as_deref(),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                fs: sub_m.value_of("fs"),
+                unmatched_report: sub_m.value_of("unmatched-report").map(Path::new),
+                expected_mapping: sub_m.value_of("expected-mapping").map(Path::new),
+                selection,
+                resume: sub_m.value_of("resume-from").map(Path::new),
+                format: sub_m.value_of("format").unwrap(),
+                access: sub_m.value_of("access").unwrap(),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+                ddrescue_map: sub_m.value_of("ddrescue-map").map(Path::new),
+                origin_ddrescue_map: sub_m.value_of("origin-ddrescue-map").map(Path::new),
+                db: sub_m.value_of("db").map(Path::new),
+                abort: sub_m.value_of("abort-if-below").map(|percent| {
+                    let after = sub_m.value_of("abort-after").map_or(1000, |n| n.parse().unwrap());
+                    (percent.parse().unwrap(), after)
+                }),
+                priority_ranges: sub_m.value_of("priority-ranges").map(Path::new),
+                priority_fs: sub_m.value_of("priority-fs"),
+                hot_first: sub_m.is_present("hot-first"),
+                also_index: sub_m
+                    .values_of("also-index")
+                    .map_or(Vec::new(), |paths| paths.map(Path::new).collect()),
+                lookup_cache: sub_m.value_of("lookup-cache").map(Path::new),
+                confidence_model: sub_m.value_of("confidence-model").unwrap(),
+                min_confidence: sub_m.value_of("min-confidence").map(|c| c.parse::<f64>().unwrap()),
+                min_match: sub_m
+                    .value_of("min-match")
+                    .map(|percent| percent.parse::<f64>().unwrap()),
+                top_n: sub_m.value_of("top-n").map(|n| n.parse::<usize>().unwrap()),
+                passes: sub_m.value_of("passes").map_or(1, |n| n.parse().unwrap()),
+                instability_report: sub_m.value_of("instability-report").map(Path::new),
+                watch: sub_m
+                    .value_of("watch")
+                    .map(|seconds| Duration::from_secs(seconds.parse().unwrap())),
+            };
+            if jobs.len() > 1 {
+                for job in &jobs {
+                    if let Some(output) = job.output {
+                        eprintln!("{} -> {}", job.cache_device.display(), output.display());
+                    }
+                }
+            }
+            find::find(index_path, &jobs, &options)
+        }
+        ("apply", Some(sub_m)) => {
+            let mapping_path = Path::new(sub_m.value_of("mapping").unwrap());
+            let journal = match sub_m.value_of("journal") {
+                Some(journal) => PathBuf::from(journal),
+                None => {
+                    let mut journal = mapping_path.as_os_str().to_owned();
+                    journal.push(".journal");
+                    PathBuf::from(journal)
+                }
+            };
+            let options = ApplyOptions {
+                cache_block_size: cache_block_size(
+                    sub_m,
+                    &[sub_m.value_of("cache_device"), sub_m.value_of("origin_device")],
+                )?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                journal: &journal,
+                resume: sub_m.is_present("resume-apply"),
+                batch: sub_m.value_of("batch").unwrap().parse::<usize>().unwrap(),
+                coalesce: sub_m.is_present("coalesce"),
+                skip_identical: sub_m.is_present("skip-identical"),
+                conflict: Conflict::parse(sub_m.value_of("conflict").unwrap()),
+                expected_mapping: sub_m.value_of("expected-mapping").map(Path::new),
+                duplicates: sub_m.value_of("duplicates").map(Path::new),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                verify: sub_m.is_present("verify"),
+                in_place: sub_m.is_present("in-place"),
+                snapshot_cow: sub_m.value_of("snapshot-cow").map(|value| {
+                    let (path, size) = value.split_once(',').unwrap();
+                    (Path::new(path), parse_size(size).unwrap())
+                }),
+                yes: sub_m.is_present("yes"),
+                priority_ranges: sub_m.value_of("priority-ranges").map(Path::new),
+                priority_fs: sub_m.value_of("priority-fs"),
+                hot_first: sub_m.is_present("hot-first"),
+                force: sub_m.is_present("force"),
+            };
+            apply::apply(
+                mapping_path,
+                Path::new(sub_m.value_of("cache_device").unwrap()),
+                Path::new(sub_m.value_of("origin_device").unwrap()),
+                &options,
+            )
+        }
+        ("reverse-find", Some(sub_m)) => {
+            let blocks = sub_m.value_of("blocks").map(|spec| Selection::parse(spec).unwrap());
+            let bytes = sub_m.value_of("bytes").map(|spec| Selection::parse(spec).unwrap());
+            let options = ReverseOptions {
+                cache_block_size: cache_block_size(
+                    sub_m,
+                    &[sub_m.value_of("cache_device"), sub_m.value_of("origin_device")],
+                )?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                blocks: blocks.as_ref(),
+                bytes: bytes.as_ref(),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+            };
+            reverse::reverse_find(
+                Path::new(sub_m.value_of("cache_device").unwrap()),
+                Path::new(sub_m.value_of("origin_device").unwrap()),
+                &options,
+            )
+        }
+        ("shift-scan", Some(sub_m)) => {
+            let options = ShiftOptions {
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                scan_bytes: parse_size(sub_m.value_of("scan-bytes").unwrap()).unwrap(),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+            };
+            shift::shift_scan(
+                Path::new(sub_m.value_of("cache_device").unwrap()),
+                Path::new(sub_m.value_of("origin_device").unwrap()),
+                &options,
+            )
+        }
+        ("verify-mapping", Some(sub_m)) => {
+            let options = VerifyOptions {
+                cache_block_size: cache_block_size(
+                    sub_m,
+                    &[sub_m.value_of("cache_device"), sub_m.value_of("origin_device")],
+                )?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+            };
+            verify::verify(
+                Path::new(sub_m.value_of("mapping").unwrap()),
+                Path::new(sub_m.value_of("cache_device").unwrap()),
+                Path::new(sub_m.value_of("origin_device").unwrap()),
+                &options,
+            )
+        }
+        ("check-mapping", Some(sub_m)) => {
+            let options = CheckOptions {
+                cache_block_size: cache_block_size(sub_m, &[sub_m.value_of("cache-device")])?,
+                index: sub_m.value_of("index").map(Path::new),
+                cache_device: sub_m.value_of("cache-device").map(Path::new),
+                min_confidence: sub_m.value_of("min-confidence").map(|c| c.parse::<f64>().unwrap()),
+            };
+            check::check(Path::new(sub_m.value_of("mapping").unwrap()), &options)
+        }
+        ("classify", Some(sub_m)) => {
+            let options = VerifyOptions {
+                cache_block_size: cache_block_size(
+                    sub_m,
+                    &[sub_m.value_of("cache_device"), sub_m.value_of("origin_device")],
+                )?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+            };
+            verify::classify(
+                Path::new(sub_m.value_of("mapping").unwrap()),
+                Path::new(sub_m.value_of("cache_device").unwrap()),
+                Path::new(sub_m.value_of("origin_device").unwrap()),
+                sub_m.value_of("output").map(Path::new),
+                sub_m.value_of("cache-mode") == Some("writethrough"),
+                &options,
+            )
+        }
+        ("mirror-compare", Some(sub_m)) => {
+            let devices = ["cache_device", "mirror_a", "mirror_b"].map(|name| sub_m.value_of(name));
+            let options = VerifyOptions {
+                cache_block_size: cache_block_size(sub_m, &devices)?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+            };
+            mirror::compare(
+                Path::new(sub_m.value_of("mapping").unwrap()),
+                Path::new(sub_m.value_of("cache_device").unwrap()),
+                [
+                    Path::new(sub_m.value_of("mirror_a").unwrap()),
+                    Path::new(sub_m.value_of("mirror_b").unwrap()),
+                ],
+                sub_m.value_of("output").map(Path::new),
+                &options,
+            )
+        }
+        ("reconcile", Some(sub_m)) => reconcile::reconcile(
+            Path::new(sub_m.value_of("mapping").unwrap()),
+            Path::new(sub_m.value_of("metadata").unwrap()),
+            sub_m.value_of("output").map(Path::new),
+        ),
+        ("plan-writeback", Some(sub_m)) => {
+            let options = PlanOptions {
+                copy_unknown: sub_m.is_present("copy-unknown"),
+                writethrough: sub_m.value_of("cache-mode") == Some("writethrough"),
+                output: sub_m.value_of("output").map(Path::new),
+            };
+            writeback::plan(
+                Path::new(sub_m.value_of("mapping").unwrap()),
+                Path::new(sub_m.value_of("metadata").unwrap()),
+                &options,
+            )
+        }
+        ("merge", Some(sub_m)) => {
+            let options = MergeOptions {
+                cache_block_size: cache_block_size(
+                    sub_m,
+                    &[sub_m.value_of("cache_device"), sub_m.value_of("origin_device")],
+                )?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                origin_partition: sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                conflict: Conflict::parse(sub_m.value_of("conflict").unwrap()),
+                expected_mapping: sub_m.value_of("expected-mapping").map(Path::new),
+                duplicates: sub_m.value_of("duplicates").map(Path::new),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+            };
+            merge::merge(
+                Path::new(sub_m.value_of("mapping").unwrap()),
+                Path::new(sub_m.value_of("cache_device").unwrap()),
+                Path::new(sub_m.value_of("origin_device").unwrap()),
+                Path::new(sub_m.value_of("output").unwrap()),
+                &options,
+            )
+        }
+        ("extract", Some(sub_m)) => {
+            let blocks = sub_m.value_of("blocks").map(|spec| Selection::parse(spec).unwrap());
+            let options = ExtractOptions {
+                cache_block_size: cache_block_size(sub_m, &[sub_m.value_of("cache_device")])?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                blocks: blocks.as_ref(),
+                unmatched: sub_m.value_of("unmatched").map(Path::new),
+                output_dir: sub_m.value_of("output-dir").map(Path::new),
+                tar: sub_m.value_of("tar").map(Path::new),
+                force: sub_m.is_present("force"),
+            };
+            extract::extract(Path::new(sub_m.value_of("cache_device").unwrap()), &options)
+        }
+        ("carve", Some(sub_m)) => {
+            let blocks = sub_m.value_of("blocks").map(|spec| Selection::parse(spec).unwrap());
+            let options = CarveOptions {
+                cache_block_size: cache_block_size(sub_m, &[sub_m.value_of("cache_device")])?,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                blocks: blocks.as_ref(),
+                unmatched: sub_m.value_of("unmatched").map(Path::new),
+                output: sub_m.value_of("output").map(Path::new),
+                force: sub_m.is_present("force"),
+            };
+            carve::carve(Path::new(sub_m.value_of("cache_device").unwrap()), &options)
+        }
+        ("writecache", Some(sub_m)) => {
+            let options = WritecacheOptions {
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                block_size: parse_size(sub_m.value_of("block-size").unwrap()).unwrap(),
+                origin_size: sub_m.value_of("origin-size").map(|size| parse_size(size).unwrap()),
+                index: sub_m.value_of("index").map(Path::new),
+                content_only: sub_m.is_present("content-only"),
+                output: sub_m.value_of("output").map(Path::new),
+                force: sub_m.is_present("force"),
+            };
+            writecache::writecache(Path::new(sub_m.value_of("cache_device").unwrap()), &options)
+        }
+        ("coverage", Some(sub_m)) => {
+            let origin_size = match sub_m.value_of("origin") {
+                Some(origin) => open_device(
+                    Path::new(origin),
+                    sub_m.value_of("origin-partition").map(|p| p.parse::<usize>().unwrap()),
+                )?
+                .size(),
+                None => parse_size(sub_m.value_of("origin-size").unwrap()).unwrap(),
+            };
+            let options = CoverageOptions {
+                cache_block_size: sectors_arg(sub_m, "cache-block-size", None),
+                origin_size,
+                region_blocks: sub_m.value_of("region").unwrap().parse::<usize>().unwrap().max(1),
+                visualize: sub_m.value_of("visualize").map(Path::new),
+                report: sub_m.value_of("report").map(Path::new),
+            };
+            coverage::coverage(Path::new(sub_m.value_of("mapping").unwrap()), &options)
+        }
+        ("export", Some(sub_m)) => {
+            let entries = mapping::read(Path::new(sub_m.value_of("mapping").unwrap()))?;
+            let extents = export::coalesce(&entries);
+            let cache_block_size = sectors_arg(sub_m, "cache-block-size", None);
+            let sector_size = sector_size(sub_m, None);
+            let format = sub_m.value_of("format").unwrap();
+            eprintln!("{} mapped blocks in {} extents", entries.len(), extents.len());
+            match sub_m.value_of("output") {
+                Some(output) => export::write(
+                    &mut io::BufWriter::new(File::create(output)?),
+                    &extents,
+                    cache_block_size,
+                    sector_size,
+                    format,
+                ),
+                None => export::write(
+                    &mut io::stdout().lock(),
+                    &extents,
+                    cache_block_size,
+                    sector_size,
+                    format,
+                ),
+            }
         }
+        ("inspect", Some(sub_m)) => {
+            let options = InspectOptions {
+                block_size: sectors_arg(sub_m, "block-size", sub_m.value_of("device")),
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                index: sub_m.value_of("index").map(Path::new),
+                preview: parse_size(sub_m.value_of("preview").unwrap()).unwrap(),
+            };
+            inspect::inspect(
+                Path::new(sub_m.value_of("device").unwrap()),
+                sub_m.value_of("block").unwrap().parse::<usize>().unwrap(),
+                &options,
+            )
+        }
+        ("lookup", Some(sub_m)) => {
+            let values = |name| sub_m.values_of(name).map_or(Vec::new(), |values| values.collect());
+            let cache_devices: Vec<&Path> = values("cache-device").into_iter().map(Path::new).collect();
+            let options = LookupOptions {
+                cache_block_size: cache_block_size(sub_m, &[cache_devices.first().and_then(|path| path.to_str())])?,
+                digests: values("digest"),
+                files: values("file").into_iter().map(Path::new).collect(),
+                bytes: values("bytes"),
+                cache_devices,
+                partition: sub_m.value_of("partition").map(|p| p.parse::<usize>().unwrap()),
+                bwlimit: sub_m.value_of("bwlimit").map(|r| r.parse::<f64>().unwrap()),
+                force: sub_m.is_present("force"),
+            };
+            lookup::lookup(Path::new(sub_m.value_of("index").unwrap()), &options)
+        }
+        ("index", Some(sub_m)) => match sub_m.subcommand() {
+            ("convert", Some(sub_m)) => {
+                let options = ConvertOptions {
+                    explicit: if sub_m.is_present("explicit") {
+                        Some(true)
+                    } else if sub_m.is_present("positional") {
+                        Some(false)
+                    } else {
+                        None
+                    },
+                    shard_size: sub_m.value_of("shard-size").map(|n| parse_size(n).unwrap()),
+                    digest_bytes: sub_m.value_of("digest-bytes").map(|n| n.parse::<usize>().unwrap()),
+                    drop_filter: sub_m.is_present("drop-filter"),
+                    origin: sub_m.value_of("origin").map(Path::new),
+                    device_size: sub_m.value_of("device-size").map(|n| parse_size(n).unwrap()),
+                };
+                convert::convert(
+                    Path::new(sub_m.value_of("index").unwrap()),
+                    Path::new(sub_m.value_of("output").unwrap()),
+                    &options,
+                )
+            }
+            ("gc", Some(sub_m)) => {
+                let exclude = match sub_m.value_of("exclude-ranges") {
+                    Some(path) => {
+                        let mut exclude = Selection::default();
+                        exclude.add_file(Path::new(path))?;
+                        Some(exclude)
+                    }
+                    None => None,
+                };
+                let options = GcOptions {
+                    max_duplicates: sub_m.value_of("max-duplicates").unwrap().parse::<usize>().unwrap(),
+                    keep_zero: sub_m.is_present("keep-zero"),
+                    exclude: exclude.as_ref(),
+                    shard_size: sub_m.value_of("shard-size").map(|n| parse_size(n).unwrap()),
+                    force: sub_m.is_present("force"),
+                };
+                gc::gc(
+                    Path::new(sub_m.value_of("index").unwrap()),
+                    Path::new(sub_m.value_of("output").unwrap()),
+                    &options,
+                )
+            }
+            _ => Ok(()),
+        },
+        ("lvm", Some(sub_m)) => {
+            let pvs: Vec<&Path> = sub_m.values_of("pv").unwrap().map(Path::new).collect();
+            lvm::print(&lvm::discover(&pvs)?);
+            Ok(())
+        }
+        ("list-partitions", Some(sub_m)) => list_partitions(Path::new(sub_m.value_of("device").unwrap())),
+        ("serve", Some(sub_m)) => serve::serve(Path::new(sub_m.value_of("socket").unwrap())),
+        ("completions", Some(sub_m)) => {
+            let shell = sub_m.value_of("shell").unwrap().parse().unwrap();
+            app.clone().gen_completions_to("cache_guess", shell, &mut io::stdout());
+            Ok(())
+        }
+        ("config", Some(sub_m)) => match sub_m.subcommand() {
+            ("init", Some(sub_m)) => config::init(&app, sub_m.value_of("output").map(Path::new)),
+            _ => Ok(()),
+        },
         _ => Ok(()),
+    };
+    luks::close_all();
+    if let (Some(subcommand), Some(path)) = (
+        matches.subcommand_name(),
+        matches.subcommand().1.and_then(|sub_m| sub_m.value_of("metrics")),
+    ) {
+        if let Err(e) = metrics::write(Path::new(path), subcommand, started, result.is_ok()) {
+            eprintln!("warning: could not write the metrics to {}: {}", path, e);
+        }
+    }
+    let error_report = matches.subcommand().1.and_then(|sub_m| sub_m.value_of("error-report"));
+    if let Err(e) = block_errors::summarize(error_report.map(Path::new)) {
+        eprintln!("warning: could not write the error summary: {}", e);
+    }
+    if result.is_ok() && block_errors::count() > 0 {
+        process::exit(block_errors::EXIT_CODE);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_with_suffixes_in_either_case() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("256k"), Ok(256 << 10));
+        assert_eq!(parse_size("256K"), Ok(256 << 10));
+        assert_eq!(parse_size("64KiB"), Ok(64 << 10));
+        assert_eq!(parse_size("8m"), Ok(8 << 20));
+        assert_eq!(parse_size("8g"), Ok(8 << 30));
+        assert_eq!(parse_size("8GIB"), Ok(8 << 30));
+        assert_eq!(parse_size("2T"), Ok(2 << 40));
+        assert_eq!(parse_size("2tib"), Ok(2 << 40));
+    }
+
+    #[test]
+    fn refuses_sizes_that_are_not() {
+        let e = parse_size("8x").unwrap_err();
+        assert!(e.contains("unknown size suffix 'x'"), "{}", e);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("-4K").is_err());
+        let e = parse_size(&format!("{}T", usize::MAX >> 20)).unwrap_err();
+        assert!(e.contains("too large"), "{}", e);
+    }
+
+    #[test]
+    fn refuses_zero_where_it_makes_no_sense() {
+        assert_eq!(is_positive("1".to_string()), Ok(()));
+        assert_eq!(
+            is_positive("0".to_string()),
+            Err("'0' is not a positive number".to_string())
+        );
+        assert!(is_positive("-1".to_string()).is_err());
+        assert_eq!(is_positive_size("1".to_string()), Ok(()));
+        assert_eq!(
+            is_positive_size("0K".to_string()),
+            Err("'0K' is not a positive size".to_string())
+        );
+        assert!(is_positive_size("0x".to_string())
+            .unwrap_err()
+            .contains("unknown size suffix"));
+        assert_eq!(is_number("0".to_string()), Ok(()));
     }
 }
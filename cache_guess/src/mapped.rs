@@ -0,0 +1,237 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use memmap2::{Mmap, MmapMut, MmapOptions};
+
+use crate::split;
+
+/// A read-only view of a device or image, optionally narrowed to a window such as a partition.
+#[derive(Debug)]
+pub struct MappedFile {
+    pub mmap: Mmap,
+    pub base: usize,
+    size: usize,
+    /// The file the mapping is of, which is not the path it was opened by for an unlocked LUKS device or a
+    /// snapshot; none for split series and for contents put together in memory.
+    file: Option<File>,
+}
+
+// Offsets and sizes are usize throughout because they index straight into mappings, and the on-disk formats
+// store them as u64; a narrower usize would wrap past 4 GiB instead of failing.
+const _: () = assert!(usize::BITS >= u64::BITS, "device offsets need a 64-bit target");
+
+const BLKSSZGET: libc::c_ulong = 0x1268;
+const BLKPBSZGET: libc::c_ulong = 0x127b;
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// The length of a regular file or block device; `metadata().len()` is 0 for the latter, so block devices are
+/// asked with BLKGETSIZE64, and seeking to the end is the fallback for anything that refuses.
+pub fn device_len(file: &mut File) -> io::Result<usize> {
+    if file.metadata()?.file_type().is_block_device() {
+        let mut size: u64 = 0;
+        if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } == 0 {
+            return Ok(size as usize);
+        }
+    }
+    Ok(file.seek(SeekFrom::End(0))? as usize)
+}
+
+/// The logical sector size of a block device, 512 for anything else.
+pub fn logical_sector_size(path: &Path) -> usize {
+    let Ok(file) = File::open(path) else {
+        return 512;
+    };
+    let mut size: libc::c_int = 0;
+    match unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET as _, &mut size) } {
+        0 if size > 0 => size as usize,
+        _ => 512,
+    }
+}
+
+/// The physical sector size of a block device, which may be larger than its logical one (512e drives); 512 for
+/// anything else.
+pub fn physical_sector_size(path: &Path) -> usize {
+    let Ok(file) = File::open(path) else {
+        return 512;
+    };
+    let mut size: libc::c_uint = 0;
+    match unsafe { libc::ioctl(file.as_raw_fd(), BLKPBSZGET as _, &mut size) } {
+        0 if size > 0 => size as usize,
+        _ => logical_sector_size(path),
+    }
+}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(chunks) = split::series(path) {
+            let (mmap, size) = split::map(&chunks)?;
+            return Ok(Self::from_mmap(mmap, size));
+        }
+        let mut file = File::open(path)?;
+        let size = device_len(&mut file)?;
+        let mmap = unsafe { MmapOptions::new().len(size).map(&file)? };
+        Ok(Self {
+            mmap,
+            base: 0,
+            size,
+            file: Some(file),
+        })
+    }
+
+    /// Contents that were put together in memory rather than read from a file.
+    pub fn from_mmap(mmap: Mmap, size: usize) -> Self {
+        Self {
+            mmap,
+            base: 0,
+            size,
+            file: None,
+        }
+    }
+
+    pub fn window(self, offset: usize, size: usize) -> io::Result<Self> {
+        if offset + size > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("range {}+{} is past the device end", offset, size),
+            ));
+        }
+        Ok(Self {
+            base: self.base + offset,
+            size,
+            ..self
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The file the view was mapped from, to read the same bytes with plain reads; `base` is the view's offset in it.
+    pub fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+
+    pub fn slice(&self, offset: usize, len: usize) -> &[u8] {
+        &self.mmap[self.base + offset..self.base + offset + len]
+    }
+}
+
+/// A shared, writable mapping. Kept apart from `MappedFile` so that nothing that only reads can end up holding one.
+#[derive(Debug)]
+pub struct WritableMappedFile {
+    mmap: MmapMut,
+    file: File,
+    pub base: usize,
+    size: usize,
+}
+
+impl WritableMappedFile {
+    /// Creates or truncates a file of `size` bytes, for output such as an index.
+    pub fn create(path: &Path, size: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(size as u64)?;
+        let mmap = unsafe { MmapOptions::new().map_mut(&file)? };
+        Ok(Self {
+            mmap,
+            file,
+            base: 0,
+            size,
+        })
+    }
+
+    /// Opens an existing device for writing, with `O_EXCL` so the kernel refuses a block device that is mounted
+    /// or claimed by device-mapper. Split images are never written to.
+    pub fn open_existing(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if split::series(path).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is the first file of a split image, which cannot be written to",
+                    path.display()
+                ),
+            ));
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_EXCL)
+            .open(path)?;
+        let size = device_len(&mut file)?;
+        let mmap = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+        Ok(Self {
+            mmap,
+            file,
+            base: 0,
+            size,
+        })
+    }
+
+    pub fn window(self, offset: usize, size: usize) -> io::Result<Self> {
+        if offset + size > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("range {}+{} is past the device end", offset, size),
+            ));
+        }
+        Ok(Self {
+            base: self.base + offset,
+            size,
+            ..self
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn slice_mut(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        &mut self.mmap[self.base + offset..self.base + offset + len]
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.mmap.flush()
+    }
+
+    pub fn slice(&self, offset: usize, len: usize) -> &[u8] {
+        &self.mmap[self.base + offset..self.base + offset + len]
+    }
+
+    /// Drops the flushed pages of a range from this mapping and the page cache, so that reading it again goes to
+    /// the device rather than returning what was just written. Only as good as the device's own write cache.
+    pub fn evict(&self, offset: usize, len: usize) -> io::Result<()> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = (self.base + offset) / page_size * page_size;
+        let end = self.base + offset + len;
+        let result = unsafe {
+            libc::madvise(
+                self.mmap.as_ptr().add(start) as *mut libc::c_void,
+                end - start,
+                libc::MADV_DONTNEED,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        match unsafe {
+            libc::posix_fadvise(
+                self.file.as_raw_fd(),
+                start as libc::off_t,
+                (end - start) as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            )
+        } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::provenance;
+
+/// The best match of one cache block, as printed by `find`, or a copy planned by `plan-writeback` or `classify`.
+#[derive(Debug, Clone)]
+pub struct MappingEntry {
+    pub cache_block: usize,
+    pub origin_block: usize,
+    /// Other candidates scored exactly as well, so the content alone does not decide the mapping.
+    pub tied: bool,
+    /// As scored by `find`; plans and reconciled mappings carry none.
+    pub confidence: Option<f64>,
+    /// Share of the fs-blocks found in place, in percent; likewise only from `find`.
+    pub match_percent: Option<f64>,
+}
+
+fn parse_line(line: &str) -> Option<MappingEntry> {
+    let (cache_block, rest) = line.split_once(" -> ")?;
+    // `plan-writeback` and `classify` lines carry a `# reason` instead of match statistics.
+    let (origin_block, match_percent, notes) = match rest.split_once(" (") {
+        Some((origin_block, rest)) => {
+            let (match_percent, notes) = rest.split_once("% match")?;
+            (origin_block, match_percent.parse().ok(), notes)
+        }
+        None => {
+            let (origin_block, notes) = rest.split_once(" # ")?;
+            (origin_block, None, notes)
+        }
+    };
+    let confidence = notes
+        .strip_prefix(", ")
+        .and_then(|notes| notes.split_once(" confidence)"))
+        .and_then(|(confidence, _)| confidence.parse().ok());
+    Some(MappingEntry {
+        cache_block: cache_block.trim().parse().ok()?,
+        origin_block: origin_block.trim().parse().ok()?,
+        tied: notes.contains(" [tied]"),
+        confidence,
+        match_percent,
+    })
+}
+
+fn json_number(line: &str, key: &str) -> Option<usize> {
+    let (_, rest) = line.split_once(&format!("\"{}\":", key))?;
+    let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+fn json_float(line: &str, key: &str) -> Option<f64> {
+    let (_, rest) = line.split_once(&format!("\"{}\":", key))?;
+    let end = rest.find([',', '}']).unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// A `--format jsonl` line; the first candidate is the best one. Blocks without candidates, partial cache blocks at
+/// the device end, checkpoints, device labels and the provenance yield `Some(None)`.
+fn parse_json_line(line: &str) -> Option<Option<MappingEntry>> {
+    if line.starts_with("{\"checkpoint\":")
+        || line.starts_with("{\"device\":")
+        || line.starts_with(provenance::JSON_PROVENANCE)
+    {
+        return Some(None);
+    }
+    let cache_block = json_number(line, "cache_block")?;
+    if line.contains("\"partial_fs_blocks\":") {
+        return Some(None);
+    }
+    Some(json_number(line, "origin_block").map(|origin_block| MappingEntry {
+        cache_block,
+        origin_block,
+        tied: line.contains("\"tie\":true"),
+        confidence: json_float(line, "confidence"),
+        match_percent: json_float(line, "match"),
+    }))
+}
+
+/// A `--format csv` row under the header `columns`. Only a leading `device` column can hold a quoted comma, so the
+/// row is split from the end. Unmatched blocks and partial ones at the device end, classified `short`, yield
+/// `Some(None)`.
+fn parse_csv_line(line: &str, columns: &[String]) -> Option<Option<MappingEntry>> {
+    let mut fields: Vec<&str> = line.rsplitn(columns.len(), ',').collect();
+    if fields.len() != columns.len() {
+        return None;
+    }
+    fields.reverse();
+    let field = |name: &str| columns.iter().position(|column| column == name).map(|i| fields[i]);
+    let cache_block = field("cache_block")?.parse().ok()?;
+    let origin_block = field("origin_block")?;
+    if origin_block.is_empty() || field("classification") == Some("short") {
+        return Some(None);
+    }
+    Some(Some(MappingEntry {
+        cache_block,
+        origin_block: origin_block.parse().ok()?,
+        tied: field("classification") == Some("tied"),
+        confidence: field("confidence").and_then(|confidence| confidence.parse().ok()),
+        match_percent: field("match").and_then(|match_percent| match_percent.parse().ok()),
+    }))
+}
+
+/// Reads the best-match lines of a `find` result file; `#` lines (runner-ups, notes, the match of a partial cache
+/// block at the device end) are skipped.
+pub fn read(path: &Path) -> io::Result<Vec<MappingEntry>> {
+    let mut entries = Vec::new();
+    let mut csv_columns: Option<Vec<String>> = None;
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with("cache_block,") || line.starts_with("device,cache_block,") {
+            csv_columns = Some(line.split(',').map(str::to_string).collect());
+            continue;
+        }
+        let entry = if let Some(columns) = &csv_columns {
+            parse_csv_line(&line, columns)
+        } else if line.starts_with('{') {
+            parse_json_line(&line)
+        } else {
+            parse_line(&line).map(Some)
+        };
+        let entry = entry.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: not a mapping line: '{}'", path.display(), number + 1, line),
+            )
+        })?;
+        entries.extend(entry);
+    }
+    provenance::record_input(path, format!("mapping, {} entries", entries.len()));
+    Ok(entries)
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use std::io;
+
+/// Memory this process can still allocate: `MemAvailable`, capped by the cgroup v2 limit when there is one.
+pub fn available() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let mut available = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<usize>()
+        .ok()?
+        * 1024;
+    let cgroup = |name: &str| {
+        fs::read_to_string(format!("/sys/fs/cgroup/{}", name))
+            .ok()
+            .and_then(|value| value.trim().parse::<usize>().ok())
+    };
+    // memory.max is "max" without a limit, which does not parse.
+    if let (Some(max), Some(current)) = (cgroup("memory.max"), cgroup("memory.current")) {
+        available = available.min(max.saturating_sub(current));
+    }
+    Some(available)
+}
+
+pub fn gib(bytes: usize) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64)
+}
+
+/// Refuses to start loading `what` when it would need more memory than is available, unless forced.
+pub fn preflight(what: &str, needed: usize, advice: &str, force: bool) -> io::Result<()> {
+    let Some(available) = available() else {
+        return Ok(());
+    };
+    eprintln!(
+        "{} needs about {} of memory, {} available",
+        what,
+        gib(needed),
+        gib(available)
+    );
+    if needed <= available {
+        return Ok(());
+    }
+    let msg = format!(
+        "{} needs about {} of memory but only {} is available; {}",
+        what,
+        gib(needed),
+        gib(available),
+        advice
+    );
+    if !force {
+        return Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!("{} (--force to try anyway)", msg),
+        ));
+    }
+    eprintln!("warning: {}", msg);
+    Ok(())
+}
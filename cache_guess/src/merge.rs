@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::conflict::{self, Conflict};
+use crate::mapping::{self, MappingEntry};
+use crate::output::Output;
+use crate::throttle::Throttle;
+use crate::verify::Reader;
+use crate::{inuse, log_complete, log_status, open_device};
+
+pub struct MergeOptions<'a> {
+    pub cache_block_size: usize,
+    pub partition: Option<usize>,
+    pub origin_partition: Option<usize>,
+    pub conflict: Conflict,
+    /// As for apply.
+    pub expected_mapping: Option<&'a Path>,
+    pub duplicates: Option<&'a Path>,
+    pub bwlimit: Option<f64>,
+    pub force: bool,
+}
+
+/// Writes the recovered image: the origin front to back, with every mapped block taken from the cache instead.
+/// `output` of `-` streams it to stdout, so it can go straight into a compressor or over the network.
+pub fn merge(
+    mapping_path: &Path,
+    cache_device_path: &Path,
+    origin_path: &Path,
+    output: &Path,
+    options: &MergeOptions,
+) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path, origin_path], options.force)?;
+    let to_stdout = output == Path::new("-");
+    if !to_stdout && fs::canonicalize(output).ok() == Some(fs::canonicalize(origin_path)?) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the output must not be the origin, use apply to write to it in place",
+        ));
+    }
+
+    let cache_block_size = options.cache_block_size;
+    let (tied, entries): (Vec<MappingEntry>, Vec<MappingEntry>) =
+        mapping::read(mapping_path)?.into_iter().partition(|entry| entry.tied);
+    if !tied.is_empty() {
+        eprintln!("leaving {} cache blocks with tied candidates out", tied.len());
+    }
+    let cache_device = Reader::open(cache_device_path, options.partition)?;
+    let entries = conflict::resolve_copies(
+        entries,
+        options.conflict,
+        &cache_device,
+        cache_block_size,
+        options.expected_mapping,
+        options.duplicates,
+    )?;
+    let origin = open_device(origin_path, options.origin_partition)?;
+    let mut sources: HashMap<usize, usize> = HashMap::new();
+    for entry in &entries {
+        if (entry.cache_block + 1) * cache_block_size > cache_device.size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cache block {} is past the cache device end", entry.cache_block),
+            ));
+        }
+        if entry.origin_block * cache_block_size >= origin.size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("origin block {} is past the origin device end", entry.origin_block),
+            ));
+        }
+        sources.insert(entry.origin_block, entry.cache_block);
+    }
+
+    let mut out = BufWriter::new(Output::create((!to_stdout).then_some(output))?);
+    let mut throttle = Throttle::new(options.bwlimit);
+    let size = origin.size();
+    for (origin_block, offset) in (0..size).step_by(cache_block_size).enumerate() {
+        if origin_block % 64 == 0 {
+            log_status(offset, size, "bytes", false);
+        }
+        let len = cache_block_size.min(size - offset);
+        throttle.consume(len);
+        match sources.get(&origin_block) {
+            Some(&cache_block) => out.write_all(cache_device.view().slice(cache_block * cache_block_size, len))?,
+            None => out.write_all(origin.slice(offset, len))?,
+        }
+    }
+    out.into_inner().map_err(|e| e.into_error())?.commit()?;
+    log_complete(size, "bytes");
+    eprintln!(
+        "\n{} of {} origin blocks taken from the cache",
+        sources.len(),
+        size.div_ceil(cache_block_size)
+    );
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::output::{json_string, Output};
+use crate::provenance;
+
+/// What `hash_blocks` hashed, and the nanoseconds it took over it on all threads together.
+static HASHED_BYTES: AtomicU64 = AtomicU64::new(0);
+static HASH_NANOS: AtomicU64 = AtomicU64::new(0);
+
+struct Phase {
+    unit: String,
+    done: usize,
+    seconds: Option<f64>,
+}
+
+/// Every progress phase that completed, in order.
+static PHASES: Mutex<Vec<Phase>> = Mutex::new(Vec::new());
+/// Counts and durations in seconds by name, in the order they were first added to.
+static COUNTERS: Mutex<Vec<(&str, usize)>> = Mutex::new(Vec::new());
+static TIMINGS: Mutex<Vec<(&str, f64)>> = Mutex::new(Vec::new());
+
+pub fn hashed(bytes: usize, elapsed: Duration) {
+    HASHED_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+    HASH_NANOS.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub fn phase(unit: &str, done: usize, elapsed: Option<Duration>) {
+    PHASES.lock().unwrap().push(Phase {
+        unit: unit.to_string(),
+        done,
+        seconds: elapsed.map(|elapsed| elapsed.as_secs_f64()),
+    });
+}
+
+pub fn count(name: &'static str, n: usize) {
+    let mut counters = COUNTERS.lock().unwrap();
+    match counters.iter_mut().find(|(counter, _)| *counter == name) {
+        Some((_, total)) => *total += n,
+        None => counters.push((name, n)),
+    }
+}
+
+pub fn time(name: &'static str, elapsed: Duration) {
+    let mut timings = TIMINGS.lock().unwrap();
+    match timings.iter_mut().find(|(timing, _)| *timing == name) {
+        Some((_, total)) => *total += elapsed.as_secs_f64(),
+        None => timings.push((name, elapsed.as_secs_f64())),
+    }
+}
+
+/// Fields of a `key: value` file under /proc/self, such as `io` and `status`.
+fn proc_field(file: &str, name: &str) -> Option<usize> {
+    fs::read_to_string(format!("/proc/self/{}", file))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn json_number(value: Option<f64>) -> String {
+    value.map_or("null".to_string(), |value| format!("{:.3}", value))
+}
+
+fn json_count(value: Option<usize>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
+
+/// Writes what the run measured to `path` as one JSON object, for comparing runs with different parameters.
+pub fn write(path: &Path, subcommand: &str, started: Instant, ok: bool) -> io::Result<()> {
+    let phases: Vec<String> = PHASES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|phase| {
+            let rate = phase.seconds.map(|seconds| phase.done as f64 / seconds.max(1e-3));
+            format!(
+                "{{\"unit\": {}, \"done\": {}, \"seconds\": {}, \"rate\": {}}}",
+                json_string(&phase.unit),
+                phase.done,
+                json_number(phase.seconds),
+                json_number(rate)
+            )
+        })
+        .collect();
+    let counters: Vec<String> = COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, count)| format!("\"{}\": {}", name, count))
+        .collect();
+    let timings: Vec<String> = TIMINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, seconds)| format!("\"{}_seconds\": {:.3}", name, seconds))
+        .collect();
+    let hashed = HASHED_BYTES.load(Ordering::Relaxed);
+    let hash_seconds = HASH_NANOS.load(Ordering::Relaxed) as f64 / 1e9;
+    let hash_rate = (hash_seconds > 0.0).then(|| hashed as f64 / hash_seconds);
+    let mut out = Output::create(Some(path))?;
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"command\": {},", json_string(&provenance::command_line()))?;
+    writeln!(out, "  \"subcommand\": {},", json_string(subcommand))?;
+    writeln!(out, "  \"ok\": {},", ok)?;
+    writeln!(out, "  \"wall_seconds\": {:.3},", started.elapsed().as_secs_f64())?;
+    writeln!(out, "  \"phases\": [{}],", phases.join(", "))?;
+    writeln!(out, "  \"timings\": {{{}}},", timings.join(", "))?;
+    writeln!(
+        out,
+        "  \"hash\": {{\"bytes\": {}, \"seconds\": {:.3}, \"bytes_per_second\": {}}},",
+        hashed,
+        hash_seconds,
+        json_number(hash_rate)
+    )?;
+    writeln!(out, "  \"counters\": {{{}}},", counters.join(", "))?;
+    // Storage I/O as the kernel accounted it, page cache hits left out; rchar and wchar include them.
+    writeln!(
+        out,
+        "  \"io\": {{\"read_bytes\": {}, \"write_bytes\": {}, \"rchar\": {}, \"wchar\": {}}},",
+        json_count(proc_field("io", "read_bytes")),
+        json_count(proc_field("io", "write_bytes")),
+        json_count(proc_field("io", "rchar")),
+        json_count(proc_field("io", "wchar"))
+    )?;
+    writeln!(
+        out,
+        "  \"peak_memory_bytes\": {}",
+        json_count(proc_field("status", "VmHWM").map(|kib| kib * 1024))
+    )?;
+    writeln!(out, "}}")?;
+    out.commit()
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::output::Output;
+use crate::throttle::Throttle;
+use crate::verify::{read_mapping, Reader, VerifyOptions};
+use crate::{hash_block, inuse, log_complete, log_status, provenance, BLOCK_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Source {
+    Either,
+    A,
+    B,
+    Unknown,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Either => "either",
+            Self::A => "a",
+            Self::B => "b",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// The fs-blocks of one cache-block-sized region of a mirror half, `Err` for those that could not be read. A read
+/// error on the whole region is retried an fs-block at a time, so one bad sector costs only its own fs-block.
+fn read_region(half: &Reader, region: usize, cache_block_size: usize) -> Vec<Result<Vec<u8>, String>> {
+    if let Ok(data) = half.read_unrecorded(region, cache_block_size) {
+        return data.chunks(BLOCK_SIZE).map(|chunk| Ok(chunk.to_vec())).collect();
+    }
+    let fs_blocks = cache_block_size / BLOCK_SIZE;
+    (region * fs_blocks..(region + 1) * fs_blocks)
+        .map(|block| half.read(block, BLOCK_SIZE))
+        .collect()
+}
+
+/// As `Reader::read` reports it.
+const PAST_END: &str = "past the device end";
+
+/// Which half to trust for one fs-block, and why.
+fn decide(a: &Result<Vec<u8>, String>, b: &Result<Vec<u8>, String>, cached: &[Vec<u8>]) -> (Source, &'static str) {
+    let (a, b) = match (a, b) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Ok(_), Err(e)) if e == PAST_END => return (Source::A, "past the end of b"),
+        (Err(e), Ok(_)) if e == PAST_END => return (Source::B, "past the end of a"),
+        (Ok(_), Err(_)) => return (Source::A, "b unreadable"),
+        (Err(_), Ok(_)) => return (Source::B, "a unreadable"),
+        (Err(_), Err(_)) => return (Source::Unknown, "unreadable on both halves"),
+    };
+    if a == b {
+        return (Source::Either, "identical");
+    }
+    if cached.is_empty() {
+        return (Source::Unknown, "halves differ, no cache copy");
+    }
+    let (a, b) = (hash_block(a), hash_block(b));
+    let digests: Vec<Vec<u8>> = cached.iter().map(|data| hash_block(data)).collect();
+    match (digests.contains(&a), digests.contains(&b)) {
+        (true, false) => (Source::A, "cache copy matches a"),
+        (false, true) => (Source::B, "cache copy matches b"),
+        _ => (Source::Unknown, "halves differ, cache copy matches neither"),
+    }
+}
+
+/// Compares the two halves of a RAID1 origin fs-block by fs-block and decides which one to trust where they
+/// disagree: the half that holds the same data as the mapped cache block wins. Writes the decisions as runs of
+/// fs-blocks, `first..end source reason`, where the source is `a`, `b`, `either` or `unknown`.
+pub fn compare(
+    mapping_path: &Path,
+    cache_device_path: &Path,
+    half_paths: [&Path; 2],
+    output: Option<&Path>,
+    options: &VerifyOptions,
+) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path, half_paths[0], half_paths[1]], options.force)?;
+    let cache_block_size = options.cache_block_size;
+    let mut mapped: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut tied = 0;
+    for entry in read_mapping(mapping_path, cache_block_size)? {
+        if entry.tied {
+            tied += 1;
+        } else {
+            mapped.entry(entry.origin_block).or_default().push(entry.cache_block);
+        }
+    }
+    if tied > 0 {
+        eprintln!("ignoring {} tied mapping entries", tied);
+    }
+    let cache_device = Reader::open(cache_device_path, options.partition)?;
+    let a = Reader::open(half_paths[0], options.origin_partition)?;
+    let b = Reader::open(half_paths[1], options.origin_partition)?;
+    if a.size() != b.size() {
+        eprintln!(
+            "warning: the halves differ in size ({} and {} bytes); past the smaller one only the larger is used",
+            a.size(),
+            b.size()
+        );
+    }
+    let mut throttle = Throttle::new(options.bwlimit);
+
+    let fs_blocks = cache_block_size / BLOCK_SIZE;
+    let regions = a.size().max(b.size()).div_ceil(cache_block_size);
+    let mut out = Output::create(output)?;
+    provenance::write_comments(&mut out)?;
+    writeln!(
+        out,
+        "# a = {}, b = {}; runs of {} byte fs-blocks: first..end source reason",
+        half_paths[0].display(),
+        half_paths[1].display(),
+        BLOCK_SIZE
+    )?;
+    let mut run: Option<(usize, Source, &str)> = None;
+    let mut counts: HashMap<Source, usize> = HashMap::new();
+    for region in 0..regions {
+        log_status(region, regions, "regions", false);
+        throttle.consume(2 * cache_block_size);
+        let (data_a, data_b) = (
+            read_region(&a, region, cache_block_size),
+            read_region(&b, region, cache_block_size),
+        );
+        let differ = data_a
+            .iter()
+            .zip(&data_b)
+            .any(|(a, b)| a.is_err() || b.is_err() || a != b);
+        let cached: Vec<Vec<u8>> = match mapped.get(&region) {
+            Some(cache_blocks) if differ => cache_blocks
+                .iter()
+                .filter_map(|&cache_block| {
+                    throttle.consume(cache_block_size);
+                    cache_device.read(cache_block, cache_block_size).ok()
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        for (i, (block_a, block_b)) in data_a.iter().zip(&data_b).enumerate() {
+            let block = region * fs_blocks + i;
+            if block * BLOCK_SIZE >= a.size().max(b.size()) {
+                break;
+            }
+            let copies: Vec<Vec<u8>> = cached
+                .iter()
+                .map(|data| data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE].to_vec())
+                .collect();
+            let (source, reason) = decide(block_a, block_b, &copies);
+            *counts.entry(source).or_insert(0) += 1;
+            match run {
+                Some((_, run_source, run_reason)) if run_source == source && run_reason == reason => {}
+                _ => {
+                    if let Some((first, run_source, run_reason)) = run {
+                        writeln!(out, "{}..{} {} {}", first, block, run_source.name(), run_reason)?;
+                    }
+                    run = Some((block, source, reason));
+                }
+            }
+        }
+    }
+    log_complete(regions, "regions");
+    if let Some((first, source, reason)) = run {
+        let end = a.size().max(b.size()).div_ceil(BLOCK_SIZE);
+        writeln!(out, "{}..{} {} {}", first, end, source.name(), reason)?;
+    }
+    out.commit()?;
+    let count = |source| counts.get(&source).copied().unwrap_or(0);
+    eprintln!(
+        "\n{} fs-blocks identical, {} from a, {} from b, {} undecided",
+        count(Source::Either),
+        count(Source::A),
+        count(Source::B),
+        count(Source::Unknown)
+    );
+    Ok(())
+}
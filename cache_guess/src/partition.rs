@@ -0,0 +1,163 @@
+use std::io;
+
+use crate::MappedFile;
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_PROTECTIVE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+#[derive(Debug)]
+pub struct Partition {
+    pub number: usize,
+    pub start: usize,
+    pub size: usize,
+    pub kind: String,
+    pub name: String,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read(device: &MappedFile, offset: usize, len: usize) -> io::Result<&[u8]> {
+    if offset.checked_add(len).is_none_or(|end| end > device.size()) {
+        return Err(invalid(format!(
+            "partition table read of {} bytes at {} is past the device end",
+            len, offset
+        )));
+    }
+    Ok(device.slice(offset, len))
+}
+
+fn format_guid(guid: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{}",
+        le32(guid, 0),
+        le16(guid, 4),
+        le16(guid, 6),
+        guid[8],
+        guid[9],
+        guid[10..16].iter().map(|b| format!("{:02x}", b)).collect::<String>()
+    )
+}
+
+fn is_extended(kind: u8) -> bool {
+    matches!(kind, 0x05 | 0x0f | 0x85)
+}
+
+/// Reads the GPT if present (trying 512 and 4096 byte sectors), the MBR otherwise.
+pub fn read_table(device: &MappedFile) -> io::Result<Vec<Partition>> {
+    let mbr = read(device, 0, 512)?;
+    if le16(mbr, 510) != MBR_SIGNATURE {
+        return Err(invalid("no MBR signature found".to_string()));
+    }
+    if (0..4).any(|i| mbr[446 + i * 16 + 4] == MBR_PROTECTIVE) {
+        for sector_size in [512, 4096] {
+            if let Some(partitions) = read_gpt(device, sector_size)? {
+                return Ok(partitions);
+            }
+        }
+        return Err(invalid("protective MBR without a GPT header".to_string()));
+    }
+    read_mbr(device)
+}
+
+fn read_gpt(device: &MappedFile, sector_size: usize) -> io::Result<Option<Vec<Partition>>> {
+    let header = read(device, sector_size, 92)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let entries_lba = le64(header, 72) as usize;
+    let entry_count = le32(header, 80) as usize;
+    let entry_size = le32(header, 84) as usize;
+    if entry_size < 128 {
+        return Err(invalid(format!("bad GPT entry size {}", entry_size)));
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..entry_count {
+        let entry = read(device, entries_lba * sector_size + i * entry_size, 128)?;
+        if entry[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = le64(entry, 32) as usize;
+        let last = le64(entry, 40) as usize;
+        let name: Vec<u16> = (0..36)
+            .map(|c| le16(entry, 56 + c * 2))
+            .take_while(|&c| c != 0)
+            .collect();
+        partitions.push(Partition {
+            number: i + 1,
+            start: first * sector_size,
+            size: (last + 1).saturating_sub(first) * sector_size,
+            kind: format_guid(&entry[0..16]),
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+    Ok(Some(partitions))
+}
+
+fn read_mbr(device: &MappedFile) -> io::Result<Vec<Partition>> {
+    let mbr = read(device, 0, 512)?;
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        let kind = entry[4];
+        let start = le32(entry, 8) as usize * 512;
+        let size = le32(entry, 12) as usize * 512;
+        if kind == 0 || size == 0 {
+            continue;
+        }
+        if is_extended(kind) {
+            extended = Some(start);
+        }
+        partitions.push(Partition {
+            number: i + 1,
+            start,
+            size,
+            kind: format!("{:#04x}", kind),
+            name: String::new(),
+        });
+    }
+
+    // Logical partitions form a chain of EBRs, each relative to the start of the extended partition.
+    if let Some(extended_start) = extended {
+        let mut ebr_offset = extended_start;
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+            let ebr = read(device, ebr_offset, 512)?;
+            if le16(ebr, 510) != MBR_SIGNATURE {
+                return Err(invalid(format!("bad EBR signature at {}", ebr_offset)));
+            }
+            let size = le32(ebr, 446 + 12) as usize * 512;
+            if size != 0 {
+                partitions.push(Partition {
+                    number,
+                    start: ebr_offset + le32(ebr, 446 + 8) as usize * 512,
+                    size,
+                    kind: format!("{:#04x}", ebr[446 + 4]),
+                    name: String::new(),
+                });
+            }
+            let next = le32(ebr, 462 + 8) as usize * 512;
+            if next == 0 || !is_extended(ebr[462 + 4]) {
+                break;
+            }
+            ebr_offset = extended_start + next;
+        }
+    }
+    Ok(partitions)
+}
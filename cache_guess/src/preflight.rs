@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::find::lookup_entry_bytes;
+use crate::identity::Identity;
+use crate::index::Index;
+use crate::layout::Layout;
+use crate::superblock::Superblock;
+use crate::verify::Reader;
+use crate::{check_alignment, format_duration, hash, inuse, memory, BLOCK_SIZE, HASH_BYTES};
+
+/// What dm-cache accepts as a data block size.
+const MIN_CACHE_BLOCK: usize = 32 << 10;
+const MAX_CACHE_BLOCK: usize = 1 << 30;
+
+pub struct PreflightOptions<'a> {
+    pub cache_block_size: usize,
+    /// `--cache-block-size` was given rather than defaulted, so it is checked against the metadata.
+    pub explicit_block_size: bool,
+    pub origin: Option<&'a Path>,
+    pub metadata_device: Option<&'a Path>,
+    pub partition: Option<usize>,
+    pub origin_partition: Option<usize>,
+    /// Cache blocks spread over the device that are matched against the index.
+    pub samples: usize,
+}
+
+/// What the checks found, printed as it is found.
+#[derive(Default)]
+struct Findings {
+    problems: usize,
+    warnings: usize,
+}
+
+impl Findings {
+    fn ok(&mut self, msg: String) {
+        println!("ok: {}", msg);
+    }
+
+    fn warn(&mut self, msg: String) {
+        println!("warning: {}", msg);
+        self.warnings += 1;
+    }
+
+    fn problem(&mut self, msg: String) {
+        println!("problem: {}", msg);
+        self.problems += 1;
+    }
+}
+
+/// Opens a device and reads its first and last fs-block, so an unreadable one fails here and not hours into a run.
+fn readable(what: &str, path: &Path, partition: Option<usize>, findings: &mut Findings) -> Option<Reader> {
+    let reader = match Reader::open(path, partition) {
+        Ok(reader) => reader,
+        Err(e) => {
+            findings.problem(format!("{} {}: {}", what, path.display(), e));
+            return None;
+        }
+    };
+    let fs_blocks = reader.size() / BLOCK_SIZE;
+    if fs_blocks == 0 {
+        findings.problem(format!(
+            "{} {} holds {} bytes, less than an fs-block",
+            what,
+            path.display(),
+            reader.size()
+        ));
+        return None;
+    }
+    for fs_block in [0, fs_blocks - 1] {
+        if let Err(e) = reader.read(fs_block, BLOCK_SIZE) {
+            findings.problem(format!(
+                "{} {}: fs-block {}: {}; image it with ddrescue and pass the map with --ddrescue-map",
+                what,
+                path.display(),
+                fs_block,
+                e
+            ));
+            return None;
+        }
+    }
+    match inuse::usage(path) {
+        Ok(Some(reason)) => findings.warn(format!(
+            "{} {} is in use ({}); it can change under a run, use --live-snapshot or stop what uses it",
+            what,
+            path.display(),
+            reason
+        )),
+        Ok(None) => {}
+        Err(e) => findings.warn(format!(
+            "{} {}: cannot tell whether it is in use: {}",
+            what,
+            path.display(),
+            e
+        )),
+    }
+    findings.ok(format!("{} {} reads, {} bytes", what, path.display(), reader.size()));
+    Some(reader)
+}
+
+fn check_block_size(cache_block_size: usize, findings: &mut Findings) {
+    let sectors = cache_block_size / 512;
+    if !cache_block_size.is_multiple_of(BLOCK_SIZE) {
+        findings.problem(format!(
+            "cache block size {} bytes is not a multiple of the {} byte fs-blocks indexes hash; check \
+             --cache-block-size and --sector-size",
+            cache_block_size, BLOCK_SIZE
+        ));
+    } else if !(MIN_CACHE_BLOCK..=MAX_CACHE_BLOCK).contains(&cache_block_size)
+        || !cache_block_size.is_multiple_of(MIN_CACHE_BLOCK)
+    {
+        findings.problem(format!(
+            "cache block size {} sectors is not one dm-cache uses: 64 to 2097152 sectors in steps of 64; the \
+             metadata superblock records the real one (--metadata-device)",
+            sectors
+        ));
+    } else {
+        findings.ok(format!(
+            "cache block size {} sectors ({} bytes)",
+            sectors, cache_block_size
+        ));
+    }
+}
+
+/// Matches a sample of cache blocks against the index, streamed rather than loaded. Returns how long reading the
+/// samples and the index took, and how many bytes were sampled, for the estimate.
+fn mini_match(
+    index: &Index,
+    cache: &Reader,
+    cache_block_size: usize,
+    samples: usize,
+    findings: &mut Findings,
+) -> io::Result<(Duration, Duration, usize)> {
+    let digest_len = index.digest_len();
+    let sample = index.sample_bytes().unwrap_or(BLOCK_SIZE);
+    let cache_blocks = cache.size() / cache_block_size;
+    let picked: Vec<usize> = (0..samples.min(cache_blocks))
+        .map(|i| i * cache_blocks / samples.min(cache_blocks))
+        .collect();
+
+    let started = Instant::now();
+    let mut wanted: HashMap<Vec<u8>, Vec<(usize, usize)>> = HashMap::new();
+    let (mut zero, mut unreadable, mut read) = (0, 0, 0);
+    for &cache_block in &picked {
+        let data = match cache.read(cache_block, cache_block_size) {
+            Ok(data) => data,
+            Err(_) => {
+                unreadable += 1;
+                continue;
+            }
+        };
+        read += data.len();
+        let blocks: Vec<&[u8]> = data
+            .chunks(BLOCK_SIZE)
+            .map(|block| &block[..sample])
+            .filter(|block| block.iter().any(|&byte| byte != 0))
+            .collect();
+        if blocks.is_empty() {
+            zero += 1;
+            continue;
+        }
+        let positions: Vec<usize> = data
+            .chunks(BLOCK_SIZE)
+            .enumerate()
+            .filter(|(_, block)| block[..sample].iter().any(|&byte| byte != 0))
+            .map(|(fs_block, _)| fs_block)
+            .collect();
+        for (digest, fs_block) in hash::hash_blocks(&blocks).into_iter().zip(positions) {
+            wanted
+                .entry(digest[..digest_len].to_vec())
+                .or_default()
+                .push((cache_block, fs_block));
+        }
+    }
+    let read_time = started.elapsed();
+
+    let started = Instant::now();
+    // fs-blocks found where the cache block grid puts them, by cache block, and otherwise by how far off it.
+    let mut in_place: HashMap<usize, usize> = HashMap::new();
+    let mut shifts: HashMap<usize, usize> = HashMap::new();
+    index.for_each(|entry, digest| {
+        for &(cache_block, fs_block) in wanted.get(digest).into_iter().flatten() {
+            let origin_offset = entry * BLOCK_SIZE;
+            let offset = fs_block * BLOCK_SIZE;
+            if origin_offset % cache_block_size == offset {
+                *in_place.entry(cache_block).or_default() += 1;
+            } else {
+                *shifts
+                    .entry((origin_offset + cache_block_size - offset) % cache_block_size)
+                    .or_default() += 1;
+            }
+        }
+    })?;
+    let index_time = started.elapsed();
+
+    let nonzero = picked.len() - zero - unreadable;
+    if unreadable > 0 {
+        findings.warn(format!(
+            "{} of {} sampled cache blocks do not read; image the cache device with ddrescue and pass the map with \
+             --ddrescue-map",
+            unreadable,
+            picked.len()
+        ));
+    }
+    let matched = in_place.len();
+    let summary = format!(
+        "{} of {} sampled cache blocks match the index in place ({} zero-filled, left out)",
+        matched, nonzero, zero
+    );
+    let shifted: usize = shifts.values().sum();
+    let common_shift = shifts.iter().max_by_key(|&(_, count)| count).map(|(&shift, _)| shift);
+    if nonzero == 0 {
+        findings.warn(format!("{}; nothing to tell the parameters by", summary));
+    } else if matched == 0 {
+        let advice = match common_shift {
+            Some(shift) => format!(
+                "their fs-blocks are in the origin, but most {} bytes off the cache block grid: check the partition, \
+                 --data-offset and --cache-block-size, or run shift-scan",
+                shift
+            ),
+            None => "check that the index is of this cache's origin and the cache device is the cache data, not \
+                     the metadata"
+                .to_string(),
+        };
+        findings.problem(format!("{}; {}", summary, advice));
+    } else if shifted > in_place.values().sum::<usize>() {
+        findings.warn(format!(
+            "{}, but more fs-blocks are found {} bytes off the cache block grid; the cache block size or an offset \
+             may be wrong",
+            summary,
+            common_shift.unwrap()
+        ));
+    } else {
+        findings.ok(summary);
+    }
+    Ok((read_time, index_time, read))
+}
+
+/// Checks the inputs of a `find` run without doing it: that the devices read, their sizes and the cache block size
+/// make sense, the index is of this origin and fits in memory, and a sample of cache blocks matches it in place.
+/// Every problem is printed with what to do about it, and fails the check.
+pub fn preflight(index_path: &Path, cache_device_path: &Path, options: &PreflightOptions) -> io::Result<()> {
+    let mut findings = Findings::default();
+    let mut cache_block_size = options.cache_block_size;
+
+    let index = match Index::open(index_path) {
+        Ok(index) => Some(index),
+        Err(e) => {
+            findings.problem(format!(
+                "index {}: {}; rebuild it with collect",
+                index_path.display(),
+                e
+            ));
+            None
+        }
+    };
+    if let Some(index) = &index {
+        findings.ok(format!(
+            "index {}: {} entries, {} byte digests{}{}",
+            index_path.display(),
+            index.entry_count(),
+            index.digest_len(),
+            index
+                .sample_bytes()
+                .map_or(String::new(), |sample| format!(" of the first {} bytes", sample)),
+            match index.shard_count() {
+                1 => String::new(),
+                shards => format!(", {} shards", shards),
+            }
+        ));
+        if index.origin().is_none() {
+            findings.warn("legacy index without a recorded origin, nothing to check --origin against".to_string());
+        }
+    }
+
+    if let Some(metadata_device) = options.metadata_device {
+        match Superblock::read(metadata_device) {
+            Ok(superblock) => {
+                let metadata_block_size = 512 * superblock.data_block_size;
+                if options.explicit_block_size && metadata_block_size != cache_block_size {
+                    findings.problem(format!(
+                        "--cache-block-size is {} sectors, the metadata says {}; leave it out to use the metadata's",
+                        cache_block_size / 512,
+                        superblock.data_block_size
+                    ));
+                }
+                cache_block_size = metadata_block_size;
+                findings.ok(format!(
+                    "metadata: version {}, policy {}, {} cache blocks of {} sectors",
+                    superblock.version, superblock.policy, superblock.cache_blocks, superblock.data_block_size
+                ));
+            }
+            Err(e) => findings.problem(format!(
+                "{}; find needs the metadata device itself, or leave --metadata-device out",
+                e
+            )),
+        }
+    }
+    check_block_size(cache_block_size, &mut findings);
+    if let Some(path) = cache_device_path.to_str() {
+        if let Err(e) = check_alignment("the cache block size", cache_block_size, &[path]) {
+            findings.problem(e.to_string());
+        }
+    }
+
+    let cache = readable("cache device", cache_device_path, options.partition, &mut findings);
+    if let Some(cache) = &cache {
+        if cache.size() < cache_block_size {
+            findings.problem(format!(
+                "the cache device holds {} bytes, less than a cache block; check --partition and --data-offset",
+                cache.size()
+            ));
+        } else if !cache.size().is_multiple_of(cache_block_size) {
+            findings.warn(format!(
+                "the cache device ends in a partial cache block, {} bytes past the last whole one",
+                cache.size() % cache_block_size
+            ));
+        }
+        if let Some(superblock) = options.metadata_device.and_then(|path| Superblock::read(path).ok()) {
+            let needed = superblock.cache_blocks * cache_block_size;
+            if needed > cache.size() {
+                findings.problem(format!(
+                    "the metadata has {} cache blocks, {} bytes, but the cache device holds {}; check --partition \
+                     and that this is the cache of that metadata",
+                    superblock.cache_blocks,
+                    needed,
+                    cache.size()
+                ));
+            }
+        }
+    }
+
+    let origin = options
+        .origin
+        .and_then(|path| readable("origin", path, options.origin_partition, &mut findings));
+    let indexed = index.as_ref().and_then(Index::origin);
+    if let (Some(origin), Some(path), Some((device_size, recorded))) = (&origin, options.origin, indexed) {
+        let layout = Layout::read(index_path)?;
+        if layout.is_none() && origin.size() != device_size {
+            findings.problem(format!(
+                "the index was built from a {} byte device, --origin {} has {} bytes; check --origin-partition or \
+                 collect the index again",
+                device_size,
+                path.display(),
+                origin.size()
+            ));
+        }
+        let mismatches = recorded.mismatches(&Identity::of(origin.view()));
+        if !recorded.is_empty() && !mismatches.is_empty() {
+            findings.problem(format!(
+                "--origin does not look like the indexed device: {}",
+                mismatches.join("; ")
+            ));
+        }
+    }
+    if let (Some(cache), Some((device_size, _))) = (&cache, indexed) {
+        if cache.size() > device_size {
+            findings.warn(format!(
+                "the cache device is larger than the indexed origin, {} against {} bytes; are they swapped?",
+                cache.size(),
+                device_size
+            ));
+        }
+    }
+    if let Some(index) = &index {
+        if origin.is_none() && (index.digest_len() < HASH_BYTES || index.sample_bytes().is_some()) {
+            findings.warn(
+                "the index holds truncated or sampled digests; give find --origin to confirm its matches".to_string(),
+            );
+        }
+        let needed = index.entry_count() * lookup_entry_bytes(index);
+        match memory::available() {
+            Some(available) if needed > available && index.shard_count() > 1 => findings.warn(format!(
+                "the index needs about {} of memory, {} available; find searches it a shard at a time, with a \
+                 pass over the cache for each",
+                memory::gib(needed),
+                memory::gib(available)
+            )),
+            Some(available) if needed > available => findings.problem(format!(
+                "the index needs about {} of memory, {} available; rebuild it with collect --filter-hash or split \
+                 it with --shard-size",
+                memory::gib(needed),
+                memory::gib(available)
+            )),
+            Some(available) => findings.ok(format!(
+                "the index needs about {} of memory, {} available",
+                memory::gib(needed),
+                memory::gib(available)
+            )),
+            None => {}
+        }
+    }
+
+    if let (Some(index), Some(cache)) = (&index, &cache) {
+        if cache.size() >= cache_block_size && cache_block_size.is_multiple_of(BLOCK_SIZE) {
+            let (read_time, index_time, read) =
+                mini_match(index, cache, cache_block_size, options.samples, &mut findings)?;
+            if read > 0 {
+                // Sampled reads are scattered, so this errs long for a device that streams faster.
+                let scan = read_time.mul_f64(cache.size() as f64 / read as f64);
+                println!(
+                    "estimate: loading the index about {}, scanning the cache device about {}",
+                    format_duration(index_time),
+                    format_duration(scan)
+                );
+            }
+        }
+    }
+
+    eprintln!("{} problems, {} warnings", findings.problems, findings.warnings);
+    if findings.problems > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} problems found; fix them before a find run", findings.problems),
+        ));
+    }
+    Ok(())
+}
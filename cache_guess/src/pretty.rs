@@ -0,0 +1,205 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+
+use crate::output::Output;
+
+/// How many stretches of the cache device the summary splits it into, at most.
+const REGIONS: usize = 16;
+/// Characters of a region's bar.
+const BAR: usize = 32;
+/// Origin blocks are numbered up to the origin size, which the results do not tell; this fits the heading.
+const ORIGIN_WIDTH: usize = 12;
+
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+const DIM: &str = "2";
+const BOLD: &str = "1";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Unscanned,
+    Mapped,
+    Ambiguous,
+    Unmatched,
+}
+
+/// The best candidate, or the runner-up, of a cache block as `--format pretty` lists it.
+pub struct Row {
+    pub origin_block: usize,
+    pub match_percent: f64,
+    pub confidence: f64,
+    pub notes: String,
+}
+
+/// `find --format pretty`: cache blocks by what became of them, mapped ones as they are matched, then the ambiguous
+/// ones with their runner-up, the unmatched ones as ranges and a summary of the device region by region. Colors
+/// only go to a terminal, and not with NO_COLOR set.
+pub struct PrettyReport {
+    color: bool,
+    width: usize,
+    classes: Vec<Class>,
+    ambiguous: Vec<String>,
+    /// Ranges of unmatched cache blocks, end exclusive.
+    unmatched: Vec<(usize, usize)>,
+}
+
+impl PrettyReport {
+    pub fn new(out: &Output, total_blocks: usize) -> Self {
+        Self {
+            color: !out.is_file() && io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            width: total_blocks.to_string().len().max("cache block".len()),
+            classes: vec![Class::Unscanned; total_blocks],
+            ambiguous: Vec::new(),
+            unmatched: Vec::new(),
+        }
+    }
+
+    fn paint(&self, text: &str, code: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn confidence_color(confidence: f64) -> &'static str {
+        if confidence >= 0.9 {
+            GREEN
+        } else if confidence >= 0.5 {
+            YELLOW
+        } else {
+            RED
+        }
+    }
+
+    fn row(&self, cache_block: &str, row: &Row) -> String {
+        let color = Self::confidence_color(row.confidence);
+        format!(
+            "  {:>w$}  {:>o$}  {}  {}{}",
+            cache_block,
+            row.origin_block,
+            self.paint(&format!("{:>7.3}%", row.match_percent), color),
+            self.paint(&format!("{:>10.3}", row.confidence), color),
+            row.notes,
+            w = self.width,
+            o = ORIGIN_WIDTH
+        )
+    }
+
+    fn heading(&self, title: &str) -> String {
+        format!(
+            "{}\n{}",
+            self.paint(title, BOLD),
+            self.paint(
+                &format!(
+                    "  {:>w$}  {:>o$}  {:>8}  {:>10}",
+                    "cache block",
+                    "origin block",
+                    "match",
+                    "confidence",
+                    w = self.width,
+                    o = ORIGIN_WIDTH
+                ),
+                DIM
+            )
+        )
+    }
+
+    pub fn start(&self, out: &mut Output) -> io::Result<()> {
+        writeln!(out, "{}", self.heading("MAPPED"))
+    }
+
+    pub fn mapped(&mut self, out: &mut Output, cache_block: usize, best: &Row) -> io::Result<()> {
+        self.classes[cache_block] = Class::Mapped;
+        writeln!(out, "{}", self.row(&cache_block.to_string(), best))
+    }
+
+    /// A cache block whose best candidate is tied, tainted or only a partial match; `class` says which.
+    pub fn ambiguous(&mut self, cache_block: usize, class: &str, best: &Row, runner_up: Option<&Row>) {
+        self.classes[cache_block] = Class::Ambiguous;
+        let mut line = self.row(&cache_block.to_string(), best);
+        if !best.notes.contains(&format!("[{}]", class)) {
+            line += &self.paint(&format!(" [{}]", class), YELLOW);
+        }
+        if let Some(runner_up) = runner_up {
+            line += &format!("\n{}", self.paint(&self.row("or", runner_up), DIM));
+        }
+        self.ambiguous.push(line);
+    }
+
+    pub fn unmatched(&mut self, cache_block: usize) {
+        self.classes[cache_block] = Class::Unmatched;
+        match self.unmatched.last_mut() {
+            Some(last) if last.1 == cache_block => last.1 += 1,
+            _ => self.unmatched.push((cache_block, cache_block + 1)),
+        }
+    }
+
+    pub fn finish(mut self, out: &mut Output) -> io::Result<()> {
+        writeln!(out, "\n{}", self.heading("AMBIGUOUS"))?;
+        for line in &self.ambiguous {
+            writeln!(out, "{}", line)?;
+        }
+        // Cache blocks matched first for --priority-ranges come out of order.
+        self.unmatched.sort_unstable();
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for &(start, end) in &self.unmatched {
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        self.unmatched = ranges;
+        let unmatched: usize = self.unmatched.iter().map(|(start, end)| end - start).sum();
+        writeln!(
+            out,
+            "\n{}",
+            self.paint(&format!("UNMATCHED ({} cache blocks)", unmatched), BOLD)
+        )?;
+        for &(start, end) in &self.unmatched {
+            let range = if end - start == 1 {
+                start.to_string()
+            } else {
+                format!("{}..{} ({} blocks)", start, end - 1, end - start)
+            };
+            writeln!(out, "  {}", self.paint(&range, RED))?;
+        }
+
+        writeln!(out, "\n{}", self.paint("REGIONS", BOLD))?;
+        let total = self.classes.len();
+        let per_region = total.div_ceil(REGIONS).max(1);
+        for start in (0..total).step_by(per_region) {
+            let region = &self.classes[start..total.min(start + per_region)];
+            let count = |class: Class| region.iter().filter(|&&c| c == class).count();
+            let (mapped, ambiguous, unmatched) =
+                (count(Class::Mapped), count(Class::Ambiguous), count(Class::Unmatched));
+            let scanned = mapped + ambiguous + unmatched;
+            if scanned == 0 {
+                continue;
+            }
+            // Rounded shares, with what rounding leaves over or short going to the largest.
+            let mut cells = [mapped, ambiguous, unmatched].map(|n| (n * BAR + scanned / 2) / scanned);
+            let largest = (0..3).max_by_key(|&i| cells[i]).unwrap();
+            cells[largest] = BAR + cells[largest] - cells.iter().sum::<usize>();
+            let bar = format!(
+                "{}{}{}",
+                self.paint(&"#".repeat(cells[0]), GREEN),
+                self.paint(&"~".repeat(cells[1]), YELLOW),
+                self.paint(&".".repeat(cells[2]), RED)
+            );
+            writeln!(
+                out,
+                "  {:>w$}..{:<w$}  {}  {:>5.1}% mapped, {} ambiguous, {} unmatched",
+                start,
+                start + region.len() - 1,
+                bar,
+                100.0 * mapped as f64 / scanned as f64,
+                ambiguous,
+                unmatched,
+                w = total.to_string().len()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::identity::Identity;
+use crate::output::json_string;
+use crate::{hash, MappedFile};
+
+/// Every device, index and mapping this run has read, in the order they were opened.
+static INPUTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// Parameters that shape the results beyond the inputs, as `name=value`.
+static SETTINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Remembers an input so that outputs can say what they were made from.
+pub fn record_input(path: &Path, description: String) {
+    let input = format!("{} ({})", path.display(), description);
+    let mut inputs = INPUTS.lock().unwrap();
+    if !inputs.contains(&input) {
+        inputs.push(input);
+    }
+}
+
+pub fn record_setting(name: &str, value: impl std::fmt::Display) {
+    SETTINGS.lock().unwrap().push(format!("{}={}", name, value));
+}
+
+/// One value for the fingerprint samples of an identity.
+pub fn fingerprint(identity: &Identity) -> String {
+    let samples: Vec<u8> = identity
+        .fingerprint
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect();
+    format!("{:016x}", hash::xxh64(&samples))
+}
+
+/// Records a device with its size and a fingerprint of the same samples an index identity is made of.
+pub fn record_device(path: &Path, partition: Option<usize>, device: &MappedFile) {
+    let partition = partition.map_or(String::new(), |number| format!("partition {}, ", number));
+    record_input(
+        path,
+        format!(
+            "{}{} bytes, fingerprint {}",
+            partition,
+            device.size(),
+            fingerprint(&Identity::of(device))
+        ),
+    );
+}
+
+pub fn command_line() -> String {
+    let args: Vec<String> = env::args()
+        .map(|arg| {
+            if !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:@,+%".contains(c))
+            {
+                arg
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect();
+    args.join(" ")
+}
+
+/// The current time in UTC, as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as i64;
+    let (days, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    // Civil date from days since the epoch, after Howard Hinnant's days_from_civil inverse.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+pub fn inputs() -> Vec<String> {
+    INPUTS.lock().unwrap().clone()
+}
+
+/// How an output was produced: tool version, command line, time and inputs, one `key: value` per line.
+pub fn lines() -> Vec<String> {
+    let mut lines = vec![
+        format!("version: cache_guess {}", env!("CARGO_PKG_VERSION")),
+        format!("command: {}", command_line()),
+        format!("time: {}", timestamp()),
+    ];
+    lines.extend(inputs().iter().map(|input| format!("input: {}", input)));
+    lines.extend(
+        SETTINGS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|setting| format!("setting: {}", setting)),
+    );
+    lines
+}
+
+/// The `input:` and `setting:` lines of `lines` that an output line records, from either `write_comments` or
+/// `json`; none for other lines.
+pub fn recorded(line: &str) -> Vec<String> {
+    if let Some(line) = line.strip_prefix("# provenance ") {
+        let kept = line.starts_with("input: ") || line.starts_with("setting: ");
+        return kept.then(|| line.to_string()).into_iter().collect();
+    }
+    if !line.starts_with(JSON_PROVENANCE) {
+        return Vec::new();
+    }
+    let mut recorded = Vec::new();
+    for (key, prefix) in [("\"inputs\":[", "input: "), ("\"settings\":[", "setting: ")] {
+        let Some(start) = line.find(key) else {
+            continue;
+        };
+        let mut rest = &line[start + key.len()..];
+        while let Some((value, after)) = parse_json_string(rest) {
+            recorded.push(format!("{}{}", prefix, value));
+            rest = after.strip_prefix(',').unwrap_or(after);
+        }
+    }
+    recorded
+}
+
+/// The JSON string at the start of `text`, and what follows it.
+fn parse_json_string(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'u' => {
+                    let hex: String = (0..4).filter_map(|_| chars.next().map(|(_, c)| c)).collect();
+                    value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// The provenance as `#` comment lines, for text outputs whose readers skip those.
+pub fn write_comments(out: &mut impl Write) -> io::Result<()> {
+    for line in lines() {
+        writeln!(out, "# provenance {}", line)?;
+    }
+    Ok(())
+}
+
+/// The provenance as a single JSON line, for `--format jsonl` outputs.
+pub fn json() -> String {
+    let inputs: Vec<String> = INPUTS.lock().unwrap().iter().map(|input| json_string(input)).collect();
+    let settings: Vec<String> = SETTINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|setting| json_string(setting))
+        .collect();
+    format!(
+        "{}{{\"version\":{},\"command\":{},\"time\":{},\"inputs\":[{}],\"settings\":[{}]}}}}",
+        JSON_PROVENANCE,
+        json_string(env!("CARGO_PKG_VERSION")),
+        json_string(&command_line()),
+        json_string(&timestamp()),
+        inputs.join(","),
+        settings.join(",")
+    )
+}
+
+pub const JSON_PROVENANCE: &str = "{\"provenance\":";
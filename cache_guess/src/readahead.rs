@@ -0,0 +1,124 @@
+use std::hint;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::Scope;
+
+use memmap2::Advice;
+
+use crate::{MappedFile, BLOCK_SIZE};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Reads the next chunk of a mapped device on a second thread while the caller hashes the current one, so that the
+/// disk is not left idle while SHA-1 runs. The pages are faulted in for real: MADV_WILLNEED on its own only starts
+/// as much readahead as the kernel sees fit.
+pub struct ReadAhead {
+    requests: SyncSender<(usize, usize)>,
+}
+
+impl ReadAhead {
+    /// Starts the reader for `device` for as long as `scope` lasts. It leaves out the fs-blocks `skip` names, such as
+    /// unrescued regions that would fault or free space that is not hashed anyway.
+    pub fn spawn<'scope, 'env>(
+        scope: &'scope Scope<'scope, 'env>,
+        device: &'env MappedFile,
+        skip: impl Fn(usize, usize) -> bool + Send + 'scope,
+    ) -> Self {
+        // One chunk in flight besides the one being hashed.
+        let (requests, incoming) = mpsc::sync_channel::<(usize, usize)>(1);
+        scope.spawn(move || {
+            for (offset, len) in incoming {
+                let len = len.min(device.size().saturating_sub(offset));
+                for block in (offset..offset + len).step_by(BLOCK_SIZE) {
+                    let block_len = BLOCK_SIZE.min(offset + len - block);
+                    if skip(block, block_len) {
+                        continue;
+                    }
+                    let data = device.slice(block, block_len);
+                    let _ = device
+                        .mmap
+                        .advise_range(Advice::WillNeed, device.base + block, block_len);
+                    hint::black_box(data.iter().step_by(PAGE_SIZE).fold(0u8, |sum, &byte| sum ^ byte));
+                }
+            }
+        });
+        Self { requests }
+    }
+
+    /// Asks for `[offset, offset + len)` to be read in the background. Dropped while the reader is still busy with
+    /// an earlier chunk, so that the caller never waits for it.
+    pub fn prefetch(&self, offset: usize, len: usize) {
+        let _ = self.requests.try_send((offset, len));
+    }
+}
+
+/// Reads until `buf` is full or the input ends; returns how much was read.
+pub fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The same for a stream, which cannot be read out of order: a second thread fills one buffer while the caller
+/// hashes the other.
+pub struct StreamAhead {
+    filled: Receiver<io::Result<Vec<u8>>>,
+    spare: Sender<Vec<u8>>,
+}
+
+impl StreamAhead {
+    /// Starts reading the first `total` bytes of `input` in chunks of `chunk` bytes; only the last one, or one cut
+    /// short by the end of the input, is shorter.
+    pub fn spawn<'scope, 'env, R: Read + Send>(
+        scope: &'scope Scope<'scope, 'env>,
+        input: &'env mut R,
+        total: usize,
+        chunk: usize,
+    ) -> Self {
+        let (filled_sender, filled) = mpsc::channel();
+        let (spare, spare_receiver) = mpsc::channel();
+        for _ in 0..2 {
+            let _ = spare.send(Vec::with_capacity(chunk));
+        }
+        scope.spawn(move || {
+            let mut offset = 0;
+            while offset < total {
+                let Ok(mut buf) = spare_receiver.recv() else {
+                    return;
+                };
+                let wanted = chunk.min(total - offset);
+                buf.resize(wanted, 0);
+                let len = match read_full(input, &mut buf) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        let _ = filled_sender.send(Err(e));
+                        return;
+                    }
+                };
+                buf.truncate(len);
+                if filled_sender.send(Ok(buf)).is_err() || len < wanted {
+                    return;
+                }
+                offset += len;
+            }
+        });
+        Self { filled, spare }
+    }
+
+    /// The next chunk, empty once the input has ended.
+    pub fn next(&self) -> io::Result<Vec<u8>> {
+        self.filled.recv().unwrap_or_else(|_| Ok(Vec::new()))
+    }
+
+    /// Hands a chunk back to be filled again.
+    pub fn recycle(&self, buf: Vec<u8>) {
+        let _ = self.spare.send(buf);
+    }
+}
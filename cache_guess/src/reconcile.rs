@@ -0,0 +1,70 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::cache_xml::CacheMetadata;
+use crate::output::Output;
+use crate::{mapping, provenance};
+
+/// Picks one origin block from what the metadata and the content say, and describes how well they agree.
+///
+/// On a conflict, the content wins for clean blocks, whose data should be identical to the origin, and the metadata
+/// for dirty ones, whose data has diverged from it and so cannot be matched reliably.
+fn merge(recorded: Option<(usize, bool)>, content: Option<(usize, bool)>) -> (usize, &'static str) {
+    match (recorded, content) {
+        (Some((origin_block, _)), Some((guess, _))) if guess == origin_block => (origin_block, "agree"),
+        (Some((origin_block, _)), Some((_, true))) => (origin_block, "conflict, content tied, metadata decides"),
+        (Some((origin_block, true)), Some(_)) => (origin_block, "conflict, keeping dirty metadata"),
+        (Some(_), Some((guess, false))) => (guess, "conflict, keeping content"),
+        (Some((origin_block, _)), None) => (origin_block, "metadata only"),
+        (None, Some((guess, true))) => (guess, "content only [tied]"),
+        (None, Some((guess, false))) => (guess, "content only"),
+        (None, None) => unreachable!(),
+    }
+}
+
+/// Merges the mappings that survive in damaged metadata with `find` results into `C -> O # agreement` lines.
+pub fn reconcile(mapping_path: &Path, metadata_path: &Path, output: Option<&Path>) -> io::Result<()> {
+    let (metadata, dropped) = CacheMetadata::read_partial(metadata_path)?;
+    if dropped > 0 {
+        eprintln!("{} damaged or duplicate mappings dropped from the metadata", dropped);
+    }
+    let content: HashMap<usize, (usize, bool)> = mapping::read(mapping_path)?
+        .into_iter()
+        .map(|entry| (entry.cache_block, (entry.origin_block, entry.tied)))
+        .collect();
+
+    let cache_blocks: BTreeSet<usize> = metadata
+        .mappings
+        .keys()
+        .copied()
+        .chain(content.keys().copied())
+        .collect();
+    let mut levels: HashMap<&str, usize> = HashMap::new();
+    let mut out = Output::create(output)?;
+    provenance::write_comments(&mut out)?;
+    for cache_block in cache_blocks {
+        let recorded = metadata.mappings.get(&cache_block);
+        let guess = content.get(&cache_block).copied();
+        let (origin_block, level) = merge(recorded.map(|recorded| (recorded.origin_block, recorded.dirty)), guess);
+        *levels.entry(level).or_insert(0) += 1;
+        let mut notes = String::new();
+        if recorded.is_some_and(|recorded| recorded.dirty) {
+            notes += ", dirty";
+        }
+        if let (Some(recorded), Some((guess, _))) = (recorded, guess) {
+            if recorded.origin_block != guess {
+                notes += &format!(", metadata says {}, content says {}", recorded.origin_block, guess);
+            }
+        }
+        writeln!(out, "{} -> {} # {}{}", cache_block, origin_block, level, notes)?;
+    }
+    out.commit()?;
+
+    let mut levels: Vec<_> = levels.into_iter().collect();
+    levels.sort_by_key(|&(level, count)| (std::cmp::Reverse(count), level));
+    for (level, count) in levels {
+        eprintln!("{:>8} {}", count, level);
+    }
+    Ok(())
+}
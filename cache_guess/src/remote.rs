@@ -0,0 +1,407 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+use memmap2::MmapOptions;
+
+use crate::readahead::read_full;
+use crate::{log_complete, log_status, memory, MappedFile};
+
+const NBD_PORT: u16 = 10809;
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OLDSTYLE_MAGIC: u64 = 0x0000_4202_8186_1253;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const FLAG_FIXED_NEWSTYLE: u16 = 1;
+const FLAG_NO_ZEROES: u16 = 2;
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = 0x8000_0001;
+const INFO_EXPORT: u16 = 0;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const CMD_READ: u16 = 0;
+const CMD_DISC: u16 = 2;
+/// Bytes asked for per read request, well below the 32 MiB servers commonly allow.
+const CHUNK: usize = 1 << 20;
+
+/// Whether a device path is one of the URIs below rather than a local path.
+pub fn is_remote(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("nbd://") || path.starts_with("ssh://"))
+}
+
+/// A device on another machine: an export of an NBD server, `nbd://host[:port][/export]`, or a device or image
+/// that ssh can cat, `ssh://[user@]host[:port]/path`.
+enum Remote {
+    Nbd {
+        host: String,
+        port: u16,
+        export: String,
+    },
+    Ssh {
+        destination: String,
+        port: Option<u16>,
+        path: String,
+    },
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Splits `host[:port]`, where an IPv6 host is in brackets.
+fn host_port(authority: &str, uri: &str) -> io::Result<(String, Option<u16>)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| invalid(format!("unclosed [ in '{}'", uri)))?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(invalid(format!("no host in '{}'", uri)));
+    }
+    let port = port
+        .map(|port| port.parse().map_err(|_| invalid(format!("bad port in '{}'", uri))))
+        .transpose()?;
+    Ok((host.to_string(), port))
+}
+
+impl Remote {
+    fn parse(path: &Path) -> io::Result<Self> {
+        let uri = path.to_string_lossy();
+        let (scheme, rest) = uri
+            .split_once("://")
+            .ok_or_else(|| invalid(format!("'{}' is not a URI", uri)))?;
+        let (authority, target) = rest.split_once('/').unwrap_or((rest, ""));
+        match scheme {
+            "nbd" => {
+                let (host, port) = host_port(authority, &uri)?;
+                Ok(Self::Nbd {
+                    host,
+                    port: port.unwrap_or(NBD_PORT),
+                    export: target.to_string(),
+                })
+            }
+            _ => {
+                let (user, authority) = match authority.rsplit_once('@') {
+                    Some((user, authority)) => (Some(user), authority),
+                    None => (None, authority),
+                };
+                let (host, port) = host_port(authority, &uri)?;
+                if target.is_empty() {
+                    return Err(invalid(format!("no path in '{}'", uri)));
+                }
+                Ok(Self::Ssh {
+                    destination: user.map_or(host.clone(), |user| format!("{}@{}", user, host)),
+                    port,
+                    path: format!("/{}", target),
+                })
+            }
+        }
+    }
+}
+
+fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    input.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn protocol(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("nbd: {}", msg))
+}
+
+/// An NBD export read front to back, a request at a time.
+struct Nbd {
+    socket: TcpStream,
+    size: usize,
+    offset: usize,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl Nbd {
+    /// Connects and negotiates the export with NBD_OPT_GO, or NBD_OPT_EXPORT_NAME where a server has no GO, and
+    /// the old-style handshake of servers from before options existed.
+    fn connect(host: &str, port: u16, export: &str) -> io::Result<Self> {
+        let mut socket = TcpStream::connect((host, port))
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot connect to {}:{}: {}", host, port, e)))?;
+        socket.set_nodelay(true)?;
+        if read_u64(&mut socket)? != NBDMAGIC {
+            return Err(protocol(format!("{}:{} is not an NBD server", host, port)));
+        }
+        let size = match read_u64(&mut socket)? {
+            OLDSTYLE_MAGIC => {
+                let size = read_u64(&mut socket)?;
+                read_u32(&mut socket)?;
+                socket.read_exact(&mut [0; 124])?;
+                size
+            }
+            IHAVEOPT => Self::negotiate(&mut socket, export)?,
+            magic => return Err(protocol(format!("unknown handshake {:#x}", magic))),
+        };
+        Ok(Self {
+            socket,
+            size: size as usize,
+            offset: 0,
+            buffer: vec![0; CHUNK],
+            start: 0,
+            end: 0,
+        })
+    }
+
+    fn negotiate(socket: &mut TcpStream, export: &str) -> io::Result<u64> {
+        let flags = read_u16(socket)?;
+        let no_zeroes = flags & FLAG_NO_ZEROES != 0;
+        socket.write_all(&u32::from(flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)).to_be_bytes())?;
+        let option = |socket: &mut TcpStream, option: u32, data: &[u8]| {
+            let mut request = Vec::with_capacity(16 + data.len());
+            request.extend(IHAVEOPT.to_be_bytes());
+            request.extend(option.to_be_bytes());
+            request.extend((data.len() as u32).to_be_bytes());
+            request.extend(data);
+            socket.write_all(&request)
+        };
+        if flags & FLAG_FIXED_NEWSTYLE != 0 {
+            let mut data = Vec::new();
+            data.extend((export.len() as u32).to_be_bytes());
+            data.extend(export.as_bytes());
+            data.extend(0u16.to_be_bytes());
+            option(socket, OPT_GO, &data)?;
+            let mut size = None;
+            loop {
+                if read_u64(socket)? != REPLY_MAGIC {
+                    return Err(protocol("bad option reply".to_string()));
+                }
+                read_u32(socket)?;
+                let reply = read_u32(socket)?;
+                let mut data = vec![0; read_u32(socket)? as usize];
+                socket.read_exact(&mut data)?;
+                match reply {
+                    REP_INFO if data.len() >= 10 && u16::from_be_bytes([data[0], data[1]]) == INFO_EXPORT => {
+                        size = Some(u64::from_be_bytes(data[2..10].try_into().unwrap()));
+                    }
+                    REP_INFO => {}
+                    REP_ACK => {
+                        return size.ok_or_else(|| protocol("the server did not say the export size".to_string()))
+                    }
+                    REP_ERR_UNSUP => break,
+                    _ if reply & 0x8000_0000 != 0 => {
+                        let mut msg = format!("the server refused export '{}' (error {:#x})", export, reply);
+                        if !data.is_empty() {
+                            msg += &format!(": {}", String::from_utf8_lossy(&data));
+                        }
+                        return Err(protocol(msg));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        option(socket, OPT_EXPORT_NAME, export.as_bytes())?;
+        let size = read_u64(socket)?;
+        read_u16(socket)?;
+        if !no_zeroes {
+            socket.read_exact(&mut [0; 124])?;
+        }
+        Ok(size)
+    }
+}
+
+impl Read for Nbd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.end {
+            if self.offset == self.size {
+                return Ok(0);
+            }
+            let len = CHUNK.min(self.size - self.offset);
+            let mut request = Vec::with_capacity(28);
+            request.extend(REQUEST_MAGIC.to_be_bytes());
+            request.extend(0u16.to_be_bytes());
+            request.extend(CMD_READ.to_be_bytes());
+            request.extend((self.offset as u64).to_be_bytes());
+            request.extend((self.offset as u64).to_be_bytes());
+            request.extend((len as u32).to_be_bytes());
+            self.socket.write_all(&request)?;
+            // The offset is the request's handle, as only one is ever outstanding.
+            if read_u32(&mut self.socket)? != SIMPLE_REPLY_MAGIC {
+                return Err(protocol("bad read reply".to_string()));
+            }
+            let error = read_u32(&mut self.socket)?;
+            if read_u64(&mut self.socket)? != self.offset as u64 {
+                return Err(protocol("reply to a read that was not asked for".to_string()));
+            }
+            if error != 0 {
+                return Err(io::Error::other(format!(
+                    "nbd: reading {} bytes at {} failed with error {}",
+                    len, self.offset, error
+                )));
+            }
+            self.socket.read_exact(&mut self.buffer[..len])?;
+            self.offset += len;
+            (self.start, self.end) = (0, len);
+        }
+        let len = buf.len().min(self.end - self.start);
+        buf[..len].copy_from_slice(&self.buffer[self.start..self.start + len]);
+        self.start += len;
+        Ok(len)
+    }
+}
+
+impl Drop for Nbd {
+    fn drop(&mut self) {
+        let mut request = Vec::with_capacity(28);
+        request.extend(REQUEST_MAGIC.to_be_bytes());
+        request.extend(0u16.to_be_bytes());
+        request.extend(CMD_DISC.to_be_bytes());
+        request.extend([0; 20]);
+        let _ = self.socket.write_all(&request);
+    }
+}
+
+/// Quotes a path for the remote shell.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
+
+fn ssh(destination: &str, port: Option<u16>, command: &str) -> Command {
+    let mut ssh = Command::new("ssh");
+    if let Some(port) = port {
+        ssh.arg("-p").arg(port.to_string());
+    }
+    ssh.arg("--").arg(destination).arg(command);
+    ssh
+}
+
+/// A remote device read front to back, as collect streams an origin.
+pub struct Stream {
+    path: PathBuf,
+    reader: Box<dyn Read + Send>,
+    child: Option<Child>,
+    /// What the other end says the device holds.
+    pub size: usize,
+}
+
+impl Stream {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let (reader, child, size): (Box<dyn Read + Send>, _, _) = match Remote::parse(path)? {
+            Remote::Nbd { host, port, export } => {
+                let nbd = Nbd::connect(&host, port, &export)?;
+                let size = nbd.size;
+                (Box::new(nbd), None, size)
+            }
+            Remote::Ssh {
+                destination,
+                port,
+                path: remote_path,
+            } => {
+                let quoted = quote(&remote_path);
+                let output = ssh(
+                    &destination,
+                    port,
+                    &format!("blockdev --getsize64 {0} 2>/dev/null || stat -Lc %s {0}", quoted),
+                )
+                .stderr(Stdio::inherit())
+                .output()
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot run ssh for {}: {}", path.display(), e)))?;
+                let size = String::from_utf8_lossy(&output.stdout).trim().parse().map_err(|_| {
+                    io::Error::other(format!("{}: cannot tell the size of {}", path.display(), remote_path))
+                })?;
+                let mut child = ssh(&destination, port, &format!("cat -- {}", quoted))
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let output = child.stdout.take().unwrap();
+                (Box::new(output), Some(child), size)
+            }
+        };
+        eprintln!("{}: {} bytes, reading it over the network", path.display(), size);
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader,
+            child,
+            size,
+        })
+    }
+
+    /// Waits for ssh once `result` is in, failing the run if it did; stops it early where the run did not read
+    /// everything.
+    pub fn finish(mut self, result: io::Result<()>) -> io::Result<()> {
+        let Some(mut child) = self.child.take() else {
+            return result;
+        };
+        if result.is_err() || read_full(&mut self.reader, &mut [0])? > 0 {
+            let _ = child.kill();
+            let _ = child.wait();
+            return result;
+        }
+        if !child.wait()?.success() {
+            return Err(io::Error::other(format!(
+                "{}: ssh failed to read it",
+                self.path.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Reads a remote device into memory, for the commands that need random access to it. Only collect streams.
+pub fn map(path: &Path, force: bool) -> io::Result<MappedFile> {
+    let mut stream = Stream::open(path)?;
+    let size = stream.size;
+    memory::preflight(
+        &format!("reading {}", path.display()),
+        size,
+        "copy the image over first, or index it with collect, which streams it",
+        force,
+    )?;
+    let mut output = MmapOptions::new().len(size.max(1)).map_anon()?;
+    let result = fill(&mut stream, &mut output[..size], path);
+    stream.finish(result)?;
+    Ok(MappedFile::from_mmap(output.make_read_only()?, size))
+}
+
+fn fill(stream: &mut Stream, target: &mut [u8], path: &Path) -> io::Result<()> {
+    let size = target.len();
+    for filled in (0..size).step_by(CHUNK) {
+        log_status(filled, size, "bytes", false);
+        let len = CHUNK.min(size - filled);
+        let read = read_full(stream, &mut target[filled..filled + len])?;
+        if read < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} ended after {} of {} bytes", path.display(), filled + read, size),
+            ));
+        }
+    }
+    log_complete(size, "bytes");
+    eprintln!();
+    Ok(())
+}
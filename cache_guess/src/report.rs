@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::coverage::LARGEST_GAPS;
+use crate::find::QUALITY_BUCKETS;
+use crate::mapping::MappingEntry;
+use crate::{export, provenance, visualize};
+
+/// Rows of the per-block table; past this the report says how many were left out.
+const MAX_TABLE_ROWS: usize = 100_000;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}table{border-collapse:collapse}\
+td,th{border:1px solid #ccc;padding:2px 8px;text-align:right}th{background:#eee}\
+.bar{background:#4a4;height:1em}img{image-rendering:pixelated;max-width:100%;border:1px solid #ccc}\
+tr.conflict td{background:#fdd}";
+
+const FILTER: &str = "function filter(q){for(const r of document.querySelectorAll('#blocks tbody tr'))\
+r.style.display=r.textContent.includes(q)?'':'none'}";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn quality_bucket(match_percent: f64) -> usize {
+    match match_percent {
+        p if p >= 100.0 => 0,
+        p if p >= 90.0 => 1,
+        p if p >= 50.0 => 2,
+        p if p > 0.0 => 3,
+        _ => 4,
+    }
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    100.0 * count as f64 / total.max(1) as f64
+}
+
+/// Writes one static HTML file with the summary, match histogram, coverage map, conflicts and a searchable table of
+/// every entry, for handing results to someone without the tool.
+pub fn write(
+    path: &Path,
+    mapping_path: &Path,
+    entries: &[MappingEntry],
+    covered: &[bool],
+    gaps: &[(usize, usize)],
+    cache_block_size: usize,
+) -> io::Result<()> {
+    let mut by_cache_block: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut by_origin_block: HashMap<usize, Vec<usize>> = HashMap::new();
+    for entry in entries {
+        by_cache_block
+            .entry(entry.cache_block)
+            .or_default()
+            .push(entry.origin_block);
+        by_origin_block
+            .entry(entry.origin_block)
+            .or_default()
+            .push(entry.cache_block);
+    }
+    let mut conflicts: Vec<String> = Vec::new();
+    let mut repeated: Vec<(&usize, &Vec<usize>)> = by_cache_block.iter().filter(|(_, v)| v.len() > 1).collect();
+    repeated.sort_unstable();
+    for (cache_block, origins) in repeated {
+        let origins: Vec<String> = origins.iter().map(usize::to_string).collect();
+        conflicts.push(format!(
+            "cache block {} is listed {} times, mapped to {}",
+            cache_block,
+            origins.len(),
+            origins.join(", ")
+        ));
+    }
+    let mut shared: Vec<(&usize, &Vec<usize>)> = by_origin_block.iter().filter(|(_, v)| v.len() > 1).collect();
+    shared.sort_unstable();
+    for (origin_block, cache_blocks) in shared {
+        let cache_blocks: Vec<String> = cache_blocks.iter().map(usize::to_string).collect();
+        conflicts.push(format!(
+            "cache blocks {} all map to origin block {}",
+            cache_blocks.join(", "),
+            origin_block
+        ));
+    }
+
+    let mut quality = [0; QUALITY_BUCKETS.len()];
+    let mut unscored = 0;
+    for entry in entries {
+        match entry.match_percent {
+            Some(match_percent) => quality[quality_bucket(match_percent)] += 1,
+            None => unscored += 1,
+        }
+    }
+    let confidences: Vec<f64> = entries.iter().filter_map(|entry| entry.confidence).collect();
+    let tied = entries.iter().filter(|entry| entry.tied).count();
+    let total_covered = covered.iter().filter(|&&covered| covered).count();
+
+    let mut out = BufWriter::new(File::create(path)?);
+    let title = escape(&mapping_path.display().to_string());
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(
+        out,
+        "<html><head><meta charset=\"utf-8\"><title>cache_guess: {}</title><style>{}</style><script>{}</script></head><body>",
+        title, STYLE, FILTER
+    )?;
+    writeln!(out, "<h1>{}</h1>", title)?;
+
+    writeln!(out, "<h2>Summary</h2><table>")?;
+    let rows = [
+        ("mapped cache blocks", entries.len().to_string()),
+        ("cache block size", format!("{} bytes", cache_block_size)),
+        ("extents", export::coalesce(entries).len().to_string()),
+        ("tied", tied.to_string()),
+        ("conflicts", conflicts.len().to_string()),
+        (
+            "mean confidence",
+            match confidences.len() {
+                0 => "-".to_string(),
+                n => format!("{:.3}", confidences.iter().sum::<f64>() / n as f64),
+            },
+        ),
+        (
+            "origin covered",
+            format!(
+                "{} of {} blocks ({:.1}%)",
+                total_covered,
+                covered.len(),
+                percent(total_covered, covered.len())
+            ),
+        ),
+        ("uncovered runs", gaps.len().to_string()),
+    ];
+    for (name, value) in rows {
+        writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", name, value)?;
+    }
+    writeln!(out, "</table>")?;
+
+    writeln!(out, "<h2>Best match quality</h2><table>")?;
+    let scored = entries.len() - unscored;
+    for (label, count) in QUALITY_BUCKETS.iter().zip(quality) {
+        writeln!(
+            out,
+            "<tr><th>{}</th><td>{}</td><td>{:.1}%</td><td style=\"width:20em;text-align:left\">\
+             <div class=\"bar\" style=\"width:{:.1}%\"></div></td></tr>",
+            label,
+            count,
+            percent(count, scored),
+            percent(count, scored)
+        )?;
+    }
+    if unscored > 0 {
+        writeln!(
+            out,
+            "<tr><th>no statistics</th><td>{}</td><td></td><td></td></tr>",
+            unscored
+        )?;
+    }
+    writeln!(out, "</table>")?;
+
+    writeln!(
+        out,
+        "<h2>Coverage map</h2><p>Cache blocks above the gray line, origin blocks below; green is a sure match, red \
+         a poor one, blue mapped without a confidence, black unmapped.</p>"
+    )?;
+    writeln!(
+        out,
+        "<img alt=\"coverage map\" src=\"data:image/png;base64,{}\">",
+        base64(&visualize::png(entries, covered.len()))
+    )?;
+    if !gaps.is_empty() {
+        writeln!(out, "<h3>Largest uncovered runs</h3><ul>")?;
+        for &(len, start) in gaps.iter().take(LARGEST_GAPS) {
+            writeln!(
+                out,
+                "<li>origin blocks {}-{} ({} blocks)</li>",
+                start,
+                start + len - 1,
+                len
+            )?;
+        }
+        writeln!(out, "</ul>")?;
+    }
+
+    writeln!(out, "<h2>Conflicts</h2>")?;
+    if conflicts.is_empty() {
+        writeln!(out, "<p>None.</p>")?;
+    } else {
+        writeln!(out, "<ul>")?;
+        for conflict in &conflicts {
+            writeln!(out, "<li>{}</li>", conflict)?;
+        }
+        writeln!(out, "</ul>")?;
+    }
+
+    writeln!(
+        out,
+        "<h2>Blocks</h2><p><input placeholder=\"filter\" oninput=\"filter(this.value)\"></p>\
+         <table id=\"blocks\"><thead><tr><th>cache block</th><th>origin block</th><th>match</th>\
+         <th>confidence</th><th>notes</th></tr></thead><tbody>"
+    )?;
+    let mut sorted: Vec<&MappingEntry> = entries.iter().collect();
+    sorted.sort_by_key(|entry| entry.cache_block);
+    for entry in sorted.iter().take(MAX_TABLE_ROWS) {
+        let conflict = by_cache_block[&entry.cache_block].len() > 1 || by_origin_block[&entry.origin_block].len() > 1;
+        let mut notes = Vec::new();
+        if entry.tied {
+            notes.push("tied");
+        }
+        if conflict {
+            notes.push("conflict");
+        }
+        writeln!(
+            out,
+            "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            if conflict { " class=\"conflict\"" } else { "" },
+            entry.cache_block,
+            entry.origin_block,
+            entry.match_percent.map_or("-".to_string(), |p| format!("{:.1}%", p)),
+            entry.confidence.map_or("-".to_string(), |c| format!("{:.3}", c)),
+            notes.join(", ")
+        )?;
+    }
+    writeln!(out, "</tbody></table>")?;
+    if sorted.len() > MAX_TABLE_ROWS {
+        writeln!(
+            out,
+            "<p>{} more entries left out; see {}.</p>",
+            sorted.len() - MAX_TABLE_ROWS,
+            title
+        )?;
+    }
+    writeln!(out, "<h2>Provenance</h2><table>")?;
+    for line in provenance::lines() {
+        let (key, value) = line.split_once(": ").unwrap_or(("", &line));
+        writeln!(
+            out,
+            "<tr><th>{}</th><td style=\"text-align:left\">{}</td></tr>",
+            key,
+            escape(value)
+        )?;
+    }
+    writeln!(out, "</table></body></html>")?;
+    out.flush()
+}
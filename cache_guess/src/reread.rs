@@ -0,0 +1,226 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use memmap2::MmapOptions;
+
+use crate::block_errors::{self, Kind};
+use crate::output::Output;
+use crate::{
+    compressed, hash, log_complete, log_status, memory, metrics, provenance, remote, split, MappedFile, BLOCK_SIZE,
+};
+
+/// Covers the logical block size of any device O_DIRECT is used on.
+const ALIGN: usize = 4096;
+/// What every pass reads before the next pass reads it again.
+const CHUNK: usize = 1 << 20;
+
+/// Reads that go to the device every time. With O_DIRECT the page cache is left out; where that is unavailable,
+/// the cached pages are dropped before each read, or every pass after the first would see what the first read.
+struct Rereader {
+    file: File,
+    direct: bool,
+    buffer: Vec<u8>,
+    aligned: usize,
+}
+
+impl Rereader {
+    fn open(path: &Path, base: usize) -> io::Result<Self> {
+        let direct = base
+            .is_multiple_of(ALIGN)
+            .then(|| {
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECT)
+                    .open(path)
+                    .ok()
+            })
+            .flatten();
+        let (file, direct) = match direct {
+            Some(file) => (file, true),
+            None => (File::open(path)?, false),
+        };
+        let buffer = vec![0; CHUNK + ALIGN];
+        let aligned = buffer.as_ptr().align_offset(ALIGN);
+        Ok(Self {
+            file,
+            direct,
+            buffer,
+            aligned,
+        })
+    }
+
+    /// Fills `buf` from `offset` of the file, which is aligned wherever O_DIRECT is used.
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        if !self.direct {
+            unsafe {
+                libc::posix_fadvise(
+                    self.file.as_raw_fd(),
+                    offset as libc::off_t,
+                    buf.len() as libc::off_t,
+                    libc::POSIX_FADV_DONTNEED,
+                )
+            };
+            return self.file.read_exact_at(buf, offset as u64);
+        }
+        // The length has to be aligned too; only a read at the very end of the file comes back short.
+        let len = buf.len().next_multiple_of(ALIGN);
+        let chunk = &mut self.buffer[self.aligned..self.aligned + len];
+        let mut filled = 0;
+        while filled < len {
+            match self.file.read_at(&mut chunk[filled..], (offset + filled) as u64)? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled < buf.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past the file end"));
+        }
+        buf.copy_from_slice(&chunk[..buf.len()]);
+        Ok(())
+    }
+}
+
+/// A cache device as the passes over it read it.
+pub struct Reread {
+    /// Every fs-block with the content most passes read.
+    pub device: MappedFile,
+    /// fs-blocks that no content was read for in more than half of the passes, as byte ranges; they are as
+    /// unreliable as unrescued ones.
+    pub unreliable: Vec<(usize, usize)>,
+}
+
+/// Reads `device` `passes` times over, a chunk at a time, and keeps the content of each fs-block that most of the
+/// reads agree on. fs-blocks that read differently, or failed to read in some passes, are listed in `report` by
+/// cache block, with how many versions there were and how many reads agreed on the one kept.
+pub fn reread(
+    path: &Path,
+    device: &MappedFile,
+    passes: usize,
+    cache_block_size: usize,
+    report: Option<&Path>,
+    force: bool,
+) -> io::Result<Reread> {
+    if split::series(path).is_some() || compressed::Format::detect(path).is_some() || remote::is_remote(path) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}: --passes rereads a local device or plain image, not a split, compressed or remote one",
+                path.display()
+            ),
+        ));
+    }
+    let size = device.size();
+    memory::preflight(
+        &format!("rereading {}", path.display()),
+        size,
+        "image it with ddrescue and match the image instead",
+        force,
+    )?;
+    let mut reader = Rereader::open(path, device.base)?;
+    eprintln!(
+        "reading {} {} times{}",
+        path.display(),
+        passes,
+        if reader.direct {
+            " with O_DIRECT"
+        } else {
+            ", dropping its cached pages before every read"
+        }
+    );
+    provenance::record_setting("passes", passes);
+    let mut out = report.map(|report| Output::create(Some(report))).transpose()?;
+    if let Some(out) = &mut out {
+        provenance::write_comments(out)?;
+        writeln!(out, "# {} passes over {}", passes, path.display())?;
+        writeln!(out, "# cache_block fs_block versions agreeing read_errors verdict")?;
+    }
+
+    let mut merged = MmapOptions::new().len(size.max(1)).map_anon()?;
+    let mut reads = vec![vec![0; CHUNK]; passes];
+    let mut unreliable: Vec<(usize, usize)> = Vec::new();
+    let (mut unstable, mut outvoted) = (0, 0);
+    for offset in (0..size).step_by(CHUNK) {
+        log_status(offset * passes, size * passes, "bytes", false);
+        let len = CHUNK.min(size - offset);
+        // A read error on the whole chunk is retried an fs-block at a time, so one bad sector only fails its own.
+        let mut failed = vec![vec![false; len.div_ceil(BLOCK_SIZE)]; passes];
+        for (read, failed) in reads.iter_mut().zip(&mut failed) {
+            if reader.read(device.base + offset, &mut read[..len]).is_ok() {
+                continue;
+            }
+            for (fs_block, data) in read[..len].chunks_mut(BLOCK_SIZE).enumerate() {
+                let at = device.base + offset + fs_block * BLOCK_SIZE;
+                failed[fs_block] = reader.read(at, data).is_err();
+            }
+        }
+        for (fs_block, target) in merged[offset..offset + len].chunks_mut(BLOCK_SIZE).enumerate() {
+            let range = fs_block * BLOCK_SIZE..fs_block * BLOCK_SIZE + target.len();
+            // (digest, reads, first pass that read it)
+            let mut versions: Vec<(u64, usize, usize)> = Vec::new();
+            for pass in (0..passes).filter(|&pass| !failed[pass][fs_block]) {
+                let digest = hash::xxh64(&reads[pass][range.clone()]);
+                match versions.iter_mut().find(|(version, _, _)| *version == digest) {
+                    Some((_, count, _)) => *count += 1,
+                    None => versions.push((digest, 1, pass)),
+                }
+            }
+            let errors = failed.iter().filter(|failed| failed[fs_block]).count();
+            let best = versions.iter().max_by_key(|&&(_, count, _)| count).copied();
+            if let Some((_, _, pass)) = best {
+                target.copy_from_slice(&reads[pass][range]);
+            }
+            if versions.len() == 1 && errors == 0 {
+                continue;
+            }
+            unstable += 1;
+            let agreeing = best.map_or(0, |(_, count, _)| count);
+            let kept = agreeing * 2 > passes;
+            if kept {
+                outvoted += 1;
+            } else {
+                let start = offset + fs_block * BLOCK_SIZE;
+                if errors > 0 {
+                    let detail = format!("{} of {} passes could not read it", errors, passes);
+                    block_errors::record(Kind::IoRead, path, start / BLOCK_SIZE, BLOCK_SIZE, detail);
+                }
+                match unreliable.last_mut() {
+                    Some(last) if last.1 == start => last.1 += target.len(),
+                    _ => unreliable.push((start, start + target.len())),
+                }
+            }
+            if let Some(out) = &mut out {
+                let at = offset + fs_block * BLOCK_SIZE;
+                writeln!(
+                    out,
+                    "{} {} {} {} {} {}",
+                    at / cache_block_size,
+                    at % cache_block_size / BLOCK_SIZE,
+                    versions.len(),
+                    agreeing,
+                    errors,
+                    if kept { "majority" } else { "unreliable" }
+                )?;
+            }
+        }
+    }
+    log_complete(size * passes, "bytes");
+    if let Some(out) = out {
+        out.commit()?;
+    }
+    metrics::count("unstable_fs_blocks", unstable);
+    eprintln!(
+        "\n{} of {} fs-blocks did not read the same in every pass: {} kept as most passes read them, {} without a \
+         majority and not matched",
+        unstable,
+        size.div_ceil(BLOCK_SIZE),
+        outvoted,
+        unstable - outvoted
+    );
+    Ok(Reread {
+        device: MappedFile::from_mmap(merged.make_read_only()?, size),
+        unreliable,
+    })
+}
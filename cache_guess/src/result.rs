@@ -0,0 +1,31 @@
+use crate::unmatched::PartialMatch;
+
+/// One origin cache block that some fs-blocks of a cache block were found at.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub origin_block: usize,
+    pub count: usize,
+    /// Log-likelihood ratio, in nats, of its matching fs-blocks coming from it rather than from chance, see
+    /// `scoring::rarity`.
+    pub evidence: f64,
+    pub confidence: f64,
+    /// The indexes it matches best in, by the order `find` was given them.
+    pub indexes: Vec<usize>,
+}
+
+/// Everything `find` learned about one cache block.
+#[derive(Debug, Clone)]
+pub struct BlockResult {
+    pub cache_block: usize,
+    pub candidates: Vec<Candidate>,
+    pub fake_matches: usize,
+    /// Truncated-digest hits that turned out to be different data.
+    pub collisions: usize,
+    pub partial: Vec<PartialMatch>,
+    /// fs-blocks with at least one index hit, aligned or not.
+    pub matched_fs_blocks: usize,
+    /// Runner-up candidates scoring exactly as well as the best one.
+    pub tied: usize,
+    /// Part of the cache block is in a region a ddrescue mapfile does not mark rescued.
+    pub tainted: bool,
+}
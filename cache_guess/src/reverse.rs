@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::selection::Selection;
+use crate::throttle::Throttle;
+use crate::{hash_block, inuse, log_complete, log_status, open_device, BLOCK_SIZE};
+
+pub struct ReverseOptions<'a> {
+    pub cache_block_size: usize,
+    pub partition: Option<usize>,
+    pub origin_partition: Option<usize>,
+    /// Origin blocks, in cache block size units.
+    pub blocks: Option<&'a Selection>,
+    /// Origin byte ranges.
+    pub bytes: Option<&'a Selection>,
+    pub bwlimit: Option<f64>,
+    pub force: bool,
+}
+
+/// Looks for copies of the given origin blocks anywhere on the cache device, at fs-block granularity.
+pub fn reverse_find(cache_device_path: &Path, origin_path: &Path, options: &ReverseOptions) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path, origin_path], options.force)?;
+    let cache_block_size = options.cache_block_size;
+    let origin = open_device(origin_path, options.origin_partition)?;
+    let cache_device = open_device(cache_device_path, options.partition)?;
+
+    // Origin fs-blocks to look for, by number.
+    let mut wanted = Vec::new();
+    if let Some(blocks) = options.blocks {
+        let fs_blocks = cache_block_size / BLOCK_SIZE;
+        for (start, end) in blocks.ranges(origin.size() / cache_block_size) {
+            wanted.extend(start * fs_blocks..end * fs_blocks);
+        }
+    }
+    if let Some(bytes) = options.bytes {
+        for (start, end) in bytes.ranges(origin.size()) {
+            wanted.extend(start / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE).min(origin.size() / BLOCK_SIZE));
+        }
+    }
+    wanted.sort_unstable();
+    wanted.dedup();
+
+    let zero = hash_block(&[0; BLOCK_SIZE]);
+    let mut digests: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+    let mut zeros = 0;
+    for &fs_block in &wanted {
+        let digest = hash_block(origin.slice(fs_block * BLOCK_SIZE, BLOCK_SIZE));
+        // Zero blocks are everywhere and say nothing about where a copy is.
+        if digest == zero {
+            zeros += 1;
+            continue;
+        }
+        digests.entry(digest).or_default().push(fs_block);
+    }
+    eprintln!(
+        "looking for {} origin fs-blocks ({} zero-filled ones skipped)",
+        wanted.len() - zeros,
+        zeros
+    );
+
+    let mut throttle = Throttle::new(options.bwlimit);
+    let mut found: HashMap<usize, Vec<usize>> = HashMap::new();
+    let total = cache_device.size() / BLOCK_SIZE;
+    for cache_fs_block in 0..total {
+        if cache_fs_block % 1024 == 0 {
+            log_status(cache_fs_block, total, "fs-blocks", false);
+        }
+        throttle.consume(BLOCK_SIZE);
+        let digest = hash_block(cache_device.slice(cache_fs_block * BLOCK_SIZE, BLOCK_SIZE));
+        for &origin_fs_block in digests.get(&digest).into_iter().flatten() {
+            found.entry(origin_fs_block).or_default().push(cache_fs_block);
+        }
+    }
+    log_complete(total, "fs-blocks");
+    eprintln!();
+
+    let fs_per_block = cache_block_size / BLOCK_SIZE;
+    let mut located = 0;
+    for fs_block in wanted {
+        let Some(copies) = found.get(&fs_block) else {
+            continue;
+        };
+        located += 1;
+        for &copy in copies {
+            let aligned = copy % fs_per_block == fs_block % fs_per_block;
+            println!(
+                "origin block {} fs-block {} -> cache block {} fs-block {}{}",
+                fs_block / fs_per_block,
+                fs_block % fs_per_block,
+                copy / fs_per_block,
+                copy % fs_per_block,
+                if aligned { "" } else { " (misaligned)" }
+            );
+        }
+    }
+    eprintln!(
+        "{} of {} origin fs-blocks have a copy in the cache",
+        located,
+        digests.values().map(Vec::len).sum::<usize>()
+    );
+    Ok(())
+}
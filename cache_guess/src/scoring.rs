@@ -0,0 +1,145 @@
+use crate::result::BlockResult;
+
+// Neighbors further apart than this in the origin are treated as unrelated.
+const NEIGHBOR_DISTANCE: usize = 16;
+// How much full neighbor support can close the gap to a confidence of 1.
+const NEIGHBOR_WEIGHT: f64 = 0.5;
+
+// The likelihood model's odds, as the chances behind them. An fs-block of the genuine origin block still matches
+// unless it was written after the index was made, which dirty cache blocks often were.
+const MATCH_GIVEN_GENUINE: f64 = 0.5;
+// Data can occur elsewhere in the origin without the index knowing, copied or at a position it did not sample,
+// so no single fs-block is taken as stronger evidence than this chance of coincidence allows.
+const COINCIDENCE: f64 = 1e-3;
+// How much likelier full neighbor support is for the genuine origin block than for a wrong one.
+const NEIGHBOR_RATIO: f64 = 20.0;
+
+fn fraction(count: usize, fs_blocks: usize) -> f64 {
+    count as f64 / fs_blocks as f64
+}
+
+/// How well `neighbor` supports its adjacent cache block mapping to `origin_block`: 1 if the neighbor's
+/// own match sits right next to it in the origin, falling off with distance and with the neighbor's match
+/// fraction. `None` if the neighbor matched nothing and so says nothing either way.
+fn support(neighbor: &BlockResult, expected: Option<usize>, fs_blocks: usize) -> Option<f64> {
+    if neighbor.candidates.is_empty() {
+        return None;
+    }
+    let expected = expected?;
+    let best = neighbor
+        .candidates
+        .iter()
+        .filter_map(|candidate| {
+            let distance = candidate.origin_block.abs_diff(expected);
+            (distance <= NEIGHBOR_DISTANCE).then(|| fraction(candidate.count, fs_blocks) / (1.0 + distance as f64))
+        })
+        .fold(0.0, f64::max);
+    Some(best)
+}
+
+/// Shannon entropy of the bytes of `block`, in bits per byte: 0 for a block of one repeated byte, 8 for noise.
+pub fn entropy(block: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in block {
+        counts[byte as usize] += 1;
+    }
+    let len = block.len().max(1) as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// What one matching fs-block says for the origin block it matches at: the log of how unlikely its data is to
+/// be there by chance, given that `copies` of the index's `entries` hold it, weighed by how much its contents
+/// can tell apart at all.
+pub fn rarity(entries: usize, copies: usize, entropy: f64) -> f64 {
+    let chance = (copies.max(1) as f64 / entries.max(1) as f64).max(COINCIDENCE);
+    -chance.ln() * entropy / 8.0
+}
+
+/// Scores candidates as the probability that the cache block came from them, where the probabilities of all
+/// candidates and of none at all add up to 1. Each candidate's odds against chance combine the `evidence` of
+/// its matching fs-blocks, what the fs-blocks that do not match say against it, and how well the neighboring
+/// cache blocks map next to it.
+pub fn likelihood(result: &mut BlockResult, prev: Option<&BlockResult>, next: Option<&BlockResult>, fs_blocks: usize) {
+    let prev = prev.filter(|prev| prev.cache_block + 1 == result.cache_block);
+    let next = next.filter(|next| next.cache_block == result.cache_block + 1);
+    let log_odds: Vec<f64> = result
+        .candidates
+        .iter()
+        .map(|candidate| {
+            let misses = fs_blocks.saturating_sub(candidate.count);
+            let neighbor = [
+                prev.and_then(|prev| support(prev, candidate.origin_block.checked_sub(1), fs_blocks)),
+                next.and_then(|next| support(next, Some(candidate.origin_block + 1), fs_blocks)),
+            ]
+            .into_iter()
+            .flatten()
+            .fold(0.0, f64::max);
+            candidate.evidence
+                + candidate.count as f64 * MATCH_GIVEN_GENUINE.ln()
+                + misses as f64 * (1.0 - MATCH_GIVEN_GENUINE).ln()
+                + neighbor * NEIGHBOR_RATIO.ln()
+        })
+        .collect();
+    // Log-sum-exp over the candidates and "none of them", whose odds are 1.
+    let max = log_odds.iter().copied().fold(0.0, f64::max);
+    let total = (-max).exp() + log_odds.iter().map(|odds| (odds - max).exp()).sum::<f64>();
+    for (candidate, odds) in result.candidates.iter_mut().zip(log_odds) {
+        candidate.confidence = (odds - max).exp() / total;
+    }
+    rank(result);
+}
+
+/// Combines, per candidate, the share of fs-blocks matching it, the share of *matched* fs-blocks that agree
+/// on it, and whether the neighboring cache blocks map next to it. Neighbor consistency only ever boosts
+/// (cache blocks are not generally promoted in origin order), so an isolated full match stays at 1.
+/// Candidates are re-ranked by the result.
+pub fn score(result: &mut BlockResult, prev: Option<&BlockResult>, next: Option<&BlockResult>, fs_blocks: usize) {
+    let prev = prev.filter(|prev| prev.cache_block + 1 == result.cache_block);
+    let next = next.filter(|next| next.cache_block == result.cache_block + 1);
+    let matched = result.matched_fs_blocks.max(1);
+    for candidate in &mut result.candidates {
+        let match_fraction = fraction(candidate.count, fs_blocks);
+        let agreement = candidate.count as f64 / matched as f64;
+        let base = (match_fraction + agreement) / 2.0;
+
+        let supports: Vec<f64> = [
+            prev.and_then(|prev| support(prev, candidate.origin_block.checked_sub(1), fs_blocks)),
+            next.and_then(|next| support(next, Some(candidate.origin_block + 1), fs_blocks)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let neighbor = if supports.is_empty() {
+            0.0
+        } else {
+            supports.iter().sum::<f64>() / supports.len() as f64
+        };
+        candidate.confidence = base + (1.0 - base) * NEIGHBOR_WEIGHT * neighbor;
+    }
+    rank(result);
+}
+
+/// Sorts the candidates best first and counts the ties for the best.
+fn rank(result: &mut BlockResult) {
+    // The origin block breaks what is left of a tie, so the order never depends on hash map iteration.
+    result.candidates.sort_by(|a, b| {
+        b.confidence
+            .total_cmp(&a.confidence)
+            .then(b.count.cmp(&a.count))
+            .then(a.origin_block.cmp(&b.origin_block))
+    });
+    result.tied = match result.candidates.split_first() {
+        Some((best, rest)) => rest
+            .iter()
+            .take_while(|candidate| candidate.confidence == best.confidence && candidate.count == best.count)
+            .count(),
+        None => 0,
+    };
+}
@@ -0,0 +1,154 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A set of block numbers, given as `n`, `start..end`, `start..=end` or `start..`, comma separated.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    ranges: Vec<(usize, usize)>,
+}
+
+fn parse_range(part: &str) -> Result<(usize, usize), String> {
+    let number = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("'{}' is not a block number or range", part))
+    };
+    if let Some((start, end)) = part.split_once("..=") {
+        Ok((number(start)?, number(end)? + 1))
+    } else if let Some((start, end)) = part.split_once("..") {
+        let end = if end.trim().is_empty() {
+            usize::MAX
+        } else {
+            number(end)?
+        };
+        Ok((number(start)?, end))
+    } else {
+        let block = number(part)?;
+        Ok((block, block + 1))
+    }
+}
+
+impl Selection {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut selection = Self::default();
+        selection.add(spec)?;
+        Ok(selection)
+    }
+
+    fn add(&mut self, spec: &str) -> Result<(), String> {
+        for part in spec.split(',').filter(|part| !part.trim().is_empty()) {
+            self.ranges.push(parse_range(part)?);
+        }
+        self.merge();
+        Ok(())
+    }
+
+    /// Adds the blocks `start..end`.
+    pub fn add_range(&mut self, start: usize, end: usize) {
+        self.ranges.push((start, end));
+        self.merge();
+    }
+
+    fn merge(&mut self) {
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    /// Drops every block up to and including `last`.
+    pub fn remove_through(&mut self, last: usize) {
+        self.ranges.retain(|&(_, end)| end > last + 1);
+        if let Some(first) = self.ranges.first_mut() {
+            first.0 = first.0.max(last + 1);
+        }
+    }
+
+    /// Adds the blocks listed in a file, one number or range per line; `#` starts a comment.
+    pub fn add_file(&mut self, path: &Path) -> io::Result<()> {
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            self.add(line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+
+    /// The selected ranges below `total` as `(start, end)`, end exclusive, in ascending order.
+    pub fn ranges(&self, total: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.ranges
+            .iter()
+            .take_while(move |&&(start, _)| start < total)
+            .map(move |&(start, end)| (start, end.min(total)))
+    }
+
+    /// The selected blocks below `total`, in ascending order.
+    pub fn blocks(&self, total: usize) -> impl Iterator<Item = usize> + '_ {
+        self.ranges(total).flat_map(|(start, end)| start..end)
+    }
+
+    pub fn count(&self, total: usize) -> usize {
+        self.ranges
+            .iter()
+            .map(|&(start, end)| end.min(total).saturating_sub(start))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_numbers_and_ranges() {
+        let selection = Selection::parse("7, 1..3,10..=11 ,,20..").unwrap();
+        assert_eq!(selection.ranges, [(1, 3), (7, 8), (10, 12), (20, usize::MAX)]);
+        assert_eq!(selection.blocks(22).collect::<Vec<_>>(), [1, 2, 7, 10, 11, 20, 21]);
+        assert_eq!(selection.count(22), 7);
+        assert_eq!(selection.ranges(9).collect::<Vec<_>>(), [(1, 3), (7, 8)]);
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        let mut selection = Selection::parse("5..10,8..12,12").unwrap();
+        assert_eq!(selection.ranges, [(5, 13)]);
+        selection.add_range(0, 5);
+        assert_eq!(selection.ranges, [(0, 13)]);
+    }
+
+    #[test]
+    fn refuses_what_is_not_a_block() {
+        let e = Selection::parse("1,two").unwrap_err();
+        assert_eq!(e, "'two' is not a block number or range");
+        assert!(Selection::parse("3..x").is_err());
+        assert!(Selection::parse("-1").is_err());
+    }
+
+    #[test]
+    fn remove_through_drops_the_blocks_done() {
+        let mut selection = Selection::parse("0..3,5..").unwrap();
+        selection.remove_through(1);
+        assert_eq!(selection.ranges, [(2, 3), (5, usize::MAX)]);
+        selection.remove_through(6);
+        assert_eq!(selection.ranges, [(7, usize::MAX)]);
+        assert_eq!(selection.blocks(10).collect::<Vec<_>>(), [7, 8, 9]);
+    }
+
+    #[test]
+    fn reads_block_files() {
+        let path = std::env::temp_dir().join(format!("cache_guess-blocks-{}.txt", std::process::id()));
+        fs::write(&path, "# hot blocks\n3\n10..12  # the superblock area\n\n").unwrap();
+        let mut selection = Selection::default();
+        selection.add_file(&path).unwrap();
+        assert_eq!(selection.blocks(100).collect::<Vec<_>>(), [3, 10, 11]);
+        fs::write(&path, "3\nfour\n").unwrap();
+        assert!(selection.add_file(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
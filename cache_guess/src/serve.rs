@@ -0,0 +1,539 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::output::json_string;
+
+/// stderr lines kept per job; earlier ones are dropped.
+const LOG_LINES: usize = 200;
+
+/// A parsed JSON value, just enough for requests.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Self::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+
+    /// Writes the value back out, for echoing request ids.
+    fn encode(&self) -> String {
+        match self {
+            Self::Null => "null".to_string(),
+            Self::Bool(b) => b.to_string(),
+            Self::Number(n) => n.to_string(),
+            Self::String(s) => json_string(s),
+            Self::Array(items) => format!("[{}]", items.iter().map(Json::encode).collect::<Vec<_>>().join(",")),
+            Self::Object(fields) => format!(
+                "{{{}}}",
+                fields
+                    .iter()
+                    .map(|(name, value)| format!("{}:{}", json_string(name), value.encode()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_space();
+        if parser.pos != parser.text.len() {
+            return Err(format!("trailing characters at {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    fn skip_space(&mut self) {
+        while self.text.get(self.pos).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        if self.text[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(format!("unexpected characters at {}", self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_space();
+        match self.text.get(self.pos) {
+            None => Err("unexpected end".to_string()),
+            Some(b'n') => self.expect("null", Json::Null),
+            Some(b't') => self.expect("true", Json::Bool(true)),
+            Some(b'f') => self.expect("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_space();
+                if self.text.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_space();
+                    match self.text.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(format!("expected , or ] at {}", self.pos)),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_space();
+                if self.text.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    if self.text.get(self.pos) != Some(&b'"') {
+                        return Err(format!("expected a key at {}", self.pos));
+                    }
+                    let name = self.string()?;
+                    self.skip_space();
+                    if self.text.get(self.pos) != Some(&b':') {
+                        return Err(format!("expected : at {}", self.pos));
+                    }
+                    self.pos += 1;
+                    fields.push((name, self.value()?));
+                    self.skip_space();
+                    match self.text.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(format!("expected , or }} at {}", self.pos)),
+                    }
+                }
+            }
+            Some(_) => {
+                let start = self.pos;
+                while self
+                    .text
+                    .get(self.pos)
+                    .is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c))
+                {
+                    self.pos += 1;
+                }
+                std::str::from_utf8(&self.text[start..self.pos])
+                    .ok()
+                    .and_then(|number| number.parse().ok())
+                    .map(Json::Number)
+                    .ok_or_else(|| format!("unexpected characters at {}", start))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&c) = self.text.get(self.pos) else {
+                return Err("unterminated string".to_string());
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return String::from_utf8(bytes).map_err(|_| "string is not UTF-8".to_string()),
+                b'\\' => {
+                    let escaped = self.text.get(self.pos).copied();
+                    self.pos += 1;
+                    match escaped {
+                        Some(b'"') => bytes.push(b'"'),
+                        Some(b'\\') => bytes.push(b'\\'),
+                        Some(b'/') => bytes.push(b'/'),
+                        Some(b'n') => bytes.push(b'\n'),
+                        Some(b't') => bytes.push(b'\t'),
+                        Some(b'r') => bytes.push(b'\r'),
+                        Some(b'b') => bytes.push(8),
+                        Some(b'f') => bytes.push(12),
+                        Some(b'u') => {
+                            let code = self
+                                .text
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .ok_or_else(|| format!("bad \\u escape at {}", self.pos))?;
+                            self.pos += 4;
+                            let c = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            bytes.extend_from_slice(c.to_string().as_bytes());
+                        }
+                        _ => return Err(format!("bad escape at {}", self.pos - 1)),
+                    }
+                }
+                c => bytes.push(c),
+            }
+        }
+    }
+}
+
+/// A subcommand run as a child process on behalf of a client.
+struct Job {
+    args: Vec<String>,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    exit_code: Option<i32>,
+    /// Its stdout, the results, line by line.
+    results: Vec<String>,
+    log: Vec<String>,
+    /// The last progress line, and what has been written since the last line break, such as a question.
+    status: String,
+    pending: String,
+}
+
+impl Job {
+    fn running(&mut self) -> bool {
+        if self.exit_code.is_none() {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                // Killed by a signal has no code.
+                self.exit_code = Some(status.code().unwrap_or(-1));
+            }
+        }
+        self.exit_code.is_none()
+    }
+
+    /// The conflict `conflict::ask` is waiting on an answer for, as it printed it.
+    fn conflict(&self) -> Option<Vec<String>> {
+        self.question("keep which", "conflict: ")
+    }
+
+    /// The confirmation `confirm::confirm` is waiting on, the name of the device to write to, with the summary
+    /// before it; without `--yes` apply asks it before writing anything.
+    fn confirmation(&self) -> Option<Vec<String>> {
+        self.question("type ", "about to write to ")
+    }
+
+    /// The log from the last line starting with `first` on and the prompt, if the job waits on one starting with
+    /// `prompt`.
+    fn question(&self, prompt: &str, first: &str) -> Option<Vec<String>> {
+        if !self.pending.starts_with(prompt) {
+            return None;
+        }
+        let start = self.log.iter().rposition(|line| line.starts_with(first))?;
+        let mut lines = self.log[start..].to_vec();
+        lines.push(self.pending.clone());
+        Some(lines)
+    }
+}
+
+type Jobs = Arc<Mutex<BTreeMap<usize, Job>>>;
+
+fn json_strings(lines: &[String]) -> String {
+    format!(
+        "[{}]",
+        lines.iter().map(|line| json_string(line)).collect::<Vec<_>>().join(",")
+    )
+}
+
+/// Collects a job's stderr: progress lines overwrite each other with `\r`, everything else is kept as log.
+fn follow_stderr(jobs: Jobs, number: usize, mut stderr: impl Read) {
+    let mut buf = [0; 4096];
+    while let Ok(len @ 1..) = stderr.read(&mut buf) {
+        let mut jobs = jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&number) else {
+            return;
+        };
+        for c in String::from_utf8_lossy(&buf[..len]).chars() {
+            match c {
+                '\r' | '\n' => {
+                    let segment = std::mem::take(&mut job.pending);
+                    if segment.contains(" % - ") {
+                        job.status = segment.trim().to_string();
+                    } else if c == '\n' {
+                        job.log.push(segment);
+                        if job.log.len() > LOG_LINES {
+                            job.log.remove(0);
+                        }
+                    }
+                }
+                c => job.pending.push(c),
+            }
+        }
+    }
+}
+
+fn follow_stdout(jobs: Jobs, number: usize, stdout: impl Read) {
+    for line in BufReader::new(stdout).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        match jobs.lock().unwrap().get_mut(&number) {
+            Some(job) => job.results.push(line),
+            None => return,
+        }
+    }
+}
+
+fn job_param<'a>(jobs: &'a mut BTreeMap<usize, Job>, params: &Json) -> Result<(usize, &'a mut Job), String> {
+    let number = params
+        .get("job")
+        .and_then(Json::as_usize)
+        .ok_or("params need a job number")?;
+    jobs.get_mut(&number)
+        .map(|job| (number, job))
+        .ok_or_else(|| format!("no job {}", number))
+}
+
+/// Runs one method; the result is encoded JSON.
+fn call(jobs: &Jobs, method: &str, params: &Json) -> Result<String, String> {
+    match method {
+        "start" => {
+            let args: Vec<String> = match params.get("args") {
+                Some(Json::Array(args)) => args
+                    .iter()
+                    .map(|arg| arg.as_str().map(str::to_string))
+                    .collect::<Option<_>>()
+                    .ok_or("args must be strings")?,
+                _ => return Err("params need args, the command line after the program name".to_string()),
+            };
+            if args.first().is_none_or(|command| command == "serve") {
+                return Err("args must start with a subcommand other than serve".to_string());
+            }
+            let program = std::env::current_exe().map_err(|e| e.to_string())?;
+            let mut child = Command::new(program)
+                .args(&args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e| e.to_string())?;
+            let (stdout, stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+            let mut locked = jobs.lock().unwrap();
+            let number = locked.keys().next_back().map_or(1, |last| last + 1);
+            eprintln!("job {}: {}", number, args.join(" "));
+            locked.insert(
+                number,
+                Job {
+                    args,
+                    stdin: child.stdin.take(),
+                    child,
+                    exit_code: None,
+                    results: Vec::new(),
+                    log: Vec::new(),
+                    status: String::new(),
+                    pending: String::new(),
+                },
+            );
+            drop(locked);
+            let (out_jobs, err_jobs) = (Arc::clone(jobs), Arc::clone(jobs));
+            thread::spawn(move || follow_stdout(out_jobs, number, stdout));
+            thread::spawn(move || follow_stderr(err_jobs, number, stderr));
+            Ok(format!("{{\"job\":{}}}", number))
+        }
+        "jobs" => {
+            let mut jobs = jobs.lock().unwrap();
+            let list: Vec<String> = jobs
+                .iter_mut()
+                .map(|(number, job)| {
+                    format!(
+                        "{{\"job\":{},\"args\":{},\"running\":{},\"exit_code\":{}}}",
+                        number,
+                        json_strings(&job.args),
+                        job.running(),
+                        job.exit_code.map_or("null".to_string(), |code| code.to_string())
+                    )
+                })
+                .collect();
+            Ok(format!("[{}]", list.join(",")))
+        }
+        "progress" => {
+            let mut jobs = jobs.lock().unwrap();
+            let (_, job) = job_param(&mut jobs, params)?;
+            let lines = params
+                .get("log")
+                .and_then(Json::as_usize)
+                .unwrap_or(20)
+                .min(job.log.len());
+            Ok(format!(
+                "{{\"running\":{},\"exit_code\":{},\"status\":{},\"log\":{},\"conflict\":{},\"confirmation\":{}}}",
+                job.running(),
+                job.exit_code.map_or("null".to_string(), |code| code.to_string()),
+                json_string(&job.status),
+                json_strings(&job.log[job.log.len() - lines..]),
+                job.conflict().map_or("null".to_string(), |lines| json_strings(&lines)),
+                job.confirmation()
+                    .map_or("null".to_string(), |lines| json_strings(&lines))
+            ))
+        }
+        "results" => {
+            let mut jobs = jobs.lock().unwrap();
+            let (_, job) = job_param(&mut jobs, params)?;
+            let from = params
+                .get("from")
+                .and_then(Json::as_usize)
+                .unwrap_or(0)
+                .min(job.results.len());
+            let running = job.running();
+            Ok(format!(
+                "{{\"lines\":{},\"next\":{},\"done\":{}}}",
+                json_strings(&job.results[from..]),
+                job.results.len(),
+                !running
+            ))
+        }
+        "answer" => {
+            let mut jobs = jobs.lock().unwrap();
+            let (_, job) = job_param(&mut jobs, params)?;
+            let answer = params
+                .get("answer")
+                .and_then(Json::as_str)
+                .ok_or("params need an answer, such as \"1\" or \"s\", or the device name to confirm")?;
+            if job.conflict().is_none() && job.confirmation().is_none() {
+                return Err("the job is not waiting on a conflict or a confirmation".to_string());
+            }
+            let stdin = job.stdin.as_mut().ok_or("the job's input is closed")?;
+            writeln!(stdin, "{}", answer)
+                .and_then(|_| stdin.flush())
+                .map_err(|e| e.to_string())?;
+            job.pending.clear();
+            Ok("true".to_string())
+        }
+        "cancel" => {
+            let mut jobs = jobs.lock().unwrap();
+            let (number, job) = job_param(&mut jobs, params)?;
+            if job.running() {
+                // Like Ctrl-C, so find writes its checkpoint and apply stops between batches.
+                unsafe {
+                    libc::kill(job.child.id() as libc::pid_t, libc::SIGINT);
+                }
+                eprintln!("job {}: cancelled", number);
+            }
+            Ok("true".to_string())
+        }
+        "forget" => {
+            let mut jobs = jobs.lock().unwrap();
+            let (number, job) = job_param(&mut jobs, params)?;
+            if job.running() {
+                return Err("the job is still running, cancel it first".to_string());
+            }
+            jobs.remove(&number);
+            Ok("true".to_string())
+        }
+        _ => Err(format!("unknown method {}", method)),
+    }
+}
+
+fn respond(request: &str, jobs: &Jobs) -> String {
+    let request = match Parser::parse(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{{\"code\":-32700,\"message\":{}}}}}",
+                json_string(&e)
+            )
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Json::Null).encode();
+    let Some(method) = request.get("method").and_then(Json::as_str) else {
+        return format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":-32600,\"message\":\"no method\"}}}}",
+            id
+        );
+    };
+    match call(jobs, method, request.get("params").unwrap_or(&Json::Null)) {
+        Ok(result) => format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}", id, result),
+        Err(e) => format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":-32000,\"message\":{}}}}}",
+            id,
+            json_string(&e)
+        ),
+    }
+}
+
+fn handle(stream: UnixStream, jobs: Jobs) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(out, "{}", respond(&line, &jobs))?;
+    }
+    Ok(())
+}
+
+/// Serves JSON-RPC 2.0 on a Unix socket, one request and one response per line. Each job is a subcommand run as a
+/// child process: its stdout is the results, its stderr the progress and log, and its stdin answers the questions
+/// of `--conflict interactive` and the confirmation of apply without `--yes`, which `progress` shows. Methods:
+/// `start {args}`, `jobs`, `progress {job, log}`, `results {job, from}`, `answer {job, answer}`, `cancel {job}`
+/// and `forget {job}`.
+pub fn serve(socket: &Path) -> io::Result<()> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already being served", socket.display()),
+            ));
+        }
+        fs::remove_file(socket)?;
+    }
+    // Jobs can write to devices, so the socket is for its owner only, from the start.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    eprintln!("serving on {}", socket.display());
+    let jobs: Jobs = Arc::new(Mutex::new(BTreeMap::new()));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let jobs = Arc::clone(&jobs);
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, jobs) {
+                eprintln!("connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::mapped::logical_sector_size;
+use crate::throttle::Throttle;
+use crate::{inuse, log_complete, log_status, open_device, BLOCK_SIZE};
+
+const BASE: u64 = 0x100_0000_01b3;
+const MAX_LISTED_SHIFTS: usize = 8;
+
+/// Rabin-Karp style polynomial hash of one fs-block, mod 2^64.
+fn block_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0u64, |hash, &byte| {
+        hash.wrapping_mul(BASE).wrapping_add(byte as u64 + 1)
+    })
+}
+
+pub struct ShiftOptions {
+    pub partition: Option<usize>,
+    pub origin_partition: Option<usize>,
+    /// How much of the cache device to slide over, from its start.
+    pub scan_bytes: usize,
+    pub bwlimit: Option<f64>,
+    pub force: bool,
+}
+
+/// Slides an fs-block sized window over the cache device one byte at a time, looking for origin fs-blocks at
+/// any byte offset, and reports how far off the fs-block grid the copies sit. A cache image extracted from the
+/// wrong starting offset shows up as one dominant non-zero shift.
+pub fn shift_scan(cache_device_path: &Path, origin_path: &Path, options: &ShiftOptions) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path, origin_path], options.force)?;
+    let origin = open_device(origin_path, options.origin_partition)?;
+    let cache_device = open_device(cache_device_path, options.partition)?;
+    let mut throttle = Throttle::new(options.bwlimit);
+
+    let zero = block_hash(&[0; BLOCK_SIZE]);
+    let mut origin_blocks: HashMap<u64, usize> = HashMap::new();
+    let origin_fs_blocks = origin.size() / BLOCK_SIZE;
+    for fs_block in 0..origin_fs_blocks {
+        if fs_block % 1024 == 0 {
+            log_status(fs_block, origin_fs_blocks, "origin fs-blocks", false);
+        }
+        throttle.consume(BLOCK_SIZE);
+        let hash = block_hash(origin.slice(fs_block * BLOCK_SIZE, BLOCK_SIZE));
+        if hash != zero {
+            origin_blocks.entry(hash).or_insert(fs_block);
+        }
+    }
+    log_complete(origin_fs_blocks, "origin fs-blocks");
+    eprintln!();
+
+    let scan = options.scan_bytes.min(cache_device.size());
+    if scan < BLOCK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the cache device is smaller than one fs-block",
+        ));
+    }
+    let data = cache_device.slice(0, scan);
+    throttle.consume(scan);
+    let top = (0..BLOCK_SIZE).fold(1u64, |power, _| power.wrapping_mul(BASE));
+    let mut hash = block_hash(&data[..BLOCK_SIZE]);
+    let mut shifts: HashMap<usize, usize> = HashMap::new();
+    let mut position = 0;
+    loop {
+        if position % (1 << 20) == 0 {
+            log_status(position, scan, "bytes", false);
+        }
+        if let Some(&fs_block) = origin_blocks.get(&hash) {
+            let window = &data[position..position + BLOCK_SIZE];
+            if window == origin.slice(fs_block * BLOCK_SIZE, BLOCK_SIZE) {
+                *shifts.entry(position % BLOCK_SIZE).or_insert(0) += 1;
+            }
+        }
+        if position + BLOCK_SIZE >= scan {
+            break;
+        }
+        hash = hash
+            .wrapping_mul(BASE)
+            .wrapping_add(data[position + BLOCK_SIZE] as u64 + 1)
+            .wrapping_sub(top.wrapping_mul(data[position] as u64 + 1));
+        position += 1;
+    }
+    log_complete(scan, "bytes");
+    eprintln!();
+
+    let mut shifts: Vec<(usize, usize)> = shifts.into_iter().collect();
+    shifts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    if shifts.is_empty() {
+        println!("no origin fs-block found at any offset in the first {} bytes", scan);
+        return Ok(());
+    }
+    for &(shift, count) in shifts.iter().take(MAX_LISTED_SHIFTS) {
+        println!("shift {} bytes: {} fs-blocks", shift, count);
+    }
+    match shifts[0].0 {
+        0 => println!("data is on the fs-block grid, no shift detected"),
+        shift => println!(
+            "data sits {} bytes past the fs-block grid: the image likely starts {} bytes early (or {} late)",
+            shift,
+            shift,
+            BLOCK_SIZE - shift
+        ),
+    }
+    let sector_size = logical_sector_size(cache_device_path);
+    if !shifts[0].0.is_multiple_of(sector_size) {
+        println!(
+            "the shift is not a multiple of the {} byte sectors of {}, as a wrong partition or image start would be; \
+             suspect the tool that copied the image",
+            sector_size,
+            cache_device_path.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,364 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::provenance;
+
+/// In 512 byte sectors, as device-mapper tables count.
+const CHUNK_SECTORS: usize = 8;
+
+/// A persistent dm-snapshot of an origin block device with its copy-on-write store in a loop-attached file: writes to
+/// `device` land in the store and leave the origin untouched until the snapshot is merged.
+pub struct Snapshot {
+    pub name: String,
+    pub device: PathBuf,
+    origin: PathBuf,
+    cow: PathBuf,
+    loop_device: String,
+    sectors: usize,
+}
+
+fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot run {} for the snapshot: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The loop device a file is already attached to.
+fn attached_loop(cow: &Path) -> io::Result<Option<String>> {
+    let listing = run("losetup", &["-j", &cow.to_string_lossy()])?;
+    Ok(listing
+        .lines()
+        .next()
+        .and_then(|line| line.split_once(':'))
+        .map(|(device, _)| device.to_string()))
+}
+
+impl Snapshot {
+    /// Creates the snapshot over `origin`, with a new store of `cow_size` bytes at `cow`. Resuming finds the
+    /// snapshot still active, or sets it up again over the store the interrupted run wrote to.
+    pub fn setup(origin: &Path, origin_size: usize, cow: &Path, cow_size: usize, resume: bool) -> io::Result<Self> {
+        let file_name = cow
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let name = format!(
+            "cache_guess-{}",
+            file_name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
+        );
+        let sectors = origin_size / 512;
+        let active = run("dmsetup", &["info", &name]).is_ok();
+        if active && !resume {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "snapshot {} already exists, use --resume-apply to continue writing to it",
+                    name
+                ),
+            ));
+        }
+        let created = !resume || !cow.exists();
+        if created {
+            let file = OpenOptions::new().write(true).create_new(true).open(cow).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("cannot create the snapshot store {}: {}", cow.display(), e),
+                )
+            })?;
+            file.set_len(cow_size as u64)?;
+        }
+        let (loop_device, attached) = match attached_loop(cow)? {
+            Some(loop_device) => (loop_device, false),
+            None => (run("losetup", &["-f", "--show", &cow.to_string_lossy()])?, true),
+        };
+        if !active {
+            let table = format!(
+                "0 {} snapshot {} {} P {}",
+                sectors,
+                origin.display(),
+                loop_device,
+                CHUNK_SECTORS
+            );
+            if let Err(e) = run("dmsetup", &["create", &name, "--table", &table]) {
+                // Leave nothing behind that would get in the way of trying again.
+                if attached {
+                    let _ = run("losetup", &["-d", &loop_device]);
+                }
+                if created {
+                    let _ = fs::remove_file(cow);
+                }
+                return Err(e);
+            }
+        }
+        let snapshot = Self {
+            device: PathBuf::from(format!("/dev/mapper/{}", name)),
+            name,
+            origin: origin.to_path_buf(),
+            cow: cow.to_path_buf(),
+            loop_device,
+            sectors,
+        };
+        eprintln!(
+            "writing through snapshot {} with its store in {} ({}); {} stays untouched until it is merged",
+            snapshot.device.display(),
+            snapshot.cow.display(),
+            snapshot.loop_device,
+            origin.display()
+        );
+        Ok(snapshot)
+    }
+
+    /// Fails when the store ran full, which invalidates the snapshot and everything written to it.
+    pub fn check(&self) -> io::Result<()> {
+        let status = run("dmsetup", &["status", &self.name])?;
+        let fields: Vec<&str> = status.split_whitespace().collect();
+        match fields.get(3) {
+            Some(&usage) if usage.contains('/') => {
+                eprintln!("snapshot store {} sectors used", usage);
+                Ok(())
+            }
+            _ => Err(io::Error::other(format!(
+                "snapshot {} is no longer valid ({}), its store is probably full; discard it and start over with a \
+                 larger --snapshot-cow",
+                self.name, status
+            ))),
+        }
+    }
+
+    pub fn print_instructions(&self) {
+        let merge = format!(
+            "0 {} snapshot-merge {} {} P {}",
+            self.sectors,
+            self.origin.display(),
+            self.loop_device,
+            CHUNK_SECTORS
+        );
+        eprintln!("\ncheck the result on {}, then either", self.device.display());
+        eprintln!("  keep it, merging the snapshot into {}:", self.origin.display());
+        eprintln!("    dmsetup remove {}", self.name);
+        eprintln!("    dmsetup create {} --table '{}'", self.name, merge);
+        eprintln!("    # once dmsetup status {} shows the merge done:", self.name);
+        eprintln!("    dmsetup remove {} && losetup -d {}", self.name, self.loop_device);
+        eprintln!("  or roll back, discarding it:");
+        eprintln!(
+            "    dmsetup remove {} && losetup -d {} && rm {}",
+            self.name,
+            self.loop_device,
+            self.cow.display()
+        );
+    }
+}
+
+/// `dmsetup` with a table on its stdin, which unlike --table may have several lines.
+fn dmsetup_with_table(args: &[&str], table: &str) -> io::Result<()> {
+    let mut child = Command::new("dmsetup")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot run dmsetup for the snapshot: {}", e)))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("{}\n", table).as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "dmsetup {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// The device-mapper name of a dm device, from sysfs.
+fn dm_name(path: &Path) -> Option<String> {
+    let device = fs::canonicalize(path).ok()?;
+    let kernel_name = device.file_name()?.to_string_lossy().into_owned();
+    let name = fs::read_to_string(format!("/sys/block/{}/dm/name", kernel_name)).ok()?;
+    Some(name.trim().to_string())
+}
+
+/// One device while it is snapshotted: its own table moved to `real`, which it now forwards to as a
+/// snapshot-origin so that writes copy the old data to the store first, and `snapshot` showing it as it was.
+struct Live {
+    name: String,
+    table: String,
+    real: String,
+    snapshot: String,
+    loop_device: String,
+    cow: PathBuf,
+}
+
+impl Live {
+    fn remove(&self) -> io::Result<()> {
+        run("dmsetup", &["suspend", &self.name])?;
+        let restored = dmsetup_with_table(&["reload", &self.name], &self.table);
+        run("dmsetup", &["resume", &self.name])?;
+        restored?;
+        run("dmsetup", &["remove", &self.snapshot])?;
+        run("dmsetup", &["remove", &self.real])?;
+        run("losetup", &["-d", &self.loop_device])?;
+        fs::remove_file(&self.cow)
+    }
+}
+
+/// Temporary read-only snapshots of devices that are still in use, for collect and find to read a consistent state
+/// of them; removed again when dropped. Only device-mapper devices of linear and striped targets, such as LVM
+/// volumes, can be snapshotted in place like this.
+pub struct LiveSnapshots {
+    /// Directory for the copy-on-write stores and the size of each.
+    store: Option<(PathBuf, usize)>,
+    taken: Vec<Live>,
+}
+
+impl LiveSnapshots {
+    pub fn new(store: Option<(PathBuf, usize)>) -> Self {
+        Self {
+            store,
+            taken: Vec::new(),
+        }
+    }
+
+    /// The device to read for `path`: a new snapshot of it, or `path` itself without a store.
+    pub fn take(&mut self, path: &Path) -> io::Result<PathBuf> {
+        let Some((dir, store_size)) = &self.store else {
+            return Ok(path.to_path_buf());
+        };
+        let Some(name) = dm_name(path) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not a device-mapper device, which --live-snapshot needs; stop what writes to it or \
+                     put it under LVM",
+                    path.display()
+                ),
+            ));
+        };
+        let table = run("dmsetup", &["table", &name])?;
+        let mut sectors = 0;
+        for line in table.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, length, "linear" | "striped", ..] => sectors += length.parse::<usize>().unwrap_or(0),
+                [_, _, target, ..] => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} is a {} target, which cannot be mapped twice; snapshot the devices under it instead",
+                            path.display(),
+                            target
+                        ),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        let tag = format!("cache_guess-live-{}-{}", std::process::id(), self.taken.len());
+        let cow = dir.join(format!("{}.cow", tag));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&cow)
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("cannot create the snapshot store {}: {}", cow.display(), e),
+                )
+            })?;
+        file.set_len(*store_size as u64)?;
+        let loop_device = match run("losetup", &["-f", "--show", &cow.to_string_lossy()]) {
+            Ok(loop_device) => loop_device,
+            Err(e) => {
+                let _ = fs::remove_file(&cow);
+                return Err(e);
+            }
+        };
+        let live = Live {
+            table: table.clone(),
+            real: format!("{}-real", tag),
+            snapshot: tag,
+            name,
+            loop_device,
+            cow,
+        };
+        let real_device = format!("/dev/mapper/{}", live.real);
+        // Writes wait while the device is suspended, so none slips past the snapshot.
+        let snapshot_table = format!(
+            "0 {} snapshot {} {} N {}",
+            sectors, real_device, live.loop_device, CHUNK_SECTORS
+        );
+        let origin_table = format!("0 {} snapshot-origin {}", sectors, real_device);
+        let step = |step: usize| match step {
+            0 => run("dmsetup", &["suspend", &live.name]).map(|_| ()),
+            1 => dmsetup_with_table(&["create", &live.real], &live.table),
+            2 => dmsetup_with_table(&["create", &live.snapshot], &snapshot_table),
+            3 => dmsetup_with_table(&["reload", &live.name], &origin_table),
+            _ => run("dmsetup", &["resume", &live.name]).map(|_| ()),
+        };
+        for done in 0..5 {
+            if let Err(e) = step(done) {
+                // Undo what was done, newest first; the device must not stay suspended.
+                if done >= 4 {
+                    let _ = dmsetup_with_table(&["reload", &live.name], &live.table);
+                }
+                if done >= 1 {
+                    let _ = run("dmsetup", &["resume", &live.name]);
+                }
+                if done >= 3 {
+                    let _ = run("dmsetup", &["remove", &live.snapshot]);
+                }
+                if done >= 2 {
+                    let _ = run("dmsetup", &["remove", &live.real]);
+                }
+                let _ = run("losetup", &["-d", &live.loop_device]);
+                let _ = fs::remove_file(&live.cow);
+                return Err(e);
+            }
+        }
+        let device = PathBuf::from(format!("/dev/mapper/{}", live.snapshot));
+        eprintln!(
+            "reading {} through the snapshot {}, with its store in {}; should this run be killed, remove it with\n  \
+             dmsetup suspend {n} && printf '{}\\n' | dmsetup reload {n} && dmsetup resume {n} && dmsetup remove {} {} \
+             && losetup -d {} && rm {}",
+            path.display(),
+            device.display(),
+            live.cow.display(),
+            live.table.replace('\n', "\\n"),
+            live.snapshot,
+            live.real,
+            live.loop_device,
+            live.cow.display(),
+            n = live.name,
+        );
+        provenance::record_setting("live_snapshot", format!("{} as {}", path.display(), device.display()));
+        self.taken.push(live);
+        Ok(device)
+    }
+}
+
+impl Drop for LiveSnapshots {
+    fn drop(&mut self) {
+        for live in self.taken.drain(..).rev() {
+            match live.remove() {
+                Ok(()) => eprintln!("removed the snapshot of {}", live.name),
+                Err(e) => eprintln!(
+                    "warning: could not remove the snapshot {} of {}: {}",
+                    live.snapshot, live.name, e
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use memmap2::{Mmap, MmapOptions};
+
+/// The files of a split image, when `path` is its first chunk: `disk.img.000` followed by `disk.img.001` and so
+/// on, with the same number of digits.
+pub fn series(path: &Path) -> Option<Vec<PathBuf>> {
+    let extension = path.extension()?.to_str()?;
+    if extension.is_empty() || !extension.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let first: usize = extension.parse().ok()?;
+    let chunks: Vec<PathBuf> = (first..)
+        .map(|number| path.with_extension(format!("{:0width$}", number, width = extension.len())))
+        .take_while(|chunk| chunk.exists())
+        .collect();
+    (chunks.len() > 1).then_some(chunks)
+}
+
+/// Maps the chunks back to back into one read-only mapping, so blocks that straddle two files read as one
+/// slice. All chunks but the last must therefore be a multiple of the page size, as split images are.
+pub fn map(chunks: &[PathBuf]) -> io::Result<(Mmap, usize)> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut files = Vec::with_capacity(chunks.len());
+    let mut size = 0;
+    for (number, chunk) in chunks.iter().enumerate() {
+        let file = File::open(chunk)?;
+        let len = file.metadata()?.len() as usize;
+        if number + 1 < chunks.len() && !len.is_multiple_of(page_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is {} bytes, not a multiple of the {} byte page size",
+                    chunk.display(),
+                    len,
+                    page_size
+                ),
+            ));
+        }
+        files.push((file, size, len));
+        size += len;
+    }
+
+    // Reserve the whole range, then replace it piece by piece; dropping the reservation unmaps all of it.
+    let mut mmap = MmapOptions::new().len(size).map_anon()?;
+    let start = mmap.as_mut_ptr();
+    for (file, offset, len) in files.iter().filter(|(_, _, len)| *len > 0) {
+        let mapped = unsafe {
+            libc::mmap(
+                start.add(*offset) as *mut libc::c_void,
+                *len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_FIXED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if mapped == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    eprintln!(
+        "{}: concatenating {} files, {} bytes",
+        chunks[0].display(),
+        chunks.len(),
+        size
+    );
+    Ok((mmap.make_read_only()?, size))
+}
@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{provenance, MappedFile};
+
+const SUPERBLOCK_SIZE: usize = 4096;
+// Octal in the kernel source.
+const MAGIC: u64 = 0o6142003;
+const CSUM_XOR: u32 = 9031977;
+
+/// The geometry fields of a dm-cache metadata superblock.
+#[derive(Debug)]
+pub struct Superblock {
+    pub version: u32,
+    pub policy: String,
+    /// In sectors, like `--cache-block-size`.
+    pub data_block_size: usize,
+    pub cache_blocks: usize,
+    /// In bytes, from the number of blocks the metadata space map manages.
+    pub metadata_size: usize,
+}
+
+/// Where the cache data starts on the cache devices `--data-offset` was given for.
+static DATA_OFFSETS: Mutex<Vec<(PathBuf, usize)>> = Mutex::new(Vec::new());
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    // The kernel's dm_bm_checksum leaves out the final inversion.
+    crc
+}
+
+fn invalid(path: &Path, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+}
+
+impl Superblock {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let mut block = vec![0; SUPERBLOCK_SIZE];
+        File::open(path)?.read_exact(&mut block)?;
+        Self::parse(&block).map_err(|msg| invalid(path, msg))
+    }
+
+    fn parse(block: &[u8]) -> Result<Self, String> {
+        let u32_at = |offset: usize| u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap());
+
+        if u64_at(32) != MAGIC {
+            return Err("no dm-cache metadata superblock".to_string());
+        }
+        let csum = crc32c(&block[4..]) ^ CSUM_XOR;
+        if u32_at(0) != csum {
+            return Err(format!(
+                "superblock checksum is {:08x}, expected {:08x}",
+                u32_at(0),
+                csum
+            ));
+        }
+        let policy = &block[44..60];
+        let policy = &policy[..policy.iter().position(|&b| b == 0).unwrap_or(policy.len())];
+        Ok(Self {
+            version: u32_at(40),
+            policy: String::from_utf8_lossy(policy).into_owned(),
+            data_block_size: u32_at(244) as usize,
+            cache_blocks: u32_at(252) as usize,
+            // The space map root starts with its number of blocks; metadata_block_size is in sectors.
+            metadata_size: u64_at(76) as usize * u32_at(248) as usize * 512,
+        })
+    }
+}
+
+pub fn set_data_offset(path: &Path, offset: usize) {
+    DATA_OFFSETS.lock().unwrap().push((path.to_path_buf(), offset));
+}
+
+/// The cache data area of `device`. Where the metadata is kept at the start of the same device, its superblock
+/// tells how far it reaches, and cache block 0 is right after it; `--data-offset` says so instead.
+pub fn data_area(path: &Path, device: MappedFile) -> io::Result<MappedFile> {
+    let explicit = DATA_OFFSETS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(cache_device, _)| cache_device == path)
+        .map(|&(_, offset)| offset);
+    if let Some(offset) = explicit {
+        if offset >= device.size() {
+            return Err(invalid(
+                path,
+                format!("--data-offset {} is past the {} byte device end", offset, device.size()),
+            ));
+        }
+        provenance::record_setting("data_offset", offset);
+        let size = device.size() - offset;
+        return device.window(offset, size);
+    }
+    if device.size() < SUPERBLOCK_SIZE {
+        return Ok(device);
+    }
+    let Ok(superblock) = Superblock::parse(device.slice(0, SUPERBLOCK_SIZE)) else {
+        return Ok(device);
+    };
+    if superblock.metadata_size == 0 || superblock.metadata_size >= device.size() {
+        eprintln!(
+            "warning: {} holds dm-cache metadata and no cache data after it; name the cache device",
+            path.display()
+        );
+        return Ok(device);
+    }
+    let offset = superblock.metadata_size;
+    let size = device.size() - offset;
+    eprintln!(
+        "{}: dm-cache metadata in the first {} bytes, cache blocks start after it (--data-offset overrides)",
+        path.display(),
+        offset
+    );
+    let data_size = superblock.cache_blocks * superblock.data_block_size * 512;
+    if data_size > size {
+        eprintln!(
+            "warning: the metadata has {} cache blocks of {} sectors, {} bytes, but only {} follow it",
+            superblock.cache_blocks, superblock.data_block_size, data_size, size
+        );
+    }
+    provenance::record_setting("data_offset", offset);
+    device.window(offset, size)
+}
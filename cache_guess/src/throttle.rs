@@ -0,0 +1,31 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Paces I/O to an average rate by sleeping whenever we get ahead of it.
+pub struct Throttle {
+    bytes_per_sec: Option<f64>,
+    start: Instant,
+    consumed: u64,
+}
+
+impl Throttle {
+    pub fn new(mb_per_sec: Option<f64>) -> Self {
+        Self {
+            bytes_per_sec: mb_per_sec.map(|rate| rate * 1_000_000.0),
+            start: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        let Some(rate) = self.bytes_per_sec else {
+            return;
+        };
+        self.consumed += bytes as u64;
+        let due = Duration::from_secs_f64(self.consumed as f64 / rate);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
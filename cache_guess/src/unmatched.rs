@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::{provenance, BLOCK_SIZE};
+
+const MAX_LISTED_PARTIAL: usize = 8;
+
+/// A cache fs-block whose digest exists in the index, but at a different position within its cache block.
+#[derive(Debug, Clone)]
+pub struct PartialMatch {
+    pub fs_block: usize,
+    pub origin_offset: usize,
+}
+
+pub struct UnmatchedReport {
+    out: BufWriter<File>,
+    count: usize,
+}
+
+pub fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let p = count as f64 / data.len() as f64;
+            -p * p.log2()
+        })
+        .sum::<f64>()
+        .abs()
+}
+
+fn hint(entropy: f64, zero_fraction: f64, partial: &[PartialMatch], cache_block_size: usize) -> String {
+    if zero_fraction == 1.0 {
+        return "zero-filled".to_string();
+    }
+    if !partial.is_empty() {
+        // A consistent distance between cache and origin fs-block numbers means the content is there, just shifted.
+        let fs_blocks = cache_block_size / BLOCK_SIZE;
+        let deltas: Vec<isize> = partial
+            .iter()
+            .map(|m| (m.origin_offset / BLOCK_SIZE) as isize - m.fs_block as isize)
+            .collect();
+        if deltas.iter().all(|&delta| delta == deltas[0]) {
+            return format!(
+                "misaligned by {} fs-blocks, check cache block size or offset",
+                deltas[0].rem_euclid(fs_blocks as isize)
+            );
+        }
+        return "scattered partial matches, check cache block size".to_string();
+    }
+    if entropy > 7.9 {
+        "high entropy, likely dirty encrypted/compressed data or garbage".to_string()
+    } else if zero_fraction > 0.9 {
+        "mostly zero".to_string()
+    } else {
+        "structured data with no origin copy, likely dirty".to_string()
+    }
+}
+
+impl UnmatchedReport {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        provenance::write_comments(&mut out)?;
+        writeln!(out, "# cache_block entropy zero_fraction partial_matches hint")?;
+        Ok(Self { out, count: 0 })
+    }
+
+    pub fn record(
+        &mut self,
+        cache_block: usize,
+        data: &[u8],
+        partial: &[PartialMatch],
+        cache_block_size: usize,
+    ) -> io::Result<()> {
+        let entropy = entropy(data);
+        let zero_fraction = data.iter().filter(|&&byte| byte == 0).count() as f64 / data.len() as f64;
+        writeln!(
+            self.out,
+            "{} entropy={:.3} zeros={:.3}% partial={} ({})",
+            cache_block,
+            entropy,
+            zero_fraction * 100.0,
+            partial.len(),
+            hint(entropy, zero_fraction, partial, cache_block_size)
+        )?;
+        for m in partial.iter().take(MAX_LISTED_PARTIAL) {
+            writeln!(
+                self.out,
+                "#  fs-block {} -> origin offset {} (fs-block {} of cache block {})",
+                m.fs_block,
+                m.origin_offset,
+                m.origin_offset / BLOCK_SIZE % (cache_block_size / BLOCK_SIZE),
+                m.origin_offset / cache_block_size
+            )?;
+        }
+        if partial.len() > MAX_LISTED_PARTIAL {
+            writeln!(self.out, "#  ... {} more", partial.len() - MAX_LISTED_PARTIAL)?;
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<usize> {
+        self.out.flush()?;
+        Ok(self.count)
+    }
+}
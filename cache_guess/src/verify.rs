@@ -0,0 +1,319 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::block_errors::{self, Kind};
+use crate::cache_xml::CacheMetadata;
+use crate::mapping::{self, MappingEntry};
+use crate::output::Output;
+use crate::throttle::Throttle;
+use crate::{hash_block, inuse, log_complete, log_status, open_device, provenance, MappedFile, BLOCK_SIZE};
+
+pub struct VerifyOptions {
+    pub cache_block_size: usize,
+    pub partition: Option<usize>,
+    pub origin_partition: Option<usize>,
+    pub bwlimit: Option<f64>,
+    pub force: bool,
+}
+
+/// A device read through its partition window with plain reads, so a bad sector is an error and not a SIGBUS.
+/// The reads go to the file the view was mapped from, so an unlocked LUKS device or a snapshot and not the path
+/// given; split, remote and compressed images, which have no such file, are read from the mapping.
+pub struct Reader {
+    path: PathBuf,
+    file: Option<File>,
+    view: MappedFile,
+}
+
+impl Reader {
+    pub fn open(path: &Path, partition: Option<usize>) -> io::Result<Self> {
+        Self::over(path, open_device(path, partition)?)
+    }
+
+    /// Reads `view`, a window of the device at `path` that is already mapped, with plain reads as well.
+    pub fn over(path: &Path, view: MappedFile) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: view.file().map(File::try_clone).transpose()?,
+            view,
+        })
+    }
+
+    /// Reads `view` from the mapping, for data that is in memory and not on the device, such as a reread's majority.
+    pub fn in_memory(path: &Path, view: MappedFile) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file: None,
+            view,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> usize {
+        self.view.size()
+    }
+
+    pub fn view(&self) -> &MappedFile {
+        &self.view
+    }
+
+    /// Asks the kernel to read `[offset, offset + len)` of the view ahead, without faulting it in, and false when
+    /// the view is only read from the mapping, which `ReadAhead` then faults in itself.
+    pub fn will_need(&self, offset: usize, len: usize) -> bool {
+        let Some(file) = &self.file else {
+            return false;
+        };
+        unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                (self.view.base + offset) as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            );
+        }
+        true
+    }
+
+    /// The data of `block`, or why there is none, which goes into the block errors of the run as well.
+    pub fn read(&self, block: usize, block_size: usize) -> Result<Vec<u8>, String> {
+        self.read_unrecorded(block, block_size).inspect_err(|e| {
+            let past_end = (block + 1) * block_size > self.view.size();
+            let kind = if past_end { Kind::Format } else { Kind::IoRead };
+            block_errors::record(kind, &self.path, block, block_size, e.clone());
+        })
+    }
+
+    /// `read` for a read that is tried again in smaller pieces when it fails, and only those count.
+    pub fn read_unrecorded(&self, block: usize, block_size: usize) -> Result<Vec<u8>, String> {
+        self.read_at(block * block_size, block_size)
+    }
+
+    /// `len` bytes from `offset` on, for a caller that records its own errors.
+    pub fn read_at(&self, offset: usize, len: usize) -> Result<Vec<u8>, String> {
+        if offset + len > self.view.size() {
+            return Err("past the device end".to_string());
+        }
+        let Some(file) = &self.file else {
+            return Ok(self.view.slice(offset, len).to_vec());
+        };
+        let mut data = vec![0; len];
+        file.read_exact_at(&mut data, (self.view.base + offset) as u64)
+            .map_err(|e| format!("read error: {}", e))?;
+        Ok(data)
+    }
+}
+
+/// Reads a mapping from `find` output, or from `cache_dump` XML if the file looks like it.
+pub fn read_mapping(path: &Path, cache_block_size: usize) -> io::Result<Vec<MappingEntry>> {
+    if path.extension().is_none_or(|extension| extension != "xml") {
+        return mapping::read(path);
+    }
+    let metadata = CacheMetadata::read(path)?;
+    if metadata.block_size.is_some_and(|size| size * 512 != cache_block_size) {
+        eprintln!(
+            "warning: metadata uses {} byte cache blocks, but --cache-block-size is {} bytes",
+            metadata.block_size.unwrap() * 512,
+            cache_block_size
+        );
+    }
+    let mut entries: Vec<MappingEntry> = metadata
+        .mappings
+        .iter()
+        .map(|(&cache_block, recorded)| MappingEntry {
+            cache_block,
+            origin_block: recorded.origin_block,
+            tied: false,
+            confidence: None,
+            match_percent: None,
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.cache_block);
+    Ok(entries)
+}
+
+/// How many fs-blocks of the cache block and its mapped origin block hold the same data, or why they could not be
+/// compared.
+fn compare(
+    cache_device: &Reader,
+    origin: &Reader,
+    entry: &MappingEntry,
+    cache_block_size: usize,
+) -> Result<usize, String> {
+    let cache = cache_device
+        .read(entry.cache_block, cache_block_size)
+        .map_err(|e| format!("cache block {}", e))?;
+    let origin = origin
+        .read(entry.origin_block, cache_block_size)
+        .map_err(|e| format!("origin block {}", e))?;
+    Ok(cache
+        .chunks(BLOCK_SIZE)
+        .zip(origin.chunks(BLOCK_SIZE))
+        .filter(|(a, b)| hash_block(a) == hash_block(b))
+        .count())
+}
+
+/// Re-reads both sides of every mapping entry and reports whether the data really is the same.
+pub fn verify(
+    mapping_path: &Path,
+    cache_device_path: &Path,
+    origin_path: &Path,
+    options: &VerifyOptions,
+) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path, origin_path], options.force)?;
+    let cache_block_size = options.cache_block_size;
+    let entries = read_mapping(mapping_path, cache_block_size)?;
+    let cache_device = Reader::open(cache_device_path, options.partition)?;
+    let origin = Reader::open(origin_path, options.origin_partition)?;
+    let mut throttle = Throttle::new(options.bwlimit);
+
+    let fs_blocks = cache_block_size / BLOCK_SIZE;
+    let (mut confirmed, mut contradicted, mut unverifiable) = (0, 0, 0);
+    for (done, entry) in entries.iter().enumerate() {
+        log_status(done, entries.len(), "entries", false);
+        throttle.consume(2 * cache_block_size);
+        let verdict = match compare(&cache_device, &origin, entry, cache_block_size) {
+            Err(reason) => {
+                unverifiable += 1;
+                format!("unverifiable ({})", reason)
+            }
+            Ok(same) => {
+                if same == fs_blocks {
+                    confirmed += 1;
+                    "confirmed".to_string()
+                } else {
+                    contradicted += 1;
+                    format!("contradicted ({} of {} fs-blocks match)", same, fs_blocks)
+                }
+            }
+        };
+        println!("{} -> {} {}", entry.cache_block, entry.origin_block, verdict);
+    }
+    log_complete(entries.len(), "entries");
+    eprintln!(
+        "\n{} confirmed, {} contradicted, {} unverifiable",
+        confirmed, contradicted, unverifiable
+    );
+    Ok(())
+}
+
+/// Sorts every mapped cache block into clean (the origin already holds the same data), dirty (the cache copy
+/// differs and needs writing back) and unknown (tied or unreadable). Dirty blocks come out as `C -> O # dirty`
+/// lines ordered by origin block, so `apply` copies only those; the rest become `#clean` and `#unknown` comments.
+/// A writethrough cache has nothing to write back: blocks that differ point at corruption on one side or the other
+/// and become `#corrupt` comments, leaving `apply` nothing to copy.
+pub fn classify(
+    mapping_path: &Path,
+    cache_device_path: &Path,
+    origin_path: &Path,
+    output: Option<&Path>,
+    writethrough: bool,
+    options: &VerifyOptions,
+) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path, origin_path], options.force)?;
+    let cache_block_size = options.cache_block_size;
+    let entries = read_mapping(mapping_path, cache_block_size)?;
+    let cache_device = Reader::open(cache_device_path, options.partition)?;
+    let origin = Reader::open(origin_path, options.origin_partition)?;
+    let mut throttle = Throttle::new(options.bwlimit);
+
+    let fs_blocks = cache_block_size / BLOCK_SIZE;
+    let (mut dirty, mut clean, mut unknown) = (Vec::new(), Vec::new(), Vec::new());
+    for (done, entry) in entries.iter().enumerate() {
+        log_status(done, entries.len(), "entries", false);
+        if entry.tied {
+            unknown.push((entry, "tied candidates".to_string()));
+            continue;
+        }
+        throttle.consume(2 * cache_block_size);
+        match compare(&cache_device, &origin, entry, cache_block_size) {
+            Err(reason) => unknown.push((entry, reason)),
+            Ok(same) if same == fs_blocks => clean.push(entry),
+            Ok(same) => dirty.push((entry, fs_blocks - same)),
+        }
+    }
+    log_complete(entries.len(), "entries");
+    dirty.sort_by_key(|(entry, _)| (entry.origin_block, entry.cache_block));
+
+    let mut out = Output::create(output)?;
+    provenance::write_comments(&mut out)?;
+    for (entry, differing) in &dirty {
+        if writethrough {
+            writeln!(
+                out,
+                "#corrupt {} -> {}: {} of {} fs-blocks differ",
+                entry.cache_block, entry.origin_block, differing, fs_blocks
+            )?;
+        } else {
+            writeln!(
+                out,
+                "{} -> {} # dirty, {} of {} fs-blocks differ",
+                entry.cache_block, entry.origin_block, differing, fs_blocks
+            )?;
+        }
+    }
+    for entry in &clean {
+        writeln!(out, "#clean {} -> {}", entry.cache_block, entry.origin_block)?;
+    }
+    for (entry, reason) in &unknown {
+        writeln!(
+            out,
+            "#unknown {} -> {}: {}",
+            entry.cache_block, entry.origin_block, reason
+        )?;
+    }
+    out.commit()?;
+    if writethrough {
+        eprintln!(
+            "\n{} differ from the origin, {} clean, {} unknown",
+            dirty.len(),
+            clean.len(),
+            unknown.len()
+        );
+        if !dirty.is_empty() {
+            eprintln!(
+                "a writethrough cache always matches the origin, so these are corruption on the cache or the origin, \
+                 or wrong mappings; nothing was planned for writing back"
+            );
+        }
+    } else {
+        eprintln!(
+            "\n{} dirty, {} clean, {} unknown",
+            dirty.len(),
+            clean.len(),
+            unknown.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_reads_the_file_the_view_was_mapped_from() {
+        let dir = std::env::temp_dir();
+        let given = dir.join(format!("cache_guess-reader-given-{}", std::process::id()));
+        let mapped = dir.join(format!("cache_guess-reader-mapped-{}", std::process::id()));
+        std::fs::write(&given, vec![0xaa; 8192]).unwrap();
+        let mut contents = vec![0; 4096];
+        contents.extend((0..4096).map(|i| i as u8));
+        std::fs::write(&mapped, &contents).unwrap();
+
+        // As for a LUKS device, whose view is of its unlocked mapping and not of the path on the command line.
+        let view = MappedFile::open(&mapped).unwrap().window(4096, 4096).unwrap();
+        let reader = Reader::over(&given, view).unwrap();
+        let read = reader.read_at(16, 32);
+        std::fs::remove_file(&given).unwrap();
+        std::fs::remove_file(&mapped).unwrap();
+        assert_eq!(read.unwrap(), contents[4096 + 16..4096 + 48]);
+        assert!(reader.will_need(0, 4096));
+        assert_eq!(reader.path(), given);
+    }
+}
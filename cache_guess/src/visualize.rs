@@ -0,0 +1,248 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::mapping::MappingEntry;
+use crate::provenance;
+
+/// Pixels per row of a PNG panel, and the most rows one may have before blocks are binned together.
+const PNG_WIDTH: usize = 1024;
+const PNG_ROWS: usize = 1024;
+/// Characters per line of an ASCII panel, and the most lines.
+const ASCII_WIDTH: usize = 64;
+const ASCII_ROWS: usize = 32;
+/// Largest stored deflate block.
+const STORED_BLOCK: usize = 65535;
+
+/// A run of blocks drawn as one pixel or character.
+#[derive(Clone, Default)]
+struct Cell {
+    blocks: usize,
+    mapped: usize,
+    /// Mapped blocks with a confidence, and its sum; plans and reconciled mappings carry none.
+    scored: usize,
+    confidence: f64,
+}
+
+impl Cell {
+    fn add(&mut self, confidence: Option<Option<f64>>) {
+        self.blocks += 1;
+        if let Some(confidence) = confidence {
+            self.mapped += 1;
+            if let Some(confidence) = confidence {
+                self.scored += 1;
+                self.confidence += confidence;
+            }
+        }
+    }
+
+    fn rgb(&self) -> [u8; 3] {
+        if self.mapped == 0 {
+            return [0, 0, 0];
+        }
+        // Dimmer the fewer of the binned blocks are mapped, but never as dark as nothing mapped.
+        let brightness = 0.3 + 0.7 * self.mapped as f64 / self.blocks as f64;
+        let [r, g, b] = if self.scored == 0 {
+            [80.0, 120.0, 255.0]
+        } else {
+            // Red for a poor match through yellow to green for a sure one.
+            let confidence = (self.confidence / self.scored as f64).clamp(0.0, 1.0);
+            [
+                255.0 * (2.0 - 2.0 * confidence).min(1.0),
+                255.0 * (2.0 * confidence).min(1.0),
+                0.0,
+            ]
+        };
+        [(r * brightness) as u8, (g * brightness) as u8, (b * brightness) as u8]
+    }
+
+    fn ascii(&self) -> char {
+        if self.mapped == 0 {
+            return ' ';
+        }
+        if self.scored == 0 {
+            return 'o';
+        }
+        match self.confidence / self.scored as f64 {
+            c if c < 0.25 => '.',
+            c if c < 0.5 => ':',
+            c if c < 0.75 => '+',
+            _ => '#',
+        }
+    }
+}
+
+/// One row-major strip of cells, each covering `per_cell` consecutive blocks.
+struct Panel {
+    title: String,
+    cells: Vec<Cell>,
+    per_cell: usize,
+}
+
+impl Panel {
+    fn new(
+        title: &str,
+        blocks: usize,
+        width: usize,
+        rows: usize,
+        value: impl Fn(usize) -> Option<Option<f64>>,
+    ) -> Self {
+        let per_cell = blocks.div_ceil(width * rows).max(1);
+        let mut cells = vec![Cell::default(); blocks.div_ceil(per_cell)];
+        for block in 0..blocks {
+            cells[block / per_cell].add(value(block));
+        }
+        Self {
+            title: title.to_string(),
+            cells,
+            per_cell,
+        }
+    }
+}
+
+/// The cache side, one cell per cache block in the mapping, and the origin side the way `coverage` counts it.
+fn panels(entries: &[MappingEntry], origin_blocks: usize, width: usize, rows: usize) -> [Panel; 2] {
+    let cache_blocks = entries.iter().map(|entry| entry.cache_block + 1).max().unwrap_or(0);
+    let mut by_cache_block = vec![None; cache_blocks];
+    let mut by_origin_block = vec![None; origin_blocks];
+    for entry in entries {
+        by_cache_block[entry.cache_block] = Some(entry.confidence);
+        if !entry.tied && entry.origin_block < origin_blocks {
+            by_origin_block[entry.origin_block] = Some(entry.confidence);
+        }
+    }
+    [
+        Panel::new("cache blocks", cache_blocks, width, rows, |block| by_cache_block[block]),
+        Panel::new("origin blocks", origin_blocks, width, rows, |block| {
+            by_origin_block[block]
+        }),
+    ]
+}
+
+fn crc32(data: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data.iter().flat_map(|part| part.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&[kind, data]).to_be_bytes());
+}
+
+/// A zlib stream of stored, uncompressed deflate blocks: most of a mapping image is flat color, but this keeps
+/// the encoder to a few lines without a compression crate.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(STORED_BLOCK).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        out.push((i + 1 == blocks.len()) as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    if blocks.is_empty() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+/// The panels stacked top to bottom, separated by a gray row; cells past a panel's end are dark gray.
+fn encode_png(panels: &[Panel]) -> Vec<u8> {
+    let width = panels
+        .iter()
+        .map(|panel| panel.cells.len().min(PNG_WIDTH))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut pixels = Vec::new();
+    let mut height = 0;
+    for (i, panel) in panels.iter().enumerate() {
+        if i > 0 {
+            pixels.push(0);
+            pixels.extend(std::iter::repeat_n(128, width * 3));
+            height += 1;
+        }
+        for row in panel.cells.chunks(width) {
+            pixels.push(0);
+            for x in 0..width {
+                pixels.extend_from_slice(&row.get(x).map_or([40, 40, 40], Cell::rgb));
+            }
+            height += 1;
+        }
+    }
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8-bit RGB, no interlacing.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut out, b"IHDR", &header);
+    // An uncompressed UTF-8 text chunk, which image viewers show as the comment.
+    let mut provenance = b"Comment\0\0\0\0\0".to_vec();
+    provenance.extend_from_slice(provenance::lines().join("\n").as_bytes());
+    png_chunk(&mut out, b"iTXt", &provenance);
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&pixels));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// The mapping drawn as a PNG image, as `visualize` writes it.
+pub fn png(entries: &[MappingEntry], origin_blocks: usize) -> Vec<u8> {
+    let panels = panels(entries, origin_blocks, PNG_WIDTH, PNG_ROWS);
+    for panel in &panels {
+        if panel.per_cell > 1 {
+            eprintln!("{}: {} per pixel", panel.title, panel.per_cell);
+        }
+    }
+    encode_png(&panels)
+}
+
+fn write_ascii(out: &mut impl Write, panels: &[Panel]) -> io::Result<()> {
+    provenance::write_comments(out)?;
+    writeln!(
+        out,
+        "# ' ' unmapped, '.' <25% confidence, ':' <50%, '+' <75%, '#' above, 'o' mapped without a confidence"
+    )?;
+    for panel in panels {
+        writeln!(out, "# {}, {} per character", panel.title, panel.per_cell)?;
+        for (row, cells) in panel.cells.chunks(ASCII_WIDTH).enumerate() {
+            let line: String = cells.iter().map(Cell::ascii).collect();
+            writeln!(out, "{:>12} |{}|", row * ASCII_WIDTH * panel.per_cell, line)?;
+        }
+    }
+    out.flush()
+}
+
+/// Draws the mapping one pixel per block, colored by match confidence: a PNG for a `.png` path, ASCII art for
+/// anything else, `-` for stdout.
+pub fn visualize(path: &Path, entries: &[MappingEntry], origin_blocks: usize) -> io::Result<()> {
+    if path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("png"))
+    {
+        return fs::write(path, png(entries, origin_blocks));
+    }
+    let panels = panels(entries, origin_blocks, ASCII_WIDTH, ASCII_ROWS);
+    if path == Path::new("-") {
+        return write_ascii(&mut io::stdout().lock(), &panels);
+    }
+    write_ascii(&mut BufWriter::new(File::create(path)?), &panels)
+}
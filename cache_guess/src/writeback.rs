@@ -0,0 +1,116 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::cache_xml::CacheMetadata;
+use crate::output::Output;
+use crate::{mapping, provenance};
+
+pub struct PlanOptions<'a> {
+    /// Copy blocks the metadata does not mention when the content points somewhere; off, they are skipped.
+    pub copy_unknown: bool,
+    /// The cache was writethrough, so its blocks are never newer than the origin and nothing is copied.
+    pub writethrough: bool,
+    pub output: Option<&'a Path>,
+}
+
+enum Step {
+    Copy { origin_block: usize, reason: String },
+    Skip { reason: String },
+}
+
+/// Decides one cache block: dirty blocks hold the only current copy and go back to where the metadata puts them,
+/// clean ones are already on the origin. Content matches fill in where the metadata has nothing to say.
+fn decide(recorded: Option<(usize, bool)>, content: Option<(usize, bool)>, copy_unknown: bool) -> Step {
+    match (recorded, content) {
+        (Some((origin_block, true)), Some((guess, _))) if guess == origin_block => Step::Copy {
+            origin_block,
+            reason: "dirty, content agrees with metadata".to_string(),
+        },
+        (Some((origin_block, true)), Some((guess, _))) => Step::Copy {
+            origin_block,
+            reason: format!("dirty, metadata wins over content match to {}", guess),
+        },
+        (Some((origin_block, true)), None) => Step::Copy {
+            origin_block,
+            reason: "dirty, metadata only".to_string(),
+        },
+        (Some((origin_block, false)), _) => Step::Skip {
+            reason: format!("clean, origin block {} is current", origin_block),
+        },
+        (None, Some((_, true))) => Step::Skip {
+            reason: "not in metadata, content match is tied".to_string(),
+        },
+        (None, Some((origin_block, false))) if copy_unknown => Step::Copy {
+            origin_block,
+            reason: "not in metadata, content match only".to_string(),
+        },
+        (None, Some((origin_block, false))) => Step::Skip {
+            reason: format!("not in metadata, content matches {}", origin_block),
+        },
+        (None, None) => unreachable!(),
+    }
+}
+
+/// Writes a copy plan for a writeback cache: `C -> O # reason` lines ordered by origin block, which `apply`
+/// reads like a mapping, and `#skip C: reason` lines for everything left alone.
+pub fn plan(mapping_path: &Path, metadata_path: &Path, options: &PlanOptions) -> io::Result<()> {
+    let metadata = CacheMetadata::read(metadata_path)?;
+    let content: HashMap<usize, (usize, bool)> = mapping::read(mapping_path)?
+        .into_iter()
+        .map(|entry| (entry.cache_block, (entry.origin_block, entry.tied)))
+        .collect();
+
+    let cache_blocks: BTreeSet<usize> = metadata
+        .mappings
+        .keys()
+        .copied()
+        .chain(content.keys().copied())
+        .collect();
+    let mut copies = Vec::new();
+    let mut skips = Vec::new();
+    let mut marked_dirty = 0;
+    for cache_block in cache_blocks {
+        let recorded = metadata
+            .mappings
+            .get(&cache_block)
+            .map(|recorded| (recorded.origin_block, recorded.dirty));
+        if options.writethrough {
+            if recorded.is_some_and(|(_, dirty)| dirty) {
+                marked_dirty += 1;
+            }
+            skips.push((cache_block, "writethrough, the origin is current".to_string()));
+            continue;
+        }
+        match decide(recorded, content.get(&cache_block).copied(), options.copy_unknown) {
+            Step::Copy { origin_block, reason } => copies.push((origin_block, cache_block, reason)),
+            Step::Skip { reason } => skips.push((cache_block, reason)),
+        }
+    }
+    if marked_dirty > 0 {
+        eprintln!(
+            "warning: the metadata marks {} blocks dirty, which a writethrough cache never leaves; check the cache \
+             mode, or classify the blocks against the origin to look for corruption",
+            marked_dirty
+        );
+    }
+    copies.sort();
+    if let Some(window) = copies.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        eprintln!(
+            "warning: cache blocks {} and {} would both be copied to origin block {}",
+            window[0].1, window[1].1, window[0].0
+        );
+    }
+
+    let mut out = Output::create(options.output)?;
+    provenance::write_comments(&mut out)?;
+    for (origin_block, cache_block, reason) in &copies {
+        writeln!(out, "{} -> {} # {}", cache_block, origin_block, reason)?;
+    }
+    for (cache_block, reason) in &skips {
+        writeln!(out, "#skip {}: {}", cache_block, reason)?;
+    }
+    out.commit()?;
+    eprintln!("{} cache blocks to copy, {} left alone", copies.len(), skips.len());
+    Ok(())
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::block_errors::{self, Kind};
+use crate::index::Index;
+use crate::output::Output;
+use crate::{hash, inuse, log_complete, log_status, open_device, provenance, MappedFile, BLOCK_SIZE};
+
+const MAGIC: u32 = 0x7763_7368;
+const VERSION: u32 = 1;
+/// The superblock is padded to this, and the entries follow it.
+const SUPERBLOCK_SIZE: usize = 64;
+/// An entry: the origin sector the block caches, and the sequence number of the write that put it there.
+const ENTRY_SIZE: usize = 16;
+/// What a free entry's fields are set to.
+const FREE: u64 = u64::MAX;
+
+pub struct WritecacheOptions<'a> {
+    pub partition: Option<usize>,
+    /// The block size to assume where the superblock is damaged.
+    pub block_size: usize,
+    /// Origin size, for entries that point past it; the index's origin when not given.
+    pub origin_size: Option<usize>,
+    /// Matches the blocks the metadata does not vouch for by content against this index.
+    pub index: Option<&'a Path>,
+    /// Leaves the metadata aside and matches every block by content.
+    pub content_only: bool,
+    pub output: Option<&'a Path>,
+    pub force: bool,
+}
+
+/// The layout of an SSD mode dm-writecache device: a superblock, an entry per cache block, then from
+/// `data_offset` on the blocks themselves.
+struct Layout {
+    block_size: usize,
+    blocks: usize,
+    /// The sequence number of the last committed write; entries past it were never committed.
+    seq_count: u64,
+    data_offset: usize,
+}
+
+/// Where the kernel puts the blocks: as many as fit after their entries, which take whole blocks.
+fn data_offset(blocks: usize, block_size: usize) -> usize {
+    (SUPERBLOCK_SIZE + blocks * ENTRY_SIZE).next_multiple_of(block_size)
+}
+
+impl Layout {
+    fn parse(device: &MappedFile) -> Result<Self, String> {
+        if device.size() < SUPERBLOCK_SIZE {
+            return Err("smaller than a dm-writecache superblock".to_string());
+        }
+        let block = device.slice(0, SUPERBLOCK_SIZE);
+        let u32_at = |offset: usize| u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap());
+        if u32_at(0) != MAGIC {
+            return Err("no dm-writecache superblock".to_string());
+        }
+        if u32_at(4) != VERSION {
+            return Err(format!(
+                "dm-writecache superblock version {}, not {}",
+                u32_at(4),
+                VERSION
+            ));
+        }
+        let block_size = u32_at(8) as usize;
+        if !block_size.is_power_of_two() || !(512..=1 << 20).contains(&block_size) {
+            return Err(format!("dm-writecache superblock says {} byte blocks", block_size));
+        }
+        let blocks = u64_at(16) as usize;
+        let data_offset = blocks
+            .checked_mul(ENTRY_SIZE)
+            .map(|_| data_offset(blocks, block_size))
+            .filter(|&offset| offset + blocks * block_size <= device.size())
+            .ok_or_else(|| {
+                format!(
+                    "dm-writecache superblock says {} blocks, more than the device holds",
+                    blocks
+                )
+            })?;
+        Ok(Self {
+            block_size,
+            blocks,
+            seq_count: u64_at(24),
+            data_offset,
+        })
+    }
+
+    /// The layout the kernel would have made the device with, for when the superblock does not tell.
+    fn guess(device_size: usize, block_size: usize) -> Self {
+        let mut blocks = device_size / (block_size + ENTRY_SIZE);
+        while blocks > 0 && data_offset(blocks, block_size) + blocks * block_size > device_size {
+            blocks -= 1;
+        }
+        Self {
+            block_size,
+            blocks,
+            seq_count: FREE,
+            data_offset: data_offset(blocks, block_size),
+        }
+    }
+}
+
+/// How the metadata maps one block, and what is wrong with it if it cannot be taken as it is.
+enum Entry {
+    Free,
+    Mapped { origin_block: usize, seq_count: u64 },
+    Damaged(&'static str),
+}
+
+fn read_entries(device: &MappedFile, layout: &Layout, origin_size: Option<usize>) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = (0..layout.blocks)
+        .map(|block| {
+            let raw = device.slice(SUPERBLOCK_SIZE + block * ENTRY_SIZE, ENTRY_SIZE);
+            let sector = u64::from_le_bytes(raw[..8].try_into().unwrap());
+            let seq_count = u64::from_le_bytes(raw[8..].try_into().unwrap());
+            if sector == FREE && seq_count == FREE {
+                return Entry::Free;
+            }
+            if sector == FREE || seq_count == FREE {
+                return Entry::Damaged("half free");
+            }
+            if seq_count > layout.seq_count {
+                return Entry::Damaged("written after the last commit");
+            }
+            let offset = sector as usize * 512;
+            if !offset.is_multiple_of(layout.block_size) {
+                return Entry::Damaged("origin sector not on a block boundary");
+            }
+            if origin_size.is_some_and(|size| offset + layout.block_size > size) {
+                return Entry::Damaged("origin sector past the origin end");
+            }
+            Entry::Mapped {
+                origin_block: offset / layout.block_size,
+                seq_count,
+            }
+        })
+        .collect();
+    // Where blocks cache the same origin block, the latest write holds the data; two of the same write cannot both.
+    let mut latest: HashMap<usize, (usize, u64)> = HashMap::new();
+    let mut conflicting = Vec::new();
+    for (block, entry) in entries.iter().enumerate() {
+        let &Entry::Mapped {
+            origin_block,
+            seq_count,
+        } = entry
+        else {
+            continue;
+        };
+        match latest.get(&origin_block) {
+            Some(&(other, other_seq)) if other_seq == seq_count => conflicting.extend([other, block]),
+            Some(&(_, other_seq)) if other_seq > seq_count => {}
+            _ => {
+                latest.insert(origin_block, (block, seq_count));
+            }
+        }
+    }
+    for block in conflicting {
+        entries[block] = Entry::Damaged("the same write as another block's");
+    }
+    for (block, entry) in entries.iter_mut().enumerate() {
+        if let Entry::Mapped { origin_block, .. } = entry {
+            if latest[origin_block].0 != block {
+                *entry = Entry::Free;
+            }
+        }
+    }
+    entries
+}
+
+/// The best match of a block found by content: origin block, fs-blocks of it found in place, non-zero fs-blocks
+/// looked up, and whether another origin block matched as well.
+struct ContentMatch {
+    origin_block: usize,
+    count: usize,
+    looked_up: usize,
+    tied: bool,
+}
+
+/// Matches `blocks` against the index, streamed rather than loaded as `lookup` does. Blocks smaller than an
+/// fs-block are looked up by their start, which an index collected with `--sample-bytes` of the block size covers:
+/// only those caching the start of an indexed fs-block are found.
+fn match_content(
+    index: &Index,
+    device: &MappedFile,
+    layout: &Layout,
+    blocks: &[usize],
+) -> io::Result<HashMap<usize, ContentMatch>> {
+    let block_size = layout.block_size;
+    let sample = index.sample_bytes().unwrap_or(BLOCK_SIZE);
+    if block_size < sample {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the index digests {} bytes per fs-block, more than a {} byte writecache block; collect it with \
+                 --sample-bytes {}",
+                sample, block_size, block_size
+            ),
+        ));
+    }
+    let digest_len = index.digest_len();
+    let pieces = (block_size / BLOCK_SIZE).max(1);
+    let mut wanted: HashMap<Vec<u8>, Vec<(usize, usize)>> = HashMap::new();
+    let mut looked_up: HashMap<usize, usize> = HashMap::new();
+    for (done, &block) in blocks.iter().enumerate() {
+        log_status(done, blocks.len(), "blocks", false);
+        let data = device.slice(layout.data_offset + block * block_size, block_size);
+        let starts: Vec<usize> = (0..pieces)
+            .map(|piece| piece * BLOCK_SIZE)
+            .filter(|&start| data[start..start + sample].iter().any(|&byte| byte != 0))
+            .collect();
+        let slices: Vec<&[u8]> = starts.iter().map(|&start| &data[start..start + sample]).collect();
+        for (digest, &start) in hash::hash_blocks(&slices).into_iter().zip(&starts) {
+            wanted
+                .entry(digest[..digest_len].to_vec())
+                .or_default()
+                .push((block, start));
+        }
+        looked_up.insert(block, starts.len());
+    }
+    log_complete(blocks.len(), "blocks");
+
+    let mut votes: HashMap<(usize, usize), usize> = HashMap::new();
+    index.for_each(|entry, digest| {
+        for &(block, start) in wanted.get(digest).into_iter().flatten() {
+            let origin_offset = entry * BLOCK_SIZE;
+            if origin_offset >= start && (origin_offset - start).is_multiple_of(block_size) {
+                *votes.entry((block, (origin_offset - start) / block_size)).or_default() += 1;
+            }
+        }
+    })?;
+    let mut found: HashMap<usize, ContentMatch> = HashMap::new();
+    for ((block, origin_block), count) in votes {
+        let best = found.entry(block).or_insert(ContentMatch {
+            origin_block,
+            count: 0,
+            looked_up: looked_up[&block],
+            tied: false,
+        });
+        if count > best.count || (count == best.count && origin_block < best.origin_block) {
+            best.tied = count == best.count;
+            best.origin_block = origin_block;
+            best.count = count;
+        } else if count == best.count {
+            best.tied = true;
+        }
+    }
+    Ok(found)
+}
+
+/// Reads the mapping out of a dm-writecache cache device in SSD mode: every block and the origin block its
+/// metadata entry says it caches, the latest write where several cache the same. Blocks whose entries are damaged,
+/// or all of them when the superblock is, are matched by content against an index instead. The output is in the
+/// format `apply` reads, in writecache blocks counted from where the data starts.
+pub fn writecache(cache_device_path: &Path, options: &WritecacheOptions) -> io::Result<()> {
+    inuse::ensure_unused(&[cache_device_path], options.force)?;
+    let device = open_device(cache_device_path, options.partition)?;
+    let index = options.index.map(Index::open).transpose()?;
+    let origin_size = options
+        .origin_size
+        .or_else(|| index.as_ref().and_then(Index::origin).map(|(size, _)| size));
+
+    let (layout, intact) = match Layout::parse(&device) {
+        Ok(layout) => {
+            eprintln!(
+                "dm-writecache: {} blocks of {} bytes from byte {}, last commit {}",
+                layout.blocks, layout.block_size, layout.data_offset, layout.seq_count
+            );
+            (layout, true)
+        }
+        Err(e) => {
+            let layout = Layout::guess(device.size(), options.block_size);
+            eprintln!(
+                "{}: {}; assuming {} blocks of {} bytes from byte {}, matched by content",
+                cache_device_path.display(),
+                e,
+                layout.blocks,
+                layout.block_size,
+                layout.data_offset
+            );
+            (layout, false)
+        }
+    };
+    provenance::record_setting("block_size", layout.block_size);
+    provenance::record_setting("data_offset", layout.data_offset);
+    let entries = if !intact || options.content_only {
+        let reason = if intact {
+            "left aside for --content-only"
+        } else {
+            "superblock damaged"
+        };
+        (0..layout.blocks).map(|_| Entry::Damaged(reason)).collect()
+    } else {
+        let entries = read_entries(&device, &layout, origin_size);
+        for (block, entry) in entries.iter().enumerate() {
+            if let Entry::Damaged(reason) = entry {
+                let detail = format!("writecache metadata entry: {}", reason);
+                block_errors::record(Kind::Format, cache_device_path, block, layout.block_size, detail);
+            }
+        }
+        entries
+    };
+
+    let damaged: Vec<usize> = (0..entries.len())
+        .filter(|&block| matches!(entries[block], Entry::Damaged(_)))
+        .collect();
+    let content = match &index {
+        Some(index) if !damaged.is_empty() => match_content(index, &device, &layout, &damaged)?,
+        _ => HashMap::new(),
+    };
+
+    let mut out = Output::create(options.output)?;
+    provenance::write_comments(&mut out)?;
+    writeln!(
+        out,
+        "# dm-writecache blocks of {} bytes from byte {}: apply with --cache-block-size {}B --data-offset {}",
+        layout.block_size, layout.data_offset, layout.block_size, layout.data_offset
+    )?;
+    let (mut mapped, mut free, mut matched) = (0, 0, 0);
+    let mut reasons: HashMap<&str, usize> = HashMap::new();
+    for (block, entry) in entries.iter().enumerate() {
+        match entry {
+            Entry::Free => free += 1,
+            Entry::Mapped {
+                origin_block,
+                seq_count,
+            } => {
+                writeln!(
+                    out,
+                    "{} -> {} # writecache metadata, seq {}",
+                    block, origin_block, seq_count
+                )?;
+                mapped += 1;
+            }
+            Entry::Damaged(reason) => {
+                *reasons.entry(reason).or_default() += 1;
+                let Some(found) = content.get(&block) else {
+                    writeln!(out, "#{} unmatched, entry {}", block, reason)?;
+                    continue;
+                };
+                writeln!(
+                    out,
+                    "{} -> {} # content match, {} of {} fs-blocks{}, entry {}",
+                    block,
+                    found.origin_block,
+                    found.count,
+                    found.looked_up,
+                    if found.tied { " [tied]" } else { "" },
+                    reason
+                )?;
+                matched += 1;
+            }
+        }
+    }
+    out.commit()?;
+
+    eprintln!(
+        "\n{} blocks mapped by the metadata, {} free or superseded, {} left to match by content",
+        mapped,
+        free,
+        damaged.len()
+    );
+    let mut reasons: Vec<_> = reasons.into_iter().collect();
+    reasons.sort_unstable();
+    for (reason, count) in reasons {
+        eprintln!("  {:>8} {}", count, reason);
+    }
+    if !damaged.is_empty() {
+        match &index {
+            Some(_) => eprintln!("{} of them matched", matched),
+            None => eprintln!("give --index to match them by content"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::io;
+
+use crate::MappedFile;
+
+const XFS_SB_MAGIC: u32 = 0x5846_5342; // "XFSB"
+const XFS_AGF_MAGIC: u32 = 0x5841_4746; // "XAGF"
+const XFS_ABTB_MAGIC: u32 = 0x4142_5442; // "ABTB", v4 free space by block
+const XFS_ABTB_CRC_MAGIC: u32 = 0x4142_3342; // "AB3B", v5 free space by block
+
+const SHORT_BTREE_HEADER: usize = 16;
+const SHORT_BTREE_CRC_HEADER: usize = 56;
+const MAX_BTREE_LEVELS: u32 = 8;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("xfs: {}", msg))
+}
+
+fn read(device: &MappedFile, offset: usize, len: usize) -> io::Result<&[u8]> {
+    if offset.checked_add(len).is_none_or(|end| end > device.size()) {
+        return Err(invalid(format!(
+            "read of {} bytes at {} is past the device end",
+            len, offset
+        )));
+    }
+    Ok(device.slice(offset, len))
+}
+
+fn be16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+struct Geometry {
+    block_size: usize,
+    sector_size: usize,
+    ag_blocks: usize,
+    ag_count: usize,
+}
+
+impl Geometry {
+    fn read(device: &MappedFile) -> io::Result<Self> {
+        let sb = read(device, 0, 512)?;
+        if be32(sb, 0) != XFS_SB_MAGIC {
+            return Err(invalid("no superblock found at offset 0".to_string()));
+        }
+        let geometry = Self {
+            block_size: be32(sb, 4) as usize,
+            sector_size: be16(sb, 102) as usize,
+            ag_blocks: be32(sb, 84) as usize,
+            ag_count: be32(sb, 88) as usize,
+        };
+        if !geometry.block_size.is_power_of_two() || geometry.block_size < 512 || geometry.sector_size < 512 {
+            return Err(invalid(format!(
+                "implausible geometry (block size {}, sector size {})",
+                geometry.block_size, geometry.sector_size
+            )));
+        }
+        Ok(geometry)
+    }
+
+    fn ag_offset(&self, ag: usize) -> usize {
+        ag * self.ag_blocks * self.block_size
+    }
+}
+
+/// Byte ranges of what xfs_repair cannot do without: the superblock and AG headers at the start of every
+/// allocation group, and an internal log.
+pub fn critical_ranges(device: &MappedFile) -> io::Result<Vec<(usize, usize)>> {
+    let geometry = Geometry::read(device)?;
+    // The superblock, AGF, AGI and AGFL take a sector each.
+    let mut ranges: Vec<(usize, usize)> = (0..geometry.ag_count)
+        .map(|ag| {
+            (
+                geometry.ag_offset(ag),
+                geometry.ag_offset(ag) + 4 * geometry.sector_size,
+            )
+        })
+        .collect();
+    let sb = read(device, 0, 512)?;
+    let log_start = be64(sb, 48);
+    let log_blocks = be32(sb, 96) as usize;
+    // Filesystem block numbers keep the allocation group in the bits above sb_agblklog.
+    let ag_block_log = sb[124] as u32;
+    if log_start != 0 && ag_block_log < 64 {
+        let ag = (log_start >> ag_block_log) as usize;
+        let ag_block = (log_start & ((1 << ag_block_log) - 1)) as usize;
+        let start = geometry.ag_offset(ag) + ag_block * geometry.block_size;
+        ranges.push((start, start + log_blocks * geometry.block_size));
+    }
+    Ok(ranges)
+}
+
+/// Byte ranges of the origin that the filesystem considers unallocated.
+#[derive(Debug)]
+pub struct FreeSpace {
+    extents: Vec<(usize, usize)>,
+}
+
+impl FreeSpace {
+    /// Walks the by-block free space btree of every allocation group.
+    pub fn read(device: &MappedFile) -> io::Result<Self> {
+        let geometry = Geometry::read(device)?;
+        let mut extents = Vec::new();
+
+        for ag in 0..geometry.ag_count {
+            let agf = read(device, geometry.ag_offset(ag) + geometry.sector_size, 64)?;
+            if be32(agf, 0) != XFS_AGF_MAGIC {
+                return Err(invalid(format!("bad AGF magic in allocation group {}", ag)));
+            }
+            let root = be32(agf, 16) as usize;
+            let levels = be32(agf, 28);
+            if levels == 0 || levels > MAX_BTREE_LEVELS {
+                return Err(invalid(format!(
+                    "bad btree depth {} in allocation group {}",
+                    levels, ag
+                )));
+            }
+            walk_btree(device, &geometry, ag, root, levels - 1, &mut extents)?;
+        }
+
+        extents.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(extents.len());
+        for (start, end) in extents {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ok(Self { extents: merged })
+    }
+
+    /// Whether the whole range `offset..offset + len` is unallocated.
+    pub fn contains(&self, offset: usize, len: usize) -> bool {
+        let idx = self.extents.partition_point(|&(_, end)| end <= offset);
+        match self.extents.get(idx) {
+            Some(&(start, end)) => start <= offset && offset + len <= end,
+            None => false,
+        }
+    }
+
+    pub fn extent_count(&self) -> usize {
+        self.extents.len()
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.extents.iter().map(|(start, end)| end - start).sum()
+    }
+}
+
+fn walk_btree(
+    device: &MappedFile,
+    geometry: &Geometry,
+    ag: usize,
+    agbno: usize,
+    level: u32,
+    extents: &mut Vec<(usize, usize)>,
+) -> io::Result<()> {
+    let ag_offset = geometry.ag_offset(ag);
+    let block = read(device, ag_offset + agbno * geometry.block_size, geometry.block_size)?;
+    let header = match be32(block, 0) {
+        XFS_ABTB_MAGIC => SHORT_BTREE_HEADER,
+        XFS_ABTB_CRC_MAGIC => SHORT_BTREE_CRC_HEADER,
+        magic => {
+            return Err(invalid(format!(
+                "bad free space btree magic {:#010x} at AG {} block {}",
+                magic, ag, agbno
+            )))
+        }
+    };
+    if be16(block, 4) as u32 != level {
+        return Err(invalid(format!("unexpected btree level at AG {} block {}", ag, agbno)));
+    }
+    let records = be16(block, 6) as usize;
+
+    if level == 0 {
+        if header + records * 8 > block.len() {
+            return Err(invalid(format!("too many records at AG {} block {}", ag, agbno)));
+        }
+        for record in 0..records {
+            let start = be32(block, header + record * 8) as usize;
+            let count = be32(block, header + record * 8 + 4) as usize;
+            let offset = ag_offset + start * geometry.block_size;
+            extents.push((offset, offset + count * geometry.block_size));
+        }
+        return Ok(());
+    }
+
+    let max_records = (geometry.block_size - header) / 12;
+    if records > max_records {
+        return Err(invalid(format!("too many keys at AG {} block {}", ag, agbno)));
+    }
+    let pointers = header + max_records * 8;
+    for record in 0..records {
+        let child = be32(block, pointers + record * 4) as usize;
+        walk_btree(device, geometry, ag, child, level - 1, extents)?;
+    }
+    Ok(())
+}
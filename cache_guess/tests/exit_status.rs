@@ -0,0 +1,94 @@
+//! The exit status contract: 0 for a clean run, 1 for one that stopped on an error, and 3 for one that went through
+//! but skipped blocks over errors, which `--error-report` then lists as JSON.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const CACHE_BLOCK: usize = 64 * 1024;
+const INDEX_BLOCK: usize = 8 * 1024;
+
+fn cache_guess(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cache_guess"))
+        .current_dir(dir)
+        .arg("--quiet")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// An origin of 64 cache blocks of data that does not repeat, a cache holding origin blocks 2 and 60, and an index
+/// of the origin, whose entries span two index blocks after the header.
+fn fixture(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cache_guess-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let origin: Vec<u8> = (0..64 * CACHE_BLOCK)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(dir.join("origin.img"), &origin).unwrap();
+    let cache = [
+        &origin[2 * CACHE_BLOCK..3 * CACHE_BLOCK],
+        &origin[60 * CACHE_BLOCK..61 * CACHE_BLOCK],
+    ]
+    .concat();
+    fs::write(dir.join("cache.img"), cache).unwrap();
+    let collect = cache_guess(&dir, &["collect", "origin.idx", "origin.img"]);
+    assert!(collect.status.success(), "{}", String::from_utf8_lossy(&collect.stderr));
+    dir
+}
+
+fn find(dir: &Path, extra: &[&str]) -> Output {
+    let mut args = vec!["find", "origin.idx", "cache.img", "--cache-block-size", "64K"];
+    args.extend(extra);
+    cache_guess(dir, &args)
+}
+
+#[test]
+fn clean_run_exits_0() {
+    let dir = fixture("clean");
+    let found = find(&dir, &["--error-report", "errors.json"]);
+    assert_eq!(found.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&found.stdout);
+    assert!(
+        stdout.contains("\n0 -> 2 ") && stdout.contains("\n1 -> 60 "),
+        "{}",
+        stdout
+    );
+    assert!(!dir.join("errors.json").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupt_index_block_exits_1_or_with_skipping_3() {
+    let dir = fixture("corrupt");
+    let index = dir.join("origin.idx");
+    let mut data = fs::read(&index).unwrap();
+    data[2 * INDEX_BLOCK + 100] ^= 0xff;
+    fs::write(&index, data).unwrap();
+
+    let stopped = find(&dir, &[]);
+    assert_eq!(stopped.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&stopped.stderr);
+    assert!(stderr.contains("checksum mismatch in index block 2"), "{}", stderr);
+
+    let skipped = find(&dir, &["--skip-corrupt-index-blocks", "--error-report", "errors.json"]);
+    assert_eq!(skipped.status.code(), Some(3));
+    // The entries of the first index block still match; those of origin block 60 were in the corrupt one.
+    let stdout = String::from_utf8_lossy(&skipped.stdout);
+    assert!(stdout.contains("\n0 -> 2 ") && !stdout.contains("-> 60 "), "{}", stdout);
+    let report = fs::read_to_string(dir.join("errors.json")).unwrap();
+    assert!(report.starts_with("{\"command\": "), "{}", report);
+    let counts = "\"errors\": 1, \"by_kind\": {\"io-read\": 0, \"io-write\": 0, \"checksum\": 1, \"format\": 0}";
+    assert!(report.contains(counts), "{}", report);
+    let block = "{\"kind\": \"checksum\", \"device\": \"origin.idx\", \"block\": 2, \"block_size\": 8192, \
+                 \"detail\": \"index block checksum mismatch\"}";
+    assert!(report.contains(block), "{}", report);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,124 @@
+//! A cache device that ends in a partial cache block: its match is reported, but no mapping reader takes it for a
+//! block that apply or merge could copy whole.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const CACHE_BLOCK: usize = 64 * 1024;
+
+fn cache_guess(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cache_guess"))
+        .current_dir(dir)
+        .arg("--quiet")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// An origin of 16 cache blocks of data that does not repeat and a cache holding origin block 2 and then the first
+/// half of origin block 9, so it is one and a half cache blocks long.
+fn fixture(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cache_guess-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let origin: Vec<u8> = (0..16 * CACHE_BLOCK)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(dir.join("origin.img"), &origin).unwrap();
+    let cache = [
+        &origin[2 * CACHE_BLOCK..3 * CACHE_BLOCK],
+        &origin[9 * CACHE_BLOCK..9 * CACHE_BLOCK + CACHE_BLOCK / 2],
+    ]
+    .concat();
+    fs::write(dir.join("cache.img"), cache).unwrap();
+    let collect = cache_guess(&dir, &["collect", "origin.idx", "origin.img"]);
+    assert!(collect.status.success(), "{}", String::from_utf8_lossy(&collect.stderr));
+    dir
+}
+
+fn run(dir: &Path, args: &[&str]) -> String {
+    let output = cache_guess(dir, args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn partial_last_cache_block_is_reported_but_not_mapped() {
+    let dir = fixture("partial-block");
+    let find = ["find", "origin.idx", "cache.img", "--cache-block-size", "64K"];
+    let summary = run(&dir, &[&find[..], &["--output", "mapping.txt"]].concat());
+    assert!(summary.contains("1 of 2 cache blocks mapped"), "{}", summary);
+    let text = fs::read_to_string(dir.join("mapping.txt")).unwrap();
+    assert!(text.contains("\n0 -> 2 ") && text.contains("\n#1 -> 9 "), "{}", text);
+    assert!(
+        text.contains("\n#1 partial cache block, 4 of 8 fs-blocks before the device end\n"),
+        "{}",
+        text
+    );
+    assert!(!text.lines().any(|line| line.starts_with("1 -> ")), "{}", text);
+
+    run(
+        &dir,
+        &[&find[..], &["--format", "jsonl", "--output", "mapping.jsonl"]].concat(),
+    );
+    run(
+        &dir,
+        &[&find[..], &["--format", "csv", "--output", "mapping.csv"]].concat(),
+    );
+    let csv = fs::read_to_string(dir.join("mapping.csv")).unwrap();
+    assert!(csv.contains("\n1,9,50.000,") && csv.ends_with(",short\n"), "{}", csv);
+
+    let check = [
+        "--cache-device",
+        "cache.img",
+        "--index",
+        "origin.idx",
+        "--cache-block-size",
+        "64K",
+    ];
+    for mapping in ["mapping.txt", "mapping.jsonl", "mapping.csv"] {
+        let extents = run(&dir, &["export", mapping, "--cache-block-size", "64K"]);
+        assert_eq!(
+            extents, "cache_start,cache_end,origin_start,origin_end\n0,128,256,384\n",
+            "{}",
+            mapping
+        );
+        let checked = cache_guess(&dir, &[&["check-mapping", mapping][..], &check].concat());
+        assert!(checked.status.success());
+        let checked = String::from_utf8_lossy(&checked.stderr);
+        assert!(
+            checked.contains("1 entries in 1 extents, 0 tied, 0 problems"),
+            "{}: {}",
+            mapping,
+            checked
+        );
+    }
+
+    // Only cache block 0 is copied; apply neither stops at cache block 1 nor writes origin block 9.
+    fs::write(dir.join("target.img"), vec![0; 16 * CACHE_BLOCK]).unwrap();
+    let apply = [
+        "apply",
+        "mapping.txt",
+        "cache.img",
+        "target.img",
+        "--cache-block-size",
+        "64K",
+        "--yes",
+    ];
+    run(&dir, &apply);
+    let origin = fs::read(dir.join("origin.img")).unwrap();
+    let target = fs::read(dir.join("target.img")).unwrap();
+    assert_eq!(
+        target[2 * CACHE_BLOCK..3 * CACHE_BLOCK],
+        origin[2 * CACHE_BLOCK..3 * CACHE_BLOCK]
+    );
+    assert!(target[9 * CACHE_BLOCK..10 * CACHE_BLOCK].iter().all(|&byte| byte == 0));
+    fs::remove_dir_all(dir).unwrap();
+}
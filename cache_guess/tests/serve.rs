@@ -0,0 +1,127 @@
+//! A front-end driving apply through `serve`: the confirmation apply asks before writing shows in `progress`, and
+//! `answer` gives it the device name.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CACHE_BLOCK: usize = 64 * 1024;
+
+/// Kills the server when the test ends, however it does.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+struct Client {
+    stream: UnixStream,
+    responses: BufReader<UnixStream>,
+    id: usize,
+}
+
+impl Client {
+    fn connect(socket: &Path) -> Self {
+        let started = Instant::now();
+        let stream = loop {
+            match UnixStream::connect(socket) {
+                Ok(stream) => break stream,
+                Err(e) if started.elapsed() > Duration::from_secs(10) => panic!("{}: {}", socket.display(), e),
+                Err(_) => thread::sleep(Duration::from_millis(20)),
+            }
+        };
+        Self {
+            responses: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+            id: 0,
+        }
+    }
+
+    fn call(&mut self, method: &str, params: &str) -> String {
+        self.id += 1;
+        let request = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"{}\",\"params\":{}}}",
+            self.id, method, params
+        );
+        writeln!(self.stream, "{}", request).unwrap();
+        let mut response = String::new();
+        self.responses.read_line(&mut response).unwrap();
+        response
+    }
+
+    /// Polls `progress` until `done` holds for its response.
+    fn wait_for(&mut self, job: &str, done: impl Fn(&str) -> bool) -> String {
+        let started = Instant::now();
+        loop {
+            let progress = self.call("progress", &format!("{{\"job\":{}}}", job));
+            if done(&progress) {
+                return progress;
+            }
+            assert!(started.elapsed() < Duration::from_secs(30), "{}", progress);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+#[test]
+fn apply_confirmation_is_shown_and_answered() {
+    let dir = std::env::temp_dir().join(format!("cache_guess-serve-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let origin: Vec<u8> = (0..4 * CACHE_BLOCK).map(|i| (i * 7 / 4096 + i / 251) as u8).collect();
+    fs::write(dir.join("origin.img"), &origin).unwrap();
+    fs::write(dir.join("cache.img"), &origin[2 * CACHE_BLOCK..3 * CACHE_BLOCK]).unwrap();
+    fs::write(dir.join("target.img"), vec![0; 4 * CACHE_BLOCK]).unwrap();
+    fs::write(dir.join("mapping.txt"), "0 -> 2 (100.000% match, 1.000 confidence)\n").unwrap();
+
+    let socket = dir.join("serve.sock");
+    let _server = Server(
+        Command::new(env!("CARGO_BIN_EXE_cache_guess"))
+            .current_dir(&dir)
+            .args(["serve", "serve.sock"])
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let mut client = Client::connect(&socket);
+    let args = "[\"apply\",\"mapping.txt\",\"cache.img\",\"target.img\",\"--cache-block-size\",\"64K\",\"--in-place\"]";
+    let started = client.call("start", &format!("{{\"args\":{}}}", args));
+    assert!(started.contains("\"result\":{\"job\":1}"), "{}", started);
+
+    let waiting = client.wait_for("1", |progress| !progress.contains("\"confirmation\":null"));
+    assert!(waiting.contains("\"running\":true"), "{}", waiting);
+    assert!(
+        waiting.contains("\"confirmation\":[\"about to write to target.img"),
+        "{}",
+        waiting
+    );
+    assert!(waiting.contains("type target.img to write to it"), "{}", waiting);
+    assert!(waiting.contains("\"conflict\":null"), "{}", waiting);
+    assert_eq!(fs::read(dir.join("target.img")).unwrap(), vec![0; 4 * CACHE_BLOCK]);
+
+    let answered = client.call("answer", "{\"job\":1,\"answer\":\"target.img\"}");
+    assert!(answered.contains("\"result\":true"), "{}", answered);
+    let finished = client.wait_for("1", |progress| progress.contains("\"running\":false"));
+    assert!(finished.contains("\"exit_code\":0"), "{}", finished);
+    let target = fs::read(dir.join("target.img")).unwrap();
+    assert_eq!(
+        target[2 * CACHE_BLOCK..3 * CACHE_BLOCK],
+        origin[2 * CACHE_BLOCK..3 * CACHE_BLOCK]
+    );
+
+    let again = client.call("answer", "{\"job\":1,\"answer\":\"target.img\"}");
+    assert!(
+        again.contains("not waiting on a conflict or a confirmation"),
+        "{}",
+        again
+    );
+    drop(client);
+    fs::remove_dir_all(dir).unwrap();
+}